@@ -0,0 +1,154 @@
+//! `--calibre-manifest`: a folder Calibre can import with titles, authors and series intact.
+//!
+//! Every book gets its own folder holding the decrypted file (hard-linked when the
+//! filesystem allows, copied otherwise) and a `metadata.opf`, the layout Calibre's
+//! "Add books from directories... one book per directory" reads. `manifest.csv` and
+//! `manifest.json` list the same books with the paths of the real output files, for
+//! scripts driving `calibredb add`.
+
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::types::sanitize_path_component;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CalibreBook {
+    pub id: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub series: Option<String>,
+    /// The decrypted file in the output directory
+    pub path: PathBuf,
+}
+
+#[derive(Serialize)]
+struct ManifestRow<'a> {
+    #[serde(flatten)]
+    book: &'a CalibreBook,
+    opf: PathBuf,
+}
+
+/// Writes the import folder for `books` into `dir` and returns how many books it holds.
+pub fn write_manifest(dir: &Path, books: &[CalibreBook]) -> io::Result<usize> {
+    fs::create_dir_all(dir)?;
+
+    let mut rows = Vec::new();
+    for book in books {
+        let folder_name = if book.title == book.id {
+            book.id.clone()
+        } else {
+            format!("{} ({})", book.title, book.id)
+        };
+        let folder = dir.join(sanitize_path_component(&folder_name).unwrap_or_else(|| book.id.clone()));
+        fs::create_dir_all(&folder)?;
+
+        let opf = folder.join("metadata.opf");
+        fs::write(&opf, render_opf(book))?;
+
+        // Replace any link from an earlier run, which may point at an older decryption
+        if let Some(file_name) = book.path.file_name() {
+            let target = folder.join(file_name);
+            let _ = fs::remove_file(&target);
+            fs::hard_link(&book.path, &target)
+                .or_else(|_| fs::copy(&book.path, &target).map(|_| ()))?;
+        }
+
+        rows.push(ManifestRow { book, opf });
+    }
+
+    fs::write(dir.join("manifest.json"), serde_json::to_string_pretty(&rows)?)?;
+    fs::write(dir.join("manifest.csv"), render_csv(&rows))?;
+    Ok(rows.len())
+}
+
+// An OPF 2.0 package with just the metadata Calibre reads from metadata.opf
+fn render_opf(book: &CalibreBook) -> String {
+    let mut metadata = vec![
+        format!("<dc:identifier opf:scheme=\"RIDI\" id=\"ridi_id\">{}</dc:identifier>", escape_xml(&book.id)),
+        format!("<dc:title>{}</dc:title>", escape_xml(&book.title)),
+    ];
+    if let Some(author) = &book.author {
+        metadata.push(format!("<dc:creator opf:role=\"aut\">{}</dc:creator>", escape_xml(author)));
+    }
+    if let Some(series) = &book.series {
+        metadata.push(format!("<meta name=\"calibre:series\" content=\"{}\"/>", escape_xml(series)));
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" unique-identifier=\"ridi_id\" version=\"2.0\">\n\
+         \x20   <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:opf=\"http://www.idpf.org/2007/opf\">\n\
+         {}\
+         \x20   </metadata>\n\
+         </package>\n",
+        metadata.iter().map(|line| format!("        {}\n", line)).collect::<String>()
+    )
+}
+
+fn render_csv(rows: &[ManifestRow]) -> String {
+    let mut csv = String::from("id,title,authors,series,path,opf\n");
+    for row in rows {
+        let fields = [
+            row.book.id.as_str(),
+            row.book.title.as_str(),
+            row.book.author.as_deref().unwrap_or_default(),
+            row.book.series.as_deref().unwrap_or_default(),
+            &row.book.path.to_string_lossy(),
+            &row.opf.to_string_lossy(),
+        ];
+        let line: Vec<_> = fields.iter().map(|field| format!("\"{}\"", field.replace('"', "\"\""))).collect();
+        csv.push_str(&line.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_manifest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("Re_Zero Vol. 1.epub");
+        fs::write(&output, b"decrypted").unwrap();
+
+        let books = [CalibreBook {
+            id: "1234567890".to_string(),
+            title: "Re:Zero \"Vol. 1\" & more".to_string(),
+            author: Some("Tappei Nagatsuki".to_string()),
+            series: Some("Re:Zero".to_string()),
+            path: output.clone(),
+        }];
+        let manifest_dir = temp_dir.path().join("calibre");
+        assert_eq!(write_manifest(&manifest_dir, &books).unwrap(), 1);
+
+        let folder = manifest_dir.join("Re_Zero _Vol. 1_ & more (1234567890)");
+        let opf = fs::read_to_string(folder.join("metadata.opf")).unwrap();
+        assert!(opf.contains("<dc:title>Re:Zero &quot;Vol. 1&quot; &amp; more</dc:title>"));
+        assert!(opf.contains("<dc:creator opf:role=\"aut\">Tappei Nagatsuki</dc:creator>"));
+        assert!(opf.contains("<meta name=\"calibre:series\" content=\"Re:Zero\"/>"));
+        assert_eq!(fs::read(folder.join("Re_Zero Vol. 1.epub")).unwrap(), b"decrypted");
+
+        // The manifests point at the real output file
+        let csv = fs::read_to_string(manifest_dir.join("manifest.csv")).unwrap();
+        assert!(csv.starts_with("id,title,authors,series,path,opf\n"));
+        assert!(csv.contains("\"Re:Zero \"\"Vol. 1\"\" & more\""));
+        assert!(csv.contains(&output.to_string_lossy().to_string()));
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(manifest_dir.join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(json[0]["path"], output.to_string_lossy().as_ref());
+        assert_eq!(json[0]["opf"], folder.join("metadata.opf").to_string_lossy().as_ref());
+
+        // Running again replaces what's there
+        assert_eq!(write_manifest(&manifest_dir, &books).unwrap(), 1);
+    }
+}
@@ -4,13 +4,86 @@ use serde_json::Value;
 use std::fs;
 use std::path::PathBuf;
 use std::time::Duration;
+#[cfg(any(target_os = "macos", target_os = "windows"))]
 use std::process::Command;
 use aes::cipher::{BlockDecrypt, KeyInit};
+#[cfg(any(target_os = "macos", target_os = "windows"))]
 use base64::Engine;
 use sha1::{Sha1, Digest};
 
 pub struct CredentialManager {
     client: Client,
+    cache_path: Option<PathBuf>,
+    devices_url: String,
+    max_retries: u32,
+}
+
+/// How requests to the RIDI API are made
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub user_agent: String,
+    /// Time limit for each request
+    pub timeout: Duration,
+    /// Proxy URL (e.g. `http://proxy:3128`) used instead of `HTTPS_PROXY`/`HTTP_PROXY`
+    pub proxy: Option<String>,
+    /// PEM certificates trusted on top of the system ones, for proxies that intercept TLS
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            user_agent: concat!("ridiculous/", env!("CARGO_PKG_VERSION")).to_string(),
+            timeout: Duration::from_secs(10),
+            proxy: None,
+            ca_cert: None,
+        }
+    }
+}
+
+const DEVICES_API_URL: &str = "https://account.ridibooks.com/api/user-devices/app";
+
+// Delay before the first retry of a failed API call; doubles with each attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+// How long a validation verdict from the RIDI API is reused
+const VALIDATION_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+// A cached verdict, keyed by a SHA-256 of the credentials so they never hit the disk
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct CachedVerdict {
+    checked_at: u64, // seconds since the Unix epoch
+    error: Option<String>,
+}
+
+/// One device registered to a RIDI account, as listed by the devices API
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    /// The nickname shown in RIDI's device management page
+    pub name: Option<String>,
+    /// e.g. `PC`, `ANDROID`, `IOS`
+    pub device_type: Option<String>,
+    pub last_used: Option<String>,
+}
+
+impl DeviceInfo {
+    // Entries without a device_id are skipped; the other fields are best effort since
+    // the API isn't documented
+    fn from_json(entry: &Value) -> Option<Self> {
+        let text = |keys: &[&str]| keys.iter().find_map(|key| match entry.get(*key)? {
+            Value::String(text) if !text.trim().is_empty() => Some(text.trim().to_string()),
+            Value::Number(number) => Some(number.to_string()),
+            _ => None,
+        });
+
+        Some(Self {
+            device_id: text(&["device_id"])?,
+            name: text(&["device_nick", "name"]),
+            device_type: text(&["device_code", "type"]),
+            last_used: text(&["last_used", "last_used_at"]),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -19,15 +92,82 @@ pub struct RidiCredentials {
     pub user_idx: u64,
 }
 
+impl Default for CredentialManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CredentialManager {
     pub fn new() -> Self {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(10))
-            .user_agent("ridiculous/0.3.5")
-            .build()
-            .expect("Failed to create HTTP client");
+        let client = Self::build_client(&ClientOptions::default()).expect("Failed to create HTTP client");
+
+        let cache_path = dirs::cache_dir()
+            .map(|dir| dir.join("ridiculous_credential_cache.json"));
+
+        Self {
+            client,
+            cache_path,
+            devices_url: DEVICES_API_URL.to_string(),
+            max_retries: crate::types::Config::default().max_retries,
+        }
+    }
+
+    /// Total attempts for an API call that fails with a 5xx or a connection error
+    /// (0 falls back to the default, as for book decryption)
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = if max_retries == 0 {
+            crate::types::Config::default().max_retries
+        } else {
+            max_retries
+        };
+        self
+    }
+
+    /// Make API requests with a different user agent, timeout, proxy or root certificates
+    pub fn with_options(mut self, options: &ClientOptions) -> Result<Self> {
+        self.client = Self::build_client(options)?;
+        Ok(self)
+    }
+
+    // reqwest already honors HTTPS_PROXY, HTTP_PROXY and NO_PROXY; an explicit proxy replaces them
+    fn build_client(options: &ClientOptions) -> Result<Client> {
+        let mut builder = Client::builder()
+            .timeout(options.timeout)
+            .user_agent(options.user_agent.as_str());
 
-        Self { client }
+        if let Some(proxy) = &options.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .with_context(|| format!("❌ Invalid proxy URL: {}\n💡 Use a URL like http://proxy.example.com:3128", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(ca_cert) = &options.ca_cert {
+            let pem = fs::read(ca_cert)
+                .with_context(|| format!("❌ Could not read CA certificate {}", ca_cert.display()))?;
+            let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("❌ {} is not a PEM certificate bundle", ca_cert.display()))?;
+            if certificates.is_empty() {
+                return Err(anyhow::anyhow!("❌ No certificates found in {}", ca_cert.display()));
+            }
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        builder.build().context("❌ Failed to create HTTP client")
+    }
+
+    /// Point validation at a different endpoint (used by tests)
+    pub fn with_devices_url(mut self, devices_url: impl Into<String>) -> Self {
+        self.devices_url = devices_url.into();
+        self
+    }
+
+    /// Use a different validation cache file, or `None` to disable caching
+    pub fn with_cache_path(mut self, cache_path: Option<PathBuf>) -> Self {
+        self.cache_path = cache_path;
+        self
     }
 
     /// Extracts device_id and user_idx from the Ridibooks Sentry scope file
@@ -85,7 +225,7 @@ impl CredentialManager {
                 .and_then(|d| d.get("url"))
                 .and_then(|v| v.as_str());
 
-            if category == Some("http") && url.map_or(false, |u| u.contains("reading-data-api.ridibooks.com/progress/positions")) {
+            if category == Some("http") && url.is_some_and(|u| u.contains("reading-data-api.ridibooks.com/progress/positions")) {
                 // Extract device_id from http.query field
                 // Note: The key is literally "http.query" (with a dot in the key name)
                 if let Some(query) = breadcrumb.get("data")
@@ -247,7 +387,7 @@ public class WinCred {
 "#;
 
         let output = Command::new("powershell")
-            .args(&["-NoProfile", "-NonInteractive", "-Command", script])
+            .args(["-NoProfile", "-NonInteractive", "-Command", script])
             .output()
             .context("Failed to run PowerShell command")?;
 
@@ -333,7 +473,7 @@ public class WinCred {
 
         // Process key: UTF-8 encode, PKCS7-pad to next multiple of 16, use first 32 bytes for AES-256
         let key_bytes = key_string.as_bytes();
-        let padded_len = ((key_bytes.len() + 15) / 16) * 16;
+        let padded_len = key_bytes.len().div_ceil(16) * 16;
         let pad_byte = (padded_len - key_bytes.len()) as u8;
         let mut padded_key = key_bytes.to_vec();
         padded_key.resize(padded_len, pad_byte);
@@ -481,45 +621,193 @@ public class WinCred {
         }
     }
 
-    pub async fn validate(&self, device_id: &str, user_idx: &str) -> Result<()> {
-        // Validate input format first
-        if device_id.len() != 36 {
-            return Err(anyhow::anyhow!("Invalid device ID format (expected 36 characters)"));
+    /// Structural checks that need no network: device_id must be a UUID
+    /// (8-4-4-4-12 hex digits) and user_idx a positive integer.
+    pub fn validate_format(device_id: &str, user_idx: &str) -> Result<()> {
+        // The uuid crate also takes the braced, urn and unhyphenated forms, which RIDI doesn't
+        let is_uuid = uuid::Uuid::try_parse(device_id)
+            .is_ok_and(|uuid| uuid.hyphenated().to_string().eq_ignore_ascii_case(device_id));
+        if !is_uuid {
+            return Err(anyhow::anyhow!(
+                "Invalid device ID format: device_id must be a UUID like 12345678-1234-1234-1234-123456789012 (got {} characters)",
+                device_id.chars().count()
+            ));
         }
-        
+
         if user_idx.is_empty() {
             return Err(anyhow::anyhow!("User index cannot be empty"));
         }
-        
-        let url = "https://account.ridibooks.com/api/user-devices/app";
-        
-        let response = self.client
-            .get(url)
-            .header("X-Device-Id", device_id)
-            .header("X-User-Idx", user_idx)
-            .send()
-            .await
-            .context("Failed to connect to RIDI API")?;
+
+        if !user_idx.parse::<u64>().is_ok_and(|idx| idx > 0) {
+            return Err(anyhow::anyhow!("Invalid user index '{}' (expected a positive number)", user_idx));
+        }
+
+        Ok(())
+    }
+
+    /// A credential as typed or pasted, without the surrounding whitespace and quotes
+    /// that often come along when copying it from a web page or JSON.
+    pub fn clean_credential(value: &str) -> String {
+        value.trim().trim_matches(|c| c == '"' || c == '\'').trim().to_string()
+    }
+
+    /// Checks the credentials against the RIDI API. A verdict from the last hour is
+    /// reused unless `force_refresh` is set; connection failures are never cached.
+    pub async fn validate(&self, device_id: &str, user_idx: &str, force_refresh: bool) -> Result<()> {
+        Self::validate_format(device_id, user_idx)?;
+
+        let cache_key = Self::cache_key(device_id, user_idx);
+        if !force_refresh {
+            if let Some(verdict) = self.cached_verdict(&cache_key) {
+                return match verdict.error {
+                    None => Ok(()),
+                    Some(error) => Err(anyhow::anyhow!(error)),
+                };
+            }
+        }
+
+        let verdict = self.query_devices(device_id, user_idx).await?;
+        self.store_verdict(&cache_key, verdict.as_ref().err().cloned());
+
+        verdict.map_err(|error| anyhow::anyhow!(error))
+    }
+
+    /// The devices registered to the account, to check which one `device_id` is
+    pub async fn list_devices(&self, device_id: &str, user_idx: &str) -> Result<Vec<DeviceInfo>> {
+        Self::validate_format(device_id, user_idx)?;
+
+        let devices = self.fetch_devices(device_id, user_idx).await?
+            .map_err(|error| anyhow::anyhow!(error))?;
+        Ok(devices.iter().filter_map(DeviceInfo::from_json).collect())
+    }
+
+    async fn query_devices(&self, device_id: &str, user_idx: &str) -> Result<std::result::Result<(), String>> {
+        Ok(match self.fetch_devices(device_id, user_idx).await? {
+            Ok(devices) if !devices.is_empty() => Ok(()),
+            Ok(_) => Err("No valid devices found for these credentials".to_string()),
+            Err(error) => Err(error),
+        })
+    }
+
+    // The `result` array of the devices API.
+    // Outer error: the API couldn't be reached. Inner error: the API rejected the credentials.
+    // Connection errors and 5xx responses are retried with exponential backoff; 4xx is final.
+    async fn fetch_devices(&self, device_id: &str, user_idx: &str) -> Result<std::result::Result<Vec<Value>, String>> {
+        let mut attempt = 0;
+        let response = loop {
+            attempt += 1;
+            let result = self.client
+                .get(&self.devices_url)
+                .header("X-Device-Id", device_id)
+                .header("X-User-Idx", user_idx)
+                .send()
+                .await;
+
+            let transient_error = match result {
+                Ok(response) if response.status().is_server_error() => {
+                    anyhow::anyhow!("RIDI API is temporarily unavailable: HTTP {}", response.status())
+                }
+                Ok(response) => break response,
+                Err(e) => anyhow::Error::new(e).context("Failed to connect to RIDI API"),
+            };
+
+            if attempt >= self.max_retries {
+                return Err(transient_error);
+            }
+            tokio::time::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt - 1)).await;
+        };
         
         if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Invalid credentials: HTTP {} - Check your device_id and user_idx", 
-                response.status()
-            ));
+            let status = response.status();
+            let explanation = response.text().await.ok()
+                .and_then(|body| Self::api_error_message(&body))
+                .map(|message| format!(" - RIDI says: {}", message))
+                .unwrap_or_default();
+            return Ok(Err(format!(
+                "Invalid credentials: HTTP {}{} - Check your device_id and user_idx",
+                status,
+                explanation
+            )));
         }
         
         let json: Value = response.json().await
             .context("Failed to parse RIDI API response")?;
-        
-        // Check if response contains valid device data
-        if let Some(result) = json.get("result") {
-            if result.as_array().map_or(false, |arr| !arr.is_empty()) {
-                return Ok(());
-            }
+
+        Ok(Ok(json.get("result")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default()))
+    }
+
+    // The `message` and `code` of a JSON error body like {"code": "...", "message": "..."},
+    // possibly nested under "error". Anything else (HTML error pages, empty bodies) gives None.
+    fn api_error_message(body: &str) -> Option<String> {
+        let json: Value = serde_json::from_str(body).ok()?;
+        let error = json.get("error").filter(|error| error.is_object()).unwrap_or(&json);
+
+        let text = |key: &str| match error.get(key)? {
+            Value::String(text) if !text.trim().is_empty() => Some(text.trim().to_string()),
+            Value::Number(number) => Some(number.to_string()),
+            _ => None,
+        };
+        let message = text("message")
+            .or_else(|| text("error_description"))
+            .or_else(|| json.get("error").and_then(Value::as_str).map(str::to_string));
+
+        match (message, text("code")) {
+            (Some(message), Some(code)) => Some(format!("{} ({})", message, code)),
+            (Some(message), None) => Some(message),
+            (None, Some(code)) => Some(code),
+            (None, None) => None,
         }
-        
-        Err(anyhow::anyhow!("No valid devices found for these credentials"))
     }
+
+    fn cache_key(device_id: &str, user_idx: &str) -> String {
+        use sha2::Sha256;
+
+        let mut hasher = Sha256::new();
+        hasher.update(device_id.as_bytes());
+        hasher.update(b":");
+        hasher.update(user_idx.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn load_cache(&self) -> std::collections::HashMap<String, CachedVerdict> {
+        self.cache_path.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn cached_verdict(&self, cache_key: &str) -> Option<CachedVerdict> {
+        let verdict = self.load_cache().remove(cache_key)?;
+        let age = unix_now().saturating_sub(verdict.checked_at);
+        (age < VALIDATION_CACHE_TTL.as_secs()).then_some(verdict)
+    }
+
+    // Best effort: a cache that can't be written just means the next run asks the API again
+    fn store_verdict(&self, cache_key: &str, error: Option<String>) {
+        let Some(cache_path) = &self.cache_path else { return };
+
+        let now = unix_now();
+        let mut cache = self.load_cache();
+        cache.retain(|_, verdict| now.saturating_sub(verdict.checked_at) < VALIDATION_CACHE_TTL.as_secs());
+        cache.insert(cache_key.to_string(), CachedVerdict { checked_at: now, error });
+
+        if let Some(parent) = cache_path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        if let Ok(content) = serde_json::to_string_pretty(&cache) {
+            let _ = fs::write(cache_path, content);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
 }
 
 #[cfg(test)]
@@ -572,7 +860,7 @@ mod tests {
                 .and_then(|d| d.get("url"))
                 .and_then(|v| v.as_str());
 
-            if category == Some("http") && url.map_or(false, |u| u.contains("reading-data-api.ridibooks.com/progress/positions")) {
+            if category == Some("http") && url.is_some_and(|u| u.contains("reading-data-api.ridibooks.com/progress/positions")) {
                 if let Some(query) = breadcrumb.get("data")
                     .and_then(|d| d.get("http.query"))
                     .and_then(|q| q.as_str())
@@ -633,4 +921,248 @@ mod tests {
 
         assert_eq!(found_device_id, None);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_validation_cache() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cache_path = temp_dir.path().join("credential_cache.json");
+        let manager = CredentialManager::new().with_cache_path(Some(cache_path.clone()));
+
+        let device_id = "12345678-1234-1234-1234-123456789012";
+        let key = CredentialManager::cache_key(device_id, "123");
+        manager.store_verdict(&key, Some("No valid devices found for these credentials".to_string()));
+
+        // A fresh verdict is answered from the cache without any network access
+        let error = manager.validate(device_id, "123", false).await.unwrap_err();
+        assert!(error.to_string().contains("No valid devices"));
+
+        // Only the hash and the verdict are written to disk
+        let content = fs::read_to_string(&cache_path).unwrap();
+        assert!(content.contains(&key));
+        assert!(!content.contains(device_id));
+        assert!(!content.contains("\"123\""));
+
+        // Expired verdicts are ignored
+        let mut cache = manager.load_cache();
+        cache.get_mut(&key).unwrap().checked_at -= VALIDATION_CACHE_TTL.as_secs();
+        fs::write(&cache_path, serde_json::to_string(&cache).unwrap()).unwrap();
+        assert!(manager.cached_verdict(&key).is_none());
+    }
+
+    // Serves one canned HTTP response per connection, in order
+    async fn mock_devices_api(responses: Vec<(&'static str, &'static str)>) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/user-devices/app", listener.local_addr().unwrap());
+        let requests = std::sync::Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn test_validate_retries_server_errors() {
+        use std::sync::atomic::Ordering;
+
+        let device_id = "12345678-1234-1234-1234-123456789012";
+        let (url, requests) = mock_devices_api(vec![
+            ("503 Service Unavailable", "{}"),
+            ("503 Service Unavailable", "{}"),
+            ("200 OK", r#"{"result": [{"device_id": "12345678-1234-1234-1234-123456789012"}]}"#),
+        ]).await;
+
+        let manager = CredentialManager::new()
+            .with_cache_path(None)
+            .with_devices_url(url)
+            .with_max_retries(3);
+        manager.validate(device_id, "123", true).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Client errors mean bad credentials and are not retried
+        let (url, requests) = mock_devices_api(vec![
+            ("401 Unauthorized", "{}"),
+            ("200 OK", r#"{"result": [{}]}"#),
+        ]).await;
+
+        let manager = CredentialManager::new()
+            .with_cache_path(None)
+            .with_devices_url(url)
+            .with_max_retries(3);
+        let error = manager.validate(device_id, "123", true).await.unwrap_err();
+        assert!(error.to_string().contains("401"));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_validate_through_proxy() {
+        use std::sync::atomic::Ordering;
+
+        // The mock answers whatever it's asked, so it can stand in for the proxy of a
+        // devices API that isn't reachable directly
+        let (url, requests) = mock_devices_api(vec![
+            ("200 OK", r#"{"result": [{"device_id": "12345678-1234-1234-1234-123456789012"}]}"#),
+        ]).await;
+        let proxy = url.trim_end_matches("/api/user-devices/app");
+
+        let options = ClientOptions { proxy: Some(proxy.to_string()), ..Default::default() };
+        let manager = CredentialManager::new()
+            .with_cache_path(None)
+            .with_devices_url("http://account.ridibooks.invalid/api/user-devices/app")
+            .with_options(&options)
+            .unwrap();
+        manager.validate("12345678-1234-1234-1234-123456789012", "123", true).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let options = ClientOptions { proxy: Some("not a proxy url".to_string()), ..Default::default() };
+        assert!(CredentialManager::new().with_options(&options).is_err());
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let options = ClientOptions { ca_cert: Some(temp_dir.path().join("missing.pem")), ..Default::default() };
+        let error = CredentialManager::new().with_options(&options).err().unwrap();
+        assert!(error.to_string().contains("missing.pem"));
+
+        let empty = temp_dir.path().join("empty.pem");
+        fs::write(&empty, "").unwrap();
+        let options = ClientOptions { ca_cert: Some(empty), ..Default::default() };
+        assert!(CredentialManager::new().with_options(&options).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_custom_client_options() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Records the request and then never answers, so only the timeout ends it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/user-devices/app", listener.local_addr().unwrap());
+        let (sender, received) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            sender.send(String::from_utf8_lossy(&request).to_string()).unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            socket.shutdown().await.ok();
+        });
+
+        let options = ClientOptions {
+            user_agent: "custom-agent/1.0".to_string(),
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let manager = CredentialManager::new()
+            .with_cache_path(None)
+            .with_devices_url(url)
+            .with_max_retries(1)
+            .with_options(&options)
+            .unwrap();
+
+        let started = std::time::Instant::now();
+        assert!(manager.validate("12345678-1234-1234-1234-123456789012", "123", true).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(received.await.unwrap().to_lowercase().contains("user-agent: custom-agent/1.0"));
+
+        let options = ClientOptions { user_agent: "bad\nagent".to_string(), ..Default::default() };
+        assert!(CredentialManager::new().with_options(&options).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_api_error_body_is_surfaced() {
+        let (url, _) = mock_devices_api(vec![
+            ("401 Unauthorized", r#"{"code": "DEVICE_NOT_REGISTERED", "message": "This device is not registered"}"#),
+            ("403 Forbidden", "<html><body>Forbidden</body></html>"),
+        ]).await;
+
+        let manager = CredentialManager::new()
+            .with_cache_path(None)
+            .with_devices_url(url);
+        let device_id = "12345678-1234-1234-1234-123456789012";
+
+        let error = manager.validate(device_id, "123", true).await.unwrap_err().to_string();
+        assert!(error.contains("401"), "{}", error);
+        assert!(error.contains("This device is not registered (DEVICE_NOT_REGISTERED)"), "{}", error);
+
+        // A body that isn't JSON leaves the plain status message
+        let error = manager.validate(device_id, "123", true).await.unwrap_err().to_string();
+        assert!(error.contains("403"), "{}", error);
+        assert!(!error.contains("RIDI says"), "{}", error);
+        assert!(!error.contains("html"), "{}", error);
+
+        assert_eq!(
+            CredentialManager::api_error_message(r#"{"error": {"code": 4011, "message": "Token expired"}}"#).as_deref(),
+            Some("Token expired (4011)")
+        );
+        assert_eq!(
+            CredentialManager::api_error_message(r#"{"error": "invalid_request"}"#).as_deref(),
+            Some("invalid_request")
+        );
+        assert_eq!(CredentialManager::api_error_message(r#"{"result": []}"#), None);
+        assert_eq!(CredentialManager::api_error_message(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_list_devices() {
+        let (url, _) = mock_devices_api(vec![
+            ("200 OK", r#"{"result": [
+                {"device_id": "12345678-1234-1234-1234-123456789012", "device_nick": "Study PC", "device_code": "PC", "last_used": "2024-05-01 10:00:00"},
+                {"device_id": "87654321-4321-4321-4321-210987654321", "device_code": "ANDROID"},
+                {"device_nick": "No id"}
+            ]}"#),
+            ("401 Unauthorized", r#"{"message": "Login required"}"#),
+        ]).await;
+
+        let manager = CredentialManager::new()
+            .with_cache_path(None)
+            .with_devices_url(url);
+        let device_id = "12345678-1234-1234-1234-123456789012";
+
+        let devices = manager.list_devices(device_id, "123").await.unwrap();
+        assert_eq!(devices, [
+            DeviceInfo {
+                device_id: device_id.to_string(),
+                name: Some("Study PC".to_string()),
+                device_type: Some("PC".to_string()),
+                last_used: Some("2024-05-01 10:00:00".to_string()),
+            },
+            DeviceInfo {
+                device_id: "87654321-4321-4321-4321-210987654321".to_string(),
+                name: None,
+                device_type: Some("ANDROID".to_string()),
+                last_used: None,
+            },
+        ]);
+
+        let error = manager.list_devices(device_id, "123").await.unwrap_err();
+        assert!(error.to_string().contains("Login required"));
+    }
+}
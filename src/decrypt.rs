@@ -0,0 +1,1714 @@
+//! Core RIDI decryption routines shared by the CLI and the GUI.
+//!
+//! The decryption functions work on in-memory bytes or plain readers and
+//! writers so they can be tested without touching a RIDI library on disk.
+
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use indicatif::ProgressBar;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use zip::ZipArchive;
+
+use crate::types::{redact, BookFormat, BookInfo, Compress, Config, Recompress};
+
+/// Why a book doesn't need decrypting, as decided by [`should_skip`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// A decrypted copy already exists in the output directory.
+    AlreadyDecryptedOutput,
+    /// The book file in the library is already a readable ZIP, not an encrypted one.
+    PlaintextContainer,
+    /// The book still needs decrypting.
+    None,
+}
+
+impl SkipReason {
+    /// Whether the book can be used without decrypting it
+    pub fn is_skipped(&self) -> bool {
+        *self != SkipReason::None
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            SkipReason::AlreadyDecryptedOutput => "already decrypted in the output directory",
+            SkipReason::PlaintextContainer => "book file is already plaintext",
+            SkipReason::None => "needs decrypting",
+        }
+    }
+}
+
+/// Which RIDI DRM scheme protects a book, detected once in [`BookInfo::new`] and
+/// dispatched on by [`decrypt_for_version`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrmVersion {
+    /// The whole book is a single AES-128-CBC blob.
+    V1,
+    /// A ZIP container whose entries are encrypted one by one.
+    V11,
+    /// A `.v<N>` filename marker for a scheme this version can't decrypt.
+    Unknown(u16),
+}
+
+impl DrmVersion {
+    pub fn from_number(version: u16) -> Self {
+        match version {
+            1 => DrmVersion::V1,
+            11 => DrmVersion::V11,
+            other => DrmVersion::Unknown(other),
+        }
+    }
+
+    /// The version named by the marker in `{id}.v<N>.epub` style filenames, if any
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        filename.split('.')
+            .skip(1)
+            .find_map(|part| part.strip_prefix('v')?.parse().ok())
+            .map(Self::from_number)
+    }
+}
+
+impl std::fmt::Display for DrmVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DrmVersion::V1 => write!(f, "v1"),
+            DrmVersion::V11 => write!(f, "v11"),
+            DrmVersion::Unknown(version) => write!(f, "v{}", version),
+        }
+    }
+}
+
+/// The skip rule both front-ends use, so a book skipped by one is skipped by the other.
+///
+/// An existing output wins over [`SkipReason::PlaintextContainer`], so a plaintext
+/// book that has already been copied to the output directory isn't copied again.
+pub fn should_skip(book: &BookInfo, config: &Config) -> SkipReason {
+    if existing_output(book, config).is_some() {
+        return SkipReason::AlreadyDecryptedOutput;
+    }
+
+    if is_plaintext_container(book) {
+        return SkipReason::PlaintextContainer;
+    }
+
+    SkipReason::None
+}
+
+/// The decrypted copy of `book` in the output directory under its default name, if
+/// there is one. Copies written under other names (e.g. with `organize_output`) aren't found.
+/// A compressed copy counts too, whatever `config.compress` is now, and so does the
+/// extension-less folder `--flatten` unpacks an EPUB or comic into, whatever `config.flatten` is.
+pub fn existing_output(book: &BookInfo, config: &Config) -> Option<PathBuf> {
+    let mut formats = vec![book.format.clone()];
+    // A v1 comic only turns out to be one after decrypting, so it was written as .cbz
+    if book.format == BookFormat::Epub {
+        formats.push(BookFormat::Comic);
+    }
+    let compressions = [config.compress, Compress::None, Compress::Gzip, Compress::Zstd];
+
+    formats.into_iter()
+        .flat_map(|format| {
+            let flattens = matches!(format, BookFormat::Epub | BookFormat::Comic);
+            let book = BookInfo { format, ..book.clone() };
+            let output_path = book.get_output_dir(config).join(book.get_output_filename());
+            let folder = flattens.then(|| output_path.with_extension("")).filter(|folder| folder.is_dir());
+            compressions.map(|compress| compress.apply(output_path.clone()))
+                .into_iter()
+                .filter(|output_path| output_path.is_file())
+                .chain(folder)
+        })
+        .next()
+}
+
+/// True for a DRM-free EPUB: a readable ZIP without the `.v<N>` filename marker.
+/// Running it through AES would destroy it, so callers copy it as-is instead.
+pub fn is_plaintext_container(book: &BookInfo) -> bool {
+    // Plain zips look like v11 containers, so only the filename marker tells a
+    // plaintext .epub apart from an encrypted .v11.epub
+    if book.book_filename.contains(".v") {
+        return false;
+    }
+    // A zipped PDF always needs its PDF taken out, see decrypt_zipped_pdf
+    if book.format == BookFormat::Pdf {
+        return false;
+    }
+
+    std::fs::File::open(book.get_book_file_path())
+        .ok()
+        .and_then(|file| ZipArchive::new(file).ok())
+        .is_some_and(|zip| !zip.is_empty())
+}
+
+const IMAGE_EXTENSIONS: [&str; 7] = [".jpg", ".jpeg", ".png", ".gif", ".webp", ".bmp", ".avif"];
+
+/// Tells an EPUB from a comic by the ZIP's entry names: an OPF package document
+/// makes it an EPUB, otherwise mostly page images make it a [`BookFormat::Comic`].
+/// `None` when `zip` isn't a ZIP or has neither.
+///
+/// Entry names aren't encrypted, so this works on v11 books before decrypting too.
+pub fn detect_zip_format<R: Read + Seek>(zip: R) -> Option<BookFormat> {
+    let zip = ZipArchive::new(zip).ok()?;
+    let names: Vec<_> = zip.file_names()
+        .map(str::to_lowercase)
+        .filter(|name| !name.ends_with('/'))
+        .collect();
+
+    if names.iter().any(|name| name.ends_with(".opf")) {
+        return Some(BookFormat::Epub);
+    }
+
+    let images = names.iter()
+        .filter(|name| IMAGE_EXTENSIONS.iter().any(|ext| name.ends_with(ext)))
+        .count();
+    (images > 0 && images * 2 > names.len()).then_some(BookFormat::Comic)
+}
+
+/// The format `book` turned out to have once decrypted to `decrypted_path`: an EPUB
+/// that's mostly page images is a [`BookFormat::Comic`], and so gets a `.cbz` name.
+pub fn decrypted_format(book: &BookInfo, decrypted_path: &Path) -> BookFormat {
+    if book.format != BookFormat::Epub {
+        return book.format.clone();
+    }
+    File::open(decrypted_path).ok()
+        .and_then(detect_zip_format)
+        .unwrap_or(BookFormat::Epub)
+}
+
+/// Checks that a decrypted file looks like `book`'s format before it's moved into place.
+/// A wrong key doesn't always trip the padding check, and would otherwise leave garbage
+/// where later runs take it for the decrypted book.
+pub fn verify_decrypted_output(book: &BookInfo, output_path: &Path) -> Result<(), DecryptError> {
+    if !is_valid_output(&book.format, output_path) {
+        return Err(DecryptError::InvalidOutput {
+            book_id: book.id.clone(),
+            format: book.format.as_str().to_uppercase(),
+        });
+    }
+    Ok(())
+}
+
+/// Structural check of a decrypted file: a `%PDF-` header for PDFs, a usable ZIP for
+/// EPUBs and comics. Files of unknown format pass.
+pub fn is_valid_output(format: &BookFormat, output_path: &Path) -> bool {
+    match format {
+        BookFormat::Pdf => {
+            let mut header = [0u8; 5];
+            File::open(output_path)
+                .and_then(|mut file| file.read_exact(&mut header))
+                .map(|_| &header == b"%PDF-")
+                .unwrap_or(false)
+        }
+        BookFormat::Epub => File::open(output_path)
+            .ok()
+            .and_then(|file| ZipArchive::new(file).ok())
+            .is_some_and(|mut archive| is_epub_archive(&mut archive)),
+        BookFormat::Comic => File::open(output_path)
+            .ok()
+            .and_then(|file| ZipArchive::new(file).ok())
+            .is_some_and(|archive| !archive.is_empty()),
+        BookFormat::Unknown => true,
+    }
+}
+
+// A readable central directory is required; a mimetype entry, when present, must name EPUB
+fn is_epub_archive(archive: &mut ZipArchive<File>) -> bool {
+    if archive.is_empty() {
+        return false;
+    }
+
+    let mut mimetype = String::new();
+    match archive.by_name("mimetype") {
+        Ok(mut entry) => entry.read_to_string(&mut mimetype).is_ok()
+            && mimetype.trim() == "application/epub+zip",
+        Err(zip::result::ZipError::FileNotFound) => true,
+        Err(_) => false,
+    }
+}
+
+/// Why a decryption step failed. Kept structured so callers can tell a wrong
+/// device_id or a corrupted file (never worth retrying) from an I/O hiccup.
+#[derive(Debug, thiserror::Error)]
+pub enum DecryptError {
+    #[error("❌ {what} is too small ({len} bytes, expected at least {expected})\n\
+             💡 The file appears corrupted. Try re-downloading the book in RIDI app.")]
+    TooSmall { what: &'static str, len: usize, expected: usize },
+
+    #[error("📥 Incomplete download: {reason}\n\
+             💡 Open RIDI to finish downloading this book, then run again.")]
+    IncompleteDownload { reason: String },
+
+    #[error("❌ Failed to decrypt .dat file with provided device_id\n\
+             🔑 Device ID used: {device_id}\n\
+             \n\
+             💡 Possible causes:\n\
+             1. Wrong device_id - this book was downloaded on a different device\n\
+             2. Check https://account.ridibooks.com/api/user-devices/app for all your devices\n\
+             3. Try the device_id from the device where you downloaded this book\n\
+             4. If you have multiple devices, try each device_id until one works")]
+    WrongDevice {
+        /// Already masked with [`redact`], since these messages end up in bug reports
+        device_id: String,
+    },
+
+    #[error("❌ Decrypted .dat data contains invalid text\n\
+             💡 This shouldn't happen - the .dat file might be corrupted.\n\
+             Try re-downloading the book in RIDI app.")]
+    InvalidUtf8,
+
+    #[error("❌ Decrypted .dat data is too short ({len} characters, expected 84+)\n\
+             💡 The .dat file appears corrupted. Try re-downloading the book.")]
+    KeyDerivationFailed { len: usize },
+
+    #[error("❌ Book decryption failed: {reason}\n\
+             \n\
+             💡 This usually means:\n\
+             1. Wrong device_id - the book was downloaded on a different device\n\
+             2. The book key extraction succeeded but book decryption failed\n\
+             3. The book file might be corrupted\n\
+             \n\
+             🔧 Try:\n\
+             - Use device_id from the device where you downloaded this book\n\
+             - Check all your devices at: https://account.ridibooks.com/api/user-devices/app\n\
+             - Re-download the book in RIDI app if problem persists")]
+    PaddingError { reason: String },
+
+    #[error("❌ Failed to read v11 book as ZIP: {0}\n\
+             💡 The book file appears corrupted. Try re-downloading it in RIDI app.")]
+    InvalidZip(#[from] zip::result::ZipError),
+
+    #[error("❌ Could not read {what}: {}\n\
+             💡 Make sure the book is properly downloaded and the file exists.", .path.display())]
+    Unreadable {
+        what: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("❌ Refusing v11 book entry {name:?}: {reason}\n\
+             💡 The book file is malformed or has been tampered with. Try re-downloading it in RIDI app.")]
+    UnsafeEntry { name: String, reason: &'static str },
+
+    #[error("❌ No entry of this zipped PDF decrypted to a PDF\n\
+             💡 The credentials are probably from a different device than the one this book was downloaded on.\n\
+             Check all your devices at: https://account.ridibooks.com/api/user-devices/app")]
+    NoPdfEntry,
+
+    #[error("❌ Decrypted {book_id} is not a valid {format} file\n\
+             💡 The credentials are probably from a different device than the one this book was downloaded on.\n\
+             Check all your devices at: https://account.ridibooks.com/api/user-devices/app")]
+    InvalidOutput { book_id: String, format: String },
+
+    #[error("❌ Unsupported DRM version: v{version}\n\
+             💡 This book uses a RIDI DRM scheme this version can't decrypt yet.\n\
+             Check for a newer release of ridiculous.")]
+    UnsupportedDrmVersion { version: u16 },
+
+    #[error("❌ I/O error while decrypting: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl DecryptError {
+    /// Only transient I/O failures can succeed on a second attempt
+    pub fn is_retryable(&self) -> bool {
+        use std::io::ErrorKind;
+
+        match self {
+            DecryptError::Io(error) | DecryptError::Unreadable { source: error, .. } => matches!(
+                error.kind(),
+                ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
+
+    fn padding(reason: impl std::fmt::Display) -> Self {
+        DecryptError::PaddingError { reason: reason.to_string() }
+    }
+}
+
+/// Decrypt a book from a RIDI library into memory, using `config.device_id`.
+///
+/// DRM-free books are returned unchanged. Large books are better served by
+/// [`read_book_key`] and [`decrypt_book_to_file`], which stream v1 books to disk.
+///
+/// ```no_run
+/// use miette::IntoDiagnostic;
+/// use ridiculous::{decrypt_book, Config, LibraryFinder};
+///
+/// fn main() -> miette::Result<()> {
+///     let config = Config {
+///         device_id: "12345678-1234-1234-1234-123456789012".to_string(),
+///         user_idx: "1234567".to_string(),
+///         ..Default::default()
+///     };
+///
+///     for book in LibraryFinder::new().find_books(&config)? {
+///         let content = decrypt_book(&book, &config).into_diagnostic()?;
+///         std::fs::write(book.get_output_filename(), content).into_diagnostic()?;
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn decrypt_book(book: &BookInfo, config: &Config) -> Result<Vec<u8>, DecryptError> {
+    let book_path = book.get_book_file_path();
+    if is_plaintext_container(book) {
+        return read_file(&book_path, "book file");
+    }
+
+    let key = read_book_key(book, &config.device_id)?;
+    let data = read_file(&book_path, "book file")?;
+    if book.drm_version == DrmVersion::V11 && book.format == BookFormat::Pdf {
+        return decrypt_zipped_pdf(&data, &key, &NoProgress);
+    }
+    decrypt_for_version(book.drm_version, &data, &key)
+}
+
+// Books decrypt_library works on at once, like the CLI's --parallel default
+const LIBRARY_PARALLELISM: usize = 4;
+
+/// Decrypt every book in the libraries found for `config`, yielding each book with the
+/// path it was written to (or why it couldn't be) as soon as it's done.
+///
+/// Books go to their default output path under the output directory. Ones already
+/// decrypted there are yielded with that path as they are, DRM-free books are copied
+/// as-is, and books of unrecognized format are left out, as are free samples unless
+/// `config.include_samples` is set. Image-only EPUBs are written as `.cbz` comics, and a
+/// decrypted file that doesn't look like its format is never written, see
+/// [`verify_decrypted_output`]. A few books are decrypted at
+/// once, and only a few results wait to be taken before decryption pauses, so a slow
+/// consumer slows it down rather than piling up results. Books that haven't started
+/// when the stream is dropped aren't decrypted.
+///
+/// Must be called within a Tokio runtime.
+///
+/// ```no_run
+/// use ridiculous::{decrypt_library, Config};
+/// use tokio_stream::StreamExt;
+///
+/// #[tokio::main]
+/// async fn main() -> miette::Result<()> {
+///     let config = Config {
+///         device_id: "12345678-1234-1234-1234-123456789012".to_string(),
+///         user_idx: "1234567".to_string(),
+///         output_directory: Some("decrypted".to_string()),
+///         ..Default::default()
+///     };
+///
+///     let mut results = decrypt_library(&config)?;
+///     while let Some((book, result)) = results.next().await {
+///         match result {
+///             Ok(path) => println!("✅ {} -> {}", book.get_display_name(), path.display()),
+///             Err(e) => eprintln!("❌ {}: {}", book.get_display_name(), e),
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn decrypt_library(config: &Config) -> miette::Result<impl Stream<Item = (BookInfo, Result<PathBuf, DecryptError>)>> {
+    let books = crate::library_finder::LibraryFinder::new().find_books(config)?;
+    let (sender, receiver) = mpsc::channel(LIBRARY_PARALLELISM);
+    let semaphore = Arc::new(Semaphore::new(LIBRARY_PARALLELISM));
+
+    let wanted = |book: &BookInfo| book.format != BookFormat::Unknown && (config.include_samples || !book.is_sample);
+    for book in books.into_iter().filter(wanted) {
+        let (sender, semaphore, config) = (sender.clone(), semaphore.clone(), config.clone());
+        tokio::spawn(async move {
+            let Ok(_permit) = semaphore.acquire().await else { return };
+            // The stream was dropped
+            if sender.is_closed() {
+                return;
+            }
+
+            let result = tokio::task::spawn_blocking({
+                let book = book.clone();
+                move || decrypt_to_output(&book, &config)
+            })
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e).into()));
+            // The slot is only given up once there's room for the result
+            let _ = sender.send((book, result)).await;
+        });
+    }
+
+    Ok(ReceiverStream::new(receiver))
+}
+
+// One book of decrypt_library, written to its default output path
+fn decrypt_to_output(book: &BookInfo, config: &Config) -> Result<PathBuf, DecryptError> {
+    if let Some(output_path) = existing_output(book, config) {
+        return Ok(output_path);
+    }
+
+    let output_dir = book.get_output_dir(config);
+    std::fs::create_dir_all(&output_dir)?;
+    let partial = PartialOutput::new(&output_dir.join(book.get_output_filename()));
+
+    let mut book = book.clone();
+    if is_plaintext_container(&book) {
+        std::fs::copy(book.get_book_file_path(), partial.path())?;
+    } else {
+        let key = read_book_key(&book, &config.device_id)?;
+        decrypt_book_to_file(&book, &key, partial.path(), config.recompress, &NoProgress)?;
+        // Named and checked as the CLI and GUI do; the partial file is removed on failure
+        book.format = decrypted_format(&book, partial.path());
+        verify_decrypted_output(&book, partial.path())?;
+    }
+
+    let output_path = config.compress.apply(output_dir.join(book.get_output_filename()));
+    match config.compress {
+        Compress::None => partial.persist(&output_path)?,
+        compress => write_compressed(partial.path(), &output_path, compress, 0)?,
+    }
+    Ok(output_path)
+}
+
+/// Decrypt an in-memory book protected by DRM `version`.
+pub fn decrypt_for_version(version: DrmVersion, data: &[u8], key: &[u8; 16]) -> Result<Vec<u8>, DecryptError> {
+    match version {
+        DrmVersion::V1 => decrypt_v1(data, key),
+        DrmVersion::V11 => decrypt_v11(data, key),
+        DrmVersion::Unknown(version) => Err(DecryptError::UnsupportedDrmVersion { version }),
+    }
+}
+
+/// Read a book's `.dat` file and derive its content key for `device_id`.
+///
+/// Books RIDI hasn't finished downloading fail up front with
+/// [`DecryptError::IncompleteDownload`] instead of a padding error later on.
+pub fn read_book_key(book: &BookInfo, device_id: &str) -> Result<[u8; 16], DecryptError> {
+    if let Some(reason) = book.incomplete_download() {
+        return Err(DecryptError::IncompleteDownload { reason });
+    }
+    let dat = read_file(&book.get_data_file_path(), ".dat file")?;
+    let key = derive_book_key(&dat, device_id)?;
+    // Tells apart "every book gets the same (wrong) key" from keys that vary per book
+    tracing::debug!(book = %book.id, fingerprint = %key_fingerprint(&key), "Derived content key");
+    Ok(key)
+}
+
+/// A short, non-reversible name for a content key, for logs: the first 4 hex digits of
+/// its SHA-256. Two books with different fingerprints have different keys; the key
+/// itself can't be recovered from it.
+pub fn key_fingerprint(key: &[u8; 16]) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(key)[..2].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Where decryption reports how far it has got, so any UI can follow along.
+/// Both methods do nothing unless overridden.
+pub trait ProgressSink {
+    /// What's being done right now, such as the v11 entry being decrypted
+    fn set_phase(&self, _phase: &str) {}
+
+    /// How much of the book is done, from 0.0 to 1.0
+    fn set_fraction(&self, _fraction: f32) {}
+}
+
+/// A [`ProgressSink`] that ignores everything, for callers without a UI.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {}
+
+/// A [`ProgressSink`] that moves an `indicatif` bar through `range` of its positions
+/// and shows the phase as its message.
+pub struct BarProgress<'a> {
+    pub bar: &'a ProgressBar,
+    pub range: Range<u64>,
+}
+
+impl ProgressSink for BarProgress<'_> {
+    fn set_phase(&self, phase: &str) {
+        self.bar.set_message(phase.to_string());
+    }
+
+    fn set_fraction(&self, fraction: f32) {
+        let span = self.range.end - self.range.start;
+        self.bar.set_position(self.range.start + (span as f32 * fraction.clamp(0.0, 1.0)) as u64);
+    }
+}
+
+/// Decrypt a book straight into `output_path`, removing it again if decryption fails.
+///
+/// v1 books are streamed since large PDFs can run to hundreds of MB, advancing
+/// `progress` as the file is read; v11 books are ZIPs and need random access, so
+/// they're decrypted in memory, advancing `progress` per entry as in
+/// [`decrypt_v11_with_progress`], with entries compressed according to `recompress`.
+/// PDFs packaged as a ZIP are written out as the PDF alone, see [`decrypt_zipped_pdf`].
+pub fn decrypt_book_to_file(
+    book: &BookInfo,
+    key: &[u8; 16],
+    output_path: &Path,
+    recompress: Recompress,
+    progress: &dyn ProgressSink,
+) -> Result<(), DecryptError> {
+    let book_path = book.get_book_file_path();
+
+    let result = match book.drm_version {
+        DrmVersion::V11 if book.format == BookFormat::Pdf => read_file(&book_path, "book file")
+            .and_then(|encrypted| decrypt_zipped_pdf(&encrypted, key, progress))
+            .and_then(|decrypted| Ok(std::fs::write(output_path, decrypted)?)),
+        DrmVersion::V11 => read_file(&book_path, "book file")
+            .and_then(|encrypted| decrypt_v11_with_progress(&encrypted, key, recompress, progress))
+            .and_then(|decrypted| Ok(std::fs::write(output_path, decrypted)?)),
+        DrmVersion::V1 => open_file(&book_path, "book file")
+            .and_then(|file| {
+                let total = file.metadata()?.len();
+                let reader = ProgressReader { inner: file, read: 0, total, progress };
+                Ok((reader, File::create(output_path)?))
+            })
+            .and_then(|(reader, writer)| decrypt_v1_streaming(reader, writer, key))
+            .map(|_| ()),
+        DrmVersion::Unknown(version) => return Err(DecryptError::UnsupportedDrmVersion { version }),
+    };
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(output_path);
+    }
+    result
+}
+
+/// Unpack the ZIP at `zip_path` into the folder `output_dir`, keeping its internal
+/// structure, for `--flatten`. Entries go through the same checks as in [`decrypt_v11`].
+/// The folder is filled in as `<output_dir>.part` (named for `attempt` like
+/// [`PartialOutput::for_attempt`]) and only moved into place, replacing whatever is
+/// there, once every entry is written.
+pub fn extract_zip(zip_path: &Path, output_dir: &Path, attempt: u32) -> Result<(), DecryptError> {
+    let file = open_file(zip_path, "book file")?;
+    let archive_len = file.metadata()?.len();
+    let mut zip = ZipArchive::new(file)?;
+
+    let partial = staging_path(output_dir, attempt);
+    // Left behind by a run that was killed halfway
+    let _ = std::fs::remove_dir_all(&partial);
+
+    let result = extract_entries(&mut zip, archive_len, &partial).and_then(|()| {
+        if output_dir.is_dir() {
+            std::fs::remove_dir_all(output_dir)?;
+        } else if output_dir.exists() {
+            std::fs::remove_file(output_dir)?;
+        }
+        Ok(std::fs::rename(&partial, output_dir)?)
+    });
+
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&partial);
+    }
+    result
+}
+
+fn extract_entries<R: Read + Seek>(zip: &mut ZipArchive<R>, archive_len: u64, dir: &Path) -> Result<(), DecryptError> {
+    std::fs::create_dir_all(dir)?;
+
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        let name = file.name().to_string();
+        check_entry_name(&name)?;
+
+        // Names use `/`, but check_entry_name also treats `\` as a separator. Each part
+        // must be a plain name here too, e.g. not a Windows drive like `C:`.
+        let mut path = dir.to_path_buf();
+        for part in name.split(['/', '\\']).filter(|part| !part.is_empty() && *part != ".") {
+            if !Path::new(part).components().all(|component| matches!(component, Component::Normal(_))) {
+                return Err(DecryptError::UnsafeEntry { name, reason: "it isn't a plain relative path here" });
+            }
+            path.push(part);
+        }
+
+        if file.is_dir() {
+            std::fs::create_dir_all(&path)?;
+            continue;
+        }
+        let data = read_entry(&mut file, archive_len)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, data)?;
+    }
+
+    Ok(())
+}
+
+/// Write the file at `source` to `output_path` compressed with `compress`, through the
+/// [`PartialOutput`] for `attempt`, so nothing is left at `output_path` if it fails.
+pub fn write_compressed(source: &Path, output_path: &Path, compress: Compress, attempt: u32) -> std::io::Result<()> {
+    use std::io::BufWriter;
+
+    let partial = PartialOutput::for_attempt(output_path, attempt);
+    let mut reader = File::open(source)?;
+    let writer = BufWriter::new(File::create(partial.path())?);
+
+    let writer = match compress {
+        Compress::None => {
+            let mut writer = writer;
+            std::io::copy(&mut reader, &mut writer)?;
+            writer
+        }
+        Compress::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            std::io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?
+        }
+        Compress::Zstd => {
+            // ruzstd panics on I/O errors, which aborts a release build, so they're caught
+            // on the way and returned once it's done
+            let mut source = CaughtErrors::new(reader);
+            let mut drain = CaughtErrors::new(writer);
+            ruzstd::encoding::compress(&mut source, &mut drain, ruzstd::encoding::CompressionLevel::Fastest);
+            source.into_result()?;
+            drain.into_result()?
+        }
+    };
+
+    writer.into_inner().map_err(std::io::IntoInnerError::into_error)?.sync_all()?;
+    partial.persist(output_path)
+}
+
+/// The contents of the output file at `path`, decompressed as [`Compress::of_path`] says.
+pub fn open_output(path: &Path) -> std::io::Result<Box<dyn Read>> {
+    let file = std::io::BufReader::new(File::open(path)?);
+    Ok(match Compress::of_path(path) {
+        Compress::None => Box::new(file),
+        Compress::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+        Compress::Zstd => Box::new(ruzstd::decoding::StreamingDecoder::new(file).map_err(std::io::Error::other)?),
+    })
+}
+
+// A reader or writer that never fails: the first error is kept for `into_result`, and
+// from then on reads return nothing and writes are dropped
+struct CaughtErrors<T> {
+    inner: T,
+    error: Option<std::io::Error>,
+}
+
+impl<T> CaughtErrors<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, error: None }
+    }
+
+    fn into_result(self) -> std::io::Result<T> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.inner),
+        }
+    }
+}
+
+impl<R: Read> Read for CaughtErrors<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.error.is_some() {
+            return Ok(0);
+        }
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.error = Some(e);
+                    return Ok(0);
+                }
+                read => return read,
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for CaughtErrors<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.error.is_none() {
+            if let Err(e) = self.inner.write_all(buf) {
+                self.error = Some(e);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.error.is_none() {
+            if let Err(e) = self.inner.flush() {
+                self.error = Some(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A `<output>.part` file that's removed again unless [`PartialOutput::persist`] moves
+/// it into place, so a failed or interrupted write never leaves a truncated output
+/// that a later run would take for an already decrypted book.
+pub struct PartialOutput {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl PartialOutput {
+    pub fn new(output_path: &Path) -> Self {
+        Self::for_attempt(output_path, 0)
+    }
+
+    /// Like [`PartialOutput::new`] for the first attempt at a book, while each retry
+    /// gets its own `<output>.<attempt>.part`, so an attempt still running after it
+    /// timed out never shares a file with the one that replaced it.
+    pub fn for_attempt(output_path: &Path, attempt: u32) -> Self {
+        Self { path: staging_path(output_path, attempt), persisted: false }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rename the finished file to `output_path` and keep it.
+    pub fn persist(mut self, output_path: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, output_path)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+// `<output>.part`, or `<output>.<attempt>.part` for a retry
+fn staging_path(output_path: &Path, attempt: u32) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    if attempt > 0 {
+        path.push(format!(".{}", attempt));
+    }
+    path.push(".part");
+    PathBuf::from(path)
+}
+
+impl Drop for PartialOutput {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Reports the fraction of the underlying reader consumed so far
+struct ProgressReader<'a, R> {
+    inner: R,
+    read: u64,
+    total: u64,
+    progress: &'a dyn ProgressSink,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+
+        if self.total > 0 {
+            self.progress.set_fraction(self.read.min(self.total) as f32 / self.total as f32);
+        }
+        Ok(n)
+    }
+}
+
+fn open_file(path: &Path, what: &'static str) -> Result<File, DecryptError> {
+    File::open(path).map_err(|source| DecryptError::Unreadable { what, path: path.to_path_buf(), source })
+}
+
+fn read_file(path: &Path, what: &'static str) -> Result<Vec<u8>, DecryptError> {
+    std::fs::read(path).map_err(|source| DecryptError::Unreadable { what, path: path.to_path_buf(), source })
+}
+
+/// Derive a book's content key from its `.dat` file and the device_id it was downloaded with.
+pub fn derive_book_key(dat: &[u8], device_id: &str) -> Result<[u8; 16], DecryptError> {
+    if dat.len() < 32 {
+        return Err(DecryptError::TooSmall { what: ".dat file", len: dat.len(), expected: 32 });
+    }
+
+    let mut key = [0; 16];
+    let device_bytes = device_id.as_bytes();
+    let key_len = std::cmp::min(16, device_bytes.len());
+    key[..key_len].copy_from_slice(&device_bytes[..key_len]);
+
+    let mut iv = [0; 16];
+    iv.copy_from_slice(&dat[0..16]);
+
+    let mut encrypted = dat[16..].to_vec();
+
+    let plaintext = cbc::Decryptor::<aes::Aes128>::new(&key.into(), &iv.into())
+        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut encrypted)
+        .map_err(|_| DecryptError::WrongDevice { device_id: redact(device_id) })?;
+
+    let plaintext_str = std::str::from_utf8(plaintext)
+        .map_err(|_| DecryptError::InvalidUtf8)?;
+
+    if plaintext_str.len() < 84 {
+        return Err(DecryptError::KeyDerivationFailed { len: plaintext_str.len() });
+    }
+
+    // The content key lives at characters 68..84 of the decrypted text
+    let mut result = [0; 16];
+    result.copy_from_slice(&plaintext_str.as_bytes()[68..84]);
+    // Only sizes; never the key itself
+    tracing::trace!(dat_bytes = dat.len(), plaintext_chars = plaintext_str.len(), key_bytes = result.len(),
+                    "Derived content key from .dat");
+
+    Ok(result)
+}
+
+/// Decrypt a v1 book: the whole file is a single AES-CBC stream prefixed with its IV.
+///
+/// The CLI and GUI use [`decrypt_v1_streaming`]; this is kept for library callers.
+pub fn decrypt_v1(data: &[u8], key: &[u8; 16]) -> Result<Vec<u8>, DecryptError> {
+    if data.len() < 16 {
+        return Err(DecryptError::TooSmall { what: "Book file", len: data.len(), expected: 16 });
+    }
+
+    let mut iv = [0; 16];
+    iv.copy_from_slice(&data[0..16]);
+
+    let mut encrypted = data[16..].to_vec();
+    tracing::trace!(blocks = encrypted.len() / 16, "Decrypting v1 book in memory");
+
+    let decrypted = cbc::Decryptor::<aes::Aes128>::new(key.into(), &iv.into())
+        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut encrypted)
+        .map_err(DecryptError::padding)?;
+
+    Ok(decrypted.to_vec())
+}
+
+// Plaintext is written out in chunks of this size; must be a multiple of the AES block size
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Decrypt a v1 book from `reader` into `writer` without holding the whole file in memory.
+///
+/// Only one chunk plus the final block (held back until its PKCS7 padding can be
+/// stripped) is buffered at a time. Returns the number of plaintext bytes written.
+pub fn decrypt_v1_streaming<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    key: &[u8; 16],
+) -> Result<u64, DecryptError> {
+    use aes::cipher::block_padding::{Padding, Pkcs7};
+
+    let mut iv = [0; 16];
+    let iv_len = read_full(&mut reader, &mut iv)?;
+    if iv_len < iv.len() {
+        return Err(DecryptError::TooSmall { what: "Book file", len: iv_len, expected: 16 });
+    }
+
+    let mut decryptor = cbc::Decryptor::<aes::Aes128>::new(key.into(), &iv.into());
+    let mut buffer = vec![0u8; STREAM_CHUNK_SIZE];
+    let mut held_block: Option<aes::Block> = None;
+    let mut written = 0u64;
+    let mut blocks = 0u64;
+
+    loop {
+        let len = read_full(&mut reader, &mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        if len % 16 != 0 {
+            return Err(DecryptError::padding("ciphertext is not a whole number of blocks"));
+        }
+
+        for block in buffer[..len].chunks_exact_mut(16) {
+            decryptor.decrypt_block_mut(aes::Block::from_mut_slice(block));
+        }
+        blocks += (len / 16) as u64;
+
+        // The previous chunk's last block can't be padding any more
+        if let Some(block) = held_block.take() {
+            writer.write_all(&block)?;
+            written += 16;
+        }
+
+        let (body, last) = buffer[..len].split_at(len - 16);
+        writer.write_all(body)?;
+        written += body.len() as u64;
+        held_block = Some(aes::Block::clone_from_slice(last));
+    }
+
+    let last_block = held_block
+        .ok_or_else(|| DecryptError::padding("no encrypted data after the IV"))?;
+    let plaintext = Pkcs7::unpad(&last_block)
+        .map_err(|_| DecryptError::padding("invalid padding"))?;
+    writer.write_all(plaintext)?;
+    written += plaintext.len() as u64;
+    tracing::trace!(blocks, padding = 16 - plaintext.len(), written, "Decrypted v1 stream");
+
+    writer.flush()?;
+    Ok(written)
+}
+
+// Fill `buffer` as far as the reader allows, so only the final read can be short
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+/// Decrypt a v11 book: a ZIP whose entries are each encrypted with their own IV.
+///
+/// Entries that fail to decrypt are kept as-is, since some metadata is stored unencrypted.
+/// Each entry keeps the compression method and modified time it had in the original.
+/// Entries with names that would escape the folder they're extracted to, or that
+/// decompress far beyond their compressed size, fail with [`DecryptError::UnsafeEntry`].
+pub fn decrypt_v11(data: &[u8], key: &[u8; 16]) -> Result<Vec<u8>, DecryptError> {
+    decrypt_v11_with_progress(data, key, Recompress::Keep, &NoProgress)
+}
+
+/// Same as [`decrypt_v11`], compressing entries according to `recompress` and
+/// reporting to `progress` as entries are decrypted, with the current entry's
+/// name as the phase.
+pub fn decrypt_v11_with_progress(
+    data: &[u8],
+    key: &[u8; 16],
+    recompress: Recompress,
+    progress: &dyn ProgressSink,
+) -> Result<Vec<u8>, DecryptError> {
+    let mut zip = ZipArchive::new(Cursor::new(data))?;
+    tracing::trace!(entries = zip.len(), bytes = data.len(), "Decrypting v11 book");
+
+    // Create output ZIP in memory
+    let mut output_buffer = Vec::new();
+    {
+        let mut output_zip = zip::ZipWriter::new(Cursor::new(&mut output_buffer));
+
+        let entry_count = zip.len() as f32;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            let file_name = file.name().to_string();
+            check_entry_name(&file_name)?;
+            let compression = output_compression(&file_name, file.compression(), recompress);
+            let last_modified = file.last_modified();
+
+            progress.set_fraction(i as f32 / entry_count);
+            progress.set_phase(&format!("Decrypting {}", file_name));
+
+            let encrypted_data = read_entry(&mut file, data.len() as u64)?;
+            drop(file); // Release the borrow
+
+            let decrypted_data = match decrypt_v11_entry(&encrypted_data, key) {
+                Ok(decrypted) => {
+                    tracing::trace!(entry = %file_name, encrypted = encrypted_data.len(), decrypted = decrypted.len(),
+                                    "Decrypted v11 entry");
+                    decrypted
+                }
+                Err(_) => {
+                    tracing::trace!(entry = %file_name, bytes = encrypted_data.len(), "Kept v11 entry as-is (not encrypted)");
+                    encrypted_data
+                }
+            };
+
+            let options = zip::write::FileOptions::default()
+                .compression_method(compression)
+                .last_modified_time(last_modified);
+            output_zip.start_file(&file_name, options)?;
+            output_zip.write_all(&decrypted_data)?;
+        }
+
+        output_zip.finish()?;
+    }
+
+    Ok(output_buffer)
+}
+
+/// Decrypt a PDF that RIDI delivered as a v11-style ZIP, holding the encrypted PDF
+/// plus resources, into the standalone PDF.
+///
+/// The PDF is the first entry that decrypts to a `%PDF-` file, or already is one:
+/// `.pdf` entries if there are any, largest first. Nothing else in the ZIP is kept.
+/// Fails with [`DecryptError::NoPdfEntry`] when no entry turns out to be a PDF,
+/// usually because the key is wrong.
+pub fn decrypt_zipped_pdf(data: &[u8], key: &[u8; 16], progress: &dyn ProgressSink) -> Result<Vec<u8>, DecryptError> {
+    let mut zip = ZipArchive::new(Cursor::new(data))?;
+
+    let mut candidates = Vec::new();
+    for i in 0..zip.len() {
+        let file = zip.by_index(i)?;
+        if !file.is_dir() {
+            candidates.push((i, file.name().to_lowercase().ends_with(".pdf"), file.size()));
+        }
+    }
+    if candidates.iter().any(|&(_, is_pdf, _)| is_pdf) {
+        candidates.retain(|&(_, is_pdf, _)| is_pdf);
+    }
+    candidates.sort_by_key(|&(_, _, size)| std::cmp::Reverse(size));
+    tracing::trace!(entries = zip.len(), candidates = candidates.len(), "Looking for the PDF in a zipped PDF");
+
+    let candidate_count = candidates.len() as f32;
+    for (n, (i, _, _)) in candidates.into_iter().enumerate() {
+        let mut file = zip.by_index(i)?;
+        let file_name = file.name().to_string();
+        progress.set_fraction(n as f32 / candidate_count);
+        progress.set_phase(&format!("Decrypting {}", file_name));
+
+        let entry = read_entry(&mut file, data.len() as u64)?;
+        drop(file);
+        // Stored unencrypted, like the metadata entries of some v11 books
+        if entry.starts_with(b"%PDF-") {
+            return Ok(entry);
+        }
+        if let Ok(decrypted) = decrypt_v11_entry(&entry, key) {
+            if decrypted.starts_with(b"%PDF-") {
+                tracing::trace!(entry = %file_name, decrypted = decrypted.len(), "Decrypted the PDF entry");
+                return Ok(decrypted);
+            }
+        }
+    }
+
+    Err(DecryptError::NoPdfEntry)
+}
+
+// Entries that can't escape the output folder when the book is unpacked:
+// relative, without `..`, under either path separator
+fn check_entry_name(name: &str) -> Result<(), DecryptError> {
+    let reject = |reason| Err(DecryptError::UnsafeEntry { name: name.to_string(), reason });
+
+    let bytes = name.as_bytes();
+    if name.is_empty() {
+        return reject("the name is empty");
+    }
+    if name.contains('\0') {
+        return reject("the name contains a NUL byte");
+    }
+    if name.starts_with(['/', '\\']) || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':') {
+        return reject("absolute paths aren't allowed");
+    }
+    if name.split(['/', '\\']).any(|part| part == "..") {
+        return reject("`..` in paths isn't allowed");
+    }
+    Ok(())
+}
+
+// How much larger than its compressed size an entry may get. Encrypted entries barely
+// compress at all, and plain-text ones stay well under this; zip bombs go far beyond.
+const MAX_EXPANSION_RATIO: u64 = 100;
+// Small entries may exceed the ratio, e.g. a run of whitespace
+const MIN_EXPANSION_LIMIT: u64 = 1024 * 1024;
+
+// Never preallocate more than this for an entry, whatever size its header claims
+const MAX_ENTRY_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+// Reads an entry, stopping as soon as it grows past what its compressed size allows,
+// whatever size its header claims. The compressed size itself is only taken up to
+// `archive_len`, the length of the whole archive, which no entry can really exceed.
+fn read_entry(file: &mut zip::read::ZipFile, archive_len: u64) -> Result<Vec<u8>, DecryptError> {
+    let limit = file.compressed_size()
+        .min(archive_len)
+        .saturating_mul(MAX_EXPANSION_RATIO)
+        .max(MIN_EXPANSION_LIMIT);
+    let name = file.name().to_string();
+    let too_large = || DecryptError::UnsafeEntry {
+        name: name.clone(),
+        reason: "it expands to more than 100 times its compressed size",
+    };
+    if file.size() > limit {
+        return Err(too_large());
+    }
+
+    let mut data = Vec::with_capacity(file.size().min(limit).min(MAX_ENTRY_PREALLOCATION) as usize);
+    file.by_ref().take(limit + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > limit {
+        return Err(too_large());
+    }
+    Ok(data)
+}
+
+fn output_compression(name: &str, original: zip::CompressionMethod, recompress: Recompress) -> zip::CompressionMethod {
+    use zip::CompressionMethod::{Deflated, Stored};
+
+    match recompress {
+        // Only deflate is built in, so anything more exotic is written deflated
+        Recompress::Keep if original == Stored => Stored,
+        Recompress::Keep => Deflated,
+        // Readers expect the EPUB mimetype entry uncompressed
+        Recompress::Deflate if name == "mimetype" => Stored,
+        Recompress::Deflate => Deflated,
+        Recompress::Store => Stored,
+    }
+}
+
+// Each entry in a v11 ZIP starts with its own 16-byte IV
+fn decrypt_v11_entry(encrypted_data: &[u8], key: &[u8; 16]) -> Result<Vec<u8>, DecryptError> {
+    if encrypted_data.len() < 16 {
+        return Err(DecryptError::TooSmall { what: "v11 entry", len: encrypted_data.len(), expected: 16 });
+    }
+
+    let mut iv = [0; 16];
+    iv.copy_from_slice(&encrypted_data[0..16]);
+
+    let mut encrypted = encrypted_data[16..].to_vec();
+
+    let decrypted = cbc::Decryptor::<aes::Aes128>::new(key.into(), &iv.into())
+        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut encrypted)
+        .map_err(DecryptError::padding)?;
+
+    Ok(decrypted.to_vec())
+}
+
+/// Device id that unlocks the synthetic books written by [`write_fixture_book`]
+pub const FIXTURE_DEVICE_ID: &str = "12345678-1234-1234-1234-123456789012";
+/// Content key of the synthetic books written by [`write_fixture_book`]
+pub const FIXTURE_BOOK_KEY: [u8; 16] = *b"0123456789abcdef";
+
+/// Encrypt `plaintext` the way RIDI stores books: the IV, then AES-128-CBC with PKCS#7 padding.
+pub fn encrypt_fixture(plaintext: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Vec<u8> {
+    use aes::cipher::BlockEncryptMut;
+
+    let mut buffer = vec![0; (plaintext.len() / 16 + 1) * 16];
+    buffer[..plaintext.len()].copy_from_slice(plaintext);
+    let ciphertext = cbc::Encryptor::<aes::Aes128>::new(key.into(), iv.into())
+        .encrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut buffer, plaintext.len())
+        .expect("the buffer has room for a block of padding");
+
+    let mut output = iv.to_vec();
+    output.extend_from_slice(ciphertext);
+    output
+}
+
+/// Write `<book_dir>/<id>.dat` holding [`FIXTURE_BOOK_KEY`] for `device_id`, laid out
+/// like RIDI's: 68 characters of other data, then the key.
+pub fn write_fixture_dat(book_dir: &Path, device_id: &str) -> std::io::Result<()> {
+    let book_id = book_dir.file_name().unwrap_or_default().to_string_lossy();
+    let mut device_key = [0; 16];
+    let key_len = device_id.len().min(16);
+    device_key[..key_len].copy_from_slice(&device_id.as_bytes()[..key_len]);
+
+    let mut plaintext = "x".repeat(68).into_bytes();
+    plaintext.extend_from_slice(&FIXTURE_BOOK_KEY);
+    std::fs::create_dir_all(book_dir)?;
+    std::fs::write(book_dir.join(format!("{}.dat", book_id)), encrypt_fixture(&plaintext, &device_key, &[7; 16]))
+}
+
+const FIXTURE_PDF: &[u8] = b"%PDF-1.4\n1 0 obj << /Type /Catalog /Pages 2 0 R >> endobj\n\
+    2 0 obj << /Type /Pages /Kids [] /Count 0 >> endobj\ntrailer << /Root 1 0 R >>\n%%EOF\n";
+
+const FIXTURE_EPUB_ENTRIES: [(&str, &str); 4] = [
+    ("mimetype", "application/epub+zip"),
+    ("META-INF/container.xml", r#"<container version="1.0"><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#),
+    ("OEBPS/content.opf", r#"<package><metadata><dc:title>ridiculous self-test</dc:title></metadata></package>"#),
+    ("OEBPS/chapter1.xhtml", "<html><body><p>If you can read this, decryption works.</p></body></html>"),
+];
+
+/// Write a complete synthetic book into `book_dir`, `.dat` included, that
+/// [`FIXTURE_DEVICE_ID`] unlocks: a small PDF for v1 and a small EPUB for v11, in
+/// RIDI's layout. Returns what it should decrypt to, for [`fixture_matches`].
+pub fn write_fixture_book(book_dir: &Path, version: DrmVersion) -> std::io::Result<Vec<u8>> {
+    let book_id = book_dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+    write_fixture_dat(book_dir, FIXTURE_DEVICE_ID)?;
+
+    match version {
+        DrmVersion::V1 => {
+            std::fs::write(book_dir.join(format!("{}.v1.pdf", book_id)), encrypt_fixture(FIXTURE_PDF, &FIXTURE_BOOK_KEY, &[7; 16]))?;
+            Ok(FIXTURE_PDF.to_vec())
+        }
+        DrmVersion::V11 => {
+            let mut plaintext = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            let mut encrypted = zip::ZipWriter::new(File::create(book_dir.join(format!("{}.v11.epub", book_id)))?);
+            for (i, (name, content)) in FIXTURE_EPUB_ENTRIES.iter().enumerate() {
+                let options = zip::write::FileOptions::default().compression_method(match *name {
+                    "mimetype" => zip::CompressionMethod::Stored,
+                    _ => zip::CompressionMethod::Deflated,
+                });
+                plaintext.start_file(*name, options)?;
+                plaintext.write_all(content.as_bytes())?;
+                encrypted.start_file(*name, options)?;
+                encrypted.write_all(&encrypt_fixture(content.as_bytes(), &FIXTURE_BOOK_KEY, &[i as u8; 16]))?;
+            }
+            encrypted.finish()?;
+            Ok(plaintext.finish()?.into_inner())
+        }
+        DrmVersion::Unknown(version) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("no synthetic book for DRM v{}", version),
+        )),
+    }
+}
+
+/// Whether `decrypted` has the same contents as the `plaintext` from [`write_fixture_book`].
+/// ZIPs are compared entry by entry, since rewriting them needn't give the same bytes.
+pub fn fixture_matches(plaintext: &[u8], decrypted: &[u8]) -> bool {
+    fn entries(data: &[u8]) -> Option<Vec<(String, Vec<u8>)>> {
+        let mut zip = ZipArchive::new(Cursor::new(data)).ok()?;
+        (0..zip.len())
+            .map(|i| {
+                let mut file = zip.by_index(i).ok()?;
+                let mut content = Vec::new();
+                file.read_to_end(&mut content).ok()?;
+                Some((file.name().to_string(), content))
+            })
+            .collect()
+    }
+
+    match entries(plaintext) {
+        Some(expected) => entries(decrypted).is_some_and(|actual| actual == expected),
+        None => plaintext == decrypted,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: [u8; 16] = FIXTURE_BOOK_KEY;
+    const TEST_DEVICE_ID: &str = FIXTURE_DEVICE_ID;
+
+    fn device_key() -> [u8; 16] {
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&TEST_DEVICE_ID.as_bytes()[..16]);
+        key
+    }
+
+    #[test]
+    fn test_derive_book_key() {
+        // 68 characters of filler, the 16-character content key, then trailing data
+        let plaintext = format!("{}{}{}", "x".repeat(68), "0123456789abcdef", "trailer");
+        let dat = encrypt_fixture(plaintext.as_bytes(), &device_key(), &[7; 16]);
+
+        assert_eq!(derive_book_key(&dat, TEST_DEVICE_ID).unwrap(), TEST_KEY);
+        assert!(matches!(
+            derive_book_key(&dat, "87654321-1234-1234-1234-123456789012"),
+            Err(DecryptError::WrongDevice { .. })
+        ));
+        assert!(matches!(
+            derive_book_key(&dat[..20], TEST_DEVICE_ID),
+            Err(DecryptError::TooSmall { len: 20, .. })
+        ));
+    }
+
+    #[test]
+    fn test_key_fingerprint() {
+        // sha256("0123456789abcdef") starts 9f9f...
+        assert_eq!(key_fingerprint(&TEST_KEY), "9f9f");
+        assert_eq!(key_fingerprint(&[0; 16]), "3747");
+    }
+
+    #[test]
+    fn test_v1_round_trip() {
+        let plaintext = b"%PDF-1.4 decrypted content";
+        let encrypted = encrypt_fixture(plaintext, &TEST_KEY, &[3; 16]);
+
+        assert_eq!(decrypt_v1(&encrypted, &TEST_KEY).unwrap(), plaintext);
+        assert!(matches!(decrypt_v1(&encrypted[..8], &TEST_KEY), Err(DecryptError::TooSmall { .. })));
+        assert!(matches!(decrypt_v1(&encrypted, b"fedcba9876543210"), Err(DecryptError::PaddingError { .. })));
+    }
+
+    #[test]
+    fn test_v1_streaming_matches_in_memory() {
+        // Around chunk boundaries, including an exact multiple where the padding is a whole block
+        for len in [0, 1, 15, 16, 17, STREAM_CHUNK_SIZE - 1, STREAM_CHUNK_SIZE, STREAM_CHUNK_SIZE + 5] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt_fixture(&plaintext, &TEST_KEY, &[9; 16]);
+
+            let mut output = Vec::new();
+            let written = decrypt_v1_streaming(encrypted.as_slice(), &mut output, &TEST_KEY).unwrap();
+            assert_eq!(output, plaintext, "length {}", len);
+            assert_eq!(written, len as u64);
+        }
+
+        let encrypted = encrypt_fixture(b"%PDF-1.4", &TEST_KEY, &[9; 16]);
+        assert!(decrypt_v1_streaming(&encrypted[..8], Vec::new(), &TEST_KEY).is_err());
+        assert!(decrypt_v1_streaming(&encrypted[..16], Vec::new(), &TEST_KEY).is_err());
+        assert!(decrypt_v1_streaming(&encrypted[..20], Vec::new(), &TEST_KEY).is_err());
+        assert!(decrypt_v1_streaming(encrypted.as_slice(), Vec::new(), b"fedcba9876543210").is_err());
+    }
+
+    // Records the largest single read or write so the test can bound buffering
+    struct Probe<T> {
+        inner: T,
+        largest: usize,
+    }
+
+    impl<T: Read> Read for Probe<T> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.largest = self.largest.max(buf.len());
+            self.inner.read(buf)
+        }
+    }
+
+    impl<T: Write> Write for Probe<T> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.largest = self.largest.max(buf.len());
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_v1_streaming_large_file_stays_bounded() {
+        const SIZE: usize = 50 * 1024 * 1024;
+
+        let plaintext: Vec<u8> = (0..SIZE).map(|i| (i % 253) as u8).collect();
+        let encrypted = encrypt_fixture(&plaintext, &TEST_KEY, &[5; 16]);
+
+        let mut reader = Probe { inner: encrypted.as_slice(), largest: 0 };
+        let mut writer = Probe { inner: Vec::with_capacity(SIZE), largest: 0 };
+        let written = decrypt_v1_streaming(&mut reader, &mut writer, &TEST_KEY).unwrap();
+
+        assert_eq!(written, SIZE as u64);
+        assert!(writer.inner == plaintext);
+        assert!(reader.largest <= STREAM_CHUNK_SIZE, "read {} bytes at once", reader.largest);
+        assert!(writer.largest <= STREAM_CHUNK_SIZE, "wrote {} bytes at once", writer.largest);
+    }
+
+    #[test]
+    fn test_progress_reader_tracks_bytes_read() {
+        let data = vec![0u8; 1000];
+        let pb = ProgressBar::hidden();
+        let progress = BarProgress { bar: &pb, range: 50..80 };
+        let mut reader = ProgressReader { inner: data.as_slice(), read: 0, total: 1000, progress: &progress };
+
+        reader.read_exact(&mut [0u8; 500]).unwrap();
+        assert_eq!(pb.position(), 65);
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(pb.position(), 80);
+    }
+
+    #[test]
+    fn test_detect_zip_format() {
+        let zip_of = |names: &[&str]| {
+            let mut data = Vec::new();
+            {
+                let mut zip = zip::ZipWriter::new(Cursor::new(&mut data));
+                for name in names {
+                    zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+                    zip.write_all(b"content").unwrap();
+                }
+                zip.finish().unwrap();
+            }
+            Cursor::new(data)
+        };
+
+        let comic = zip_of(&["001.jpg", "002.JPG", "003.png", "images/004.webp", "ComicInfo.xml"]);
+        assert_eq!(detect_zip_format(comic), Some(BookFormat::Comic));
+
+        // Image-heavy EPUBs still have their OPF
+        let epub = zip_of(&["mimetype", "OEBPS/content.opf", "OEBPS/001.jpg", "OEBPS/002.jpg", "OEBPS/003.jpg"]);
+        assert_eq!(detect_zip_format(epub), Some(BookFormat::Epub));
+
+        assert_eq!(detect_zip_format(zip_of(&["readme.txt", "cover.jpg"])), None);
+        assert_eq!(detect_zip_format(Cursor::new(b"%PDF-1.4".to_vec())), None);
+    }
+
+    #[test]
+    fn test_v11_round_trip() {
+        let entries: [(&str, &[u8]); 2] = [
+            ("mimetype", b"application/epub+zip"),
+            ("OEBPS/chapter1.xhtml", b"<html><body>Hello, RIDI</body></html>"),
+        ];
+
+        let mut encrypted_zip = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(Cursor::new(&mut encrypted_zip));
+            for (i, (name, content)) in entries.iter().enumerate() {
+                zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+                zip.write_all(&encrypt_fixture(content, &TEST_KEY, &[i as u8; 16])).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+
+        let pb = ProgressBar::hidden();
+        let decrypted = decrypt_v11_with_progress(&encrypted_zip, &TEST_KEY, Recompress::Keep, &BarProgress { bar: &pb, range: 50..80 }).unwrap();
+        assert_eq!(decrypted, decrypt_v11(&encrypted_zip, &TEST_KEY).unwrap());
+        // Set before each entry, so the last update is for the last entry
+        assert_eq!(pb.position(), 65);
+        assert_eq!(pb.message(), "Decrypting OEBPS/chapter1.xhtml");
+
+        let mut output = ZipArchive::new(Cursor::new(decrypted)).unwrap();
+        assert_eq!(output.len(), entries.len());
+
+        for (name, content) in entries {
+            let mut data = Vec::new();
+            output.by_name(name).unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(data, content);
+        }
+    }
+
+    #[test]
+    fn test_v11_recompress() {
+        use zip::CompressionMethod::{Deflated, Stored};
+
+        // A comic: JPEG-like pages that don't compress, stored as RIDI does
+        let mut seed = 0x2545_f491_u32;
+        let mut noise = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 17;
+                    seed ^= seed << 5;
+                    seed as u8
+                })
+                .collect()
+        };
+        let mut entries = vec![
+            ("mimetype".to_string(), b"application/epub+zip".to_vec(), Stored),
+            ("OEBPS/content.opf".to_string(), b"<package>".repeat(200), Deflated),
+        ];
+        for page in 0..20 {
+            entries.push((format!("OEBPS/images/{:03}.jpg", page), noise(40 * 1024), Stored));
+        }
+
+        let modified = zip::DateTime::from_date_and_time(2021, 3, 14, 15, 9, 26).unwrap();
+        let mut encrypted_zip = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(Cursor::new(&mut encrypted_zip));
+            for (i, (name, content, method)) in entries.iter().enumerate() {
+                let options = zip::write::FileOptions::default()
+                    .compression_method(*method)
+                    .last_modified_time(modified);
+                zip.start_file(name, options).unwrap();
+                zip.write_all(&encrypt_fixture(content, &TEST_KEY, &[i as u8; 16])).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+
+        let decrypt_with = |recompress| decrypt_v11_with_progress(&encrypted_zip, &TEST_KEY, recompress, &NoProgress).unwrap();
+        let keep = decrypt_with(Recompress::Keep);
+        let deflate = decrypt_with(Recompress::Deflate);
+        let store = decrypt_with(Recompress::Store);
+        assert_eq!(keep, decrypt_v11(&encrypted_zip, &TEST_KEY).unwrap());
+        assert!(keep.len() < deflate.len(), "keep: {} bytes, deflate: {} bytes", keep.len(), deflate.len());
+
+        let methods = |zip_data: &[u8]| {
+            let mut zip = ZipArchive::new(Cursor::new(zip_data)).unwrap();
+            (0..zip.len())
+                .map(|i| {
+                    let file = zip.by_index(i).unwrap();
+                    assert_eq!(file.last_modified().datepart(), modified.datepart());
+                    assert_eq!(file.last_modified().timepart(), modified.timepart());
+                    file.compression()
+                })
+                .collect::<Vec<_>>()
+        };
+        let original: Vec<_> = entries.iter().map(|(_, _, method)| *method).collect();
+        assert_eq!(methods(&keep), original);
+        let deflated = methods(&deflate);
+        assert_eq!(deflated[0], Stored);
+        assert!(deflated[1..].iter().all(|method| *method == Deflated));
+        assert!(methods(&store).iter().all(|method| *method == Stored));
+
+        let mut output = ZipArchive::new(Cursor::new(deflate)).unwrap();
+        for (name, content, _) in &entries {
+            let mut data = Vec::new();
+            output.by_name(name).unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(&data, content);
+        }
+    }
+
+    #[test]
+    fn test_v11_rejects_unsafe_entry_names() {
+        for name in ["../../.bashrc", "OEBPS/../../evil.xhtml", "/etc/passwd", "\\Windows\\evil.dll", "C:\\evil.dll", "OEBPS\\..\\evil", ""] {
+            let mut encrypted_zip = Vec::new();
+            {
+                let mut zip = zip::ZipWriter::new(Cursor::new(&mut encrypted_zip));
+                zip.start_file("mimetype", zip::write::FileOptions::default()).unwrap();
+                zip.write_all(&encrypt_fixture(b"application/epub+zip", &TEST_KEY, &[0; 16])).unwrap();
+                zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+                zip.write_all(&encrypt_fixture(b"payload", &TEST_KEY, &[1; 16])).unwrap();
+                zip.finish().unwrap();
+            }
+
+            match decrypt_v11(&encrypted_zip, &TEST_KEY) {
+                Err(DecryptError::UnsafeEntry { name: rejected, .. }) => assert_eq!(rejected, name),
+                other => panic!("{:?} was not rejected: {:?}", name, other.map(|data| data.len())),
+            }
+        }
+
+        // Dots are fine as long as they don't climb out
+        for name in ["OEBPS/..hidden", "OEBPS/./chapter.xhtml", "a..b/c", "OEBPS/C:notes"] {
+            assert!(check_entry_name(name).is_ok(), "{:?} was rejected", name);
+        }
+    }
+
+    #[test]
+    fn test_v11_rejects_zip_bombs() {
+        // 8 MiB of zeros deflates to a few kilobytes
+        let bomb = vec![0; 8 * 1024 * 1024];
+        let mut encrypted_zip = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(Cursor::new(&mut encrypted_zip));
+            let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+            zip.start_file("OEBPS/bomb.xhtml", options).unwrap();
+            zip.write_all(&bomb).unwrap();
+            zip.finish().unwrap();
+        }
+
+        let error = decrypt_v11(&encrypted_zip, &TEST_KEY).unwrap_err();
+        assert!(matches!(error, DecryptError::UnsafeEntry { ref name, .. } if name == "OEBPS/bomb.xhtml"), "{}", error);
+        assert!(error.to_string().contains("compressed size"));
+
+        // Small entries that compress well are still fine
+        let mut small_zip = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(Cursor::new(&mut small_zip));
+            let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+            zip.start_file("OEBPS/blank.xhtml", options).unwrap();
+            zip.write_all(&[b' '; 64 * 1024]).unwrap();
+            zip.finish().unwrap();
+        }
+        assert!(decrypt_v11(&small_zip, &TEST_KEY).is_ok());
+    }
+
+    #[test]
+    fn test_v11_distrusts_entry_sizes_in_headers() {
+        let mut crafted_zip = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(Cursor::new(&mut crafted_zip));
+            let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            zip.start_file("OEBPS/chapter1.xhtml", options).unwrap();
+            zip.write_all(b"<html/>").unwrap();
+            zip.finish().unwrap();
+        }
+
+        // Claim close to 4 GiB both compressed and uncompressed in the central directory
+        let header = crafted_zip.windows(4).position(|window| window == b"PK\x01\x02").unwrap();
+        crafted_zip[header + 20..header + 28].copy_from_slice(&[0xf0, 0xff, 0xff, 0xff, 0xf0, 0xff, 0xff, 0xff]);
+
+        // Refused for its size, measured against the real archive, before anything is allocated
+        let error = decrypt_v11(&crafted_zip, &TEST_KEY).unwrap_err();
+        assert!(matches!(error, DecryptError::UnsafeEntry { ref name, .. } if name == "OEBPS/chapter1.xhtml"), "{}", error);
+    }
+
+    #[test]
+    fn test_decrypt_zipped_pdf() {
+        let pdf = b"%PDF-1.4\n1 0 obj << /Type /Catalog >> endobj\n%%EOF\n";
+        let mut encrypted_zip = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(Cursor::new(&mut encrypted_zip));
+            zip.start_file("resources/cover.jpg", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(&encrypt_fixture(&[0xff; 256], &TEST_KEY, &[0; 16])).unwrap();
+            zip.start_file("book.pdf", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(&encrypt_fixture(pdf, &TEST_KEY, &[1; 16])).unwrap();
+            zip.finish().unwrap();
+        }
+
+        // Only the PDF comes out, not a rezipped package
+        assert_eq!(decrypt_zipped_pdf(&encrypted_zip, &TEST_KEY, &NoProgress).unwrap(), pdf);
+        assert!(matches!(
+            decrypt_zipped_pdf(&encrypted_zip, b"fedcba9876543210", &NoProgress),
+            Err(DecryptError::NoPdfEntry)
+        ));
+    }
+
+    #[test]
+    fn test_extract_zip_refuses_unsafe_entries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let zip_path = temp_dir.path().join("book.epub");
+        {
+            let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+            zip.start_file("OEBPS/chapter1.xhtml", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"<html/>").unwrap();
+            zip.start_file("OEBPS/../../outside.txt", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"escaped").unwrap();
+            zip.finish().unwrap();
+        }
+
+        // An earlier extraction is left as it was, and nothing lands outside
+        let output_dir = temp_dir.path().join("output").join("book");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        std::fs::write(output_dir.join("old.xhtml"), b"old").unwrap();
+
+        let error = extract_zip(&zip_path, &output_dir, 0).unwrap_err();
+        assert!(matches!(error, DecryptError::UnsafeEntry { .. }), "{}", error);
+        assert_eq!(std::fs::read(output_dir.join("old.xhtml")).unwrap(), b"old");
+        assert!(!temp_dir.path().join("output").join("book.part").exists());
+        assert!(!temp_dir.path().join("outside.txt").exists());
+    }
+
+    #[test]
+    fn test_retries_stage_apart() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output_path = temp_dir.path().join("book_decrypted.pdf");
+        std::fs::write(&output_path, b"%PDF-1.4 content").unwrap();
+
+        let first = PartialOutput::for_attempt(&output_path, 0);
+        let retry = PartialOutput::for_attempt(&output_path, 2);
+        assert_eq!(first.path(), temp_dir.path().join("book_decrypted.pdf.part"));
+        assert_eq!(retry.path(), temp_dir.path().join("book_decrypted.pdf.2.part"));
+
+        // A retry compressing into place doesn't touch the first attempt's staging file
+        std::fs::write(first.path(), b"first attempt").unwrap();
+        write_compressed(&output_path, &temp_dir.path().join("book_decrypted.pdf.gz"), Compress::Gzip, 2).unwrap();
+        assert_eq!(std::fs::read(first.path()).unwrap(), b"first attempt");
+        assert!(!retry.path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_library_streams_every_book() {
+        use tokio_stream::StreamExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let library = temp_dir.path().join("library");
+        let v1 = write_fixture_book(&library.join("_123").join("1000000001"), DrmVersion::V1).unwrap();
+        let v11 = write_fixture_book(&library.join("_123").join("1000000011"), DrmVersion::V11).unwrap();
+        // Downloaded on another device
+        let other_device = library.join("_123").join("1000000002");
+        write_fixture_book(&other_device, DrmVersion::V1).unwrap();
+        write_fixture_dat(&other_device, "87654321-4321-4321-4321-210987654321").unwrap();
+
+        let config = Config {
+            device_id: TEST_DEVICE_ID.to_string(),
+            user_idx: "123".to_string(),
+            library_path: Some(library.to_string_lossy().to_string()),
+            output_directory: Some(temp_dir.path().join("output").to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let mut results: Vec<_> = decrypt_library(&config).unwrap().collect().await;
+        results.sort_by(|(a, _), (b, _)| a.id.cmp(&b.id));
+
+        let ids: Vec<_> = results.iter().map(|(book, _)| book.id.as_str()).collect();
+        assert_eq!(ids, ["1000000001", "1000000002", "1000000011"]);
+        let output = |i: usize| std::fs::read(results[i].1.as_ref().unwrap()).unwrap();
+        assert!(fixture_matches(&v1, &output(0)));
+        assert!(fixture_matches(&v11, &output(2)));
+        assert!(matches!(results[1].1, Err(DecryptError::WrongDevice { .. })));
+
+        // Books decrypted before are yielded where they are
+        let again: Vec<_> = decrypt_library(&config).unwrap().collect().await;
+        let written = again.iter().find(|(book, _)| book.id == "1000000001").unwrap();
+        assert_eq!(written.1.as_ref().unwrap(), results[0].1.as_ref().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_library_rejects_wrong_key_output() {
+        use tokio_stream::StreamExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let library = temp_dir.path().join("library");
+        let book_dir = library.join("_123").join("1000000003");
+        write_fixture_book(&book_dir, DrmVersion::V1).unwrap();
+        // Content encrypted under another key, with a byte of the second-to-last block
+        // picked so the padding check passes under the key the .dat gives anyway
+        let mut data = encrypt_fixture(FIXTURE_PDF, b"fedcba9876543210", &[7; 16]);
+        let tweak = data.len() - 17;
+        assert!((0..=255).any(|byte| {
+            data[tweak] = byte;
+            decrypt_v1(&data, &TEST_KEY).is_ok()
+        }));
+        std::fs::write(book_dir.join("1000000003.v1.pdf"), &data).unwrap();
+
+        let config = Config {
+            device_id: TEST_DEVICE_ID.to_string(),
+            user_idx: "123".to_string(),
+            library_path: Some(library.to_string_lossy().to_string()),
+            output_directory: Some(temp_dir.path().join("output").to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let results: Vec<_> = decrypt_library(&config).unwrap().collect().await;
+        let [(book, result)] = results.as_slice() else { panic!("expected one book, got {}", results.len()) };
+        assert!(matches!(result, Err(DecryptError::InvalidOutput { .. })), "{:?}", result);
+        assert_eq!(std::fs::read_dir(book.get_output_dir(&config)).unwrap().count(), 0);
+
+        // So the next run tries it again instead of taking garbage for the decrypted book
+        assert_eq!(existing_output(book, &config), None);
+    }
+}
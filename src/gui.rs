@@ -1,10 +1,16 @@
 use eframe::egui;
+use std::collections::BTreeMap;
+use std::fs;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use crate::types::{Config, BookInfo};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use crate::types::{Compress, Config, BookInfo, OnExisting, Recompress, Verbosity, CONFIG_VERSION, DEFAULT_MIN_CONFIDENCE, DEFAULT_TIMEOUT_SECONDS};
 use crate::library_finder::LibraryFinder;
 use crate::credential_manager::CredentialManager;
+use crate::decrypt::{decrypt_book_to_file, decrypted_format, read_book_key, should_skip, verify_decrypted_output, PartialOutput, ProgressSink, SkipReason};
 
 #[derive(Default, PartialEq)]
 enum AppState {
@@ -17,12 +23,15 @@ enum AppState {
 }
 
 struct DecryptionProgress {
-    current: usize,
+    current: usize, // books finished, successfully or not
     total: usize,
-    current_book: String,
+    in_flight: BTreeMap<usize, (String, f32)>, // book index -> (name, 0.0..=1.0 through it)
+    started_at: Instant,
     successful: usize,
     failed: usize,
+    timed_out: usize, // of the failed books
     is_complete: bool,
+    cancelled: bool,
     errors: Vec<(String, String)>, // (book_name, error_message)
 }
 
@@ -31,28 +40,60 @@ impl Default for DecryptionProgress {
         Self {
             current: 0,
             total: 0,
-            current_book: String::new(),
+            in_flight: BTreeMap::new(),
+            started_at: Instant::now(),
             successful: 0,
             failed: 0,
+            timed_out: 0,
             is_complete: false,
+            cancelled: false,
             errors: Vec::new(),
         }
     }
 }
 
+impl DecryptionProgress {
+    // Fraction of the whole batch done, counting the progress of books in flight
+    fn overall_fraction(&self) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        let in_flight: f32 = self.in_flight.values().map(|(_, fraction)| fraction).sum();
+        ((self.current as f32 + in_flight) / self.total as f32).min(1.0)
+    }
+
+    // Time left at the rate so far; None until there's enough progress to tell
+    fn eta(&self) -> Option<Duration> {
+        let fraction = self.overall_fraction();
+        if fraction <= 0.01 {
+            return None;
+        }
+        Some(self.started_at.elapsed().mul_f32((1.0 - fraction) / fraction))
+    }
+}
+
 pub struct RidiculousApp {
     // Configuration
     device_id: String,
     user_idx: String,
-    library_path: String,
+    library_path: String, // where to look for books; empty to auto-detect
+    output_dir: String,   // where decrypted books go; empty for the CLI's default
+    // The config file shared with the CLI, where the fields above are remembered
+    config_path: Option<PathBuf>,
 
     // State
     state: AppState,
     books: Vec<BookInfo>,
     selected_books: Vec<bool>,
+    // How many books are decrypted at once, like the CLI's --parallel
+    parallel: usize,
+    // A book taking longer is given up on so the rest carry on; timeout_seconds in the config
+    timeout_seconds: u64,
 
     // Progress tracking (wrapped in Arc<Mutex> for thread safety)
     progress: Arc<Mutex<DecryptionProgress>>,
+    // Set by the Cancel button; books not yet started are skipped
+    cancel: Arc<AtomicBool>,
 
     // Results
     error_message: String,
@@ -64,45 +105,144 @@ impl Default for RidiculousApp {
             device_id: String::new(),
             user_idx: String::new(),
             library_path: String::new(),
+            output_dir: String::new(),
+            config_path: None,
             state: AppState::Setup,
             books: Vec::new(),
             selected_books: Vec::new(),
+            parallel: 4,
+            timeout_seconds: DEFAULT_TIMEOUT_SECONDS,
             progress: Arc::new(Mutex::new(DecryptionProgress::default())),
+            cancel: Arc::new(AtomicBool::new(false)),
             error_message: String::new(),
         }
     }
 }
 
 impl RidiculousApp {
-    pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        Self::default()
+    pub fn new(_cc: &eframe::CreationContext<'_>, config_path: PathBuf) -> Self {
+        let mut app = Self {
+            config_path: Some(config_path),
+            ..Self::default()
+        };
+
+        if let Some(config) = app.load_saved_config() {
+            app.device_id = config.device_id;
+            app.user_idx = config.user_idx;
+            app.library_path = config.library_path.unwrap_or_default();
+            app.output_dir = config.output_directory.unwrap_or_default();
+            app.timeout_seconds = config.timeout_seconds;
+        }
+        app
+    }
+
+    // The saved config, with its default profile's account selected
+    fn load_saved_config(&self) -> Option<Config> {
+        let path = self.config_path.as_ref()?;
+        let content = fs::read_to_string(path).ok()?;
+        let mut config: Config = match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable config {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        crate::upgrade_config_file(&mut config, path, &content);
+        let _ = config.select_profile(None);
+        Some(config)
+    }
+
+    // Remember the credentials and folders for the next launch, and for the CLI.
+    // Nothing is written until both credentials are filled in, or if nothing changed.
+    fn save_settings(&self) {
+        let Some(path) = &self.config_path else { return };
+        if self.device_id.is_empty() || self.user_idx.is_empty() {
+            return;
+        }
+
+        // Keep every other setting in the file; a file that can't be read is never replaced
+        let mut config = if path.exists() {
+            match fs::read_to_string(path).ok().and_then(|content| toml::from_str::<Config>(&content).ok()) {
+                Some(config) => config,
+                None => return,
+            }
+        } else {
+            Config::default()
+        };
+        config.migrate();
+
+        let mut selected = config.clone();
+        let _ = selected.select_profile(None);
+        let library_path = (!self.library_path.is_empty()).then(|| self.library_path.clone());
+        let output_directory = (!self.output_dir.is_empty()).then(|| self.output_dir.clone());
+        if selected.device_id == self.device_id
+            && selected.user_idx == self.user_idx
+            && selected.library_path == library_path
+            && selected.output_directory == output_directory
+        {
+            return;
+        }
+
+        // Saved to the profile it was loaded from, for configs with [[profile]] tables
+        config.device_id = self.device_id.clone();
+        config.user_idx = self.user_idx.clone();
+        config.store_account();
+        config.library_path = library_path;
+        config.output_directory = output_directory;
+
+        let result = crate::render_config_file(&config)
+            .and_then(|content| Ok(fs::write(path, content)?));
+        if let Err(e) = result {
+            tracing::warn!("Could not save settings to {}: {}", path.display(), e);
+        }
     }
 
     fn discover_books(&mut self) {
         self.state = AppState::Discovering;
         self.books.clear();
         self.error_message.clear();
+        self.device_id = CredentialManager::clean_credential(&self.device_id);
+        self.user_idx = CredentialManager::clean_credential(&self.user_idx);
+        self.save_settings();
 
         let config = Config {
+            version: CONFIG_VERSION,
             device_id: self.device_id.clone(),
             user_idx: self.user_idx.clone(),
-            verbose: false,
+            verbose: Verbosity::Normal,
             organize_output: false,
             backup_originals: false,
-            output_directory: None,
-            library_path: if self.library_path.is_empty() {
-                None
-            } else {
-                Some(self.library_path.clone())
-            },
+            output_directory: (!self.output_dir.is_empty()).then(|| self.output_dir.clone()),
+            library_path: None,
+            extra_library_paths: Vec::new(),
+            fast_scan: false,
+            min_confidence: DEFAULT_MIN_CONFIDENCE,
+            on_existing: OnExisting::Overwrite,
+            recompress: Recompress::Keep,
+            compress: Compress::None,
+            flatten: false,
+            include_samples: false,
             max_retries: 3,
-            timeout_seconds: 30,
+            timeout_seconds: self.timeout_seconds,
+            max_book_time_seconds: 0,
+            state_path: None,
+            proxy: None,
+            ca_cert: None,
+            user_agent: None,
+            api_timeout_seconds: None,
+            default_profile: None,
+            profiles: Vec::new(),
         };
 
         let finder = LibraryFinder::new();
 
-        // Scan for books
-        match finder.find_books(&config) {
+        // Scan the chosen library folder, or auto-detect one
+        let found = if self.library_path.is_empty() {
+            finder.find_books(&config)
+        } else {
+            finder.find_books_in(std::path::Path::new(&self.library_path), &config)
+        };
+        match found {
             Ok(books) => {
                 if books.is_empty() {
                     self.error_message = "No books found in library.".to_string();
@@ -110,7 +250,7 @@ impl RidiculousApp {
                 } else {
                     // Filter out already-decrypted books (just like CLI does)
                     let books_to_decrypt: Vec<BookInfo> = books.into_iter()
-                        .filter(|book| !book.is_already_decrypted(&config))
+                        .filter(|book| should_skip(book, &config) != SkipReason::AlreadyDecryptedOutput)
                         .collect();
 
                     if books_to_decrypt.is_empty() {
@@ -152,56 +292,117 @@ impl RidiculousApp {
             let mut progress = self.progress.lock().unwrap();
             progress.current = 0;
             progress.total = books_to_decrypt.len();
+            progress.in_flight.clear();
+            progress.started_at = Instant::now();
             progress.successful = 0;
             progress.failed = 0;
+            progress.timed_out = 0;
             progress.is_complete = false;
+            progress.cancelled = false;
             progress.errors.clear();
         }
+        self.cancel.store(false, Ordering::Relaxed);
 
         self.state = AppState::Decrypting;
         self.error_message.clear();
 
         let progress = Arc::clone(&self.progress);
+        let cancel = Arc::clone(&self.cancel);
+        let parallel = self.parallel.max(1);
+        let timeout = Duration::from_secs(self.timeout_seconds);
         let device_id = self.device_id.clone();
         let user_idx = self.user_idx.clone();
-        let output_dir = if self.library_path.is_empty() {
-            None
-        } else {
-            Some(self.library_path.clone())
-        };
+        let output_dir = (!self.output_dir.is_empty()).then(|| self.output_dir.clone());
 
         // Spawn background thread for decryption
         thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
 
-            for (i, book) in books_to_decrypt.iter().enumerate() {
-                // Update current book name
-                {
-                    let mut p = progress.lock().unwrap();
-                    p.current_book = book.get_display_name();
-                }
+            // Same bounded concurrency as the CLI's batch mode
+            let semaphore = Arc::new(Semaphore::new(parallel));
 
-                // Decrypt book (calling the actual decryption function)
-                let result = rt.block_on(async {
-                    decrypt_single_book(book, &device_id, &user_idx, output_dir.as_deref()).await
-                });
-
-                // Update progress
-                {
-                    let mut p = progress.lock().unwrap();
-                    p.current = i + 1;
-                    match result {
-                        Ok(_) => p.successful += 1,
-                        Err(e) => {
-                            p.failed += 1;
-                            p.errors.push((book.get_display_name(), e.to_string()));
+            rt.block_on(async {
+                let mut handles = Vec::new();
+
+                for (i, book) in books_to_decrypt.into_iter().enumerate() {
+                    let semaphore = Arc::clone(&semaphore);
+                    let progress = Arc::clone(&progress);
+                    let cancel = Arc::clone(&cancel);
+                    let ctx = ctx.clone();
+                    let (device_id, user_idx, output_dir) = (device_id.clone(), user_idx.clone(), output_dir.clone());
+
+                    handles.push(tokio::spawn(async move {
+                        let _permit = semaphore.acquire().await
+                            .expect("Failed to acquire semaphore");
+
+                        // A book is never abandoned halfway, so cancelling leaves no partial output behind
+                        if cancel.load(Ordering::Relaxed) {
+                            progress.lock().unwrap().cancelled = true;
+                            return;
                         }
-                    }
+
+                        let name = book.get_display_name();
+                        progress.lock().unwrap().in_flight.insert(i, (name.clone(), 0.0));
+                        ctx.request_repaint();
+
+                        // Decrypt on a blocking task, which reports its progress straight into the shared state
+                        let book_progress = BookProgress { progress: Arc::clone(&progress), index: i, ctx: ctx.clone() };
+                        let abandoned = Arc::new(AtomicBool::new(false));
+                        let task = tokio::task::spawn_blocking({
+                            let abandoned = Arc::clone(&abandoned);
+                            move || {
+                                let mut book = book;
+                                let result = decrypt_single_book(&mut book, &device_id, &user_idx, output_dir.as_deref(), &book_progress, &abandoned);
+                                (book, result)
+                            }
+                        });
+                        // A blocking task can't be stopped, so a book that's stuck is left to
+                        // finish in the background without writing anything, and the next one starts
+                        let result = if timeout.is_zero() {
+                            Ok(task.await)
+                        } else {
+                            tokio::time::timeout(timeout, task).await
+                        };
+                        let timed_out = result.is_err();
+                        // Report the book under its real title once decryption has read it
+                        let (name, result) = match result {
+                            Ok(Ok((decrypted_book, result))) => (decrypted_book.get_display_name(), result),
+                            Ok(Err(e)) => (name, Err(anyhow::anyhow!("Decryption task failed: {}", e))),
+                            Err(_) => {
+                                abandoned.store(true, Ordering::Relaxed);
+                                (name, Err(anyhow::anyhow!(
+                                    "⏱️ Timed out after {}s (raise timeout_seconds in the config for very large books)",
+                                    timeout.as_secs()
+                                )))
+                            }
+                        };
+
+                        // Update progress; one lock so the counts and in-flight list always agree
+                        {
+                            let mut p = progress.lock().unwrap();
+                            p.in_flight.remove(&i);
+                            p.current += 1;
+                            match result {
+                                Ok(_) => p.successful += 1,
+                                Err(e) => {
+                                    p.failed += 1;
+                                    p.timed_out += usize::from(timed_out);
+                                    p.errors.push((name, e.to_string()));
+                                }
+                            }
+                        }
+
+                        // Request repaint
+                        ctx.request_repaint();
+                    }));
                 }
 
-                // Request repaint
-                ctx.request_repaint();
-            }
+                for handle in handles {
+                    if let Err(e) = handle.await {
+                        tracing::error!("GUI decryption task panicked: {}", e);
+                    }
+                }
+            });
 
             // Mark as complete
             {
@@ -214,228 +415,78 @@ impl RidiculousApp {
     }
 }
 
-// Simplified decryption function for GUI
-async fn decrypt_single_book(
-    book: &BookInfo,
-    device_id: &str,
-    _user_idx: &str,
-    output_dir: Option<&str>
-) -> anyhow::Result<()> {
-    use anyhow::Context;
-    use std::fs;
-    use std::io::Read as _;
-
-    // Check if book file is already in plaintext (valid zip)
-    if !book.is_v11 && !book.book_filename.contains(".v") {
-        let book_path = book.get_book_file_path();
-        if book_path.exists() {
-            if let Ok(file) = fs::File::open(&book_path) {
-                if let Ok(zip) = zip::ZipArchive::new(file) {
-                    if zip.len() > 0 {
-                        // It's already a valid zip/epub, skip it
-                        return Ok(());
-                    }
-                }
-            }
-        }
-    }
-
-    // Check if already decrypted in output location
-    let output_path = if let Some(dir) = output_dir {
-        PathBuf::from(dir).join(book.get_output_filename())
-    } else if let Some(library_path) = book.path.parent() {
-        library_path.join(book.get_output_filename())
-    } else {
-        book.path.join(book.get_output_filename())
-    };
-
-    if output_path.exists() {
-        return Ok(()); // Skip already decrypted books
-    }
-
-    // Read .dat file
-    let dat_path = book.get_data_file_path();
-    let mut dat_file = fs::File::open(&dat_path)
-        .with_context(|| format!("Failed to open .dat file: {}", dat_path.display()))?;
-
-    let mut dat_data = Vec::new();
-    dat_file.read_to_end(&mut dat_data)?;
-
-    // Extract key from .dat file
-    let key = extract_key_from_dat(&dat_data, device_id)?;
-
-    // Decrypt book
-    let decrypted_content = if book.is_v11 {
-        decrypt_v11_book(book, &key)?
-    } else {
-        decrypt_v1_book(book, &key)?
-    };
-
-    // Write decrypted book
-    fs::write(&output_path, &decrypted_content)
-        .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
-
-    Ok(())
+// Reports one in-flight book's progress to the window
+struct BookProgress {
+    progress: Arc<Mutex<DecryptionProgress>>,
+    index: usize,
+    ctx: egui::Context,
 }
 
-fn extract_key_from_dat(dat_data: &[u8], device_id: &str) -> anyhow::Result<[u8; 16]> {
-    use aes::cipher::{BlockDecryptMut, KeyIvInit};
-
-    if dat_data.len() < 32 {
-        anyhow::bail!(
-            ".dat file corrupted (only {} bytes)\n\
-             Expected at least 32 bytes. Try re-downloading the book.",
-            dat_data.len()
-        );
-    }
-
-    let key_bytes = device_id.as_bytes();
-    let mut key = [0u8; 16];
-    let len = key_bytes.len().min(16);
-    key[..len].copy_from_slice(&key_bytes[..len]);
-
-    let mut iv = [0u8; 16];
-    iv.copy_from_slice(&dat_data[0..16]);
-
-    let mut encrypted = dat_data[16..].to_vec();
-
-    let decrypted = cbc::Decryptor::<aes::Aes128>::new(&key.into(), &iv.into())
-        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut encrypted)
-        .map_err(|_| anyhow::anyhow!(
-            "Wrong device_id for this book\n\
-             \n\
-             This book was downloaded on a different device.\n\
-             Try device_id from: https://account.ridibooks.com/api/user-devices/app"
-        ))?;
-
-    // Convert to UTF-8 string (the .dat contains text data)
-    let plaintext_str = std::str::from_utf8(decrypted)
-        .map_err(|_| anyhow::anyhow!(
-            "Decrypted data contains invalid text\n\
-             The .dat file might be corrupted."
-        ))?;
-
-    if plaintext_str.len() < 84 {
-        anyhow::bail!(
-            "Decrypted data too short ({} chars)\n\
-             The .dat file appears corrupted.",
-            plaintext_str.len()
-        );
+impl ProgressSink for BookProgress {
+    fn set_fraction(&self, fraction: f32) {
+        if let Some((_, book_fraction)) = self.progress.lock().unwrap().in_flight.get_mut(&self.index) {
+            *book_fraction = fraction;
+        }
+        self.ctx.request_repaint();
     }
-
-    // Extract key from characters 68-84 (not bytes!)
-    let mut book_key = [0u8; 16];
-    let key_slice = &plaintext_str[68..84];
-    let key_bytes = key_slice.as_bytes();
-    let copy_len = std::cmp::min(16, key_bytes.len());
-    book_key[..copy_len].copy_from_slice(&key_bytes[..copy_len]);
-
-    Ok(book_key)
 }
 
-fn decrypt_v1_book(book: &BookInfo, key: &[u8; 16]) -> anyhow::Result<Vec<u8>> {
-    use aes::cipher::{BlockDecryptMut, KeyIvInit};
+// Simplified decryption function for GUI
+fn decrypt_single_book(
+    book: &mut BookInfo,
+    device_id: &str,
+    _user_idx: &str,
+    output_dir: Option<&str>,
+    progress: &dyn ProgressSink,
+    // Set once the book has timed out, after which nothing may be written
+    abandoned: &AtomicBool,
+) -> anyhow::Result<()> {
     use anyhow::Context;
-    use std::fs;
-    use std::io::Read as _;
-
-    let book_path = book.get_book_file_path();
-    let mut file = fs::File::open(&book_path)
-        .with_context(|| format!("Failed to open book file: {}", book_path.display()))?;
-
-    let mut encrypted_data = Vec::new();
-    file.read_to_end(&mut encrypted_data)?;
-
-    if encrypted_data.len() < 16 {
-        anyhow::bail!("Book file too small");
-    }
 
-    let mut iv = [0u8; 16];
-    iv.copy_from_slice(&encrypted_data[0..16]);
-
-    let mut encrypted = encrypted_data[16..].to_vec();
-
-    let decrypted = cbc::Decryptor::<aes::Aes128>::new(key.into(), &iv.into())
-        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut encrypted)
-        .map_err(|e| anyhow::anyhow!(
-            "Book decryption failed: {}\n\
-             \n\
-             Wrong device_id for this book?\n\
-             Try credentials from the device where you downloaded the book.",
-            e
-        ))?;
-
-    Ok(decrypted.to_vec())
-}
-
-fn decrypt_v11_book(book: &BookInfo, key: &[u8; 16]) -> anyhow::Result<Vec<u8>> {
-    use anyhow::Context;
-    use std::fs;
-    use std::io::{Read as _, Write as _};
-    use zip::ZipArchive;
-
-    let book_file_path = book.get_book_file_path();
-    let book_file = fs::File::open(&book_file_path)
-        .with_context(|| format!("Failed to open v11 book file: {}", book_file_path.display()))?;
-
-    let mut zip = ZipArchive::new(book_file)
-        .context("Failed to read v11 book as ZIP")?;
-
-    // Create output ZIP in memory
-    let mut output_buffer = Vec::new();
-    {
-        let mut output_zip = zip::ZipWriter::new(std::io::Cursor::new(&mut output_buffer));
-
-        for i in 0..zip.len() {
-            let mut file = zip.by_index(i)?;
-            let file_name = file.name().to_string();
-
-            // Read encrypted file data
-            let mut encrypted_data = Vec::new();
-            file.read_to_end(&mut encrypted_data)?;
-            drop(file);
-
-            // Decrypt the file
-            let decrypted_data = match decrypt_v11_file_content(&encrypted_data, key) {
-                Ok(data) => data,
-                Err(_) => encrypted_data // Keep original if decryption fails
-            };
-
-            // Write to output ZIP
-            let options = zip::write::FileOptions::default()
-                .compression_method(zip::CompressionMethod::Deflated);
-            output_zip.start_file(&file_name, options)?;
-            output_zip.write_all(&decrypted_data)?;
+    // Same skip rule as the CLI
+    let config = Config {
+        output_directory: output_dir.map(str::to_string),
+        ..Default::default()
+    };
+    let output_path = book.get_output_dir(&config).join(book.get_output_filename());
+    match should_skip(book, &config) {
+        SkipReason::AlreadyDecryptedOutput => return Ok(()),
+        // DRM-free books are copied unchanged
+        SkipReason::PlaintextContainer => {
+            let book_path = book.get_book_file_path();
+            let partial = PartialOutput::new(&output_path);
+            fs::copy(&book_path, partial.path())
+                .and_then(|_| partial.persist(&output_path))
+                .with_context(|| format!("Failed to copy {} to {}", book_path.display(), output_path.display()))?;
+            return Ok(());
         }
-
-        output_zip.finish()?;
+        SkipReason::None => {}
     }
 
-    Ok(output_buffer)
-}
-
-fn decrypt_v11_file_content(encrypted_data: &[u8], key: &[u8; 16]) -> anyhow::Result<Vec<u8>> {
-    use aes::cipher::{BlockDecryptMut, KeyIvInit};
-
-    if encrypted_data.len() < 16 {
-        anyhow::bail!("File too small for v11 decryption: {} bytes", encrypted_data.len());
+    // Decrypt book; v1 books are streamed to disk so large PDFs aren't held in memory
+    // Written to a partial file first, so a failure or a closed window never leaves a truncated output
+    let key = read_book_key(book, device_id)?;
+    let partial = PartialOutput::new(&output_path);
+    decrypt_book_to_file(book, &key, partial.path(), config.recompress, progress)?;
+
+    // Image-only EPUBs are written as .cbz comics, and a wrong key that got past the
+    // padding check is caught before anything is written
+    book.format = decrypted_format(book, partial.path());
+    verify_decrypted_output(book, partial.path())?;
+    let output_path = book.get_output_dir(&config).join(book.get_output_filename());
+    if abandoned.load(Ordering::Relaxed) {
+        anyhow::bail!("Gave up on this book after it timed out");
     }
+    partial.persist(&output_path)
+        .with_context(|| format!("Failed to write output file: {}", output_path.display()))?;
 
-    let mut iv = [0; 16];
-    iv.copy_from_slice(&encrypted_data[0..16]);
-    let mut encrypted = encrypted_data[16..].to_vec();
-
-    let decrypted = cbc::Decryptor::<aes::Aes128>::new(key.into(), &iv.into())
-        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut encrypted)
-        .map_err(|e| anyhow::anyhow!(
-            "v11 file decryption failed: {}\n\
-             \n\
-             This v11 EPUB file couldn't be decrypted.\n\
-             Likely using wrong device_id for this book.",
-            e
-        ))?;
-
-    Ok(decrypted.to_vec())
+    // Cache the real title, author and series from the OPF metadata for the progress and error lists
+    let metadata = fs::File::open(&output_path)
+        .map(crate::types::read_epub_metadata)
+        .unwrap_or_default();
+    book.apply_metadata(metadata);
+
+    Ok(())
 }
 
 impl eframe::App for RidiculousApp {
@@ -508,6 +559,20 @@ impl eframe::App for RidiculousApp {
                     });
 
                     ui.label("(Leave empty to auto-detect)");
+                    ui.add_space(10.0);
+
+                    ui.horizontal(|ui| {
+                        ui.label("Output Folder:");
+                        ui.text_edit_singleline(&mut self.output_dir)
+                            .on_hover_text("Where decrypted books are saved (optional)");
+                        if ui.button("📁 Browse...").clicked() {
+                            if let Some(path) = rfd::FileDialog::new().pick_folder() {
+                                self.output_dir = path.display().to_string();
+                            }
+                        }
+                    });
+
+                    ui.label("(Leave empty to save next to your library)");
                     ui.add_space(20.0);
 
                     if !self.error_message.is_empty() {
@@ -550,11 +615,7 @@ impl eframe::App for RidiculousApp {
                             for (i, book) in self.books.iter().enumerate() {
                                 ui.horizontal(|ui| {
                                     ui.checkbox(&mut self.selected_books[i], "");
-                                    let label = format!(
-                                        "{} ({})",
-                                        book.get_display_name(),
-                                        if book.is_v11 { "v11 DRM" } else { "v1 DRM" }
-                                    );
+                                    let label = format!("{} ({} DRM)", book.get_display_name(), book.drm_version);
                                     ui.label(label);
                                 });
                             }
@@ -575,6 +636,14 @@ impl eframe::App for RidiculousApp {
 
                     ui.add_space(10.0);
 
+                    ui.horizontal(|ui| {
+                        ui.label("Parallel:");
+                        ui.add(egui::Slider::new(&mut self.parallel, 1..=8).suffix(" books at once"))
+                            .on_hover_text("How many books to decrypt at the same time");
+                    });
+
+                    ui.add_space(10.0);
+
                     if selected_count == 0 {
                         ui.label("⚠️ Please select at least one book to decrypt");
                     } else {
@@ -591,9 +660,10 @@ impl eframe::App for RidiculousApp {
                 }
 
                 AppState::Decrypting => {
-                    let (current, total, current_book, is_complete) = {
+                    let (current, total, in_flight, overall, eta, is_complete) = {
                         let p = self.progress.lock().unwrap();
-                        (p.current, p.total, p.current_book.clone(), p.is_complete)
+                        (p.current, p.total, p.in_flight.values().cloned().collect::<Vec<_>>(),
+                         p.overall_fraction(), p.eta(), p.is_complete)
                     };
 
                     if is_complete {
@@ -602,19 +672,27 @@ impl eframe::App for RidiculousApp {
                         ui.label("🔄 Decrypting books...");
                         ui.add_space(10.0);
 
-                        let progress = if total > 0 {
-                            current as f32 / total as f32
-                        } else {
-                            0.0
-                        };
-
-                        ui.add(egui::ProgressBar::new(progress)
+                        ui.add(egui::ProgressBar::new(overall)
                             .show_percentage()
                             .text(format!("{} / {}", current, total)));
 
-                        if !current_book.is_empty() {
+                        for (book_name, book_fraction) in &in_flight {
                             ui.add_space(10.0);
-                            ui.label(format!("📖 Current: {}", current_book));
+                            ui.label(format!("📖 {}", book_name));
+                            ui.add(egui::ProgressBar::new(*book_fraction).show_percentage());
+                        }
+
+                        if let Some(eta) = eta {
+                            let secs = eta.as_secs();
+                            ui.add_space(10.0);
+                            ui.label(format!("⏱ About {}m {:02}s left", secs / 60, secs % 60));
+                        }
+
+                        ui.add_space(10.0);
+                        if self.cancel.load(Ordering::Relaxed) {
+                            ui.label("⏹ Cancelling after the books in progress...");
+                        } else if ui.button("⏹ Cancel").clicked() {
+                            self.cancel.store(true, Ordering::Relaxed);
                         }
 
                         ctx.request_repaint();
@@ -622,12 +700,18 @@ impl eframe::App for RidiculousApp {
                 }
 
                 AppState::Complete => {
-                    let (successful, failed, errors) = {
+                    let (successful, failed, timed_out, errors, cancelled, current, total) = {
                         let p = self.progress.lock().unwrap();
-                        (p.successful, p.failed, p.errors.clone())
+                        (p.successful, p.failed, p.timed_out, p.errors.clone(), p.cancelled, p.current, p.total)
                     };
 
-                    ui.heading("✅ Decryption Complete!");
+                    if cancelled {
+                        ui.heading("⏹ Decryption Cancelled");
+                        ui.add_space(10.0);
+                        ui.label(format!("Stopped after {} of {} books.", current, total));
+                    } else {
+                        ui.heading("✅ Decryption Complete!");
+                    }
                     ui.add_space(20.0);
 
                     ui.label(format!("✅ Successfully decrypted: {}", successful));
@@ -636,6 +720,9 @@ impl eframe::App for RidiculousApp {
                     } else {
                         ui.label(format!("❌ Failed: {}", failed));
                     }
+                    if timed_out > 0 {
+                        ui.colored_label(egui::Color32::RED, format!("⏱️ Of which timed out: {}", timed_out));
+                    }
 
                     // Show error details if there are any failures
                     if !errors.is_empty() {
@@ -671,7 +758,8 @@ impl eframe::App for RidiculousApp {
     }
 }
 
-pub fn run_gui() -> Result<(), eframe::Error> {
+/// Opens the window; settings are loaded from and saved to `config_path`.
+pub fn run_gui(config_path: PathBuf) -> Result<(), eframe::Error> {
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([650.0, 550.0])
@@ -682,6 +770,6 @@ pub fn run_gui() -> Result<(), eframe::Error> {
     eframe::run_native(
         "Ridiculous - RIDI Book Decryption",
         options,
-        Box::new(|cc| Ok(Box::new(RidiculousApp::new(cc)))),
+        Box::new(|cc| Ok(Box::new(RidiculousApp::new(cc, config_path)))),
     )
 }
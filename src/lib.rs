@@ -22,7 +22,7 @@
 //! ridiculous --device-id YOUR_ID --user-idx YOUR_INDEX
 //! 
 //! # Diagnostic mode
-//! ridiculous --diagnose
+//! ridiculous diagnose
 //! 
 //! # Force re-decrypt all books
 //! ridiculous --force --verbose
@@ -31,7 +31,44 @@
 pub mod types;
 pub mod library_finder;
 pub mod credential_manager;
+pub mod decrypt;
+pub mod metadata;
 
 pub use types::*;
 pub use library_finder::LibraryFinder;
-pub use credential_manager::CredentialManager;
\ No newline at end of file
+pub use credential_manager::{ClientOptions, CredentialManager, DeviceInfo};
+pub use decrypt::{
+    // Whole books and libraries
+    decrypt_book,
+    decrypt_book_to_file,
+    decrypt_library,
+    DecryptError,
+    DrmVersion,
+    // Keys and the individual DRM formats
+    read_book_key,
+    derive_book_key,
+    key_fingerprint,
+    decrypt_for_version,
+    decrypt_v1,
+    decrypt_v1_streaming,
+    decrypt_v11,
+    decrypt_v11_with_progress,
+    decrypt_zipped_pdf,
+    detect_zip_format,
+    // Outputs
+    decrypted_format,
+    existing_output,
+    extract_zip,
+    is_plaintext_container,
+    is_valid_output,
+    open_output,
+    should_skip,
+    verify_decrypted_output,
+    write_compressed,
+    PartialOutput,
+    SkipReason,
+    // Progress reporting
+    BarProgress,
+    NoProgress,
+    ProgressSink,
+};
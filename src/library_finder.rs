@@ -1,11 +1,20 @@
-use miette::{IntoDiagnostic, miette};
+use miette::miette;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 
+use crate::metadata;
 use crate::types::*;
 
 pub struct LibraryFinder {
-    common_paths: Vec<PathBuf>,
+    pub(crate) common_paths: Vec<PathBuf>,
+}
+
+impl Default for LibraryFinder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LibraryFinder {
@@ -21,17 +30,23 @@ impl LibraryFinder {
             }
         } else if cfg!(target_os = "macos") {
             if let Ok(home) = std::env::var("HOME") {
-                common_paths.push(PathBuf::from(home)
+                let home = PathBuf::from(home);
+                common_paths.push(home
                     .join("Library")
                     .join("Application Support")
                     .join("Ridibooks")
                     .join("library"));
+                // App Store installs keep their library in the app's sandbox container
+                common_paths.extend(container_library_paths(&home));
             }
         } else {
             // Linux and other Unix-like systems
             if let Some(home) = dirs::home_dir() {
                 common_paths.push(home.join(".local/share/Ridibooks/library"));
                 common_paths.push(home.join(".ridibooks/library"));
+                // Sandboxed and Windows builds run through Flatpak, Snap or Wine
+                let wine_prefix = std::env::var_os("WINEPREFIX").map(PathBuf::from);
+                common_paths.extend(compat_library_paths(&home, wine_prefix.as_deref()));
             }
         }
         
@@ -39,10 +54,25 @@ impl LibraryFinder {
     }
     
     pub fn find_library_locations(&self) -> Vec<LibraryLocation> {
-        let mut locations = Vec::new();
+        let mut locations: Vec<LibraryLocation> = Vec::new();
+
+        // Installs outside the default location are only discoverable through the registry
+        #[cfg(target_os = "windows")]
+        for path in registry_library_paths() {
+            if path.is_dir() {
+                locations.push(LibraryLocation {
+                    confidence: self.calculate_confidence(&path).max(0.9),
+                    path,
+                    source: LibrarySource::Registry,
+                });
+            }
+        }
         
         // Check common paths
         for path in &self.common_paths {
+            if locations.iter().any(|location| &location.path == path) {
+                continue;
+            }
             if path.exists() && path.is_dir() {
                 let confidence = self.calculate_confidence(path);
                 if confidence > 0.0 {
@@ -60,114 +90,308 @@ impl LibraryFinder {
         locations
     }
     
+    /// Books from every library, best library first. A book found in more than one
+    /// library is only returned once: see [`merge_libraries`].
     pub fn find_books(&self, config: &Config) -> miette::Result<Vec<BookInfo>> {
-        // Use custom library path if provided, otherwise use auto-detection
-        let library_paths = if let Some(custom_path) = &config.library_path {
-            vec![PathBuf::from(custom_path)]
-        } else {
-            self.get_library_paths(&config.user_idx)?
-        };
+        let libraries = self.find_book_libraries(config)?;
+        Ok(merge_libraries(libraries))
+    }
 
-        let mut books = Vec::new();
-        let mut checked_paths = Vec::new();
-        
-        // Try each potential library path
-        for library_path in library_paths {
-            checked_paths.push(library_path.display().to_string());
-            
-            if !library_path.exists() {
-                if config.verbose {
-                    eprintln!("⚠️  Path doesn't exist: {}", library_path.display());
+    /// Books in one known library folder, without searching anywhere else: book folders
+    /// directly inside `root` and inside its `_{user_idx}` folder.
+    pub fn find_books_in(&self, root: &Path, config: &Config) -> miette::Result<Vec<BookInfo>> {
+        let library_paths = root_paths(root, &config.user_idx, LibrarySource::UserSpecified);
+        let libraries = self.scan_library_paths(library_paths, config)?;
+        Ok(merge_libraries(libraries))
+    }
+
+    /// Every candidate library that contains books, best first. Libraries the user
+    /// configured rank above auto-detected ones; within each group, higher confidence wins.
+    /// Auto-detected folders scoring below `config.min_confidence` aren't scanned.
+    pub fn find_book_libraries(&self, config: &Config) -> miette::Result<Vec<(LibraryLocation, Vec<BookInfo>)>> {
+        let library_paths = self.search_paths(config)?;
+        self.scan_library_paths(library_paths, config)
+    }
+
+    /// How many books [`find_books`](Self::find_books) would find, without loading any:
+    /// only the folder names and file extensions are looked at, as for `--count-only`.
+    /// Libraries are searched the same way, and a book in several is counted once.
+    /// A folder whose book file turns out to be unusable still counts here. Fails
+    /// where `find_books` would, including when there are no books at all.
+    pub fn count_books(&self, config: &Config) -> miette::Result<usize> {
+        let library_paths = self.search_paths(config)?;
+        let user_specified_only = library_paths.iter().all(|(_, source)| *source == LibrarySource::UserSpecified);
+
+        let mut checked_paths: Vec<PathBuf> = Vec::new();
+        let mut unreadable = Vec::new();
+        let mut rejected = Vec::new();
+        let mut book_ids = HashSet::new();
+        for (library_path, source) in library_paths {
+            if checked_paths.contains(&library_path) {
+                continue;
+            }
+            checked_paths.push(library_path.clone());
+
+            let entries = match fs::read_dir(&library_path) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    tracing::warn!("Cannot read {}: {}", library_path.display(), e);
+                    unreadable.push(UnreadablePath::new(library_path, e));
+                    continue;
                 }
+            };
+            let confidence = self.calculate_confidence(&library_path);
+            if source != LibrarySource::UserSpecified && confidence < config.min_confidence {
+                rejected.push(LibraryLocation { path: library_path, confidence, source });
                 continue;
             }
-            
-            if config.verbose {
-                println!("🔍 Scanning: {}", library_path.display());
+
+            let mut found = 0;
+            for entry in entries.flatten() {
+                if self.is_book_directory(&entry.path()) {
+                    book_ids.insert(entry.file_name());
+                    found += 1;
+                }
+            }
+            tracing::debug!("Counted {} books in {}", found, library_path.display());
+            if config.fast_scan && found > 0 {
+                break;
+            }
+        }
+
+        if book_ids.is_empty() {
+            return Err(no_books_error(&checked_paths, &unreadable, &rejected, config.min_confidence, user_specified_only));
+        }
+        Ok(book_ids.len())
+    }
+
+    /// The existing folders `find_book_libraries` would look for books in, whether or not
+    /// they hold any yet. `--watch` watches these for new downloads.
+    pub fn library_roots(&self, config: &Config) -> miette::Result<Vec<PathBuf>> {
+        let library_paths = self.search_paths(config)?;
+
+        let mut roots: Vec<PathBuf> = Vec::new();
+        for (path, _) in library_paths {
+            if path.is_dir() && !roots.contains(&path) {
+                roots.push(path);
+            }
+        }
+        Ok(roots)
+    }
+
+    /// The book in `book_dir`, with its metadata, or None if the folder doesn't have
+    /// both a `.dat` and a book file (yet).
+    pub fn load_book(&self, book_dir: &Path) -> Option<BookInfo> {
+        if !self.is_book_directory(book_dir) {
+            return None;
+        }
+
+        let mut books = vec![BookInfo::new(book_dir.to_path_buf()).ok()?];
+        if let Some(library_path) = book_dir.parent() {
+            metadata::enrich_books(&mut books, library_path);
+        }
+        books.pop()
+    }
+
+    // Scan the existing `library_paths` in order, keeping those with books, best first
+    pub(crate) fn scan_library_paths(
+        &self,
+        library_paths: Vec<(PathBuf, LibrarySource)>,
+        config: &Config,
+    ) -> miette::Result<Vec<(LibraryLocation, Vec<BookInfo>)>> {
+        // Only a folder the user gave, such as a copy of a library: no RIDI install involved
+        let user_specified_only = library_paths.iter().all(|(_, source)| *source == LibrarySource::UserSpecified);
+        let mut checked_paths: Vec<PathBuf> = Vec::new();
+        let mut unreadable = Vec::new();
+        let mut candidates = Vec::new();
+        for (library_path, source) in library_paths {
+            if checked_paths.contains(&library_path) {
+                continue;
             }
+            checked_paths.push(library_path.clone());
             
-            // Scan the library directory for book folders
-            match fs::read_dir(&library_path) {
-                Ok(entries) => {
-                    for entry in entries {
-                        let entry = match entry {
-                            Ok(e) => e,
-                            Err(_) => continue,
-                        };
-                        let path = entry.path();
-                        
-                        if path.is_dir() {
-                            // Check if this directory contains book files
-                            if self.is_book_directory(&path) {
-                                if config.verbose {
-                                    println!("📖 Found book directory: {}", path.display());
-                                }
-                                match BookInfo::new(path) {
-                                    Ok(book) => books.push(book),
-                                    Err(e) => {
-                                        if config.verbose {
-                                            eprintln!("⚠️  Failed to process book directory: {}", e);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    
-                    // If we found books in this path, no need to check others
-                    if !books.is_empty() {
-                        break;
-                    }
+            // A folder that's there but can't be looked into is worth reporting; a missing one isn't
+            match fs::metadata(&library_path) {
+                Ok(_) => candidates.push(LibraryLocation {
+                    confidence: self.calculate_confidence(&library_path),
+                    path: library_path,
+                    source,
+                }),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    tracing::debug!("Path doesn't exist: {}", library_path.display());
                 }
-                Err(e) => {
-                    if config.verbose {
-                        eprintln!("⚠️  Cannot read directory {}: {}", library_path.display(), e);
-                    }
+                Err(e) => unreadable.push(UnreadablePath::new(library_path, e)),
+            }
+        }
+
+        // Folders the user named are always scanned, as are unreadable ones (scoring 0),
+        // so they get reported
+        let mut rejected = Vec::new();
+        candidates.retain(|candidate| {
+            let keep = candidate.source == LibrarySource::UserSpecified
+                || candidate.confidence >= config.min_confidence
+                || fs::read_dir(&candidate.path).is_err();
+            if !keep {
+                tracing::info!(
+                    "Skipping {}: confidence {}% is below the minimum of {}%",
+                    candidate.path.display(),
+                    candidate.confidence_percent(),
+                    (config.min_confidence * 100.0) as u32
+                );
+                rejected.push(candidate.clone());
+            }
+            keep
+        });
+
+        let mut libraries = Vec::new();
+        if config.fast_scan {
+            // Stop at the first candidate with books, in search order
+            for candidate in candidates {
+                let (library, errors) = self.scan_candidate(candidate);
+                unreadable.extend(errors);
+                if let Some(library) = library {
+                    libraries.push(library);
+                    break;
                 }
             }
+        } else {
+            // Every root is scanned on its own thread; results keep the search order
+            let scans: Vec<_> = std::thread::scope(|scope| {
+                let scans: Vec<_> = candidates.into_iter()
+                    .map(|candidate| scope.spawn(move || self.scan_candidate(candidate)))
+                    .collect();
+                scans.into_iter()
+                    .filter_map(|scan| scan.join().ok())
+                    .collect()
+            });
+            for (library, errors) in scans {
+                libraries.extend(library);
+                unreadable.extend(errors);
+            }
         }
         
-        if books.is_empty() {
-            return Err(miette!(
-                "No books found in any library location.\n\
-                 Checked paths:\n{}\n\n\
-                 Make sure:\n\
-                 1. RIDI app is installed\n\
-                 2. You've downloaded books in the RIDI app\n\
-                 3. Books are in one of the above locations",
-                checked_paths.join("\n")
-            ));
+        // A folder can turn up both as a root and inside another one
+        unreadable.sort_by(|a, b| a.path.cmp(&b.path));
+        unreadable.dedup_by(|a, b| a.path == b.path);
+        for path in &unreadable {
+            tracing::warn!("Cannot read {}: {}", path.path.display(), path.error);
         }
+
+        if libraries.is_empty() {
+            return Err(no_books_error(&checked_paths, &unreadable, &rejected, config.min_confidence, user_specified_only));
+        }
+
+        // Stable sort, so equally confident libraries keep the search order
+        libraries.sort_by(|(a, _), (b, _)| {
+            let user_specified = |location: &LibraryLocation| location.source == LibrarySource::UserSpecified;
+            user_specified(b).cmp(&user_specified(a))
+                .then(b.confidence.partial_cmp(&a.confidence).unwrap_or(std::cmp::Ordering::Equal))
+        });
         
-        Ok(books)
+        Ok(libraries)
+    }
+
+    // The library at `location`, or None if it holds no books, and the folders in it
+    // that couldn't be read
+    fn scan_candidate(&self, location: LibraryLocation) -> (Option<(LibraryLocation, Vec<BookInfo>)>, Vec<UnreadablePath>) {
+        tracing::debug!("Scanning: {}", location.path.display());
+
+        let mut unreadable = Vec::new();
+        let mut books = self.scan_library(&location.path, &mut unreadable);
+        if books.is_empty() {
+            return (None, unreadable);
+        }
+        metadata::enrich_books(&mut books, &location.path);
+
+        tracing::info!("Found {} books in {}", books.len(), location.path.display());
+        tracing::debug!("Confidence {:.1} for {} ({:?})", location.confidence, location.path.display(), location.source);
+        (Some((location, books)), unreadable)
+    }
+
+    // Book folders directly inside `library_path`. Folders that can't be read, it
+    // included, are added to `unreadable`.
+    fn scan_library(&self, library_path: &Path, unreadable: &mut Vec<UnreadablePath>) -> Vec<BookInfo> {
+        let mut books = Vec::new();
+
+        match fs::read_dir(library_path) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = match entry {
+                        Ok(e) => e,
+                        Err(_) => continue,
+                    };
+                    let path = entry.path();
+                    
+                    if path.is_dir() {
+                        if let Err(e) = fs::read_dir(&path) {
+                            unreadable.push(UnreadablePath::new(path, e));
+                            continue;
+                        }
+                        // Check if this directory contains book files
+                        if self.is_book_directory(&path) {
+                            tracing::debug!("Found book directory: {}", path.display());
+                            match BookInfo::new(path) {
+                                Ok(book) => books.push(book),
+                                Err(e) => tracing::warn!("Failed to process book directory: {}", e),
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => unreadable.push(UnreadablePath::new(library_path.to_path_buf(), e)),
+        }
+
+        books
     }
     
-    fn get_library_paths(&self, user_idx: &str) -> miette::Result<Vec<PathBuf>> {
+    // The custom library path if one is given, otherwise the auto-detected ones
+    fn search_paths(&self, config: &Config) -> miette::Result<Vec<(PathBuf, LibrarySource)>> {
+        if let Some(custom_path) = &config.library_path {
+            Ok(root_paths(Path::new(custom_path), &config.user_idx, LibrarySource::UserSpecified))
+        } else {
+            self.get_library_paths(config)
+        }
+    }
+
+    fn get_library_paths(&self, config: &Config) -> miette::Result<Vec<(PathBuf, LibrarySource)>> {
+        let user_idx = &config.user_idx;
         let mut paths = Vec::new();
+
+        // User-configured roots take priority and are probed the same way as the defaults
+        for root in &config.extra_library_paths {
+            paths.extend(root_paths(Path::new(root), user_idx, LibrarySource::UserSpecified));
+        }
+
+        let mut detected = Vec::new();
         
         #[cfg(target_os = "macos")]
         {
-            let base = PathBuf::from(std::env::var("HOME").into_diagnostic()?)
+            let home = known_dir(dirs::home_dir(), "home directory")?;
+            let legacy = home
                 .join("Library")
                 .join("Application Support")
                 .join("Ridibooks")
                 .join("library");
-            
-            // Try with _{user_idx} subdirectory first
-            paths.push(base.join(format!("_{}", user_idx)));
-            // Then try the base library directory
-            paths.push(base.clone());
-            // Also try scanning for any user directories
-            if base.exists() {
-                if let Ok(entries) = fs::read_dir(&base) {
-                    for entry in entries.flatten() {
-                        let path = entry.path();
-                        if path.is_dir() {
-                            let name = path.file_name()
-                                .and_then(|n| n.to_str())
-                                .unwrap_or("");
-                            if name.starts_with('_') {
-                                paths.push(path);
+
+            // The classic location, then the containers of App Store installs
+            for base in std::iter::once(legacy).chain(container_library_paths(&home)) {
+                // Try with _{user_idx} subdirectory first
+                detected.push(base.join(format!("_{}", user_idx)));
+                // Then try the base library directory
+                detected.push(base.clone());
+                // Also try scanning for any user directories
+                if base.exists() {
+                    if let Ok(entries) = fs::read_dir(&base) {
+                        for entry in entries.flatten() {
+                            let path = entry.path();
+                            if path.is_dir() {
+                                let name = path.file_name()
+                                    .and_then(|n| n.to_str())
+                                    .unwrap_or("");
+                                if name.starts_with('_') {
+                                    detected.push(path);
+                                }
                             }
                         }
                     }
@@ -177,12 +401,19 @@ impl LibraryFinder {
         
         #[cfg(target_os = "windows")]
         {
-            let base = PathBuf::from(std::env::var("APPDATA").into_diagnostic()?)
+            // Registry-reported libraries first, the default %APPDATA% location as fallback
+            for base in registry_library_paths() {
+                paths.push((base.join(format!("_{}", user_idx)), LibrarySource::Registry));
+                paths.push((base, LibrarySource::Registry));
+            }
+
+            let app_data = std::env::var_os("APPDATA").map(PathBuf::from).or_else(dirs::data_dir);
+            let base = known_dir(app_data, "AppData folder")?
                 .join("Ridibooks")
                 .join("library");
             
-            paths.push(base.join(format!("_{}", user_idx)));
-            paths.push(base.clone());
+            detected.push(base.join(format!("_{}", user_idx)));
+            detected.push(base.clone());
             
             if base.exists() {
                 if let Ok(entries) = fs::read_dir(&base) {
@@ -193,7 +424,7 @@ impl LibraryFinder {
                                 .and_then(|n| n.to_str())
                                 .unwrap_or("");
                             if name.starts_with('_') {
-                                paths.push(path);
+                                detected.push(path);
                             }
                         }
                     }
@@ -203,22 +434,26 @@ impl LibraryFinder {
         
         #[cfg(not(any(target_os = "macos", target_os = "windows")))]
         {
-            let home = std::env::var("HOME").into_diagnostic()?;
-            let base = PathBuf::from(home).join(".local/share/Ridibooks/library");
-            
-            paths.push(base.join(format!("_{}", user_idx)));
-            paths.push(base.clone());
-            
-            if base.exists() {
-                if let Ok(entries) = fs::read_dir(&base) {
-                    for entry in entries.flatten() {
-                        let path = entry.path();
-                        if path.is_dir() {
-                            let name = path.file_name()
-                                .and_then(|n| n.to_str())
-                                .unwrap_or("");
-                            if name.starts_with('_') {
-                                paths.push(path);
+            let home = known_dir(dirs::home_dir(), "home directory")?;
+            let base = home.join(".local/share/Ridibooks/library");
+            let wine_prefix = std::env::var_os("WINEPREFIX").map(PathBuf::from);
+
+            // The native location, then Flatpak, Snap and Wine installs
+            for base in std::iter::once(base).chain(compat_library_paths(&home, wine_prefix.as_deref())) {
+                detected.push(base.join(format!("_{}", user_idx)));
+                detected.push(base.clone());
+
+                if base.exists() {
+                    if let Ok(entries) = fs::read_dir(&base) {
+                        for entry in entries.flatten() {
+                            let path = entry.path();
+                            if path.is_dir() {
+                                let name = path.file_name()
+                                    .and_then(|n| n.to_str())
+                                    .unwrap_or("");
+                                if name.starts_with('_') {
+                                    detected.push(path);
+                                }
                             }
                         }
                     }
@@ -226,6 +461,7 @@ impl LibraryFinder {
             }
         }
         
+        paths.extend(detected.into_iter().map(|path| (path, LibrarySource::CommonPath)));
         Ok(paths)
     }
     
@@ -304,4 +540,331 @@ impl LibraryFinder {
         
         has_dat && has_book
     }    
-}
\ No newline at end of file
+}
+
+// A folder that was there but couldn't be read while looking for books
+#[derive(Debug)]
+struct UnreadablePath {
+    path: PathBuf,
+    error: io::Error,
+}
+
+impl UnreadablePath {
+    fn new(path: PathBuf, error: io::Error) -> Self {
+        Self { path, error }
+    }
+}
+
+// The error for when no library holds any books, listing where was looked
+fn no_books_error(
+    checked_paths: &[PathBuf],
+    unreadable: &[UnreadablePath],
+    rejected: &[LibraryLocation],
+    min_confidence: f32,
+    user_specified_only: bool,
+) -> miette::Report {
+    let hint = if user_specified_only {
+        "Make sure the folder is a RIDI `library` folder, or a copy of one: it holds a\n\
+         folder per book (with the book's .dat), directly or in a _{user_idx} folder"
+    } else {
+        "Make sure:\n\
+         1. RIDI app is installed\n\
+         2. You've downloaded books in the RIDI app\n\
+         3. Books are in one of the above locations"
+    };
+    miette!(
+        "No books found in any library location.\n\
+         Checked paths:\n{}\n\n{}{}{}",
+        checked_paths.iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        unreadable_note(unreadable),
+        rejected_note(rejected, min_confidence),
+        hint
+    )
+}
+
+// The part of the "no books" error about folders that couldn't be read, permission
+// problems first since they're the usual cause and have the usual fix; empty if none
+fn unreadable_note(unreadable: &[UnreadablePath]) -> String {
+    let (denied, other): (Vec<_>, Vec<_>) = unreadable.iter()
+        .partition(|path| path.error.kind() == io::ErrorKind::PermissionDenied);
+
+    let mut note = String::new();
+    if !denied.is_empty() {
+        note.push_str("Some locations couldn't be read (permission denied):\n");
+        for path in &denied {
+            note.push_str(&format!("{}\n", path.path.display()));
+        }
+        note.push_str("💡 Give your user read access to them (on macOS, Full Disk Access for your terminal).\n\n");
+    }
+    if !other.is_empty() {
+        note.push_str("Some locations couldn't be read:\n");
+        for path in &other {
+            note.push_str(&format!("{}: {}\n", path.path.display(), path.error));
+        }
+        note.push('\n');
+    }
+    note
+}
+
+// The auto-detected folders passed over for a low confidence score, with their scores,
+// so the threshold can be lowered when they were the right ones after all
+fn rejected_note(rejected: &[LibraryLocation], min_confidence: f32) -> String {
+    if rejected.is_empty() {
+        return String::new();
+    }
+
+    let mut note = format!("Skipped for scoring below the minimum confidence of {}%:\n", (min_confidence * 100.0) as u32);
+    for location in rejected {
+        note.push_str(&format!("{} ({}%)\n", location.path.display(), location.confidence_percent()));
+    }
+    note.push_str("💡 Lower the threshold with --min-confidence (e.g. --min-confidence 0.1) to scan them.\n\n");
+    note
+}
+
+// Where books may sit under a library root: its `_{user_idx}` folder, then the root itself
+fn root_paths(root: &Path, user_idx: &str, source: LibrarySource) -> Vec<(PathBuf, LibrarySource)> {
+    let mut paths = Vec::new();
+    if !user_idx.is_empty() {
+        paths.push((root.join(format!("_{}", user_idx)), source.clone()));
+    }
+    paths.push((root.to_path_buf(), source));
+    paths
+}
+
+// Libraries of the Mac App Store build, which is sandboxed into
+// ~/Library/Containers/<bundle-id>/Data/Library/Application Support/Ridibooks/library.
+// Rather than hardcode a bundle id, every container named after RIDI is probed.
+pub(crate) fn container_library_paths(home: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(home.join("Library").join("Containers")) else {
+        return Vec::new();
+    };
+
+    let mut paths: Vec<_> = entries.flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().to_lowercase().contains("ridi"))
+        .map(|entry| entry.path()
+            .join("Data")
+            .join("Library")
+            .join("Application Support")
+            .join("Ridibooks")
+            .join("library"))
+        .filter(|path| path.is_dir())
+        .collect();
+    paths.sort();
+    paths
+}
+
+// Libraries of RIDI run through a compatibility layer on Linux, wherever one exists:
+// - Flatpak: ~/.var/app/<app-id>/data/Ridibooks/library
+// - Snap: ~/snap/<name>/current/.local/share/Ridibooks/library
+// - Wine: <prefix>/drive_c/users/<user>/AppData/Roaming/Ridibooks/library, in $WINEPREFIX
+//   and ~/.wine
+// Only folders that are there are returned, so missing ones don't count against confidence.
+pub(crate) fn compat_library_paths(home: &Path, wine_prefix: Option<&Path>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    for app in subdirs(&home.join(".var").join("app")) {
+        paths.push(app.join("data").join("Ridibooks").join("library"));
+    }
+    for snap in subdirs(&home.join("snap")) {
+        paths.push(snap.join("current").join(".local/share/Ridibooks/library"));
+    }
+
+    let mut prefixes: Vec<PathBuf> = wine_prefix.map(Path::to_path_buf).into_iter().collect();
+    prefixes.push(home.join(".wine"));
+    prefixes.dedup();
+    for prefix in prefixes {
+        for user in subdirs(&prefix.join("drive_c").join("users")) {
+            paths.push(user.join("AppData").join("Roaming").join("Ridibooks").join("library"));
+        }
+    }
+
+    paths.retain(|path| path.is_dir());
+    paths
+}
+
+// The folders directly inside `dir`, sorted; none if it can't be read
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<_> = fs::read_dir(dir)
+        .map(|entries| entries.flatten().map(|entry| entry.path()).filter(|path| path.is_dir()).collect())
+        .unwrap_or_default();
+    dirs.sort();
+    dirs
+}
+
+// Library folders recorded in HKEY_CURRENT_USER: an explicit library path under
+// Software\Ridibooks, or the `library` folder next to a per-user install location
+#[cfg(target_os = "windows")]
+fn registry_library_paths() -> Vec<PathBuf> {
+    use winreg::enums::HKEY_CURRENT_USER;
+    use winreg::RegKey;
+
+    const UNINSTALL_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Uninstall";
+
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let mut paths = Vec::new();
+
+    if let Ok(ridibooks) = hkcu.open_subkey(r"Software\Ridibooks") {
+        for value in ["LibraryPath", "InstallLocation"] {
+            if let Ok(path) = ridibooks.get_value::<String, _>(value) {
+                let path = PathBuf::from(path);
+                paths.push(if value == "LibraryPath" { path } else { path.join("library") });
+            }
+        }
+    }
+
+    if let Ok(uninstall) = hkcu.open_subkey(UNINSTALL_KEY) {
+        for name in uninstall.enum_keys().flatten() {
+            let Ok(app) = uninstall.open_subkey(&name) else { continue };
+            let is_ridibooks = app.get_value::<String, _>("DisplayName")
+                .is_ok_and(|display_name| display_name.to_lowercase().contains("ridibooks"));
+            if !is_ridibooks {
+                continue;
+            }
+            if let Ok(install_location) = app.get_value::<String, _>("InstallLocation") {
+                paths.push(PathBuf::from(install_location).join("library"));
+            }
+        }
+    }
+
+    paths.dedup();
+    paths
+}
+
+// A platform directory the default library locations are under. Some containers and CI
+// runners have none, so point at --library-path instead of failing on a missing variable.
+fn known_dir(dir: Option<PathBuf>, what: &str) -> miette::Result<PathBuf> {
+    dir.ok_or_else(|| miette!(
+        "❌ Could not determine your {}, where RIDI libraries are looked for\n\
+         💡 Pass --library-path (or set library_path in the config) to say where your library is.",
+        what
+    ))
+}
+
+/// Flattens libraries ranked by [`LibraryFinder::find_book_libraries`] into one list,
+/// keeping each book id once. The copy from the highest-ranked library wins, its
+/// `path` records where it was found, and the ignored copies are logged.
+pub fn merge_libraries(libraries: Vec<(LibraryLocation, Vec<BookInfo>)>) -> Vec<BookInfo> {
+    let mut winners: HashMap<String, PathBuf> = HashMap::new();
+    let mut books = Vec::new();
+
+    for (_, library_books) in libraries {
+        for book in library_books {
+            if let Some(winner) = winners.get(&book.id) {
+                tracing::info!("Ignoring duplicate of book {} at {} (using {})",
+                                book.id, book.path.display(), winner.display());
+                continue;
+            }
+
+            winners.insert(book.id.clone(), book.path.clone());
+            books.push(book);
+        }
+    }
+
+    books
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_library_finder_creation() {
+        let finder = LibraryFinder::new();
+        assert!(!finder.common_paths.is_empty());
+    }
+
+    #[test]
+    fn test_macos_container_library_paths() {
+        let home = tempfile::tempdir().unwrap();
+        assert!(container_library_paths(home.path()).is_empty());
+
+        let containers = home.path().join("Library").join("Containers");
+        let library_in = |bundle_id: &str| containers.join(bundle_id)
+            .join("Data/Library/Application Support/Ridibooks/library");
+        fs::create_dir_all(library_in("com.ridi.books")).unwrap();
+        // Other apps' containers, and a RIDI one without a library yet, are left out
+        fs::create_dir_all(library_in("com.apple.Notes")).unwrap();
+        fs::create_dir_all(containers.join("com.ridibooks.viewer").join("Data")).unwrap();
+
+        assert_eq!(container_library_paths(home.path()), [library_in("com.ridi.books")]);
+    }
+
+    #[test]
+    fn test_linux_compat_library_paths() {
+        let home = tempfile::tempdir().unwrap();
+        assert!(compat_library_paths(home.path(), None).is_empty());
+
+        // A Wine prefix with RIDI installed for one of its users
+        let wine_library = home.path().join(".wine/drive_c/users/me/AppData/Roaming/Ridibooks/library");
+        let book_dir = wine_library.join("_123").join("1234567890");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("1234567890.epub"), b"fake epub content").unwrap();
+        fs::write(book_dir.join("1234567890.dat"), b"fake dat content").unwrap();
+        // Users and apps without a library aren't returned
+        fs::create_dir_all(home.path().join(".wine/drive_c/users/Public/AppData/Roaming")).unwrap();
+        fs::create_dir_all(home.path().join(".var/app/org.example.Other/data")).unwrap();
+
+        let flatpak_library = home.path().join(".var/app/com.ridi.Ridibooks/data/Ridibooks/library");
+        let snap_library = home.path().join("snap/ridibooks/current/.local/share/Ridibooks/library");
+        let other_prefix = tempfile::tempdir().unwrap();
+        let prefix_library = other_prefix.path().join("drive_c/users/me/AppData/Roaming/Ridibooks/library");
+        for library in [&flatpak_library, &snap_library, &prefix_library] {
+            fs::create_dir_all(library).unwrap();
+        }
+
+        assert_eq!(
+            compat_library_paths(home.path(), Some(other_prefix.path())),
+            [flatpak_library, snap_library, prefix_library, wine_library.clone()]
+        );
+
+        // The Wine library, the only one with books, is the most likely one
+        let finder = LibraryFinder { common_paths: compat_library_paths(home.path(), None) };
+        let locations = finder.find_library_locations();
+        assert_eq!(locations.len(), 3);
+        assert_eq!(locations[0].path, wine_library);
+        assert!(locations[0].confidence > locations[1].confidence);
+    }
+
+    #[test]
+    fn test_min_confidence_filters_detected_libraries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let write_book = |library: &std::path::Path, id: &str| {
+            let book_dir = library.join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            fs::write(book_dir.join(format!("{}.epub", id)), b"fake epub content").unwrap();
+            fs::write(book_dir.join(format!("{}.dat", id)), b"fake dat content").unwrap();
+        };
+
+        // A bare folder of books (40%) and one with RIDI metadata (70%)
+        let bare = temp_dir.path().join("bare");
+        write_book(&bare, "stray_book");
+        let root = temp_dir.path().join("library");
+        write_book(&root, "library_book");
+        fs::create_dir_all(root.join("metadata")).unwrap();
+        let detected = |paths: &[&std::path::Path]| -> Vec<_> {
+            paths.iter().map(|path| (path.to_path_buf(), LibrarySource::CommonPath)).collect()
+        };
+
+        let finder = LibraryFinder::new();
+        let config = Config::default();
+        assert_eq!(finder.scan_library_paths(detected(&[&bare, &root]), &config).unwrap().len(), 2);
+
+        let strict = Config { min_confidence: 0.5, ..Default::default() };
+        let libraries = finder.scan_library_paths(detected(&[&bare, &root]), &strict).unwrap();
+        assert_eq!(libraries.len(), 1);
+        assert_eq!(libraries[0].0.path, root);
+
+        // With nothing left, the error gives the scores to pick a threshold from
+        let error = finder.scan_library_paths(detected(&[&bare]), &strict).unwrap_err().to_string();
+        assert!(error.contains("below the minimum confidence of 50%"), "{}", error);
+        assert!(error.contains(&format!("{} (40%)", bare.display())), "{}", error);
+        assert!(error.contains("--min-confidence"), "{}", error);
+
+        // Folders the user named are scanned whatever their score
+        let named = vec![(bare.clone(), LibrarySource::UserSpecified)];
+        assert_eq!(finder.scan_library_paths(named, &strict).unwrap().len(), 1);
+    }
+}
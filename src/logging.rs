@@ -0,0 +1,130 @@
+//! Leveled logging for diagnosing runs after the fact.
+//!
+//! `--log-file` records debug-level events with timestamps, and `-v`, `-vv` and `-vvv`
+//! print info, debug and trace events to stderr: what's decided per book, library
+//! discovery, and the steps of decrypting. Registered secrets such as the device_id
+//! are masked before any line is written, so error messages that embed them can be
+//! logged as-is.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+
+use crate::types::{redact, Verbosity};
+
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Installs the global subscriber. Without `-v` or `--log-file` nothing extra is
+/// printed, so the normal console output is unchanged.
+pub fn init(log_file: Option<&Path>, verbosity: Verbosity) -> io::Result<()> {
+    let console = tracing_subscriber::fmt::layer()
+        .with_writer(Redacting(io::stderr))
+        .with_target(false)
+        .without_time()
+        .with_filter(level_filter(verbosity));
+
+    let file = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let file = Arc::new(Mutex::new(file));
+            Some(tracing_subscriber::fmt::layer()
+                .with_writer(Redacting(move || SharedFile(file.clone())))
+                .with_ansi(false)
+                .with_target(false)
+                .with_filter(level_filter(verbosity).max(LevelFilter::DEBUG)))
+        }
+        None => None,
+    };
+
+    // Only fails if a subscriber is already installed, e.g. by a test harness
+    let _ = tracing_subscriber::registry().with(console).with(file).try_init();
+    Ok(())
+}
+
+fn level_filter(verbosity: Verbosity) -> LevelFilter {
+    match verbosity {
+        Verbosity::Normal => LevelFilter::OFF,
+        Verbosity::Books => LevelFilter::INFO,
+        Verbosity::Discovery => LevelFilter::DEBUG,
+        Verbosity::Decryption => LevelFilter::TRACE,
+    }
+}
+
+/// Masks `secret` wherever it appears in later log lines.
+pub fn redact_in_logs(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+
+    let mut secrets = SECRETS.write().unwrap_or_else(|e| e.into_inner());
+    if !secrets.iter().any(|known| known == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+fn redact_secrets(line: &str) -> String {
+    let secrets = SECRETS.read().unwrap_or_else(|e| e.into_inner());
+    secrets.iter().fold(line.to_string(), |line, secret| line.replace(secret, &redact(secret)))
+}
+
+// Wraps a MakeWriter so every formatted event passes through `redact_secrets`.
+// The fmt layer writes each event with a single write_all, so secrets are never split.
+struct Redacting<M>(M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Redacting<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+struct RedactingWriter<W>(W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        self.0.write_all(redact_secrets(&line).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+struct SharedFile(Arc<Mutex<File>>);
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logged_lines_hide_secrets() {
+        redact_in_logs("87654321-4321-4321-4321-210987654321");
+
+        let mut output = Vec::new();
+        RedactingWriter(&mut output)
+            .write_all(b"Wrong device_id 87654321-4321-4321-4321-210987654321 for book 1\n")
+            .unwrap();
+
+        let line = String::from_utf8(output).unwrap();
+        assert!(!line.contains("87654321-4321"));
+        assert!(line.contains("4321 for book 1"));
+    }
+}
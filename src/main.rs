@@ -1,49 +1,232 @@
-use aes::cipher::BlockDecryptMut;
-use aes::cipher::KeyIvInit;
 use anyhow::{Context, Result};
-use clap::Parser;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use miette::{IntoDiagnostic, miette};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::Semaphore;
 use tokio::signal;
-use zip::ZipArchive;
 
-mod types;
-mod library_finder;
-mod credential_manager;
+mod logging;
+mod calibre;
+mod watch;
+mod post_process;
+mod manifest;
 
 #[cfg(feature = "gui")]
 mod gui;
 
+#[cfg(test)]
+mod test;
+
+use ridiculous::{credential_manager, decrypt, library_finder, types};
 use types::*;
 use library_finder::LibraryFinder;
-use credential_manager::CredentialManager;
+use credential_manager::{ClientOptions, CredentialManager, DeviceInfo};
+use post_process::PostProcess;
+use manifest::Manifest;
+use decrypt::{decrypt_book_to_file, write_fixture_book, FIXTURE_DEVICE_ID, BarProgress, DrmVersion, decrypted_format, read_book_key, DecryptError, existing_output, extract_zip, fixture_matches, is_plaintext_container, is_valid_output, open_output, verify_decrypted_output, write_compressed, PartialOutput};
 
 #[derive(Parser, Debug)]
 #[command(name = "ridiculous")]
 #[command(about = "Enhanced RIDI book decryption tool")]
 #[command(version = "0.3.5")]
+#[command(after_help = "Without a subcommand, books are decrypted interactively (same as `ridiculous decrypt`).")]
 struct Args {
-    #[arg(short, long)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Options shared by every subcommand; `global` lets them go before or after it
+    #[arg(short, long, global = true)]
     device_id: Option<String>,
     
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     user_idx: Option<String>,
     
-    #[arg(short, long)]
-    verbose: bool,
+    /// Print more: -v for what's decided about each book, -vv also for library discovery,
+    /// -vvv also for the steps of decrypting
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Decrypt without the banner, progress bars or per-book messages, printing only a
+    /// one-line summary (and failed books on stderr), for cron and CI. Failures don't
+    /// stop to ask whether to continue
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Ask for missing credentials on the terminal instead of passing them as arguments,
+    /// even when stdin isn't a terminal (e.g. piped in). Never prompts in --batch-mode
+    #[arg(long, global = true)]
+    prompt_credentials: bool,
+
+    /// Ask the RIDI API even if these credentials were validated in the last hour
+    #[arg(long, global = true)]
+    no_credential_cache: bool,
     
-    #[arg(long)]
+    #[arg(short, long, global = true)]
+    output_dir: Option<PathBuf>,
+    
+    #[arg(long, global = true)]
+    config_path: Option<PathBuf>,
+
+    /// Keep the processing state used by --resume in PATH instead of the cache directory
+    #[arg(long, value_name = "PATH", global = true)]
+    state_file: Option<PathBuf>,
+
+    /// Proxy for RIDI API requests, e.g. http://proxy:3128 [default: HTTPS_PROXY/HTTP_PROXY]
+    #[arg(long, value_name = "URL", global = true)]
+    proxy: Option<String>,
+
+    /// Also trust the PEM certificates in PATH, for proxies that intercept TLS
+    #[arg(long, value_name = "PATH", global = true)]
+    ca_cert: Option<PathBuf>,
+
+    /// User agent for RIDI API requests [default: ridiculous/<version>]
+    #[arg(long, value_name = "AGENT", global = true)]
+    user_agent: Option<String>,
+
+    /// Time limit for each RIDI API request, in seconds [default: 10]
+    #[arg(long, value_name = "SECONDS", global = true)]
+    api_timeout: Option<u64>,
+
+    /// Use this [[profile]] from the config file
+    #[arg(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+    
+    #[arg(long, global = true)]
+    organize: bool,
+
+    /// Only look for books in DIR (and its `_<user_idx>` folder) instead of auto-detecting libraries
+    #[arg(long, alias = "library", value_name = "DIR", global = true)]
+    library_path: Option<PathBuf>,
+
+    /// Stop looking for libraries at the first one with books (faster, but may miss books in others)
+    #[arg(long, global = true)]
+    fast_scan: bool,
+
+    /// Skip auto-detected library folders whose confidence score is below this, from 0.0
+    /// to 1.0 [default: 0.3, or min_confidence in the config]
+    #[arg(long, value_name = "0.0-1.0", value_parser = parse_confidence, global = true)]
+    min_confidence: Option<f32>,
+
+    /// Append timestamped debug logs (discovery, key derivation, retries, outcomes) to PATH.
+    /// The device_id is masked and derived keys are never logged
+    #[arg(long, value_name = "PATH", global = true)]
+    log_file: Option<PathBuf>,
+
+    // Options for a bare `ridiculous`, which decrypts
+    #[command(flatten)]
+    decrypt: DecryptArgs,
+
+    // The mode flags that predate the subcommands, kept so existing scripts still work,
+    // and --stats, --self-test, --diff, --repair-state and --count-only for their subcommands in the same style
+    #[arg(long, hide = true)]
     diagnose: bool,
 
-    #[arg(long)]
+    #[arg(long, hide = true)]
+    setup: bool,
+
+    #[arg(long, hide = true)]
     validate_only: bool,
-    
+
+    #[arg(long, hide = true)]
+    verify: bool,
+
+    #[arg(long, hide = true)]
+    stats: bool,
+
+    #[arg(long, hide = true)]
+    self_test: bool,
+
+    #[arg(long, hide = true, value_name = "OUTPUT_DIR")]
+    diff: Option<PathBuf>,
+
+    #[arg(long, hide = true)]
+    repair_state: bool,
+
+    #[arg(long, hide = true)]
+    list: bool,
+
+    #[arg(long, hide = true, requires = "list")]
+    json: bool,
+
+    #[arg(long, hide = true, conflicts_with = "json")]
+    count_only: bool,
+
+    #[cfg(feature = "gui")]
+    #[arg(long, hide = true)]
+    gui: bool,
+}
+
+// Parsed once per run, so DecryptArgs isn't worth boxing
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// List discovered books without decrypting anything
+    Scan {
+        /// Print the inventory as JSON on stdout
+        #[arg(long)]
+        json: bool,
+
+        /// Only print how many books there are, without reading any of them
+        #[arg(long, conflicts_with = "json")]
+        count_only: bool,
+    },
+
+    /// Decrypt books (the default when no subcommand is given)
+    Decrypt(DecryptArgs),
+
+    /// Check the device_id and user_idx against the RIDI API
+    Validate,
+
+    /// Check library detection, credentials and books
+    Diagnose,
+
+    /// Interactively create the config file
+    Setup,
+
+    /// Re-check the output of every completed book from the last run and report any that are corrupt
+    Verify,
+
+    /// Summarize the library (formats, DRM versions, sizes, what's decrypted) without decrypting
+    Stats,
+
+    /// Decrypt built-in synthetic books to check that this build works, without RIDI data
+    SelfTest,
+
+    /// Compare the library with a folder of decrypted books: which books aren't decrypted
+    /// there yet, and which outputs there belong to no book in the library. Read-only
+    Diff {
+        /// The folder of decrypted books [default: the configured output directory]
+        #[arg(value_name = "OUTPUT_DIR")]
+        output_dir: Option<PathBuf>,
+    },
+
+    /// Show the saved processing state (used by --resume) and clear some or all of it
+    CleanState {
+        /// Don't ask; remove entries for books that are no longer in the library
+        #[arg(long)]
+        prune: bool,
+    },
+
+    /// Bring the processing state and list of decrypted books in line with the output
+    /// directory: forget books whose outputs are gone, and record outputs written outside
+    /// a run (or before the state was kept)
+    RepairState,
+
+    /// Open the graphical interface
+    #[cfg(feature = "gui")]
+    Gui,
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+struct DecryptArgs {
     #[arg(long, default_value = "4")]
     parallel: usize,
     
@@ -53,163 +236,1163 @@ struct Args {
     #[arg(long)]
     resume: bool,
     
-    #[arg(short, long)]
-    output_dir: Option<PathBuf>,
-    
-    #[arg(long)]
-    config_path: Option<PathBuf>,
-    
-    #[arg(long)]
+    /// Decrypt every book, even ones that would normally be skipped
+    #[arg(long, alias = "no-skip")]
     force: bool,
-    
+
+    /// What to do when a book's output file already exists [default: overwrite, or on_existing in the config]
+    #[arg(long, value_enum, value_name = "POLICY")]
+    on_existing: Option<OnExisting>,
+
+    /// How entries of decrypted v11 books are compressed [default: keep, or recompress in the config]
+    #[arg(long, value_enum, value_name = "MODE")]
+    recompress: Option<Recompress>,
+
+    /// Write EPUBs and comics as folders of their decrypted contents (HTML, images, ...)
+    /// instead of single files. PDFs are written as usual
+    #[arg(long, conflicts_with = "calibre_manifest")]
+    flatten: bool,
+
+    /// Compress each output file, as `<name>.<ext>.gz` or `.zst`, e.g. for archiving.
+    /// Mostly worth it for PDFs, since EPUBs and comics are ZIPs already
+    /// [default: none, or compress in the config]
+    #[arg(long, value_enum, value_name = "MODE", conflicts_with = "calibre_manifest")]
+    compress: Option<Compress>,
+
+    /// Also decrypt free sample chapters, which are skipped otherwise
     #[arg(long)]
-    organize: bool,
+    include_samples: bool,
 
+    /// Show what would be decrypted without writing any files
     #[arg(long)]
-    library_path: Option<PathBuf>,
+    dry_run: bool,
 
-    #[cfg(feature = "gui")]
+    /// Only process books whose id or title matches this glob (repeatable, case-insensitive)
+    #[arg(long, value_name = "GLOB")]
+    only: Vec<String>,
+
+    /// Skip books whose id or title matches this glob (repeatable, case-insensitive)
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Only process books downloaded within DURATION (e.g. `7d`, `48h`), or since `last-run`
+    #[arg(long, value_name = "DURATION")]
+    since: Option<String>,
+
+    /// Only process the book ids listed in PATH, one per line or as `scan --json` output.
+    /// A line can force a book's format as `<id>:epub` or `<id>:pdf`
+    #[arg(long, value_name = "PATH")]
+    books_from: Option<PathBuf>,
+
+    /// Treat every book as FORMAT instead of detecting it, for misnamed books
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    assume_format: Option<BookFormat>,
+
+    /// Write a JSON report of the run (per-book status, output paths, durations) to PATH
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Also write a folder Calibre can import ("one book per directory", with metadata.opf
+    /// files) plus CSV/JSON manifests of the output files to DIR
+    #[arg(long, value_name = "DIR")]
+    calibre_manifest: Option<PathBuf>,
+
+    /// Choose interactively when books are found in more than one library
     #[arg(long)]
-    gui: bool,
+    pick_library: bool,
+
+    /// Tick the books to decrypt from a list before starting, after --only/--exclude.
+    /// Without a terminal, every book is decrypted
+    #[arg(long, conflicts_with_all = ["batch_mode", "watch"])]
+    select: bool,
+
+    /// Give up on a book after DURATION (e.g. 90s, 10m), however many attempts and
+    /// retries that leaves it [default: max_book_time_seconds in the config, or no limit]
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    max_book_time: Option<Duration>,
+
+    /// Stop at the first book that fails; books already being decrypted still finish
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Run CMD on each decrypted file, one book at a time. `{}` in CMD is replaced with
+    /// the file's path, which is otherwise passed as the last argument
+    #[arg(long, value_name = "CMD")]
+    post_process: Option<String>,
+
+    /// Keep running and decrypt books as RIDI downloads them, until Ctrl+C
+    #[arg(long, conflicts_with = "dry_run")]
+    watch: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+impl Args {
+    // The subcommand to run, with the older mode flags mapped onto their subcommands
+    fn selected_command(&self) -> Command {
+        if let Some(command) = &self.command {
+            return command.clone();
+        }
+
+        #[cfg(feature = "gui")]
+        if self.gui {
+            return Command::Gui;
+        }
+
+        if self.setup {
+            Command::Setup
+        } else if self.diagnose {
+            Command::Diagnose
+        } else if self.validate_only {
+            Command::Validate
+        } else if self.verify {
+            Command::Verify
+        } else if self.stats {
+            Command::Stats
+        } else if self.self_test {
+            Command::SelfTest
+        } else if let Some(output_dir) = &self.diff {
+            Command::Diff { output_dir: Some(output_dir.clone()) }
+        } else if self.repair_state {
+            Command::RepairState
+        } else if self.list || self.count_only {
+            Command::Scan { json: self.json, count_only: self.count_only }
+        } else {
+            Command::Decrypt(self.decrypt.clone())
+        }
+    }
+
+    // The -v level, which --json turns off to keep stdout clean for its consumers
+    fn verbosity(&self) -> Verbosity {
+        if self.json() {
+            return Verbosity::Normal;
+        }
+        Verbosity::from_count(self.verbose)
+    }
+
+    // JSON output keeps stdout free of anything else
+    fn json(&self) -> bool {
+        matches!(self.selected_command(), Command::Scan { json: true, .. })
+    }
+
+    // Batch runs must never block waiting for input
+    fn batch_mode(&self) -> bool {
+        matches!(self.selected_command(), Command::Decrypt(decrypt) if decrypt.batch_mode)
+    }
+}
+
+// --min-confidence takes the same 0.0-1.0 scale the scores are computed on
+fn parse_confidence(value: &str) -> Result<f32, String> {
+    let confidence: f32 = value.parse().map_err(|_| format!("`{}` isn't a number", value))?;
+    if !(0.0..=1.0).contains(&confidence) {
+        return Err(format!("{} is outside 0.0-1.0", confidence));
+    }
+    Ok(confidence)
+}
+
+// --only/--exclude glob patterns, matched against a book's id and display name
+struct BookFilter {
+    only: Vec<regex::Regex>,
+    exclude: Vec<regex::Regex>,
+}
+
+impl BookFilter {
+    fn new(only: &[String], exclude: &[String]) -> miette::Result<Self> {
+        let compile = |patterns: &[String]| patterns.iter()
+            .map(|pattern| glob_to_regex(pattern))
+            .collect::<miette::Result<Vec<_>>>();
+
+        Ok(Self {
+            only: compile(only)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    fn matches(&self, book: &BookInfo) -> bool {
+        let display_name = book.get_display_name();
+        let hit = |pattern: &regex::Regex| pattern.is_match(&book.id) || pattern.is_match(&display_name);
+
+        (self.only.is_empty() || self.only.iter().any(hit)) && !self.exclude.iter().any(hit)
+    }
+}
+
+// `*` matches any run of characters and `?` a single one; everything else is literal
+fn glob_to_regex(pattern: &str) -> miette::Result<regex::Regex> {
+    let mut regex = String::from("(?i)^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+
+    regex::Regex::new(&regex)
+        .map_err(|e| miette!("Invalid filter pattern '{}': {}", pattern, e))
+}
+
+// The --since cutoff: a duration back from now, or when the previous run started
+fn since_cutoff(since: &str, state_path: &Path) -> miette::Result<SystemTime> {
+    if since == "last-run" {
+        // State files from before `last_run` was recorded fall back to when they were written
+        let last_run = load_processing_state(state_path).ok().and_then(|state| state.last_run);
+        return last_run
+            .or_else(|| fs::metadata(state_path).and_then(|metadata| metadata.modified()).ok())
+            .ok_or_else(|| miette!(
+                "❌ --since last-run needs a previous run, but {} doesn't exist yet\n\
+                 💡 Use a duration such as --since 7d for the first run.",
+                state_path.display()
+            ));
+    }
+
+    let duration = humantime::parse_duration(since)
+        .map_err(|e| miette!("❌ Invalid --since value '{}': {}\n💡 Use a duration such as 7d or 48h, or last-run.", since, e))?;
+    SystemTime::now().checked_sub(duration)
+        .ok_or_else(|| miette!("❌ --since {} reaches back too far", since))
+}
+
+// Whether the book's .dat or book file changed after `cutoff`. Books whose times
+// can't be read are kept, since leaving them out would go unnoticed.
+fn downloaded_since(book: &BookInfo, cutoff: SystemTime, verbose: Verbosity) -> bool {
+    let modified = [book.get_data_file_path(), book.get_book_file_path()].iter()
+        .filter_map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .max();
+
+    match modified {
+        Some(modified) => modified >= cutoff,
+        None => {
+            tracing::warn!("Cannot read modification time of {}; ignoring --since for it", book.id);
+            if verbose >= Verbosity::Books {
+                println!("⚠️  Cannot tell when {} was downloaded; including it", book.get_display_name());
+            }
+            true
+        }
+    }
+}
+
+// One entry of a --books-from list: a book id, with the format to use instead of the
+// detected one when the line reads `<id>:<format>`
+#[derive(Debug, Clone, PartialEq)]
+struct ListedBook {
+    id: String,
+    format: Option<BookFormat>,
+}
+
+// Books for --books-from: either the JSON inventory printed by `scan --json`,
+// or one id per line with blank lines and `#` comments ignored
+fn read_book_ids(path: &Path) -> miette::Result<Vec<ListedBook>> {
+    #[derive(Deserialize)]
+    struct InventoryEntry {
+        id: String,
+    }
+
+    let content = fs::read_to_string(path)
+        .map_err(|e| miette!("❌ Could not read book list {}: {}", path.display(), e))?;
+
+    if content.trim_start().starts_with('[') {
+        let listings: Vec<InventoryEntry> = serde_json::from_str(&content)
+            .map_err(|e| miette!("❌ Invalid JSON book list {}: {}", path.display(), e))?;
+        return Ok(listings.into_iter().map(|listing| ListedBook { id: listing.id, format: None }).collect());
+    }
+
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let Some((id, format)) = line.split_once(':') else {
+                return Ok(ListedBook { id: line.to_string(), format: None });
+            };
+            let format = BookFormat::from_str(format.trim(), true).map_err(|_| miette!(
+                "❌ Unknown format \"{}\" for book {} in {}\n\
+                 💡 Use epub or pdf, e.g. `{}:pdf`.",
+                format.trim(),
+                id.trim(),
+                path.display(),
+                id.trim()
+            ))?;
+            Ok(ListedBook { id: id.trim().to_string(), format: Some(format) })
+        })
+        .collect()
+}
+
+// Keep only the listed books, failing if any id isn't in the library so typos don't go unnoticed
+fn restrict_to_ids(books: Vec<BookInfo>, listed: &[ListedBook]) -> miette::Result<Vec<BookInfo>> {
+    let missing: Vec<_> = listed.iter()
+        .filter(|listing| !books.iter().any(|book| book.id == listing.id))
+        .map(|listing| listing.id.as_str())
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(miette!(
+            "❌ {} book id(s) from --books-from not found in the library: {}\n\
+             💡 Run `ridiculous scan` to see the available ids.",
+            missing.len(),
+            missing.join(", ")
+        ));
+    }
+
+    Ok(books.into_iter()
+        .filter(|book| listed.iter().any(|listing| listing.id == book.id))
+        .collect())
+}
+
+// Overrides detected formats with --assume-format, or with a format given for the
+// book in the --books-from list, which wins over the flag
+fn assume_formats(books: &mut [BookInfo], assume_format: Option<&BookFormat>, listed: &[ListedBook]) {
+    for book in books {
+        let listed_format = listed.iter()
+            .find(|listing| listing.id == book.id)
+            .and_then(|listing| listing.format.as_ref());
+        if let Some(format) = listed_format.or(assume_format) {
+            if *format != book.format {
+                tracing::info!(book = %book.id, "Treating {} book as {}", book.format.as_str(), format.as_str());
+            }
+            book.format = format.clone();
+        }
+    }
+}
+
+// Books whose format neither the extension nor the content gave away are left alone
+// rather than decrypted as a guess. Call after assume_formats, which can name it.
+fn skip_unrecognized(books: Vec<BookInfo>, quiet: bool) -> Vec<BookInfo> {
+    let (unrecognized, books): (Vec<_>, Vec<_>) = books.into_iter()
+        .partition(|book| book.format == BookFormat::Unknown);
+
+    for book in &unrecognized {
+        tracing::info!(book = %book.id, "Unrecognized format ({}), skipped", book.book_filename);
+        if quiet {
+            eprintln!("skipped {}: unrecognized format", book.id);
+        } else {
+            println!("❓ {}: unrecognized format, skipped ({})", book.get_display_name(), book.book_filename);
+        }
+    }
+    if !unrecognized.is_empty() && !quiet {
+        println!("   💡 Use --assume-format epub or --assume-format pdf to decrypt them anyway");
+    }
+    books
+}
+
+// Free samples are left out unless --include-samples (or include_samples in the config).
+// Returns the other books and how many samples were skipped, for the summary.
+fn skip_samples(books: Vec<BookInfo>, config: &Config) -> (Vec<BookInfo>, usize) {
+    if config.include_samples {
+        return (books, 0);
+    }
+    let (samples, books): (Vec<_>, Vec<_>) = books.into_iter()
+        .partition(|book| book.is_sample);
+
+    for book in &samples {
+        tracing::info!(book = %book.id, "Skipped: free sample ({})", book.book_filename);
+        if config.verbose >= Verbosity::Books {
+            println!("⏭️  Skipping {}: free sample", book.get_display_name());
+        }
+    }
+    (books, samples.len())
+}
+
+// One entry of the `scan` inventory
+#[derive(Serialize, Debug)]
+struct BookListing {
+    id: String,
+    title: String,
+    format: String,
+    size_bytes: u64,
+    is_v11: bool,
+    drm_version: String,
+    already_decrypted: bool,
+    is_sample: bool,
+}
+
+impl BookListing {
+    fn new(book: &BookInfo, manifest: &Manifest, config: &Config) -> Self {
+        Self {
+            id: book.id.clone(),
+            title: book.get_display_name(),
+            format: book.format.as_str().to_string(),
+            size_bytes: fs::metadata(book.get_book_file_path()).map(|m| m.len()).unwrap_or(0),
+            is_v11: book.drm_version == DrmVersion::V11,
+            drm_version: book.drm_version.to_string(),
+            already_decrypted: is_already_decrypted(book, manifest, config),
+            is_sample: book.is_sample,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 struct ProcessingState {
-    completed: Vec<String>,
+    completed: Vec<CompletedBook>,
     failed: Vec<(String, String)>, // book_id, error
+    // Failed books whose error was DecryptError::IncompleteDownload
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    incomplete_downloads: Vec<String>,
     in_progress: Vec<String>,
+    // When the last run without failures started, for `--since last-run`
+    #[serde(default, with = "rfc3339", skip_serializing_if = "Option::is_none")]
+    last_run: Option<SystemTime>,
+    // Details for --report; only kept for the current run
+    #[serde(skip)]
+    outcomes: Vec<BookOutcome>,
+    // Free samples left out of the current run, for its summary
+    #[serde(skip)]
+    samples_skipped: usize,
 }
 
-impl Default for ProcessingState {
-    fn default() -> Self {
-        Self {
-            completed: Vec::new(),
-            failed: Vec::new(),
-            in_progress: Vec::new(),
+impl ProcessingState {
+    fn is_completed(&self, book_id: &str) -> bool {
+        self.completed.iter().any(|completed| completed.id == book_id)
+    }
+
+    fn is_incomplete_download(&self, book_id: &str) -> bool {
+        self.incomplete_downloads.iter().any(|id| id == book_id)
+    }
+
+    // Drop entries for books `keep` rejects; returns how many were removed
+    fn prune(&mut self, keep: impl Fn(&str) -> bool) -> usize {
+        let before = self.completed.len() + self.failed.len() + self.in_progress.len();
+        self.completed.retain(|completed| keep(&completed.id));
+        self.failed.retain(|(id, _)| keep(id));
+        self.in_progress.retain(|id| keep(id));
+        self.incomplete_downloads.retain(|id| keep(id));
+        before - (self.completed.len() + self.failed.len() + self.in_progress.len())
+    }
+
+    fn record(&mut self, book: &BookInfo, result: &Result<BookResult>, duration: Duration) {
+        let (output_path, error) = match result {
+            Ok(book_result) => {
+                let output_path = Some(book_result.output_path().to_path_buf());
+                self.completed.retain(|completed| completed.id != book.id);
+                self.completed.push(CompletedBook {
+                    id: book.id.clone(),
+                    output_path: output_path.clone(),
+                    duration_ms: Some(duration.as_millis() as u64),
+                    format: Some(book.format.as_str().to_string()),
+                    sha256: output_path.as_deref().and_then(|path| sha256_file(path).ok()),
+                });
+                (output_path, None)
+            }
+            Err(e) => {
+                self.failed.push((book.id.clone(), e.to_string()));
+                self.incomplete_downloads.retain(|id| id != &book.id);
+                if is_incomplete_download(e) {
+                    self.incomplete_downloads.push(book.id.clone());
+                }
+                (None, Some(e.to_string()))
+            }
+        };
+
+        match result {
+            Ok(BookResult::Decrypted(_)) => tracing::info!(book = %book.id, "Decrypted in {:.1}s", duration.as_secs_f64()),
+            Ok(BookResult::CopiedPlaintext(_)) => tracing::info!(book = %book.id, "DRM-free, copied as-is"),
+            Ok(BookResult::KeptExisting(path)) => tracing::info!(book = %book.id, "Kept existing output {}", path.display()),
+            Err(e) => tracing::warn!(book = %book.id, "Failed after {:.1}s: {}", duration.as_secs_f64(), e),
         }
+
+        self.outcomes.push(BookOutcome {
+            id: book.id.clone(),
+            title: book.get_display_name(),
+            author: book.author.clone(),
+            series: book.series.clone(),
+            status: match result {
+                Ok(BookResult::Decrypted(_)) => "completed",
+                Ok(BookResult::CopiedPlaintext(_)) => "copied",
+                Ok(BookResult::KeptExisting(_)) => "kept",
+                Err(_) => "failed",
+            },
+            output_path,
+            duration_secs: duration.as_secs_f64(),
+            error,
+            post_process_error: None,
+            source_hash: result.is_ok().then(|| manifest::source_hash(book).ok()).flatten(),
+        });
+    }
+
+    fn record_post_process_failure(&mut self, book_id: &str, error: String) {
+        tracing::warn!(book = %book_id, "Post-processing failed: {}", error);
+        if let Some(outcome) = self.outcomes.iter_mut().rev().find(|outcome| outcome.id == book_id) {
+            outcome.post_process_error = Some(error);
+        }
+    }
+}
+
+// Timestamps in the state file are RFC 3339 strings such as "2024-05-01T12:00:00Z"
+mod rfc3339 {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::time::SystemTime;
+
+    pub fn serialize<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_str(&humantime::format_rfc3339_seconds(*time).to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|time| humantime::parse_rfc3339_weak(&time).map_err(D::Error::custom))
+            .transpose()
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "CompletedBookEntry")]
+struct CompletedBook {
+    id: String,
+    output_path: Option<PathBuf>,
+    duration_ms: Option<u64>,
+    format: Option<String>,
+    sha256: Option<String>,
+}
+
+// Older state files stored completed books as bare ids
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CompletedBookEntry {
+    Id(String),
+    Full {
+        id: String,
+        #[serde(default)]
+        output_path: Option<PathBuf>,
+        #[serde(default)]
+        duration_ms: Option<u64>,
+        #[serde(default)]
+        format: Option<String>,
+        #[serde(default)]
+        sha256: Option<String>,
+    },
+}
+
+impl From<CompletedBookEntry> for CompletedBook {
+    fn from(entry: CompletedBookEntry) -> Self {
+        match entry {
+            CompletedBookEntry::Id(id) => CompletedBook {
+                id,
+                output_path: None,
+                duration_ms: None,
+                format: None,
+                sha256: None,
+            },
+            CompletedBookEntry::Full { id, output_path, duration_ms, format, sha256 } => CompletedBook {
+                id,
+                output_path,
+                duration_ms,
+                format,
+                sha256,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct BookOutcome {
+    id: String,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    series: Option<String>,
+    status: &'static str,
+    output_path: Option<PathBuf>,
+    duration_secs: f64,
+    error: Option<String>,
+    // The book was written, but --post-process failed on it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_process_error: Option<String>,
+    // For the manifest of decrypted books
+    #[serde(skip)]
+    source_hash: Option<String>,
+}
+
+// The --report document
+#[derive(Serialize)]
+struct RunReport<'a> {
+    total: usize,
+    processed: usize,
+    completed: usize,
+    copied: usize,
+    failed: usize,
+    post_process_failed: usize,
+    aborted: bool,
+    books: &'a [BookOutcome],
+}
+
+fn write_run_report(path: &Path, state: &ProcessingState, total: usize) -> Result<()> {
+    let report = RunReport {
+        total,
+        processed: state.outcomes.len(),
+        completed: state.outcomes.iter().filter(|o| o.status == "completed").count(),
+        copied: state.outcomes.iter().filter(|o| o.status == "copied").count(),
+        failed: state.outcomes.iter().filter(|o| o.status == "failed").count(),
+        post_process_failed: state.outcomes.iter().filter(|o| o.post_process_error.is_some()).count(),
+        aborted: state.outcomes.len() < total,
+        books: &state.outcomes,
+    };
+
+    write_atomically(path, |file| {
+        serde_json::to_writer_pretty(file, &report).map_err(std::io::Error::from)
+    })
+}
+
 #[tokio::main]
-async fn main() -> miette::Result<()> {
+async fn main() -> miette::Result<ExitCode> {
     // Set up panic hook for better error messages
     std::panic::set_hook(Box::new(|info| {
         eprintln!("💥 Critical error occurred:");
         eprintln!("{}", info);
         eprintln!("\n🔧 This might help:");
         eprintln!("   1. Try running with --verbose for more details");
-        eprintln!("   2. Run with --diagnose to check your setup");
+        eprintln!("   2. Run `ridiculous diagnose` to check your setup");
         eprintln!("   3. Check that RIDI is properly installed");
     }));
 
     let args = Args::parse();
 
-    // Launch GUI if requested
-    #[cfg(feature = "gui")]
-    if args.gui {
-        return gui::run_gui()
-            .map_err(|e| miette::miette!("GUI error: {}", e));
+    logging::init(args.log_file.as_deref(), args.verbosity())
+        .map_err(|e| miette!("❌ Could not open log file: {}", e))?;
+    if let Some(device_id) = &args.device_id {
+        logging::redact_in_logs(device_id);
     }
+    tracing::info!("ridiculous {} starting", env!("CARGO_PKG_VERSION"));
 
-    if args.verbose {
+    if args.verbosity() > Verbosity::Normal {
         print_welcome();
     }
 
-    // Handle special modes first
-    if args.diagnose {
-        return run_diagnostics(&args).await;
+    let result = match args.selected_command() {
+        #[cfg(feature = "gui")]
+        Command::Gui => gui::run_gui(config_file_path(&args)?)
+            .map_err(|e| miette::miette!("GUI error: {}", e)),
+        Command::Setup => run_setup_wizard(&args).await,
+        Command::Diagnose => run_diagnostics(&args).await,
+        Command::Validate => {
+            let config = load_or_create_config(&args)?;
+            validate_credentials(&config, args.no_credential_cache).await.map_err(|e| miette::miette!("{}", e))
+        }
+        Command::Verify => verify_completed_books(&args),
+        Command::Stats => print_library_stats(&args),
+        Command::SelfTest => run_self_test(),
+        Command::Diff { output_dir } => print_library_diff(&args, output_dir),
+        Command::CleanState { prune } => clean_processing_state(&args, prune),
+        Command::RepairState => repair_processing_state(&args),
+        Command::Scan { count_only: true, .. } => count_books(&args),
+        Command::Scan { json, .. } => list_books(&args, json),
+        Command::Decrypt(decrypt) if decrypt.watch => return run_watch(&args, &decrypt).await,
+        Command::Decrypt(decrypt) => return run_decrypt(&args, &decrypt).await,
+    };
+    result.map(|()| ExitCode::SUCCESS)
+}
+
+// Exit codes of a decrypt run, besides 0 for success. Errors that stop the run before
+// any book is tried (bad credentials, unreadable config, ...) also exit with 1.
+const EXIT_BOOKS_FAILED: u8 = 1;
+const EXIT_NO_BOOKS: u8 = 2;
+
+// The config with the decrypt options that override it applied
+fn load_decrypt_config(args: &Args, decrypt: &DecryptArgs) -> miette::Result<Config> {
+    let mut config = load_or_create_config(args)?;
+    if let Some(on_existing) = decrypt.on_existing {
+        config.on_existing = on_existing;
     }
-    
-    if args.validate_only {
-        let config = load_or_create_config(&args)?;
-        return validate_credentials(&config).await.map_err(|e| miette::miette!("{}", e));
+    if let Some(recompress) = decrypt.recompress {
+        config.recompress = recompress;
     }
-    
+    config.flatten |= decrypt.flatten;
+    config.include_samples |= decrypt.include_samples;
+    if let Some(compress) = decrypt.compress {
+        config.compress = compress;
+    }
+    if let Some(max_book_time) = decrypt.max_book_time {
+        config.max_book_time_seconds = max_book_time.as_secs().max(1);
+    }
+    Ok(config)
+}
+
+async fn run_decrypt(args: &Args, decrypt: &DecryptArgs) -> miette::Result<ExitCode> {
+    let quiet = args.quiet;
     // Load or create config
-    let config = load_or_create_config(&args)?;
+    let config = load_decrypt_config(args, decrypt)?;
+    let post_process = parse_post_process(decrypt)?;
     
-    // Load processing state for resume functionality
-    let state = if args.resume {
-        load_processing_state().unwrap_or_default()
+    // Keep concurrent runs from clobbering each other's state
+    let state_path = state_file_path(&config);
+    let _state_lock = if decrypt.dry_run {
+        None
     } else {
-        ProcessingState::default()
+        Some(lock_processing_state(&state_path.with_extension("lock"))?)
+    };
+
+    // Load processing state for resume functionality; the last run time carries over either way
+    let run_started = SystemTime::now();
+    let previous_state = load_processing_state(&state_path).unwrap_or_default();
+    let state = if decrypt.resume {
+        previous_state.clone()
+    } else {
+        ProcessingState {
+            last_run: previous_state.last_run,
+            ..Default::default()
+        }
     };
 
     // Find books using library finder
     let library_finder = LibraryFinder::new();
-    let books = library_finder.find_books(&config)?;
+    let libraries = match library_finder.find_book_libraries(&config) {
+        Ok(libraries) => libraries,
+        Err(e) => {
+            if quiet {
+                println!("No books found");
+            } else {
+                eprintln!("{:?}", e);
+            }
+            return Ok(ExitCode::from(EXIT_NO_BOOKS));
+        }
+    };
+    let books = select_library(libraries, decrypt.pick_library, quiet)?;
+    let manifest_path = Manifest::path_for(&state_path);
+    let mut manifest = load_manifest(&manifest_path, &previous_state, &books, &config, decrypt.dry_run)?;
 
     if books.is_empty() {
-        println!("❌ No books found. Make sure RIDI is installed and books are downloaded.");
-        return Ok(());
+        if quiet {
+            println!("No books found");
+        } else {
+            println!("❌ No books found. Make sure RIDI is installed and books are downloaded.");
+        }
+        return Ok(ExitCode::from(EXIT_NO_BOOKS));
+    }
+
+    if !decrypt.dry_run {
+        let removed = remove_leftover_partials(&books, &config);
+        if removed > 0 && !quiet {
+            println!("🧹 Removed {} unfinished .part file(s) left by an interrupted run", removed);
+        }
+    }
+
+    let listed = decrypt.books_from.as_deref().map(read_book_ids).transpose()?;
+    let mut books = match &listed {
+        Some(listed) => restrict_to_ids(books, listed)?,
+        None => books,
+    };
+    assume_formats(&mut books, decrypt.assume_format.as_ref(), listed.as_deref().unwrap_or_default());
+    let books = skip_unrecognized(books, quiet);
+
+    let filter = BookFilter::new(&decrypt.only, &decrypt.exclude)?;
+    let cutoff = decrypt.since.as_deref().map(|since| since_cutoff(since, &state_path)).transpose()?;
+    let books: Vec<_> = books.into_iter()
+        .filter(|book| filter.matches(book))
+        .filter(|book| cutoff.is_none_or(|cutoff| downloaded_since(book, cutoff, config.verbose)))
+        .collect();
+
+    if books.is_empty() {
+        if quiet {
+            println!("No books matched the filters");
+        } else {
+            println!("❌ No books matched filter (--only/--exclude/--since). Check your patterns.");
+        }
+        return Ok(ExitCode::from(EXIT_NO_BOOKS));
+    }
+
+    let (books, samples_skipped) = skip_samples(books, &config);
+    if books.is_empty() {
+        if quiet {
+            println!("Only free samples found");
+        } else {
+            println!("❌ Only free samples found ({}). Use --include-samples to decrypt them.", samples_skipped);
+        }
+        return Ok(ExitCode::from(EXIT_NO_BOOKS));
     }
 
     // Filter out already processed books - simplified logic
     let books_to_process: Vec<_> = books.into_iter()
         .filter(|book| {
-            args.force ||
-            (args.resume && !state.completed.contains(&book.id)) ||
-            (!args.resume && !book.is_already_decrypted(&config))
+            if decrypt.force {
+                return true;
+            }
+            if decrypt.resume {
+                return !state.is_completed(&book.id);
+            }
+
+            // Plaintext books still go through, to be copied to the output directory
+            let done = manifest.is_decrypted(book, &config);
+            if done {
+                tracing::info!(book = %book.id, "Skipped: already decrypted");
+            }
+            if done && config.verbose >= Verbosity::Books {
+                println!("⏭️  Skipping {}: already decrypted", book.get_display_name());
+            }
+            !done
         })
         .collect();
     
     if books_to_process.is_empty() {
-        println!("✅ All books already decrypted. Use --force to re-decrypt.");
-        return Ok(());
+        if quiet {
+            println!("All books already decrypted");
+        } else {
+            println!("✅ All books already decrypted. Use --force to re-decrypt.");
+        }
+        return Ok(ExitCode::SUCCESS);
     }
-    
-    println!("📚 Found {} books to process", books_to_process.len());
 
-    // Set up graceful shutdown
-    let state = Arc::new(tokio::sync::Mutex::new(state));
-    let state_clone = state.clone();
+    let books_to_process = if decrypt.select {
+        let selected = select_books(books_to_process, quiet)?;
+        if selected.is_empty() {
+            println!("👋 No books selected");
+            return Ok(ExitCode::SUCCESS);
+        }
+        selected
+    } else {
+        books_to_process
+    };
+
+    if !quiet {
+        println!("📚 Found {} books to process", books_to_process.len());
+    }
+    let book_count = books_to_process.len();
+
+    let shutdown = handle_shutdown_signals();
+
+    // Process books
+    let mut state = state;
+    state.samples_skipped = samples_skipped;
+    if decrypt.batch_mode {
+        process_books_batch(books_to_process, &config, &mut state, decrypt, post_process.as_ref(), quiet, shutdown.clone()).await?;
+    } else {
+        process_books_interactive(books_to_process, &config, &mut state, decrypt, post_process.as_ref(), quiet, &shutdown).await?;
+    }
+    // Interrupted, or stopped by --fail-fast
+    let stopped_early = shutdown.load(Ordering::SeqCst);
+
+    // A dry run never touches the saved state
+    if decrypt.dry_run {
+        if !quiet {
+            print_dry_run_summary(book_count);
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Save final state. Books that failed or never started this time keep `--since last-run`
+    // reaching back before it
+    let mut final_state = state;
+    if !stopped_early && final_state.outcomes.iter().all(|outcome| outcome.status != "failed") {
+        final_state.last_run = Some(run_started);
+    }
+    save_processing_state(&state_path, &final_state).map_err(|e| miette::miette!("{}", e))?;
+    record_in_manifest(&mut manifest, &final_state);
+    manifest.save(&manifest_path).map_err(|e| miette::miette!("{}", e))?;
+
+    if let Some(report_path) = &decrypt.report {
+        write_run_report(report_path, &final_state, book_count).map_err(|e| miette::miette!("{}", e))?;
+        if !quiet {
+            println!("📝 Report written to {}", report_path.display());
+        }
+    }
+
+    if let Some(manifest_dir) = &decrypt.calibre_manifest {
+        let books: Vec<_> = final_state.outcomes.iter()
+            .filter_map(|outcome| Some(calibre::CalibreBook {
+                id: outcome.id.clone(),
+                title: outcome.title.clone(),
+                author: outcome.author.clone(),
+                series: outcome.series.clone(),
+                path: outcome.output_path.clone()?,
+            }))
+            .collect();
+        let count = calibre::write_manifest(manifest_dir, &books)
+            .map_err(|e| miette!("❌ Could not write the Calibre manifest to {}: {}", manifest_dir.display(), e))?;
+        if !quiet {
+            println!("📚 Calibre import folder with {} books written to {}", count, manifest_dir.display());
+        }
+    }
+
+    if quiet {
+        print_terse_summary(&final_state, stopped_early);
+    } else {
+        print_summary(&final_state);
+        if stopped_early {
+            println!("\n⏹  Stopped before every book was processed. Run again with --resume to continue.");
+        }
+    }
 
-    // Spawn signal handler for graceful shutdown
+    let failed = final_state.outcomes.iter().any(|outcome| outcome.status == "failed");
+    Ok(ExitCode::from(if failed { EXIT_BOOKS_FAILED } else { 0 }))
+}
+
+fn parse_post_process(decrypt: &DecryptArgs) -> miette::Result<Option<PostProcess>> {
+    decrypt.post_process.as_deref()
+        .map(PostProcess::parse)
+        .transpose()
+        .map_err(|e| miette!("{}", e))
+}
+
+// Runs --post-process on the file a book was just written to. Returns why it failed;
+// books that failed or kept an existing output aren't post-processed.
+async fn post_process_output(post_process: Option<&PostProcess>, result: &Result<BookResult>) -> Option<String> {
+    let output_path = match (post_process, result) {
+        (Some(_), Ok(BookResult::Decrypted(path) | BookResult::CopiedPlaintext(path))) => path,
+        _ => return None,
+    };
+
+    match post_process?.run(output_path).await {
+        Ok(stdout) => {
+            if !stdout.is_empty() {
+                tracing::debug!("Post-process output for {}: {}", output_path.display(), stdout);
+            }
+            None
+        }
+        Err(error) => Some(error),
+    }
+}
+
+// Ctrl+C (or SIGTERM) sets the returned flag, which stops new books from starting; books in
+// progress finish and the state is saved as usual. A second Ctrl+C quits right away.
+fn handle_shutdown_signals() -> Arc<AtomicBool> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let flag = shutdown.clone();
     tokio::spawn(async move {
-        #[cfg(unix)]
-        {
-            let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())
-                .expect("Failed to setup SIGTERM handler");
-            let mut sigint = signal::unix::signal(signal::unix::SignalKind::interrupt())
-                .expect("Failed to setup SIGINT handler");
-
-            tokio::select! {
-                _ = sigterm.recv() => {
-                    eprintln!("\n⚠️  Received SIGTERM, saving state and exiting...");
+        wait_for_shutdown_signal().await;
+        flag.store(true, Ordering::SeqCst);
+        eprintln!("\n⚠️  Interrupted: finishing the books in progress, then saving state (Ctrl+C again to quit now)...");
+
+        wait_for_shutdown_signal().await;
+        eprintln!("\n⚠️  Quitting without waiting; progress since the last save is lost");
+        std::process::exit(130);
+    });
+    shutdown
+}
+
+// How long a new book folder has to go unchanged before --watch decrypts it, and how
+// often pending folders are checked
+const WATCH_SETTLE_TIME: Duration = Duration::from_secs(5);
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// --watch: decrypt the books already in the library, then every book RIDI downloads
+// until Ctrl+C. Books in the processing state or with an existing output are skipped.
+async fn run_watch(args: &Args, decrypt: &DecryptArgs) -> miette::Result<ExitCode> {
+    let quiet = args.quiet;
+    let config = load_decrypt_config(args, decrypt)?;
+    let post_process = parse_post_process(decrypt)?;
+
+    let state_path = state_file_path(&config);
+    let _state_lock = lock_processing_state(&state_path.with_extension("lock"))?;
+    let mut state = load_processing_state(&state_path).unwrap_or_default();
+
+    let finder = LibraryFinder::new();
+    let manifest_path = Manifest::path_for(&state_path);
+    let library = finder.find_books(&config).unwrap_or_default();
+    let mut manifest = load_manifest(&manifest_path, &state, &library, &config, false)?;
+    let removed = remove_leftover_partials(&library, &config);
+    if removed > 0 && !quiet {
+        println!("🧹 Removed {} unfinished .part file(s) left by an interrupted run", removed);
+    }
+    let roots = finder.library_roots(&config)?;
+    if roots.is_empty() {
+        return Err(miette!(
+            "❌ No RIDI library folder to watch\n\
+             💡 Install RIDI and download a book, or point to the library with --library-path."
+        ));
+    }
+    let (_watcher, mut changes) = watch::watch_roots(&roots)
+        .map_err(|e| miette!("❌ Could not watch the library for new books: {}", e))?;
+
+    let shutdown = handle_shutdown_signals();
+    let filter = WatchFilter::new(decrypt, &config, &state_path)?;
+
+    // What's already downloaded goes first
+    let existing = filter.select(library, &state, &manifest, quiet);
+    if !existing.is_empty() {
+        process_books_batch(existing, &config, &mut state, decrypt, post_process.as_ref(), quiet, shutdown.clone()).await?;
+        save_processing_state(&state_path, &state).map_err(|e| miette!("{}", e))?;
+        record_in_manifest(&mut manifest, &state);
+        manifest.save(&manifest_path).map_err(|e| miette!("{}", e))?;
+    }
+
+    if !quiet {
+        println!("👀 Watching for new books in {} (Ctrl+C to stop)", roots.iter()
+            .map(|root| root.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", "));
+    }
+
+    let mut pending = watch::PendingBooks::new(WATCH_SETTLE_TIME);
+    let mut poll = tokio::time::interval(WATCH_POLL_INTERVAL);
+    while !shutdown.load(Ordering::SeqCst) {
+        tokio::select! {
+            Some(path) = changes.recv() => {
+                if let Some(book_dir) = watch::book_dir_for(&path, &roots) {
+                    pending.touch(book_dir, Instant::now());
+                }
+            }
+            _ = poll.tick() => {
+                let downloaded = pending.take_settled(Instant::now()).iter()
+                    .filter_map(|book_dir| finder.load_book(book_dir))
+                    .collect();
+                let books = filter.select(downloaded, &state, &manifest, quiet);
+                if books.is_empty() {
+                    continue;
                 }
-                _ = sigint.recv() => {
-                    eprintln!("\n⚠️  Received SIGINT (Ctrl+C), saving state and exiting...");
+                if !quiet {
+                    println!("📥 {} new book(s) downloaded", books.len());
                 }
+                process_books_batch(books, &config, &mut state, decrypt, post_process.as_ref(), quiet, shutdown.clone()).await?;
+                save_processing_state(&state_path, &state).map_err(|e| miette!("{}", e))?;
+                record_in_manifest(&mut manifest, &state);
+                manifest.save(&manifest_path).map_err(|e| miette!("{}", e))?;
             }
         }
+    }
 
-        #[cfg(not(unix))]
-        {
-            signal::ctrl_c().await.expect("Failed to setup Ctrl+C handler");
-            eprintln!("\n⚠️  Received Ctrl+C, saving state and exiting...");
-        }
+    save_processing_state(&state_path, &state).map_err(|e| miette!("{}", e))?;
+    if quiet {
+        print_terse_summary(&state, false);
+    } else if state.outcomes.is_empty() {
+        println!("👋 Stopped watching; no new books were decrypted");
+    } else {
+        print_summary(&state);
+    }
 
-        let state = state_clone.lock().await;
-        let _ = save_processing_state(&state);
-        std::process::exit(0);
-    });
+    let failed = state.outcomes.iter().any(|outcome| outcome.status == "failed");
+    Ok(ExitCode::from(if failed { EXIT_BOOKS_FAILED } else { 0 }))
+}
 
-    // Process books
+// Which books --watch decrypts: the ones run_decrypt would, going by --books-from,
+// --only/--exclude, --since and --assume-format. Listed books that aren't in the library
+// yet aren't an error here, since they may still be downloaded.
+struct WatchFilter<'a> {
+    decrypt: &'a DecryptArgs,
+    config: &'a Config,
+    listed: Option<Vec<ListedBook>>,
+    filter: BookFilter,
+    cutoff: Option<SystemTime>,
+}
+
+impl<'a> WatchFilter<'a> {
+    fn new(decrypt: &'a DecryptArgs, config: &'a Config, state_path: &Path) -> miette::Result<Self> {
+        Ok(Self {
+            decrypt,
+            config,
+            listed: decrypt.books_from.as_deref().map(read_book_ids).transpose()?,
+            filter: BookFilter::new(&decrypt.only, &decrypt.exclude)?,
+            cutoff: decrypt.since.as_deref().map(|since| since_cutoff(since, state_path)).transpose()?,
+        })
+    }
+
+    // The books to decrypt, with their formats settled; done, unfinished and sample
+    // downloads are left out
+    fn select(&self, books: Vec<BookInfo>, state: &ProcessingState, manifest: &Manifest, quiet: bool) -> Vec<BookInfo> {
+        let mut books: Vec<_> = books.into_iter()
+            .filter(|book| self.wants(book, state, manifest))
+            .collect();
+        assume_formats(&mut books, self.decrypt.assume_format.as_ref(), self.listed.as_deref().unwrap_or_default());
+        skip_unrecognized(books, quiet)
+    }
+
+    fn wants(&self, book: &BookInfo, state: &ProcessingState, manifest: &Manifest) -> bool {
+        let force = self.decrypt.force;
+        self.listed.as_ref().is_none_or(|listed| listed.iter().any(|listing| listing.id == book.id))
+            && self.filter.matches(book)
+            && self.cutoff.is_none_or(|cutoff| downloaded_since(book, cutoff, self.config.verbose))
+            && (force || !state.is_completed(&book.id))
+            && (force || !manifest.is_decrypted(book, self.config))
+            && book.incomplete_download().is_none()
+            && (self.config.include_samples || !book.is_sample)
+    }
+}
+
+// Resolves on Ctrl+C, or SIGTERM on Unix
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
     {
-        let mut state_guard = state.lock().await;
-        if args.batch_mode {
-            process_books_batch(books_to_process, &config, &mut state_guard, args.parallel).await?;
-        } else {
-            process_books_interactive(books_to_process, &config, &mut state_guard).await?;
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to setup SIGTERM handler");
+        let mut sigint = signal::unix::signal(signal::unix::SignalKind::interrupt())
+            .expect("Failed to setup SIGINT handler");
+
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = sigint.recv() => {}
         }
     }
 
-    // Save final state
-    let final_state = state.lock().await;
-    save_processing_state(&final_state).map_err(|e| miette::miette!("{}", e))?;
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.expect("Failed to setup Ctrl+C handler");
+}
 
-    print_summary(&final_state);
-    Ok(())
+// --select: the books the user ticks in a list. There's no asking without a terminal,
+// so then it's all of them.
+fn select_books(books: Vec<BookInfo>, quiet: bool) -> miette::Result<Vec<BookInfo>> {
+    if !(std::io::stdin().is_terminal() && std::io::stderr().is_terminal()) {
+        if !quiet {
+            println!("💡 --select needs a terminal; processing all {} books", books.len());
+        }
+        return Ok(books);
+    }
+
+    let labels: Vec<_> = books.iter().map(book_choice_label).collect();
+    // Esc picks nothing
+    let chosen = dialoguer::MultiSelect::with_theme(&dialoguer::theme::ColorfulTheme::default())
+        .with_prompt("Books to decrypt (space: toggle, a: toggle all, enter: start)")
+        .items(&labels)
+        .max_length(20)
+        .interact_opt()
+        .into_diagnostic()?
+        .unwrap_or_default();
+
+    Ok(books.into_iter()
+        .enumerate()
+        .filter(|(i, _)| chosen.contains(i))
+        .map(|(_, book)| book)
+        .collect())
+}
+
+fn book_choice_label(book: &BookInfo) -> String {
+    let name = match &book.title {
+        Some(title) => format!("{} ({})", title, book.id),
+        None => book.id.clone(),
+    };
+    format!("{} - {}, {}", name, book.format.as_str().to_uppercase(), book.format_file_size())
+}
+
+// Use every library, preferring the best one for books found in several,
+// or let the user choose a single library when --pick-library is set
+fn select_library(
+    mut libraries: Vec<(LibraryLocation, Vec<BookInfo>)>,
+    pick_library: bool,
+    quiet: bool,
+) -> miette::Result<Vec<BookInfo>> {
+    if libraries.len() == 1 {
+        return Ok(libraries.remove(0).1);
+    }
+
+    if !pick_library {
+        if quiet {
+            return Ok(library_finder::merge_libraries(libraries));
+        }
+        let best = &libraries[0].0;
+        println!("📁 Found books in {} libraries; preferring {} (confidence: {}%) for duplicates",
+                 libraries.len(), best.path.display(), best.confidence_percent());
+        println!("   💡 Use --pick-library to use only one of them");
+        return Ok(library_finder::merge_libraries(libraries));
+    }
+
+    println!("📁 Found books in {} libraries:", libraries.len());
+    for (i, (location, books)) in libraries.iter().enumerate() {
+        println!("   [{}] {} (confidence: {}%, {} books)",
+                 i + 1,
+                 location.path.display(),
+                 location.confidence_percent(),
+                 books.len());
+    }
+
+    loop {
+        println!("Which library should be used? [1-{}, default 1]", libraries.len());
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input).into_diagnostic()?;
+
+        let choice = match input.trim() {
+            "" => Some(1),
+            answer => answer.parse::<usize>().ok(),
+        };
+        match choice {
+            Some(n) if (1..=libraries.len()).contains(&n) => return Ok(libraries.swap_remove(n - 1).1),
+            _ => println!("❌ Please enter a number between 1 and {}", libraries.len()),
+        }
+    }
 }
 
 fn print_welcome() {
@@ -220,22 +1403,28 @@ fn print_welcome() {
     println!();
 }
 
-fn mask_device_id(device_id: &str) -> String {
-    if device_id.len() <= 8 {
-        "xxxx".to_string()
-    } else {
-        format!("{}...{}", &device_id[..4], &device_id[device_id.len()-4..])
-    }
-}
+// Batch runs save their state after this many finished books, or once this much time
+// has passed since the last save, whichever comes first
+const STATE_SAVE_EVERY_BOOKS: usize = 5;
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
 
 async fn process_books_batch(
     books: Vec<BookInfo>,
     config: &Config,
     state: &mut ProcessingState,
-    max_parallel: usize,
+    decrypt: &DecryptArgs,
+    post_process: Option<&PostProcess>,
+    quiet: bool,
+    shutdown: Arc<AtomicBool>,
 ) -> miette::Result<()> {
-    let multi_progress = MultiProgress::new();
-    let semaphore = Arc::new(Semaphore::new(max_parallel));
+    let (dry_run, fail_fast) = (decrypt.dry_run, decrypt.fail_fast);
+    let state_path = state_file_path(config);
+    let multi_progress = if quiet {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
+    let semaphore = Arc::new(Semaphore::new(decrypt.parallel));
     
     let overall_pb = multi_progress.add(ProgressBar::new(books.len() as u64));
     overall_pb.set_style(
@@ -247,16 +1436,22 @@ async fn process_books_batch(
     
     let mut handles = Vec::new();
     
-    for book in books {
+    for mut book in books {
         let semaphore = semaphore.clone();
         let config = config.clone();
         let multi_progress = multi_progress.clone();
         let overall_pb = overall_pb.clone();
+        let shutdown = shutdown.clone();
         
         let handle = tokio::spawn(async move {
             let _permit = semaphore.acquire().await
                 .expect("Failed to acquire semaphore");
 
+            // Books waiting for a slot don't start once the run is interrupted
+            if shutdown.load(Ordering::SeqCst) {
+                return None;
+            }
+
             let pb = multi_progress.add(ProgressBar::new(100));
             pb.set_style(
                 ProgressStyle::default_bar()
@@ -265,33 +1460,55 @@ async fn process_books_batch(
             );
             pb.set_message(format!("📖 {}", book.get_display_name()));
 
-            let result = process_single_book(&book, &config, &pb).await;
+            let started = std::time::Instant::now();
+            let result = process_single_book(&mut book, &config, &pb, dry_run).await;
+            let duration = started.elapsed();
 
             pb.finish_with_message(match &result {
-                Ok(_) => format!("✅ {}", book.get_display_name()),
+                Ok(BookResult::CopiedPlaintext(_)) => format!("📄 {} (DRM-free, copied as-is)", book.get_display_name()),
+                Ok(BookResult::Decrypted(_)) => format!("✅ {}", book.get_display_name()),
+                Ok(BookResult::KeptExisting(_)) => format!("⏭️  {} (kept existing output)", book.get_display_name()),
                 Err(e) => format!("❌ {} - {}", book.get_display_name(), e),
             });
 
+            // --fail-fast stops the books still waiting, as Ctrl+C does
+            if fail_fast && result.is_err() {
+                shutdown.store(true, Ordering::SeqCst);
+            }
+
             overall_pb.inc(1);
 
-            (book.id.clone(), result)
+            Some((book, result, duration))
         });
         
         handles.push(handle);
     }
     
     // Wait for all tasks and collect results
+    let mut last_saved = std::time::Instant::now();
+    let mut unsaved = 0;
     for handle in handles {
         match handle.await {
-            Ok((book_id, result)) => {
-                match result {
-                    Ok(_) => state.completed.push(book_id),
-                    Err(e) => state.failed.push((book_id, e.to_string())),
+            Ok(None) => {}
+            Ok(Some((book, result, duration))) => {
+                if dry_run {
+                    continue;
+                }
+
+                state.record(&book, &result, duration);
+                unsaved += 1;
+
+                // Run here rather than in the book's task, so commands never overlap
+                if let Some(error) = post_process_output(post_process, &result).await {
+                    overall_pb.println(format!("⚠️  Post-processing failed for {}: {}", book.get_display_name(), error));
+                    state.record_post_process_failure(&book.id, error);
                 }
 
                 // Periodically save state
-                if (state.completed.len() + state.failed.len()) % 5 == 0 {
-                    let _ = save_processing_state(state);
+                if unsaved >= STATE_SAVE_EVERY_BOOKS || last_saved.elapsed() >= STATE_SAVE_INTERVAL {
+                    let _ = save_processing_state(&state_path, state);
+                    last_saved = std::time::Instant::now();
+                    unsaved = 0;
                 }
             }
             Err(e) => {
@@ -300,73 +1517,374 @@ async fn process_books_batch(
         }
     }
     
-    overall_pb.finish_with_message("🎉 Batch processing complete!");
+    if shutdown.load(Ordering::SeqCst) {
+        overall_pb.abandon_with_message("⏹  Stopped early");
+    } else {
+        overall_pb.finish_with_message("🎉 Batch processing complete!");
+    }
     Ok(())
 }
 
 async fn process_books_interactive(
-    books: Vec<BookInfo>,
+    mut books: Vec<BookInfo>,
     config: &Config,
     state: &mut ProcessingState,
+    decrypt: &DecryptArgs,
+    post_process: Option<&PostProcess>,
+    quiet: bool,
+    shutdown: &AtomicBool,
 ) -> miette::Result<()> {
-    for (i, book) in books.iter().enumerate() {
-        println!("\n📖 Processing book {}/{}: {}", 
-                 i + 1, books.len(), book.get_display_name());
-        
-        let pb = ProgressBar::new(100);
+    let dry_run = decrypt.dry_run;
+    let state_path = state_file_path(config);
+    let total = books.len();
+    for (i, book) in books.iter_mut().enumerate() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let pb = if quiet {
+            ProgressBar::hidden()
+        } else {
+            println!("\n📖 Processing book {}/{}: {}",
+                     i + 1, total, book.get_display_name());
+            ProgressBar::new(100)
+        };
         pb.set_style(
             ProgressStyle::default_bar()
                 .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent}% {msg}")
                 .expect("Failed to set progress bar style")
         );
         
-        match process_single_book(book, config, &pb).await {
-            Ok(_) => {
+        if dry_run {
+            process_single_book(book, config, &pb, true).await
+                .map_err(|e| miette::miette!("{}", e))?;
+            pb.finish_and_clear();
+            continue;
+        }
+
+        let started = std::time::Instant::now();
+        let result = process_single_book(book, config, &pb, false).await;
+        state.record(book, &result, started.elapsed());
+        let stop = decrypt.fail_fast && result.is_err();
+        if let Some(error) = post_process_output(post_process, &result).await {
+            if !quiet {
+                eprintln!("⚠️  Post-processing failed for {}: {}", book.get_display_name(), error);
+            }
+            state.record_post_process_failure(&book.id, error);
+        }
+
+        match result {
+            // Failures are listed in the summary instead, without asking whether to go on
+            _ if quiet => pb.finish_and_clear(),
+            Ok(BookResult::CopiedPlaintext(_)) => {
+                pb.finish_with_message("📄 Copied");
+                println!("📄 DRM-free, copied as-is: {}", book.get_display_name());
+            }
+            Ok(BookResult::KeptExisting(path)) => {
+                pb.finish_with_message("⏭️  Kept");
+                println!("⏭️  Kept existing output for {}: {}", book.get_display_name(), path.display());
+            }
+            Ok(BookResult::Decrypted(_)) => {
                 pb.finish_with_message("✅ Complete");
-                state.completed.push(book.id.clone());
                 println!("✅ Successfully processed: {}", book.get_display_name());
             }
             Err(e) => {
                 pb.finish_with_message("❌ Failed");
-                state.failed.push((book.id.clone(), e.to_string()));
                 eprintln!("❌ Failed to process {}: {}", book.get_display_name(), e);
                 
-                // Ask if user wants to continue
-                println!("Continue with next book? (y/n)");
-                let mut input = String::new();
-                std::io::stdin().read_line(&mut input).into_diagnostic()?;
-                if input.trim().to_lowercase() != "y" {
-                    break;
+                // Ask if user wants to continue, unless --fail-fast already decided
+                if !stop {
+                    println!("Continue with next book? (y/n)");
+                    let mut input = String::new();
+                    std::io::stdin().read_line(&mut input).into_diagnostic()?;
+                    if input.trim().to_lowercase() != "y" {
+                        break;
+                    }
                 }
             }
         }
         
-        save_processing_state(state).map_err(|e| miette::miette!("{}", e))?;
+        save_processing_state(&state_path, state).map_err(|e| miette::miette!("{}", e))?;
+        if stop {
+            break;
+        }
     }
     
     Ok(())
 }
 
+// How a book that didn't fail ended up in the output directory, and where
+#[derive(Debug, Clone, PartialEq)]
+enum BookResult {
+    Decrypted(PathBuf),
+    // DRM-free book, copied unchanged
+    CopiedPlaintext(PathBuf),
+    // --on-existing skip found an output already there and left it alone
+    KeptExisting(PathBuf),
+}
+
+impl BookResult {
+    fn output_path(&self) -> &Path {
+        match self {
+            BookResult::Decrypted(path) | BookResult::CopiedPlaintext(path) | BookResult::KeptExisting(path) => path,
+        }
+    }
+}
+
 async fn process_single_book(
-    book: &BookInfo,
+    book: &mut BookInfo,
     config: &Config,
     pb: &ProgressBar,
-) -> Result<()> {
+    dry_run: bool,
+) -> Result<BookResult> {
+    let plaintext = is_plaintext_container(book);
+
+    if dry_run {
+        let output_path = get_output_path(book, config)?;
+        let destination = match resolve_existing_output(output_path.clone(), config.on_existing) {
+            Some(path) => format!("Would write: {}", path.display()),
+            None => format!("Would keep existing: {}", output_path.display()),
+        };
+        pb.println(format!(
+            "🔎 {}\n   📄 Format: {} ({})\n   📦 Size: {}\n   📁 {}",
+            book.get_display_name(),
+            book.format.as_str(),
+            if plaintext { "no DRM, copied as-is".to_string() } else { format!("{} DRM", book.drm_version) },
+            book.format_file_size(),
+            destination
+        ));
+        return Ok(if plaintext { BookResult::CopiedPlaintext(output_path) } else { BookResult::Decrypted(output_path) });
+    }
+
+    if plaintext {
+        pb.set_message("Copying DRM-free book...");
+        let result = copy_plaintext_book(book, config).await?;
+        pb.set_position(100);
+        return Ok(result);
+    }
+
     pb.set_message("Reading book file...");
     pb.set_position(10);
 
-    // Retry logic for file operations
-    let retries = 3;
+    // Retry logic for file operations, each attempt bounded by the configured timeout
+    // and all of them together by the book's time budget
+    let shared_book: &BookInfo = book;
+    let time_limit = Duration::from_secs(config.timeout_seconds);
+    let budget = Duration::from_secs(config.max_book_time_seconds);
+    let derive_key = || {
+        let book = shared_book.clone();
+        let config = config.clone();
+        let pb = pb.clone();
+        async move {
+            // Reading the .dat file can hang on a slow drive just like the book itself
+            let abandoned = AtomicBool::new(false);
+            run_with_timeout(time_limit, &abandoned, async {
+                tokio::task::spawn_blocking(move || derive_content_key(&book, &config, &pb))
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Key derivation task failed: {}", e))?
+            }).await
+        }
+    };
+    let attempts = std::sync::atomic::AtomicU32::new(0);
+    let (decrypted, result) = within_book_budget(budget, retry_with_key(derive_key, config.max_retries, Backoff::jittered(RETRY_BASE_DELAY), pb, |key| {
+        let book = shared_book.clone();
+        let config = config.clone();
+        let pb = pb.clone();
+        let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+        // A timed-out attempt keeps running in the background, so it's marked abandoned
+        // and stages its output apart from the retry that replaces it
+        let abandoned = Arc::new(AtomicBool::new(false));
+        async move {
+            run_with_timeout(time_limit, &abandoned, async {
+                let abandoned = Arc::clone(&abandoned);
+                tokio::task::spawn_blocking(move || decrypt_book_with_original_logic(&book, &config, &key, attempt, &abandoned, &pb))
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Decryption task failed: {}", e))?
+            }).await
+        }
+    })).await?;
+
+    // Keep the real format, title, author and series for progress, summary and --organize paths
+    *book = decrypted;
+
+    Ok(result)
+}
+
+// A DRM-free book goes to the output directory unchanged, under the same name a
+// decrypted copy would get
+async fn copy_plaintext_book(book: &mut BookInfo, config: &Config) -> Result<BookResult> {
+    let book_path = book.get_book_file_path();
+
+    let metadata = fs::File::open(&book_path)
+        .map(read_epub_metadata)
+        .unwrap_or_default();
+    book.apply_metadata(metadata);
+
+    let output_path = get_output_path(book, config)?;
+    let Some(output_path) = resolve_existing_output(output_path.clone(), config.on_existing) else {
+        return Ok(BookResult::KeptExisting(output_path));
+    };
+    if let Some(parent) = output_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    if flattens(book, config) {
+        let (extract_from, extract_to) = (book_path.clone(), output_path.clone());
+        tokio::task::spawn_blocking(move || extract_zip(&extract_from, &extract_to, 0))
+            .await?
+            .with_context(|| format!("❌ Could not extract {} to {}", book_path.display(), output_path.display()))?;
+        return Ok(BookResult::CopiedPlaintext(output_path));
+    }
+
+    if config.compress != Compress::None {
+        let (compress_from, compress_to, compress) = (book_path.clone(), output_path.clone(), config.compress);
+        tokio::task::spawn_blocking(move || write_compressed(&compress_from, &compress_to, compress, 0))
+            .await?
+            .with_context(|| format!("❌ Could not compress {} to {}", book_path.display(), output_path.display()))?;
+        return Ok(BookResult::CopiedPlaintext(output_path));
+    }
+
+    let partial = PartialOutput::new(&output_path);
+    tokio::fs::copy(&book_path, partial.path()).await
+        .and_then(|_| partial.persist(&output_path))
+        .with_context(|| format!(
+            "❌ Could not copy {} to {}",
+            book_path.display(),
+            output_path.display()
+        ))?;
+
+    Ok(BookResult::CopiedPlaintext(output_path))
+}
+
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Delays between retries: `base_delay` doubled after each attempt, up to MAX_RETRY_DELAY.
+// With jitter each delay is cut to a random 50-100% of that, so books that failed
+// together (e.g. on a drive that dropped out) don't all retry at the same moment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Backoff {
+    base_delay: Duration,
+    jitter: bool,
+}
+
+impl Backoff {
+    fn jittered(base_delay: Duration) -> Self {
+        Self { base_delay, jitter: true }
+    }
+
+    // The delay after failed attempt `attempt`, counting from 0
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_RETRY_DELAY);
+        if !self.jitter {
+            return delay;
+        }
+        delay.mul_f64(0.5 + 0.5 * random_fraction())
+    }
+}
+
+// Good enough for spreading out retries: std seeds every RandomState differently
+fn random_fraction() -> f64 {
+    use std::hash::BuildHasher;
+
+    let bits = std::collections::hash_map::RandomState::new().hash_one(0u8);
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Gives up on a book once `budget` is spent, whichever attempt or retry delay it's in.
+// Dropping a running attempt marks it abandoned (see run_with_timeout), so it can't
+// still write the book's output after the book was reported as failed.
+// A zero budget never runs out.
+async fn within_book_budget<T>(
+    budget: Duration,
+    operation: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    if budget.is_zero() {
+        return operation.await;
+    }
+
+    tokio::time::timeout(budget, operation).await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!(
+            "⏱️ Gave up: exceeded time budget of {} for this book\n\
+             💡 Raise --max-book-time (or max_book_time_seconds in your config) for very large books.",
+            humantime::format_duration(budget)
+        )))
+}
+
+// Fails with a retryable timeout error if `operation` runs longer than `time_limit`
+// (a zero limit disables the timeout). Blocking work it started can't be stopped, so
+// `abandoned` is set for that work to check before writing anything whenever this
+// gives up on it: on timeout, or when dropped unfinished because the book's time
+// budget ran out.
+async fn run_with_timeout<T>(
+    time_limit: Duration,
+    abandoned: &AtomicBool,
+    operation: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let mut unfinished = AbandonOnDrop(Some(abandoned));
+    let result = if time_limit.is_zero() {
+        Some(operation.await)
+    } else {
+        tokio::time::timeout(time_limit, operation).await.ok()
+    };
+
+    match result {
+        Some(result) => {
+            unfinished.0 = None;
+            result
+        }
+        None => Err(anyhow::anyhow!(
+            "⏱️ Operation timeout after {}s\n\
+             💡 Large books on slow drives may need a higher timeout_seconds in your config.",
+            time_limit.as_secs()
+        )),
+    }
+}
+
+// Sets the flag when dropped, unless it's been taken out first
+struct AbandonOnDrop<'a>(Option<&'a AtomicBool>);
+
+impl Drop for AbandonOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(abandoned) = self.0 {
+            abandoned.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// Runs `operation` up to `max_retries` times (0 means the default), waiting as long as
+// `backoff` says after each retryable failure
+async fn retry_with_backoff<T, F, Fut>(
+    max_retries: u32,
+    backoff: Backoff,
+    pb: &ProgressBar,
+    mut operation: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let retries = if max_retries == 0 {
+        Config::default().max_retries
+    } else {
+        max_retries
+    };
     let mut last_error = None;
 
     for attempt in 0..retries {
-        match decrypt_book_with_original_logic(book, config, pb).await {
-            Ok(_) => return Ok(()),
+        match operation().await {
+            Ok(value) => return Ok(value),
             Err(e) if attempt < retries - 1 && is_retryable_error(&e) => {
-                let msg = format!("Retrying... (attempt {}/{})", attempt + 2, retries);
+                let delay = backoff.delay(attempt);
+                tracing::info!("Attempt {}/{} failed, retrying in {:.1}s: {}",
+                               attempt + 1, retries, delay.as_secs_f32(), e);
+                let msg = format!(
+                    "Retrying in {:.1}s... (attempt {}/{})",
+                    delay.as_secs_f32(), attempt + 2, retries
+                );
                 pb.set_message(msg);
                 last_error = Some(e);
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                tokio::time::sleep(delay).await;
             }
             Err(e) => return Err(e),
         }
@@ -375,41 +1893,122 @@ async fn process_single_book(
     Err(last_error.unwrap_or_else(|| anyhow::anyhow!("All retry attempts failed")))
 }
 
-// Core RIDI decryption functions (from original code)
-async fn decrypt_book_with_original_logic(
-    book: &BookInfo,
-    config: &Config,
-    pb: &ProgressBar
-) -> Result<()> {
+// The content key only depends on the .dat file, so once derived it's kept for every
+// later attempt. Deriving it is retried like the decryption that follows, until it works.
+async fn retry_with_key<T, D, DFut, F, Fut>(
+    derive_key: D,
+    max_retries: u32,
+    backoff: Backoff,
+    pb: &ProgressBar,
+    operation: F,
+) -> Result<T>
+where
+    D: Fn() -> DFut,
+    DFut: std::future::Future<Output = Result<[u8; 16]>>,
+    F: Fn([u8; 16]) -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let key = std::sync::OnceLock::new();
+    let (key, derive_key, operation) = (&key, &derive_key, &operation);
+    retry_with_backoff(max_retries, backoff, pb, || async move {
+        let derived = match key.get() {
+            Some(derived) => *derived,
+            None => {
+                let derived = derive_key().await?;
+                let _ = key.set(derived);
+                derived
+            }
+        };
+        operation(derived).await
+    }).await
+}
+
+fn derive_content_key(book: &BookInfo, config: &Config, pb: &ProgressBar) -> Result<[u8; 16]> {
     pb.set_message("Extracting decryption key...");
     pb.set_position(20);
 
-    // Get the decryption key using original logic
-    let key = decrypt_key(book, &config.device_id)?;
+    Ok(read_book_key(book, &config.device_id)?)
+}
 
+// Core RIDI decryption functions (from original code)
+// Returns the book with its detected format and OPF metadata applied, and where its
+// output went, or that an existing output was kept
+fn decrypt_book_with_original_logic(
+    book: &BookInfo,
+    config: &Config,
+    key: &[u8; 16],
+    // Numbers the attempt's own partial file
+    attempt: u32,
+    // Set once the attempt has timed out, after which nothing may be written
+    abandoned: &AtomicBool,
+    pb: &ProgressBar
+) -> Result<(BookInfo, BookResult)> {
     pb.set_message("Decrypting book content...");
     pb.set_position(50);
 
-    // Decrypt the book using the appropriate method
-    let decrypted_content = if book.is_v11 {
+    // Decrypt into a partial file first: with --organize the final path depends on
+    // metadata that can only be read from the decrypted book. It's removed on any
+    // error below, so a failed run never leaves output behind.
+    let partial = PartialOutput::for_attempt(&book.get_output_dir(config).join(book.get_output_filename()), attempt);
+
+    if let Some(parent) = partial.path().parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if book.drm_version == DrmVersion::V11 && book.format == BookFormat::Pdf {
+        pb.set_message("Extracting PDF from v11 package...");
+    } else if book.drm_version == DrmVersion::V11 {
         pb.set_message("Decrypting v11 format (per-file encryption)...");
-        decrypt_v11_book(book, &key)?
-    } else {
-        decrypt_book_content(book, &key)?
-    };
+    }
+    decrypt_book_to_file(book, key, partial.path(), config.recompress, &BarProgress { bar: pb, range: 50..80 })?;
+
+    // Format, title, author and series also decide the output path, so apply them before picking it
+    let mut book = book.clone();
+    book.format = decrypted_format(&book, partial.path());
+    verify_decrypted_output(&book, partial.path())?;
+    let metadata = fs::File::open(partial.path())
+        .map(read_epub_metadata)
+        .unwrap_or_default();
+    book.apply_metadata(metadata);
+    let book = &book;
 
     pb.set_message("Writing decrypted file...");
     pb.set_position(80);
 
-    // Write the decrypted content
+    if abandoned.load(Ordering::Relaxed) {
+        anyhow::bail!("⏱️ Gave up on this attempt after it timed out");
+    }
+
+    // Keep a copy of the encrypted originals before writing anything
+    if config.backup_originals {
+        pb.set_message("Backing up original files...");
+        backup_original_files(book)?;
+    }
+
+    // Move the decrypted content into place, unless --on-existing says to keep what's there
     let output_path = get_output_path(book, config)?;
+    let Some(output_path) = resolve_existing_output(output_path.clone(), config.on_existing) else {
+        pb.set_position(100);
+        return Ok((book.clone(), BookResult::KeptExisting(output_path)));
+    };
 
     // Ensure output directory exists
     if let Some(parent) = output_path.parent() {
         fs::create_dir_all(parent)?;
     }
 
-    fs::write(&output_path, decrypted_content)?;
+    if flattens(book, config) {
+        pb.set_message("Extracting book contents...");
+        extract_zip(partial.path(), &output_path, attempt)
+            .with_context(|| format!("❌ Could not extract to {}", output_path.display()))?;
+    } else if config.compress != Compress::None {
+        pb.set_message("Compressing decrypted file...");
+        write_compressed(partial.path(), &output_path, config.compress, attempt)
+            .with_context(|| format!("❌ Could not write {}", output_path.display()))?;
+    } else {
+        partial.persist(&output_path)
+            .with_context(|| format!("❌ Could not write {}", output_path.display()))?;
+    }
 
     pb.set_position(100);
 
@@ -417,223 +2016,178 @@ async fn decrypt_book_with_original_logic(
         pb.set_message(format!("Saved: {}", file_name.to_string_lossy()));
     }
 
-    Ok(())
+    Ok((book.clone(), BookResult::Decrypted(output_path)))
 }
 
-// Original decrypt_key function adapted
-fn decrypt_key(book_info: &BookInfo, device_id: &str) -> Result<[u8; 16]> {
-    let data_file_path = book_info.get_data_file_path();
-    let mut data_file = fs::read(&data_file_path)
-        .with_context(|| format!(
-            "❌ Could not read .dat file: {}\n\
-             💡 Make sure the book is properly downloaded and the file exists.",
-            data_file_path.display()
-        ))?;
-
-    if data_file.len() < 32 {
-        return Err(anyhow::anyhow!(
-            "❌ .dat file is corrupted or invalid (only {} bytes)\n\
-             💡 Expected at least 32 bytes. Try re-downloading the book in RIDI app.",
-            data_file.len()
-        ));
+// A --compress output has to decompress in full, to something that starts like the format
+fn is_valid_compressed_output(format: &BookFormat, output_path: &Path) -> bool {
+    let Ok(mut reader) = open_output(output_path) else {
+        return false;
+    };
+    let mut header = [0u8; 5];
+    if reader.read_exact(&mut header).is_err() {
+        return false;
     }
 
-    let mut key = [0; 16];
-    let device_bytes = device_id.as_bytes();
-    let key_len = std::cmp::min(16, device_bytes.len());
-    key[..key_len].copy_from_slice(&device_bytes[..key_len]);
-
-    let mut iv = [0; 16];
-    iv.copy_from_slice(&data_file[0..16]);
-
-    let plaintext = cbc::Decryptor::<aes::Aes128>::new(&key.into(), &iv.into())
-        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut data_file[16..])
-        .map_err(|_| anyhow::anyhow!(
-            "❌ Failed to decrypt .dat file with provided device_id\n\
-             📋 Book ID: {}\n\
-             🔑 Device ID used: {}\n\
-             \n\
-             💡 Possible causes:\n\
-             1. Wrong device_id - this book was downloaded on a different device\n\
-             2. Check https://account.ridibooks.com/api/user-devices/app for all your devices\n\
-             3. Try the device_id from the device where you downloaded this book\n\
-             4. If you have multiple devices, try each device_id until one works",
-            book_info.id,
-            device_id
-        ))?;
-
-    let plaintext_str = std::str::from_utf8(&plaintext)
-        .map_err(|_| anyhow::anyhow!(
-            "❌ Decrypted .dat data contains invalid text\n\
-             💡 This shouldn't happen - the .dat file might be corrupted.\n\
-             Try re-downloading the book in RIDI app."
-        ))?;
+    let header_matches = match format {
+        BookFormat::Pdf => &header == b"%PDF-",
+        BookFormat::Epub | BookFormat::Comic => header.starts_with(b"PK\x03\x04"),
+        BookFormat::Unknown => true,
+    };
+    header_matches && std::io::copy(&mut reader, &mut std::io::sink()).is_ok()
+}
 
-    if plaintext_str.len() < 84 {
-        return Err(anyhow::anyhow!(
-            "❌ Decrypted .dat data is too short ({} characters, expected 84+)\n\
-             💡 The .dat file appears corrupted. Try re-downloading the book.",
-            plaintext_str.len()
-        ));
+// `path` without the extension --compress adds, e.g. `book.pdf` for `book.pdf.zst`
+fn uncompressed_name(path: &Path) -> PathBuf {
+    match Compress::of_path(path) {
+        Compress::None => path.to_path_buf(),
+        _ => path.with_extension(""),
     }
-
-    let mut result = [0; 16];
-    let key_slice = &plaintext_str[68..84];
-    let key_bytes = key_slice.as_bytes();
-    let copy_len = std::cmp::min(16, key_bytes.len());
-    result[..copy_len].copy_from_slice(&key_bytes[..copy_len]);
-
-    Ok(result)
 }
 
-// Original decrypt_book function adapted
-fn decrypt_book_content(book_info: &BookInfo, key: &[u8; 16]) -> Result<Vec<u8>> {
-    let book_file_path = book_info.get_book_file_path();
-    let mut book_file = fs::read(&book_file_path)
-        .with_context(|| format!(
-            "❌ Could not read book file: {}\n\
-             💡 Make sure the book file exists and is accessible.",
-            book_file_path.display()
-        ))?;
-
-    if book_file.len() < 16 {
-        return Err(anyhow::anyhow!(
-            "❌ Book file is too small ({} bytes)\n\
-             💡 The book file appears corrupted. Try re-downloading it in RIDI app.",
-            book_file.len()
-        ));
-    }
+fn sha256_file(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
 
-    let mut iv = [0; 16];
-    iv.copy_from_slice(&book_file[0..16]);
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
 
-    let decrypted = cbc::Decryptor::<aes::Aes128>::new(key.into(), &iv.into())
-        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut book_file[16..])
-        .map_err(|error| anyhow::anyhow!(
-            "❌ Book decryption failed: {}\n\
-             📋 Book ID: {}\n\
-             📄 File: {}\n\
-             \n\
-             💡 This usually means:\n\
-             1. Wrong device_id - the book was downloaded on a different device\n\
-             2. The book key extraction succeeded but book decryption failed\n\
-             3. The book file might be corrupted\n\
-             \n\
-             🔧 Try:\n\
-             - Use device_id from the device where you downloaded this book\n\
-             - Check all your devices at: https://account.ridibooks.com/api/user-devices/app\n\
-             - Re-download the book in RIDI app if problem persists",
-            error,
-            book_info.id,
-            book_file_path.display()
-        ))?;
+// Copy the encrypted book file and its .dat to `.bak` siblings, keeping any existing backup
+fn backup_original_files(book: &BookInfo) -> Result<()> {
+    for original_path in [book.get_book_file_path(), book.get_data_file_path()] {
+        let mut backup_path = original_path.clone().into_os_string();
+        backup_path.push(".bak");
+        let backup_path = PathBuf::from(backup_path);
 
-    Ok(decrypted.to_vec())
-}
+        if backup_path.exists() {
+            continue;
+        }
 
-// V11 format decryption - each file in ZIP has its own IV
-fn decrypt_v11_file_content(encrypted_data: &[u8], key: &[u8; 16]) -> Result<Vec<u8>> {
-    if encrypted_data.len() < 16 {
-        return Err(anyhow::anyhow!(
-            "❌ v11 file too small ({} bytes)\n\
-             💡 Files in v11 EPUB need at least 16 bytes for encryption.\n\
-             This file might be corrupted or unencrypted metadata.",
-            encrypted_data.len()
-        ));
+        fs::copy(&original_path, &backup_path)
+            .with_context(|| format!(
+                "❌ Could not back up {} to {}\n\
+                 💡 Nothing was written. Free up disk space or set backup_originals = false in your config.",
+                original_path.display(),
+                backup_path.display()
+            ))?;
     }
 
-    // First 16 bytes are the IV
-    let mut iv = [0; 16];
-    iv.copy_from_slice(&encrypted_data[0..16]);
-
-    let mut encrypted = encrypted_data[16..].to_vec();
+    Ok(())
+}
 
-    // Decrypt
-    let decrypted = cbc::Decryptor::<aes::Aes128>::new(key.into(), &iv.into())
-        .decrypt_padded_mut::<aes::cipher::block_padding::Pkcs7>(&mut encrypted)
-        .map_err(|error| anyhow::anyhow!(
-            "❌ v11 file decryption failed: {}\n\
-             💡 This v11 EPUB file couldn't be decrypted with the current key.\n\
-             Likely using wrong device_id for this book.",
-            error
-        ))?;
+fn get_output_path(book: &BookInfo, config: &Config) -> Result<PathBuf> {
+    let file_name = if config.organize_output {
+        book.get_organized_path().into_os_string()
+    } else {
+        book.get_output_filename()
+    };
 
-    Ok(decrypted.to_vec())
+    let output_path = book.get_output_dir(config).join(file_name);
+    // A folder named like the file would have been
+    Ok(if flattens(book, config) { output_path.with_extension("") } else { config.compress.apply(output_path) })
 }
 
-// Decrypt v11 format book (ZIP with encrypted files inside)
-fn decrypt_v11_book(book_info: &BookInfo, key: &[u8; 16]) -> Result<Vec<u8>> {
-    let book_file_path = book_info.get_book_file_path();
-    let book_file = fs::File::open(&book_file_path)
-        .with_context(|| format!("Failed to open v11 book file: {}", book_file_path.display()))?;
+// Whether --flatten writes `book` as a folder: only ZIP-based formats have contents to unpack
+fn flattens(book: &BookInfo, config: &Config) -> bool {
+    config.flatten && matches!(book.format, BookFormat::Epub | BookFormat::Comic)
+}
 
-    let mut zip = ZipArchive::new(book_file)
-        .context("Failed to read v11 book as ZIP")?;
+// Removes the `<id>_decrypted*.part` files (and --flatten's `.part` folders) a killed or
+// crashed run left in the output directories of `books`, and returns how many, along
+// with those staged next to an --organize output. They're never taken for finished
+// output, but would otherwise pile up. Only called while holding the state lock, so no
+// other run is still writing them.
+fn remove_leftover_partials(books: &[BookInfo], config: &Config) -> usize {
+    let mut prefixes: std::collections::BTreeMap<PathBuf, Vec<String>> = std::collections::BTreeMap::new();
+    for book in books {
+        // Every book is decrypted into a partial file under its default name first, and
+        // --compress and --flatten stage the final output next to where it goes
+        let staged = [Ok(book.get_output_dir(config).join(book.get_output_filename())), get_output_path(book, config)];
+        for output_path in staged.into_iter().flatten() {
+            if let (Some(dir), Some(stem)) = (output_path.parent(), output_path.file_stem()) {
+                prefixes.entry(dir.to_path_buf()).or_default().push(stem.to_string_lossy().to_string());
+            }
+        }
+    }
 
-    // Create output ZIP in memory
-    let mut output_buffer = Vec::new();
-    {
-        let mut output_zip = zip::ZipWriter::new(std::io::Cursor::new(&mut output_buffer));
-
-        for i in 0..zip.len() {
-            let mut file = zip.by_index(i)?;
-            let file_name = file.name().to_string();
-
-            // Read encrypted file data
-            let mut encrypted_data = Vec::new();
-            file.read_to_end(&mut encrypted_data)?;
-            drop(file); // Release the borrow
-
-            // Decrypt the file
-            let decrypted_data = match decrypt_v11_file_content(&encrypted_data, key) {
-                Ok(data) => data,
-                Err(_) => {
-                    // If decryption fails, keep original (might be metadata/unencrypted)
-                    encrypted_data
-                }
+    let mut removed = 0;
+    for (output_dir, prefixes) in prefixes {
+        let Ok(entries) = fs::read_dir(&output_dir) else { continue };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let leftover = name.ends_with(".part") && prefixes.iter().any(|prefix| name.starts_with(prefix.as_str()));
+            if !leftover {
+                continue;
+            }
+            let removed_leftover = if entry.path().is_dir() {
+                fs::remove_dir_all(entry.path())
+            } else {
+                fs::remove_file(entry.path())
             };
-
-            // Write to output ZIP
-            let options = zip::write::FileOptions::default()
-                .compression_method(zip::CompressionMethod::Deflated);
-            output_zip.start_file(&file_name, options)?;
-            output_zip.write_all(&decrypted_data)?;
+            if removed_leftover.is_ok() {
+                tracing::info!("Removed leftover partial output {}", entry.path().display());
+                removed += 1;
+            }
         }
+    }
+    removed
+}
 
-        output_zip.finish()?;
+// Where to write a book whose output would go to `output_path`, or None when the
+// --on-existing policy says to leave an existing file alone
+fn resolve_existing_output(output_path: PathBuf, policy: OnExisting) -> Option<PathBuf> {
+    if !output_path.exists() {
+        return Some(output_path);
     }
 
-    Ok(output_buffer)
+    match policy {
+        OnExisting::Skip => None,
+        OnExisting::Overwrite => Some(output_path),
+        OnExisting::Rename => Some(unique_output_path(&output_path)),
+    }
 }
 
-fn get_output_path(book: &BookInfo, config: &Config) -> Result<PathBuf> {
-    let file_name = book.get_output_filename();
-
-    let base_dir = if let Some(output_dir) = &config.output_directory {
-        // Use custom output directory if specified
-        PathBuf::from(output_dir)
-    } else if let Some(library_path) = &config.library_path {
-        // Use the library path (parent of book directories)
-        PathBuf::from(library_path)
+// `<name> (1).<ext>`, `<name> (2).<ext>`, ... next to `output_path`, whichever is free first.
+// --flatten folders have no extension, whatever dots their names contain, and --compress
+// outputs keep both of theirs, as in `<name> (1).pdf.zst`.
+fn unique_output_path(output_path: &Path) -> PathBuf {
+    let (stem, extension) = if output_path.is_dir() {
+        (output_path.file_name().unwrap_or_default().to_string_lossy().to_string(), String::new())
     } else {
-        // Fallback: use parent of book directory (library folder)
-        book.path.parent()
-            .map(|p| p.to_path_buf())
-            .unwrap_or_else(|| book.path.clone())
+        let book_path = uncompressed_name(output_path);
+        let extensions = [book_path.extension(), Compress::of_path(output_path).extension().map(std::ffi::OsStr::new)];
+        (
+            book_path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+            extensions.into_iter().flatten().map(|ext| format!(".{}", ext.to_string_lossy())).collect(),
+        )
     };
 
-    Ok(base_dir.join(file_name))
+    (1..)
+        .map(|n| output_path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+        .find(|candidate| !candidate.exists())
+        .expect("some numbered name is free")
 }
 
 fn is_retryable_error(error: &anyhow::Error) -> bool {
+    // Decryption failures say exactly what went wrong, so don't guess from their text
+    if let Some(decrypt_error) = error.chain().find_map(|cause| cause.downcast_ref::<DecryptError>()) {
+        return decrypt_error.is_retryable();
+    }
+
+    // Timeouts and network failures from outside the decrypt module. A timed-out attempt
+    // stages its output apart from its retry and gives up before writing, so retrying is safe.
     let error_str = error.to_string().to_lowercase();
     error_str.contains("timeout") || 
+    error_str.contains("timed out") ||
     error_str.contains("connection") ||
     error_str.contains("network") ||
     error_str.contains("temporary") ||
     error_str.contains("io error")
 }
 
+#[allow(dead_code)] // a manual troubleshooting aid, not reachable from any command
 async fn test_all_devices(args: &Args) -> miette::Result<()> {
     use aes::cipher::{BlockDecryptMut, KeyIvInit};
 
@@ -679,7 +2233,7 @@ async fn test_all_devices(args: &Args) -> miette::Result<()> {
     }
 
     for (name, device_id) in device_ids {
-        print!("Testing {} ...\n  device_id: {}\n  ", name, device_id);
+        print!("Testing {} ...\n  device_id: {}\n  ", name, redact(device_id));
 
         let key_bytes = device_id.as_bytes();
         let mut key = [0u8; 16];
@@ -703,7 +2257,7 @@ async fn test_all_devices(args: &Args) -> miette::Result<()> {
                             let key_slice = &plaintext_str[68..84];
                             println!("  Book key extracted: {}", key_slice);
                             println!("\n🎉 This is the CORRECT device_id! Use these credentials:");
-                            println!("  --device-id {}", mask_device_id(device_id));
+                            println!("  --device-id {}", redact(device_id));
                             println!("  --user-idx <your-user-idx>\n");
                             return Ok(());
                         } else {
@@ -737,30 +2291,46 @@ async fn run_diagnostics(args: &Args) -> miette::Result<()> {
     // Check library locations
     println!("1. Checking library locations...");
     let finder = LibraryFinder::new();
-    let locations = finder.find_library_locations();
     
-    if locations.is_empty() {
-        println!("   ❌ No RIDI library locations found");
-        println!("   💡 Make sure RIDI app is installed and you've downloaded books");
+    if let Some(library_path) = &args.library_path {
+        // A copied or relocated library needs no RIDI install, so detection doesn't matter
+        println!("   📁 Using --library-path {} (auto-detection skipped)", library_path.display());
     } else {
+        let locations = finder.find_library_locations();
+        if locations.is_empty() {
+            println!("   ❌ No RIDI library locations found");
+            println!("   💡 Make sure RIDI app is installed and you've downloaded books");
+        }
         for location in locations {
             println!("   📁 Found: {} (confidence: {}%)", 
                     location.path.display(), 
-                    (location.confidence * 100.0) as u32);
+                    location.confidence_percent());
         }
     }
     
     // Check credentials if provided
     if let (Some(device_id), Some(user_idx)) = (&args.device_id, &args.user_idx) {
         println!("\n2. Checking credentials...");
-        let config = Config {
+        let mut config = Config {
             device_id: device_id.clone(),
             user_idx: user_idx.clone(),
+            library_path: args.library_path.as_ref().map(|path| path.to_string_lossy().to_string()),
             ..Default::default()
         };
+        apply_network_args(&mut config, args);
         
-        match validate_credentials(&config).await {
-            Ok(_) => println!("   ✅ Credentials valid"),
+        match validate_credentials(&config, args.no_credential_cache).await {
+            Ok(_) => {
+                println!("   ✅ Credentials valid");
+                let devices = match credential_manager(&config) {
+                    Ok(manager) => manager.list_devices(device_id, user_idx).await,
+                    Err(e) => Err(e),
+                };
+                match devices {
+                    Ok(devices) => print_device_table(&devices, device_id),
+                    Err(e) => println!("   ⚠️  Could not list devices: {}", e),
+                }
+            }
             Err(e) => println!("   ❌ Credential error: {}", e),
         }
         
@@ -787,28 +2357,228 @@ async fn run_diagnostics(args: &Args) -> miette::Result<()> {
     Ok(())
 }
 
-async fn validate_credentials(config: &Config) -> Result<()> {
-    let cred_manager = CredentialManager::new();
-    cred_manager.validate(&config.device_id, &config.user_idx).await
-        .context("Invalid credentials")
+// Books only decrypt with the key of the device they were downloaded on, so point out
+// which registered device the given device_id is. Ids are redacted like everywhere else.
+fn print_device_table(devices: &[DeviceInfo], device_id: &str) {
+    if devices.is_empty() {
+        println!("   ⚠️  RIDI listed no devices for this account");
+        return;
+    }
+
+    println!("\n   Registered devices:");
+    println!("      DEVICE ID                              NAME                 TYPE       LAST USED");
+    for device in devices {
+        let marker = if device.device_id.eq_ignore_ascii_case(device_id) { "👉" } else { "  " };
+        println!(
+            "   {} {:<38} {:<20} {:<10} {}",
+            marker,
+            redact(&device.device_id),
+            device.name.as_deref().unwrap_or("-"),
+            device.device_type.as_deref().unwrap_or("-"),
+            device.last_used.as_deref().unwrap_or("-")
+        );
+    }
+
+    if !devices.iter().any(|device| device.device_id.eq_ignore_ascii_case(device_id)) {
+        println!("   ⚠️  The given device_id isn't one of them; use the id of the device your books were downloaded on");
+    }
 }
 
-fn load_or_create_config(args: &Args) -> miette::Result<Config> {
-    let config_path = if let Some(path) = args.config_path.clone() {
-        path
+// `setup`: ask for credentials and preferences, check them, and write the config file
+async fn run_setup_wizard(args: &Args) -> miette::Result<()> {
+    let config_path = config_file_path(args)?;
+
+    println!("🧙 Ridiculous setup\n");
+
+    let mut config = if config_path.exists() {
+        println!("⚠️  A config file already exists at {}", config_path.display());
+        if !prompt_yes_no("Overwrite it?", false)? {
+            println!("👋 Setup cancelled, nothing was changed");
+            return Ok(());
+        }
+        fs::read_to_string(&config_path).ok()
+            .and_then(|content| toml::from_str(&content).ok())
+            .unwrap_or_default()
     } else {
-        let home = dirs::home_dir()
-            .ok_or_else(|| miette!("Could not determine home directory"))?;
-        home.join(".ridiculous.toml")
+        Config::default()
     };
+    // The file is rewritten below, so write it in the current format. The account asked
+    // for is the one a plain run would use.
+    config.migrate();
+    let mut selected = config.clone();
+    if selected.select_profile(None).is_ok() {
+        config.device_id = selected.device_id;
+        config.user_idx = selected.user_idx;
+    }
+
+    println!("💡 Find your device_id and user_idx at: https://account.ridibooks.com/api/user-devices/app");
+    println!("   (log in first; use the device you downloaded your books on)\n");
+
+    loop {
+        config.device_id = CredentialManager::clean_credential(&prompt("Device ID", Some(&config.device_id))?);
+        config.user_idx = CredentialManager::clean_credential(&prompt("User index", Some(&config.user_idx))?);
+        match CredentialManager::validate_format(&config.device_id, &config.user_idx) {
+            Ok(()) => break,
+            Err(e) => println!("❌ {}\n", e),
+        }
+    }
+
+    let output_directory = prompt(
+        "Output directory (leave empty to save next to your library)",
+        config.output_directory.as_deref(),
+    )?;
+    config.output_directory = (!output_directory.is_empty()).then_some(output_directory);
+    config.backup_originals = prompt_yes_no("Back up encrypted originals before decrypting?", config.backup_originals)?;
+
+    println!("\n🔐 Checking credentials with RIDI...");
+    // Proxy flags are only used for the check, not saved to the config
+    let mut check_config = config.clone();
+    apply_network_args(&mut check_config, args);
+    match validate_credentials(&check_config, true).await {
+        Ok(()) => println!("✅ Credentials valid"),
+        Err(e) => {
+            println!("❌ {:#}", e);
+            if !prompt_yes_no("Save the config anyway?", false)? {
+                println!("👋 Setup cancelled, nothing was changed");
+                return Ok(());
+            }
+        }
+    }
+
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent).into_diagnostic()?;
+    }
+    config.store_account();
+    let content = render_config_file(&config).map_err(|e| miette!("{}", e))?;
+    fs::write(&config_path, content).into_diagnostic()?;
+
+    println!("\n💾 Saved config to {}", config_path.display());
+    println!("🚀 Run `ridiculous` to decrypt your books");
+    Ok(())
+}
+
+// Bring a config file from an older version up to date, keeping the original next to it.
+// Files from a newer version are read as far as they're understood.
+fn upgrade_config_file(config: &mut Config, config_path: &Path, original: &str) {
+    if config.version > CONFIG_VERSION {
+        tracing::warn!(
+            "{} is config version {}, newer than this build understands ({}); unknown settings are ignored",
+            config_path.display(), config.version, CONFIG_VERSION
+        );
+        return;
+    }
+
+    let old_version = config.version;
+    if !config.migrate() {
+        return;
+    }
+
+    let backup_path = config_path.with_extension(format!("toml.v{}.bak", old_version));
+    let result = fs::write(&backup_path, original)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| render_config_file(config))
+        .and_then(|content| Ok(fs::write(config_path, content)?));
+    match result {
+        Ok(()) => tracing::info!(
+            "Upgraded {} from config version {} to {} (original saved as {})",
+            config_path.display(), old_version, CONFIG_VERSION, backup_path.display()
+        ),
+        Err(e) => tracing::warn!("Could not upgrade {}: {}", config_path.display(), e),
+    }
+}
+
+fn render_config_file(config: &Config) -> Result<String> {
+    Ok(format!(
+        "# Ridiculous Enhanced Configuration\n\
+         # Written by `ridiculous setup`; see the README for all options\n\n{}",
+        toml::to_string_pretty(config)?
+    ))
+}
+
+// Ask for the credentials that are missing. The device_id is typed hidden when stdin is
+// a terminal, so neither ends up in shell history or the process list.
+fn prompt_credentials(config: &mut Config) -> miette::Result<()> {
+    println!("🔑 No saved credentials found. Find them at: https://account.ridibooks.com/api/user-devices/app");
+
+    if config.device_id.is_empty() {
+        config.device_id = if std::io::stdin().is_terminal() {
+            rpassword::prompt_password("Device ID (input hidden): ").into_diagnostic()?
+        } else {
+            prompt("Device ID", None)?
+        };
+    }
+    if config.user_idx.is_empty() {
+        config.user_idx = prompt("User index", None)?;
+    }
+
+    println!("💡 Run `ridiculous setup` to save them for next time\n");
+    Ok(())
+}
+
+// Ask a question on stdin; an empty answer keeps `default`
+fn prompt(question: &str, default: Option<&str>) -> miette::Result<String> {
+    match default.filter(|value| !value.is_empty()) {
+        Some(value) => println!("{} [{}]:", question, value),
+        None => println!("{}:", question),
+    }
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input).into_diagnostic()?;
+    let answer = input.trim();
+
+    Ok(if answer.is_empty() {
+        default.unwrap_or_default().to_string()
+    } else {
+        answer.to_string()
+    })
+}
+
+fn prompt_yes_no(question: &str, default: bool) -> miette::Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    let answer = prompt(&format!("{} ({})", question, hint), None)?;
+    Ok(match answer.to_lowercase().as_str() {
+        "" => default,
+        answer => answer.starts_with('y'),
+    })
+}
+
+async fn validate_credentials(config: &Config, force_refresh: bool) -> Result<()> {
+    credential_manager(config)?.validate(&config.device_id, &config.user_idx, force_refresh).await
+        .context("Invalid credentials")
+}
+
+fn config_file_path(args: &Args) -> miette::Result<PathBuf> {
+    match &args.config_path {
+        Some(path) => Ok(path.clone()),
+        None => default_config_path(dirs::home_dir()),
+    }
+}
+
+// ~/.ridiculous.toml. Some containers and CI runners have no home directory to put it in
+fn default_config_path(home: Option<PathBuf>) -> miette::Result<PathBuf> {
+    home.map(|home| home.join(".ridiculous.toml")).ok_or_else(|| miette!(
+        "❌ Could not determine your home directory, where the config file is kept\n\
+         💡 Pass --config-path to say where the config file is (or should be created)."
+    ))
+}
+
+// The config file (if any) with the command-line options applied, before any credentials
+// are looked for; enough for commands that only need paths such as the state file
+fn load_config(args: &Args) -> miette::Result<Config> {
+    let config_path = config_file_path(args)?;
 
     let mut config = if config_path.exists() {
         let content = fs::read_to_string(&config_path).into_diagnostic()?;
-        toml::from_str(&content).into_diagnostic()?
+        let mut config: Config = toml::from_str(&content).into_diagnostic()?;
+        upgrade_config_file(&mut config, &config_path, &content);
+        config
     } else {
         Config::default()
     };
 
+    config.select_profile(args.profile.as_deref())
+        .map_err(|e| miette!("❌ {}\n💡 Check the [[profile]] entries in {}", e, config_path.display()))?;
+
     // Override with CLI args
     if let Some(device_id) = &args.device_id {
         config.device_id = device_id.clone();
@@ -822,12 +2592,29 @@ fn load_or_create_config(args: &Args) -> miette::Result<Config> {
     if let Some(library_path) = &args.library_path {
         config.library_path = Some(library_path.to_string_lossy().to_string());
     }
-    config.verbose = args.verbose;
-    config.organize_output = args.organize;
+    if let Some(state_file) = &args.state_file {
+        config.state_path = Some(state_file.to_string_lossy().to_string());
+    }
+    apply_network_args(&mut config, args);
+    config.verbose = args.verbosity();
+    config.organize_output |= args.organize;
+    config.fast_scan |= args.fast_scan;
+    if let Some(min_confidence) = args.min_confidence {
+        config.min_confidence = min_confidence;
+    }
+
+    Ok(config)
+}
+
+fn load_or_create_config(args: &Args) -> miette::Result<Config> {
+    let json = args.json();
+    // Only results go to stdout with --json or --quiet
+    let silent = json || args.quiet;
+    let mut config = load_config(args)?;
 
     // Try to extract credentials if not provided
     if config.device_id.is_empty() || config.user_idx.is_empty() {
-        if args.verbose {
+        if config.verbose >= Verbosity::Books {
             println!("🔍 Attempting to extract credentials from Ridibooks app...");
         }
 
@@ -835,16 +2622,20 @@ fn load_or_create_config(args: &Args) -> miette::Result<Config> {
         match CredentialManager::extract_credentials_permanent() {
             Ok(creds) => {
                 if config.device_id.is_empty() {
-                    println!("✅ Extracted device_id from encrypted Settings file");
+                    if !silent {
+                        println!("✅ Extracted device_id from encrypted Settings file");
+                    }
                     config.device_id = creds.device_id;
                 }
                 if config.user_idx.is_empty() {
-                    println!("✅ Extracted user_idx from Sentry file");
+                    if !silent {
+                        println!("✅ Extracted user_idx from Sentry file");
+                    }
                     config.user_idx = creds.user_idx.to_string();
                 }
             }
             Err(e) => {
-                if args.verbose {
+                if config.verbose >= Verbosity::Books {
                     println!("⚠️  Permanent extraction failed: {}", e);
                     println!("🔄 Trying fallback method (Sentry breadcrumbs)...");
                 }
@@ -853,16 +2644,20 @@ fn load_or_create_config(args: &Args) -> miette::Result<Config> {
                 match CredentialManager::extract_credentials_from_sentry() {
                     Ok((device_id, user_idx)) => {
                         if config.device_id.is_empty() {
-                            println!("✅ Extracted device_id from Sentry breadcrumbs");
+                            if !silent {
+                                println!("✅ Extracted device_id from Sentry breadcrumbs");
+                            }
                             config.device_id = device_id;
                         }
                         if config.user_idx.is_empty() {
-                            println!("✅ Extracted user_idx from Sentry file");
+                            if !silent {
+                                println!("✅ Extracted user_idx from Sentry file");
+                            }
                             config.user_idx = user_idx;
                         }
                     }
                     Err(e2) => {
-                        if args.verbose {
+                        if config.verbose >= Verbosity::Books {
                             println!("⚠️  Fallback extraction also failed: {}", e2);
                         }
                     }
@@ -871,6 +2666,12 @@ fn load_or_create_config(args: &Args) -> miette::Result<Config> {
         }
     }
 
+    // Ask for whatever is still missing when someone is there to answer
+    let can_prompt = !json && !args.batch_mode() && (args.prompt_credentials || std::io::stdin().is_terminal());
+    if can_prompt && (config.device_id.is_empty() || config.user_idx.is_empty()) {
+        prompt_credentials(&mut config)?;
+    }
+
     // Validate required fields
     if config.device_id.is_empty() || config.user_idx.is_empty() {
         return Err(miette!(
@@ -882,20 +2683,128 @@ fn load_or_create_config(args: &Args) -> miette::Result<Config> {
              3. Run this tool again (both device_id and user_idx will be auto-detected)\n\
              \n\
              Or provide credentials manually:\n\
+             - Run without --batch-mode to be asked for them\n\
              - Run with --device-id and --user-idx flags\n\
              - Create a config file at ~/.ridiculous.toml\n\
              - Get credentials from: https://account.ridibooks.com/api/user-devices/app"
         ));
     }
 
+    config.device_id = CredentialManager::clean_credential(&config.device_id);
+    config.user_idx = CredentialManager::clean_credential(&config.user_idx);
+    logging::redact_in_logs(&config.device_id);
+
+    // Reject malformed credentials before any books are scanned
+    CredentialManager::validate_format(&config.device_id, &config.user_idx)
+        .map_err(|e| miette!(
+            "❌ {}\n\
+             💡 Copy device_id and user_idx exactly as shown at: https://account.ridibooks.com/api/user-devices/app",
+            e
+        ))?;
+
     Ok(config)
 }
 
-fn load_processing_state() -> Result<ProcessingState> {
-    let state_path = dirs::cache_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join("ridiculous_state.json");
-    
+fn credential_manager(config: &Config) -> Result<CredentialManager> {
+    CredentialManager::new()
+        .with_max_retries(config.max_retries)
+        .with_options(&client_options(config))
+}
+
+// --proxy, --ca-cert, --user-agent and --api-timeout, which the setup and diagnose
+// commands also need for validation
+fn apply_network_args(config: &mut Config, args: &Args) {
+    if let Some(proxy) = &args.proxy {
+        config.proxy = Some(proxy.clone());
+    }
+    if let Some(ca_cert) = &args.ca_cert {
+        config.ca_cert = Some(ca_cert.to_string_lossy().to_string());
+    }
+    if let Some(user_agent) = &args.user_agent {
+        config.user_agent = Some(user_agent.clone());
+    }
+    if let Some(api_timeout) = args.api_timeout {
+        config.api_timeout_seconds = Some(api_timeout);
+    }
+}
+
+fn client_options(config: &Config) -> ClientOptions {
+    let defaults = ClientOptions::default();
+    ClientOptions {
+        user_agent: config.user_agent.clone().unwrap_or(defaults.user_agent),
+        timeout: config.api_timeout_seconds.map_or(defaults.timeout, Duration::from_secs),
+        proxy: config.proxy.clone(),
+        ca_cert: config.ca_cert.as_ref().map(PathBuf::from),
+    }
+}
+
+// The manifest of decrypted books. Without one yet, it's built from the books the state
+// lists as completed and from outputs under the default names, so books decrypted before
+// there was a manifest aren't decrypted again. That covers `books` and every library found,
+// not only the one this run picked. Saved right away, except in a dry run.
+fn load_manifest(
+    manifest_path: &Path,
+    state: &ProcessingState,
+    books: &[BookInfo],
+    config: &Config,
+    dry_run: bool,
+) -> miette::Result<Manifest> {
+    let existing = Manifest::load(manifest_path).map_err(|e| miette!(
+        "❌ Could not read the list of decrypted books {}: {}\n\
+         💡 Delete it to rebuild it from the state file and output directory.",
+        manifest_path.display(),
+        e
+    ))?;
+    if let Some(manifest) = existing {
+        return Ok(manifest);
+    }
+
+    let mut all_books = books.to_vec();
+    if let Ok(libraries) = LibraryFinder::new().find_book_libraries(config) {
+        all_books.extend(libraries.into_iter().flat_map(|(_, books)| books));
+    }
+
+    let mut manifest = Manifest::default();
+    for book in &all_books {
+        if manifest.output_path(&book.id).is_some() {
+            continue;
+        }
+        let from_state = state.completed.iter()
+            .find(|completed| completed.id == book.id)
+            .and_then(|completed| completed.output_path.clone())
+            .filter(|output_path| output_path.exists());
+        let Some(output_path) = from_state.or_else(|| existing_output(book, config)) else { continue };
+        if let Ok(source_hash) = manifest::source_hash(book) {
+            manifest.record(&book.id, output_path, source_hash);
+        }
+    }
+
+    if !dry_run {
+        manifest.save(manifest_path).map_err(|e| miette!("{}", e))?;
+    }
+    Ok(manifest)
+}
+
+// Adds the books this run wrote (or found already written) to the manifest
+fn record_in_manifest(manifest: &mut Manifest, state: &ProcessingState) {
+    for outcome in &state.outcomes {
+        if let (Some(output_path), Some(source_hash)) = (&outcome.output_path, &outcome.source_hash) {
+            manifest.record(&outcome.id, output_path.clone(), source_hash.clone());
+        }
+    }
+}
+
+// `state_path` from the config (or --state-file), else ridiculous_state.json in the cache directory
+fn state_file_path(config: &Config) -> PathBuf {
+    match &config.state_path {
+        Some(state_path) => PathBuf::from(state_path),
+        None => dirs::cache_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("ridiculous_state.json"),
+    }
+}
+
+fn load_processing_state(state_path: &Path) -> Result<ProcessingState> {
     if state_path.exists() {
         let content = fs::read_to_string(state_path)?;
         Ok(serde_json::from_str(&content)?)
@@ -904,37 +2813,761 @@ fn load_processing_state() -> Result<ProcessingState> {
     }
 }
 
-fn save_processing_state(state: &ProcessingState) -> Result<()> {
-    let state_path = dirs::cache_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join("ridiculous_state.json");
-
+fn save_processing_state(state_path: &Path, state: &ProcessingState) -> Result<()> {
     if let Some(parent) = state_path.parent() {
         fs::create_dir_all(parent)?;
     }
 
-    // Use atomic write: write to temp file then rename
-    let temp_path = state_path.with_extension("json.tmp");
-    let content = serde_json::to_string_pretty(state)?;
+    write_atomically(state_path, |file| {
+        serde_json::to_writer_pretty(file, state).map_err(std::io::Error::from)
+    })
+}
+
+// Write to a temp file next to `path` and rename it into place, so a crash or a failed
+// write leaves the previous contents untouched
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut fs::File) -> std::io::Result<()>,
+) -> Result<()> {
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e).with_context(|| format!("Failed to write {}", path.display()));
+    }
+
+    Ok(())
+}
+
+// Advisory lock held for the whole run; the OS releases it when the process exits
+struct StateLock {
+    _file: fs::File,
+}
+
+fn lock_processing_state(lock_path: &Path) -> miette::Result<StateLock> {
+    if let Some(parent) = lock_path.parent() {
+        fs::create_dir_all(parent).into_diagnostic()?;
+    }
+
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)
+        .into_diagnostic()?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(StateLock { _file: file }),
+        Err(fs::TryLockError::WouldBlock) => Err(miette!(
+            "❌ Another ridiculous run is already in progress\n\
+             💡 Wait for it to finish before starting a new one.\n\
+             Lock file: {}",
+            lock_path.display()
+        )),
+        Err(fs::TryLockError::Error(e)) => Err(miette!(
+            "❌ Could not lock {}: {}",
+            lock_path.display(),
+            e
+        )),
+    }
+}
+
+// The manifest as a read-only command like scan or stats sees it: built in memory, without
+// saving it, if there's none yet
+fn read_manifest(books: &[BookInfo], config: &Config) -> miette::Result<Manifest> {
+    let state_path = state_file_path(config);
+    let state = load_processing_state(&state_path).map_err(|e| miette!("{}", e))?;
+    load_manifest(&Manifest::path_for(&state_path), &state, books, config, true)
+}
+
+// Decrypted the way run_decrypt decides it, by the manifest, which also knows about
+// --organize and renamed outputs. DRM-free books need no decrypting at all.
+fn is_already_decrypted(book: &BookInfo, manifest: &Manifest, config: &Config) -> bool {
+    manifest.is_decrypted(book, config) || is_plaintext_container(book)
+}
+
+// Print the discovered books and exit without touching the processing state.
+// With --json, failures are reported as a JSON error object so scripts never see a panic.
+fn list_books(args: &Args, json: bool) -> miette::Result<()> {
+    let inventory = load_or_create_config(args).and_then(|config| {
+        let books = LibraryFinder::new().find_books(&config)?;
+        let manifest = read_manifest(&books, &config)?;
+        Ok((books, manifest, config))
+    });
+
+    if json {
+        match inventory {
+            Ok((books, manifest, config)) => {
+                let listings: Vec<_> = books.iter()
+                    .map(|book| BookListing::new(book, &manifest, &config))
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&listings).into_diagnostic()?);
+                Ok(())
+            }
+            Err(e) => {
+                println!("{}", serde_json::json!({ "error": e.to_string() }));
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let (books, manifest, config) = inventory?;
+        println!("📚 Found {} books:", books.len());
+        for book in &books {
+            println!(
+                "   {} {} [{}{}, {}]",
+                if is_already_decrypted(book, &manifest, &config) { "✅" } else { "🔒" },
+                book.get_display_name(),
+                book.format.as_str(),
+                if book.drm_version == DrmVersion::V1 { String::new() } else { format!(" {}", book.drm_version) },
+                book.format_file_size()
+            );
+        }
+        Ok(())
+    }
+}
+
+// `scan --count-only`: just the number, for scripts. No book file is opened. Fails like
+// `scan` when no books can be found.
+fn count_books(args: &Args) -> miette::Result<()> {
+    let config = load_or_create_config(args)?;
+    println!("{}", LibraryFinder::new().count_books(&config)?);
+    Ok(())
+}
+
+// Totals for `stats`, from discovery alone
+#[derive(Debug, Default, PartialEq)]
+struct LibraryStats {
+    books: usize,
+    by_format: std::collections::BTreeMap<String, usize>,
+    // "v1", "v11", ... or "no DRM" for plaintext books
+    by_drm: std::collections::BTreeMap<String, usize>,
+    encrypted_bytes: u64,
+    already_decrypted: usize,
+}
+
+impl LibraryStats {
+    fn new(books: &[BookInfo], manifest: &Manifest, config: &Config) -> Self {
+        let mut stats = LibraryStats { books: books.len(), ..Default::default() };
+        for book in books {
+            *stats.by_format.entry(book.format.as_str().to_string()).or_default() += 1;
+
+            if is_plaintext_container(book) {
+                *stats.by_drm.entry("no DRM".to_string()).or_default() += 1;
+            } else {
+                *stats.by_drm.entry(book.drm_version.to_string()).or_default() += 1;
+                stats.encrypted_bytes += fs::metadata(book.get_book_file_path()).map(|m| m.len()).unwrap_or(0);
+            }
+
+            if manifest.is_decrypted(book, config) {
+                stats.already_decrypted += 1;
+            }
+        }
+        stats
+    }
+}
+
+// `stats`: an overview of the library. Read-only; the processing state isn't touched
+fn print_library_stats(args: &Args) -> miette::Result<()> {
+    let config = load_or_create_config(args)?;
+    let books = LibraryFinder::new().find_books(&config)?;
+    let manifest = read_manifest(&books, &config)?;
+    let stats = LibraryStats::new(&books, &manifest, &config);
+
+    let counts = |counts: &std::collections::BTreeMap<String, usize>| counts.iter()
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    println!("📊 Library statistics");
+    println!("   {:<20}{}", "Books", stats.books);
+    println!("   {:<20}{}", "Formats", counts(&stats.by_format));
+    println!("   {:<20}{}", "DRM", counts(&stats.by_drm));
+    println!("   {:<20}{}", "Encrypted size", format_size(stats.encrypted_bytes));
+    println!("   {:<20}{} ({} to go)", "Already decrypted", stats.already_decrypted, stats.books - stats.already_decrypted);
+    Ok(())
+}
+
+// What `diff` found: library books without a decrypted copy in the output directory, and
+// decrypted books there that aren't in the library (any more)
+#[derive(Debug, Default)]
+struct LibraryDiff {
+    decrypted: usize,
+    // "Title (id)", or the id of a book without metadata
+    not_decrypted: Vec<String>,
+    // Paths relative to the output directory, with the book id when the manifest knows it
+    orphaned: Vec<String>,
+}
+
+impl LibraryDiff {
+    // `config` writes to `output_dir`, which must exist
+    fn new(books: &[BookInfo], manifest: &Manifest, output_dir: &Path, config: &Config) -> Self {
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let output_dir = canonical(output_dir);
+        let mut diff = LibraryDiff::default();
+
+        // The manifest knows where books went even when renamed or organized into folders
+        let mut matched = std::collections::HashSet::new();
+        for book in books {
+            let output = manifest.output_path(&book.id)
+                .map(canonical)
+                .filter(|path| path.starts_with(&output_dir) && path.exists())
+                .or_else(|| existing_output(book, config).map(|path| canonical(&path)));
+            match output {
+                Some(path) => {
+                    diff.decrypted += 1;
+                    matched.insert(path);
+                }
+                None => diff.not_decrypted.push(match &book.title {
+                    Some(title) => format!("{} ({})", title, book.id),
+                    None => book.id.clone(),
+                }),
+            }
+        }
+
+        let owners: std::collections::HashMap<_, _> = manifest.entries()
+            .map(|(id, entry)| (canonical(&entry.output_path), id))
+            .collect();
+        let mut outputs = Vec::new();
+        collect_outputs(&output_dir, &mut outputs);
+        for output in outputs.into_iter().filter(|output| !matched.contains(output)) {
+            let relative = output.strip_prefix(&output_dir).unwrap_or(&output).display().to_string();
+            diff.orphaned.push(match owners.get(&output) {
+                Some(id) => format!("{} ({})", relative, id),
+                None => relative,
+            });
+        }
+
+        diff.not_decrypted.sort();
+        diff.orphaned.sort();
+        diff
+    }
+}
+
+// Decrypted books under `dir`: book files, compressed or not, and the folders --flatten
+// writes EPUBs to
+fn collect_outputs(dir: &Path, outputs: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if path.join("mimetype").is_file() {
+                outputs.push(path);
+            } else {
+                collect_outputs(&path, outputs);
+            }
+        } else if uncompressed_name(&path).extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .is_some_and(|ext| matches!(ext.as_str(), "epub" | "pdf" | "cbz"))
+        {
+            outputs.push(path);
+        }
+    }
+}
+
+// Lays out two lists side by side under their headers. The left column is as wide as its
+// widest entry, up to MAX_COLUMN_WIDTH, past which entries are cut short with "…"
+fn two_columns(headers: [&str; 2], left: &[String], right: &[String]) -> Vec<String> {
+    const MAX_COLUMN_WIDTH: usize = 48;
+
+    let width = left.iter()
+        .map(|entry| console::measure_text_width(entry))
+        .chain([console::measure_text_width(headers[0])])
+        .max()
+        .unwrap_or(0)
+        .min(MAX_COLUMN_WIDTH);
+    let row = |left: &str, right: &str| {
+        let left = if console::measure_text_width(left) > width {
+            console::truncate_str(left, width, "…")
+        } else {
+            console::pad_str(left, width, console::Alignment::Left, None)
+        };
+        format!("{} │ {}", left, right).trim_end().to_string()
+    };
+
+    let mut rows = vec![
+        row(headers[0], headers[1]),
+        row(&"─".repeat(width), &"─".repeat(console::measure_text_width(headers[1]))),
+    ];
+    for i in 0..left.len().max(right.len()) {
+        let entry = |column: &[String]| column.get(i).cloned().unwrap_or_default();
+        rows.push(row(&entry(left), &entry(right)));
+    }
+    rows
+}
+
+// `diff`: what's left to decrypt into an output directory, and what's there that the
+// library no longer has. Read-only; neither the processing state nor the manifest is written
+fn print_library_diff(args: &Args, output_dir: Option<PathBuf>) -> miette::Result<()> {
+    let mut config = load_or_create_config(args)?;
+    let output_dir = output_dir
+        .or_else(|| config.output_directory.as_ref().map(PathBuf::from))
+        .ok_or_else(|| miette!(
+            "❌ No output directory to compare the library with\n\
+             💡 Run `ridiculous diff <OUTPUT_DIR>`, or set output_directory in the config."
+        ))?;
+    if !output_dir.is_dir() {
+        return Err(miette!("❌ Output directory {} does not exist", output_dir.display()));
+    }
+    config.output_directory = Some(output_dir.to_string_lossy().to_string());
+
+    let books = LibraryFinder::new().find_books(&config)?;
+    let state_path = state_file_path(&config);
+    let state = load_processing_state(&state_path).map_err(|e| miette!("{}", e))?;
+    let manifest = load_manifest(&Manifest::path_for(&state_path), &state, &books, &config, true)?;
+    let diff = LibraryDiff::new(&books, &manifest, &output_dir, &config);
+
+    println!("🔍 Library vs {}", output_dir.display());
+    println!("   📚 {} books in the library, {} of them decrypted there", books.len(), diff.decrypted);
+    if diff.not_decrypted.is_empty() && diff.orphaned.is_empty() {
+        println!("✅ Every book is decrypted, and every output belongs to a book in the library");
+        return Ok(());
+    }
+
+    println!();
+    let headers = [
+        format!("📥 Not decrypted yet ({})", diff.not_decrypted.len()),
+        format!("🗂️  Not in the library ({})", diff.orphaned.len()),
+    ];
+    for row in two_columns([&headers[0], &headers[1]], &diff.not_decrypted, &diff.orphaned) {
+        println!("   {}", row);
+    }
+    if !diff.not_decrypted.is_empty() {
+        println!("\n💡 Run `ridiculous --output-dir {}` to decrypt the missing books", output_dir.display());
+    }
+    Ok(())
+}
+
+// `clean-state`: show what the processing state holds, then clear the part the user picks,
+// or with --prune drop books that have left the library
+fn clean_processing_state(args: &Args, prune: bool) -> miette::Result<()> {
+    let state_path = state_file_path(&load_config(args)?);
+    let _state_lock = lock_processing_state(&state_path.with_extension("lock"))?;
+    let mut state = load_processing_state(&state_path).map_err(|e| miette!("{}", e))?;
+
+    println!("📁 State file: {}", state_path.display());
+    println!("   ✅ Completed: {}", state.completed.len());
+    println!("   ❌ Failed: {}", state.failed.len());
+    let incomplete = state.failed.iter().filter(|(id, _)| state.is_incomplete_download(id)).count();
+    if incomplete > 0 {
+        println!("   📥 Of which not fully downloaded: {}", incomplete);
+    }
+    if let Some(last_run) = state.last_run {
+        println!("   🕒 Last run: {}", humantime::format_rfc3339_seconds(last_run));
+    }
+
+    if prune {
+        // find_books fails rather than returning nothing, so a missing library never empties the state
+        let config = load_or_create_config(args)?;
+        let present: std::collections::HashSet<_> = LibraryFinder::new().find_books(&config)?
+            .into_iter()
+            .map(|book| book.id)
+            .collect();
+        let removed = state.prune(|id| present.contains(id));
+        if removed == 0 {
+            println!("✅ Every entry belongs to a book in the library, nothing to prune");
+            return Ok(());
+        }
+        save_processing_state(&state_path, &state).map_err(|e| miette!("{}", e))?;
+        println!("🧹 Removed {} entries for books no longer in the library", removed);
+        return Ok(());
+    }
+
+    let answer = prompt("Clear [c]ompleted, [f]ailed, [a]ll, or [n]othing?", Some("n"))?;
+    match answer.to_lowercase().chars().next() {
+        Some('c') => state.completed.clear(),
+        Some('f') => {
+            state.failed.clear();
+            state.incomplete_downloads.clear();
+        }
+        Some('a') => state = ProcessingState::default(),
+        _ => {
+            println!("👋 Nothing was changed");
+            return Ok(());
+        }
+    }
+    save_processing_state(&state_path, &state).map_err(|e| miette!("{}", e))?;
+    println!("🧹 State cleared");
+    Ok(())
+}
+
+// What `repair-state` changed: ids of completed books whose outputs are gone, and of
+// books found decrypted that the state didn't list
+#[derive(Debug, Default, PartialEq)]
+struct StateRepair {
+    removed: Vec<String>,
+    added: Vec<String>,
+}
+
+// Checks every completed book against its output, by the path the state or manifest
+// recorded or under the default name, and every library book the state doesn't list
+// as completed. The manifest follows the same changes.
+fn repair_state(state: &mut ProcessingState, manifest: &mut Manifest, books: &[BookInfo], config: &Config) -> StateRepair {
+    let find_output = |id: &str, recorded: Option<&Path>| {
+        recorded.filter(|path| path.exists()).map(Path::to_path_buf)
+            .or_else(|| manifest.output_path(id).filter(|path| path.exists()).map(Path::to_path_buf))
+            .or_else(|| books.iter().find(|book| book.id == id).and_then(|book| existing_output(book, config)))
+    };
+    let mut repair = StateRepair::default();
+
+    let mut kept = Vec::new();
+    for mut completed in std::mem::take(&mut state.completed) {
+        match find_output(&completed.id, completed.output_path.as_deref()) {
+            Some(output_path) => {
+                completed.output_path = Some(output_path);
+                kept.push(completed);
+            }
+            None => repair.removed.push(completed.id),
+        }
+    }
+    state.completed = kept;
+
+    for book in books {
+        if state.is_completed(&book.id) {
+            continue;
+        }
+        let Some(output_path) = find_output(&book.id, None) else { continue };
+        state.completed.push(CompletedBook {
+            id: book.id.clone(),
+            format: Some(book.format.as_str().to_string()),
+            sha256: sha256_file(&output_path).ok(),
+            output_path: Some(output_path),
+            duration_ms: None,
+        });
+        state.failed.retain(|(id, _)| id != &book.id);
+        repair.added.push(book.id.clone());
+    }
+
+    for id in &repair.removed {
+        manifest.remove(id);
+    }
+    for book in books {
+        let output_path = state.completed.iter()
+            .find(|completed| completed.id == book.id)
+            .and_then(|completed| completed.output_path.clone());
+        if let (Some(output_path), Ok(source_hash)) = (output_path, manifest::source_hash(book)) {
+            if manifest.output_path(&book.id) != Some(output_path.as_path()) {
+                manifest.record(&book.id, output_path, source_hash);
+            }
+        }
+    }
+
+    repair.removed.sort();
+    repair.added.sort();
+    repair
+}
+
+// `repair-state`: cross-check the processing state and manifest with the outputs actually
+// on disk, so --resume and skipping don't trust entries that are no longer true
+fn repair_processing_state(args: &Args) -> miette::Result<()> {
+    let config = load_or_create_config(args)?;
+    let state_path = state_file_path(&config);
+    let _state_lock = lock_processing_state(&state_path.with_extension("lock"))?;
+    let mut state = load_processing_state(&state_path).map_err(|e| miette!("{}", e))?;
+    let books = LibraryFinder::new().find_books(&config)?;
+    let manifest_path = Manifest::path_for(&state_path);
+    let mut manifest = load_manifest(&manifest_path, &state, &books, &config, true)?;
+
+    let repair = repair_state(&mut state, &mut manifest, &books, &config);
+    save_processing_state(&state_path, &state).map_err(|e| miette!("{}", e))?;
+    manifest.save(&manifest_path).map_err(|e| miette!("{}", e))?;
+
+    println!("📁 State file: {}", state_path.display());
+    if repair.removed.is_empty() && repair.added.is_empty() {
+        println!("✅ The state matches the outputs, nothing to repair");
+        return Ok(());
+    }
+    if !repair.removed.is_empty() {
+        println!("🧹 Removed {} completed books whose output is gone:", repair.removed.len());
+        for id in &repair.removed {
+            println!("   - {}", id);
+        }
+    }
+    if !repair.added.is_empty() {
+        println!("📥 Added {} books already decrypted but not recorded:", repair.added.len());
+        for id in &repair.added {
+            println!("   - {}", id);
+        }
+    }
+    println!("   ✅ Completed: {}", state.completed.len());
+    Ok(())
+}
+
+// `verify`: make sure every output recorded in the processing state is still intact
+fn verify_completed_books(args: &Args) -> miette::Result<()> {
+    let state = load_processing_state(&state_file_path(&load_config(args)?)).map_err(|e| miette!("{}", e))?;
+
+    if state.completed.is_empty() {
+        println!("ℹ️  No completed books recorded. Run a decryption first.");
+        return Ok(());
+    }
+
+    println!("🔍 Verifying {} decrypted books...", state.completed.len());
+
+    let mut problems = Vec::new();
+    let mut unchecked = 0;
+    for completed in &state.completed {
+        match check_completed_book(completed) {
+            Some(Ok(())) => {}
+            Some(Err(problem)) => problems.push((completed.id.as_str(), problem)),
+            None => unchecked += 1,
+        }
+    }
+
+    let verified = state.completed.len() - problems.len() - unchecked;
+    println!("\n📊 Verification Summary:");
+    println!("   ✅ Intact: {}", verified);
+    println!("   ❌ Problems: {}", problems.len());
+    if unchecked > 0 {
+        println!("   ⏭️  Skipped (no output path recorded): {}", unchecked);
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    println!("\n❌ Books that need attention:");
+    for (book_id, problem) in &problems {
+        println!("   - {}: {}", book_id, problem);
+    }
+    Err(miette!(
+        "{} decrypted books failed verification.\n\
+         💡 Keep the encrypted originals and re-run with --force for these books.",
+        problems.len()
+    ))
+}
+
+// None when the state doesn't say where the book was written (older state files)
+fn check_completed_book(completed: &CompletedBook) -> Option<Result<(), String>> {
+    let output_path = completed.output_path.as_deref()?;
+
+    if !output_path.exists() {
+        return Some(Err(format!("output file is missing: {}", output_path.display())));
+    }
+    // Written with --flatten; its files aren't checked one by one
+    if output_path.is_dir() {
+        let has_files = fs::read_dir(output_path).is_ok_and(|mut entries| entries.next().is_some());
+        return Some(if has_files { Ok(()) } else { Err(format!("output folder is empty: {}", output_path.display())) });
+    }
+
+    let book_path = uncompressed_name(output_path);
+    let format = completed.format.as_deref()
+        .or_else(|| book_path.extension().and_then(|ext| ext.to_str()))
+        .map(BookFormat::from_extension)
+        .unwrap_or(BookFormat::Unknown);
+    let valid = if book_path == output_path {
+        is_valid_output(&format, output_path)
+    } else {
+        is_valid_compressed_output(&format, output_path)
+    };
+    if !valid {
+        return Some(Err(format!("not a valid {} file: {}", format.as_str().to_uppercase(), output_path.display())));
+    }
+
+    if let Some(expected) = &completed.sha256 {
+        match sha256_file(output_path) {
+            Ok(actual) if &actual == expected => {}
+            Ok(_) => return Some(Err(format!("checksum changed since decryption: {}", output_path.display()))),
+            Err(e) => return Some(Err(format!("could not read {}: {}", output_path.display(), e))),
+        }
+    }
+
+    Some(Ok(()))
+}
+
+// `self-test`: decrypts synthetic books end to end, to tell a broken build apart from a
+// problem with the library or credentials. Needs no config, credentials or RIDI data.
+fn run_self_test() -> miette::Result<()> {
+    println!("🧪 Decrypting synthetic books...");
+
+    let work_dir = std::env::temp_dir().join(format!("ridiculous-self-test-{}", std::process::id()));
+    let checks = self_test_checks(&work_dir);
+    let _ = fs::remove_dir_all(&work_dir);
+
+    let mut failed = 0;
+    for (check, result) in &checks {
+        match result {
+            Ok(()) => println!("   ✅ {}", check),
+            Err(e) => {
+                failed += 1;
+                println!("   ❌ {}: {}", check, e.to_string().lines().next().unwrap_or_default().trim_start_matches("❌ "));
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(miette!(
+            "Self-test failed: {} of {} checks ({} {}, {})\n\
+             💡 This build can't decrypt books. Please include this output when reporting the problem.",
+            failed,
+            checks.len(),
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS
+        ));
+    }
+    println!("\n✅ Self-test passed: key derivation, v1 and v11 decryption and output checks work");
+    Ok(())
+}
 
-    fs::write(&temp_path, content)?;
+fn self_test_checks(work_dir: &Path) -> Vec<(&'static str, Result<()>)> {
+    vec![
+        ("v1 PDF decrypts and verifies", self_test_book(work_dir, "1000000001", DrmVersion::V1)),
+        ("v11 EPUB decrypts and verifies", self_test_book(work_dir, "1000000011", DrmVersion::V11)),
+        ("A different device_id is rejected", self_test_wrong_device(work_dir)),
+    ]
+}
 
-    // Atomic rename (overwrites destination on success)
-    fs::rename(&temp_path, &state_path)?;
+fn self_test_config(work_dir: &Path, device_id: &str) -> Config {
+    Config {
+        device_id: device_id.to_string(),
+        backup_originals: false,
+        output_directory: Some(work_dir.join("output").to_string_lossy().to_string()),
+        ..Default::default()
+    }
+}
 
+// The same derive → decrypt → verify steps as a real book, then a comparison with the original
+fn self_test_book(work_dir: &Path, book_id: &str, version: DrmVersion) -> Result<()> {
+    let book_dir = work_dir.join("library").join(book_id);
+    let plaintext = write_fixture_book(&book_dir, version)
+        .with_context(|| format!("❌ Could not write a synthetic book to {}", book_dir.display()))?;
+    let book = BookInfo::new(book_dir).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let config = self_test_config(work_dir, FIXTURE_DEVICE_ID);
+    let pb = ProgressBar::hidden();
+    let key = derive_content_key(&book, &config, &pb)?;
+    let (_, result) = decrypt_book_with_original_logic(&book, &config, &key, 0, &AtomicBool::new(false), &pb)?;
+
+    let decrypted = fs::read(result.output_path())?;
+    if !fixture_matches(&plaintext, &decrypted) {
+        anyhow::bail!("❌ The decrypted book doesn't match the original");
+    }
     Ok(())
 }
 
+// A wrong device_id has to fail at key derivation rather than produce garbage
+fn self_test_wrong_device(work_dir: &Path) -> Result<()> {
+    let book_dir = work_dir.join("library").join("1000000099");
+    write_fixture_book(&book_dir, DrmVersion::V1)?;
+    let book = BookInfo::new(book_dir).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let config = self_test_config(work_dir, "87654321-4321-4321-4321-210987654321");
+    match derive_content_key(&book, &config, &ProgressBar::hidden()) {
+        Err(e) if matches!(e.downcast_ref::<DecryptError>(), Some(DecryptError::WrongDevice { .. })) => Ok(()),
+        Err(e) => Err(e.context("❌ Failed, but not as a wrong device")),
+        Ok(_) => anyhow::bail!("❌ A key was derived for the wrong device"),
+    }
+}
+
+fn print_dry_run_summary(book_count: usize) {
+    println!("\n📊 Dry-Run Preview:");
+    println!("   🔎 Books that would be decrypted: {}", book_count);
+    println!("   💾 No files were written and the processing state was left unchanged");
+    println!("\n💡 Run again without --dry-run to decrypt these books");
+}
+
+// --quiet: one plain line on stdout for the run, and one line per failed book on stderr
+fn print_terse_summary(state: &ProcessingState, stopped_early: bool) {
+    let count = |status| state.outcomes.iter().filter(|outcome| outcome.status == status).count();
+    println!(
+        "{} decrypted, {} copied, {} kept, {} failed{}{}",
+        count("completed"),
+        count("copied"),
+        count("kept"),
+        count("failed"),
+        if state.samples_skipped > 0 { format!(", {} samples skipped", state.samples_skipped) } else { String::new() },
+        if stopped_early { " (stopped early)" } else { "" }
+    );
+
+    for outcome in state.outcomes.iter().filter(|outcome| outcome.status == "failed") {
+        let error = outcome.error.as_deref().unwrap_or_default();
+        let first_line = error.lines().next().unwrap_or_default();
+        eprintln!("failed {}: {}", outcome.id, first_line.trim_start_matches(|c: char| !c.is_alphanumeric()));
+    }
+    for outcome in &state.outcomes {
+        if let Some(error) = &outcome.post_process_error {
+            eprintln!("post-process failed {}: {}", outcome.id, error.lines().next().unwrap_or_default());
+        }
+    }
+}
+
 fn print_summary(state: &ProcessingState) {
     println!("\n📊 Processing Summary:");
     println!("   ✅ Completed: {}", state.completed.len());
+    let copied = state.outcomes.iter().filter(|outcome| outcome.status == "copied").count();
+    if copied > 0 {
+        println!("   📄 Already plaintext, copied as-is: {}", copied);
+    }
+    let kept = state.outcomes.iter().filter(|outcome| outcome.status == "kept").count();
+    if kept > 0 {
+        println!("   ⏭️  Existing output kept (--on-existing skip): {}", kept);
+    }
+    if state.samples_skipped > 0 {
+        println!("   🆓 Free samples skipped (--include-samples to decrypt them): {}", state.samples_skipped);
+    }
     println!("   ❌ Failed: {}", state.failed.len());
+    if let Some(last_run) = state.last_run {
+        println!("   🕒 Last run: {}", humantime::format_rfc3339_seconds(last_run));
+    }
+
+    // Group output files by directory so large runs stay readable
+    let mut locations: std::collections::BTreeMap<&Path, usize> = std::collections::BTreeMap::new();
+    for output_dir in state.completed.iter()
+        .filter_map(|completed| completed.output_path.as_deref())
+        .filter_map(Path::parent)
+    {
+        *locations.entry(output_dir).or_default() += 1;
+    }
+
+    if !locations.is_empty() {
+        println!("\n📁 Decrypted files saved to:");
+        for (output_dir, count) in locations {
+            println!("   - {} ({} books)", output_dir.display(), count);
+        }
+    }
     
-    if !state.failed.is_empty() {
+    // Partly downloaded books need the user to act in RIDI first, so list them apart
+    let (incomplete, failed): (Vec<_>, Vec<_>) = state.failed.iter()
+        .partition(|(id, _)| state.is_incomplete_download(id));
+
+    if !failed.is_empty() {
         println!("\n❌ Failed books:");
-        for (book_id, error) in &state.failed {
+        for (book_id, error) in &failed {
             println!("   - {}: {}", book_id, error);
         }
+    }
+    if !incomplete.is_empty() {
+        println!("\n📥 Not fully downloaded:");
+        for (book_id, _) in &incomplete {
+            println!("   - {}", book_id);
+        }
+        println!("   💡 Open RIDI and let these finish downloading (or download them again)");
+    }
+    let post_process_failed: Vec<_> = state.outcomes.iter()
+        .filter_map(|outcome| Some((outcome, outcome.post_process_error.as_ref()?)))
+        .collect();
+    if !post_process_failed.is_empty() {
+        println!("\n⚠️  Decrypted, but --post-process failed:");
+        for (outcome, error) in &post_process_failed {
+            println!("   - {}: {}", outcome.id, error);
+        }
+    }
+    if !state.failed.is_empty() {
         println!("\n💡 Use --resume to retry failed books");
     }
 }
+
+fn is_incomplete_download(error: &anyhow::Error) -> bool {
+    error.chain()
+        .find_map(|cause| cause.downcast_ref::<DecryptError>())
+        .is_some_and(|decrypt_error| matches!(decrypt_error, DecryptError::IncompleteDownload { .. }))
+}
@@ -0,0 +1,159 @@
+//! Which books have been decrypted and where to: `book.id` → the output path plus a
+//! fingerprint of the encrypted source, kept next to the processing state.
+//!
+//! Skipping a book used to mean looking for `<id>_decrypted.<ext>` in the output
+//! directory, which misses copies written under --organize, --flatten or --on-existing
+//! rename, or to an output directory that has since changed. The manifest remembers the
+//! real path, so a book is done as long as that file is there and RIDI hasn't replaced
+//! the book since.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::decrypt::existing_output;
+use crate::types::{BookInfo, Config};
+
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    version: u32,
+    books: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub output_path: PathBuf,
+    pub source_hash: String,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self { version: MANIFEST_VERSION, books: BTreeMap::new() }
+    }
+}
+
+impl Manifest {
+    /// Where the manifest for the state file at `state_path` lives
+    pub fn path_for(state_path: &Path) -> PathBuf {
+        state_path.with_extension("manifest.json")
+    }
+
+    /// The manifest at `path`, or None when there isn't one yet
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        crate::write_atomically(path, |file| serde_json::to_writer_pretty(file, self).map_err(io::Error::from))
+    }
+
+    pub fn record(&mut self, book_id: &str, output_path: PathBuf, source_hash: String) {
+        self.books.insert(book_id.to_string(), ManifestEntry { output_path, source_hash });
+    }
+
+    /// Forgets `book_id`, e.g. once its output is gone
+    pub fn remove(&mut self, book_id: &str) {
+        self.books.remove(book_id);
+    }
+
+    /// Where the book `book_id` was last decrypted to, whether or not it's still there
+    pub fn output_path(&self, book_id: &str) -> Option<&Path> {
+        self.books.get(book_id).map(|entry| entry.output_path.as_path())
+    }
+
+    /// Every recorded book id with its entry, in id order
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &ManifestEntry)> {
+        self.books.iter().map(|(id, entry)| (id.as_str(), entry))
+    }
+
+    /// Whether `book` was decrypted from the files now in the library, to an output
+    /// that's still there. A book the manifest has no entry for, such as one written by
+    /// the GUI, counts when its output is found under the default name.
+    pub fn is_decrypted(&self, book: &BookInfo, config: &Config) -> bool {
+        match self.books.get(&book.id) {
+            Some(entry) => entry.output_path.exists() && source_hash(book).is_ok_and(|hash| hash == entry.source_hash),
+            None => existing_output(book, config).is_some(),
+        }
+    }
+}
+
+/// A fingerprint of a book's files in the library: its `.dat`, which holds the key, and
+/// the book file's name and size. Far cheaper than hashing books of hundreds of MB, and
+/// it changes when RIDI replaces either file with a different download.
+pub fn source_hash(book: &BookInfo) -> io::Result<String> {
+    let book_size = fs::metadata(book.get_book_file_path())?.len();
+    // DRM-free books may come without one
+    let dat = fs::read(book.get_data_file_path()).unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    hasher.update(&dat);
+    hasher.update(book.book_filename.as_bytes());
+    hasher.update(book_size.to_le_bytes());
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_tracks_output_and_source() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let book_dir = temp_dir.path().join("1234567890");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("1234567890.dat"), b"dat").unwrap();
+        fs::write(book_dir.join("1234567890.v1.pdf"), b"encrypted").unwrap();
+        let book = BookInfo::new(book_dir.clone()).unwrap();
+
+        // Written under a name the old filename check wouldn't find
+        let output_path = temp_dir.path().join("Author").join("Title.pdf");
+        fs::create_dir_all(output_path.parent().unwrap()).unwrap();
+        fs::write(&output_path, b"%PDF-1.4").unwrap();
+
+        let config = Config {
+            output_directory: Some(temp_dir.path().join("output").to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let mut manifest = Manifest::default();
+        assert!(!manifest.is_decrypted(&book, &config));
+        manifest.record(&book.id, output_path.clone(), source_hash(&book).unwrap());
+        assert!(manifest.is_decrypted(&book, &config));
+
+        let manifest_path = Manifest::path_for(&temp_dir.path().join("ridiculous_state.json"));
+        assert_eq!(manifest_path, temp_dir.path().join("ridiculous_state.manifest.json"));
+        assert_eq!(Manifest::load(&manifest_path).unwrap(), None);
+        manifest.save(&manifest_path).unwrap();
+        let loaded = Manifest::load(&manifest_path).unwrap().unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(loaded.books[&book.id].output_path, output_path);
+
+        // A re-downloaded book needs decrypting again
+        fs::write(book_dir.join("1234567890.v1.pdf"), b"encrypted, but longer").unwrap();
+        assert!(!manifest.is_decrypted(&book, &config));
+        manifest.record(&book.id, output_path.clone(), source_hash(&book).unwrap());
+        assert!(manifest.is_decrypted(&book, &config));
+
+        // So does one whose output was deleted
+        fs::remove_file(&output_path).unwrap();
+        assert!(!manifest.is_decrypted(&book, &config));
+
+        // Without an entry, an output under the default name still counts
+        let default_output = temp_dir.path().join("output").join(book.get_output_filename());
+        fs::create_dir_all(default_output.parent().unwrap()).unwrap();
+        fs::write(&default_output, b"%PDF-1.4").unwrap();
+        assert!(Manifest::default().is_decrypted(&book, &config));
+        assert!(!manifest.is_decrypted(&book, &config));
+    }
+}
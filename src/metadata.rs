@@ -0,0 +1,187 @@
+//! Book titles, authors and series from the RIDI app's own metadata store.
+//!
+//! The app keeps metadata in a `metadata` folder at the library root, so real titles
+//! can be shown before anything is decrypted. The folder's layout isn't documented:
+//! JSON files holding one book, a list of books, or a `books`/`items` list are read,
+//! and anything else (including sqlite databases) is ignored.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::types::BookInfo;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub series: Option<String>,
+    pub is_sample: bool,
+}
+
+/// The metadata folder for books found in `library_path`. Books often live in a
+/// `_{user_idx}` folder, so the library root above it is checked too.
+pub fn find_metadata_dir(library_path: &Path) -> Option<PathBuf> {
+    std::iter::once(library_path)
+        .chain(library_path.parent())
+        .map(|dir| dir.join("metadata"))
+        .find(|dir| dir.is_dir())
+}
+
+/// Every book described in `metadata_dir`, by book id. Unreadable or unrecognized
+/// files are skipped, so an unknown layout gives an empty map.
+pub fn read_metadata_store(metadata_dir: &Path) -> HashMap<String, BookMetadata> {
+    let mut store = HashMap::new();
+
+    let entries = match fs::read_dir(metadata_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::debug!("Cannot read metadata folder {}: {}", metadata_dir.display(), e);
+            return store;
+        }
+    };
+
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+
+        let value = match fs::read_to_string(&path).map(|content| serde_json::from_str::<Value>(&content)) {
+            Ok(Ok(value)) => value,
+            _ => {
+                tracing::debug!("Skipping unrecognized metadata file {}", path.display());
+                continue;
+            }
+        };
+
+        // A file describing a single book may be named after it instead of holding its id
+        let file_stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string());
+        for record in records(&value) {
+            let id = string_field(record, &["id", "book_id", "bookId", "b_id"]).or_else(|| file_stem.clone());
+            let metadata = BookMetadata {
+                title: string_field(record, &["title"]),
+                author: author(record),
+                series: series(record),
+                is_sample: flag(record, &["is_sample", "sample", "preview"]),
+            };
+
+            if let Some(id) = id {
+                if metadata != BookMetadata::default() {
+                    store.insert(id, metadata);
+                }
+            }
+        }
+    }
+
+    tracing::debug!("Read metadata for {} books from {}", store.len(), metadata_dir.display());
+    store
+}
+
+/// Fill in missing titles, authors and series from the metadata store next to `library_path`.
+pub fn enrich_books(books: &mut [BookInfo], library_path: &Path) {
+    let Some(metadata_dir) = find_metadata_dir(library_path) else {
+        return;
+    };
+
+    let store = read_metadata_store(&metadata_dir);
+    for book in books.iter_mut() {
+        if let Some(metadata) = store.get(&book.id) {
+            book.title = book.title.take().or_else(|| metadata.title.clone());
+            book.author = book.author.take().or_else(|| metadata.author.clone());
+            book.series = book.series.take().or_else(|| metadata.series.clone());
+            book.is_sample |= metadata.is_sample;
+        }
+    }
+}
+
+// The book objects in a metadata file: a list, a `books`/`items` list, or a single object
+fn records(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(items) => items.iter().filter(|item| item.is_object()).collect(),
+        Value::Object(object) => match object.get("books").or_else(|| object.get("items")) {
+            Some(Value::Array(items)) => items.iter().filter(|item| item.is_object()).collect(),
+            _ => vec![value],
+        },
+        _ => Vec::new(),
+    }
+}
+
+// The first non-empty string (or number, for ids) among `keys`
+fn string_field(record: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter()
+        .filter_map(|key| match record.get(key)? {
+            Value::String(text) => Some(text.trim().to_string()),
+            Value::Number(number) => Some(number.to_string()),
+            _ => None,
+        })
+        .find(|text| !text.is_empty())
+}
+
+// Whether any of `keys` is true, as a boolean or as 1
+fn flag(record: &Value, keys: &[&str]) -> bool {
+    keys.iter().any(|key| match record.get(key) {
+        Some(Value::Bool(value)) => *value,
+        Some(Value::Number(number)) => number.as_u64() == Some(1),
+        _ => false,
+    })
+}
+
+// `author` as a string, or `authors` as a list of names or `{ "name": ... }` objects
+fn author(record: &Value) -> Option<String> {
+    if let Some(author) = string_field(record, &["author"]) {
+        return Some(author);
+    }
+
+    let names: Vec<_> = record.get("authors")?
+        .as_array()?
+        .iter()
+        .filter_map(|author| match author {
+            Value::String(name) => Some(name.trim().to_string()),
+            Value::Object(_) => string_field(author, &["name"]),
+            _ => None,
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    (!names.is_empty()).then(|| names.join(", "))
+}
+
+// `series` as a string or as a `{ "title": ... }` / `{ "name": ... }` object
+fn series(record: &Value) -> Option<String> {
+    string_field(record, &["series"])
+        .or_else(|| string_field(record.get("series")?, &["title", "name"]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_store_layouts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let metadata_dir = temp_dir.path().join("metadata");
+        fs::create_dir_all(&metadata_dir).unwrap();
+
+        fs::write(metadata_dir.join("library.json"), r#"{"books": [
+            {"id": "1111111111", "title": "First", "authors": [{"name": "Kim"}, "Lee"], "series": {"title": "Saga"}},
+            {"book_id": 2222222222, "title": "Second"}
+        ]}"#).unwrap();
+        fs::write(metadata_dir.join("3333333333.json"), r#"{"title": "Third", "author": "Park"}"#).unwrap();
+        fs::write(metadata_dir.join("broken.json"), "{ not json").unwrap();
+        fs::write(metadata_dir.join("library.db"), b"SQLite format 3\0").unwrap();
+
+        let store = read_metadata_store(&metadata_dir);
+        assert_eq!(store.len(), 3);
+        assert_eq!(store["1111111111"].title.as_deref(), Some("First"));
+        assert_eq!(store["1111111111"].author.as_deref(), Some("Kim, Lee"));
+        assert_eq!(store["1111111111"].series.as_deref(), Some("Saga"));
+        assert_eq!(store["2222222222"].title.as_deref(), Some("Second"));
+        assert_eq!(store["3333333333"].author.as_deref(), Some("Park"));
+
+        // Books in a `_{user_idx}` folder use the store at the library root
+        let user_dir = temp_dir.path().join("_123");
+        fs::create_dir_all(&user_dir).unwrap();
+        assert_eq!(find_metadata_dir(&user_dir), Some(metadata_dir));
+    }
+}
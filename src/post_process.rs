@@ -0,0 +1,123 @@
+//! `--post-process`: a command run on each book's output once it's written, e.g. to
+//! convert it or upload it somewhere.
+//!
+//! The command line is split into words like a shell would, but no shell runs it, so
+//! titles in output paths can't inject anything. `{}` in a word stands for the output
+//! path; without one, the path is passed as the last argument.
+
+use std::ffi::OsString;
+use std::path::Path;
+use tokio::process::Command;
+
+// How much of a failing command's output goes into its error
+const MAX_ERROR_OUTPUT: usize = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostProcess {
+    words: Vec<String>,
+}
+
+impl PostProcess {
+    pub fn parse(command: &str) -> Result<Self, String> {
+        let words = shell_words::split(command)
+            .map_err(|e| format!("❌ Invalid --post-process command: {}\n💡 Check the quotes in: {}", e, command))?;
+        if words.is_empty() {
+            return Err("❌ The --post-process command is empty".to_string());
+        }
+        Ok(Self { words })
+    }
+
+    // The program and its arguments for `output`
+    fn command_line(&self, output: &Path) -> Vec<OsString> {
+        let mut substituted = false;
+        let mut line: Vec<OsString> = self.words.iter()
+            .map(|word| {
+                if !word.contains("{}") {
+                    return OsString::from(word);
+                }
+                substituted = true;
+                let mut parts = word.split("{}");
+                let mut arg = OsString::from(parts.next().unwrap_or_default());
+                for part in parts {
+                    arg.push(output);
+                    arg.push(part);
+                }
+                arg
+            })
+            .collect();
+
+        if !substituted {
+            line.push(output.as_os_str().to_owned());
+        }
+        line
+    }
+
+    /// Runs the command on `output`, returning what it printed. A command that can't be
+    /// started or exits unsuccessfully is an error, with the end of its stderr.
+    pub async fn run(&self, output: &Path) -> Result<String, String> {
+        let line = self.command_line(output);
+        let result = Command::new(&line[0])
+            .args(&line[1..])
+            .stdin(std::process::Stdio::null())
+            .output()
+            .await
+            .map_err(|e| format!("could not run {}: {}", self.words[0], e))?;
+
+        let stdout = String::from_utf8_lossy(&result.stdout).trim().to_string();
+        if result.status.success() {
+            return Ok(stdout);
+        }
+
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        let details = if stderr.trim().is_empty() { stdout.as_str() } else { stderr.trim() };
+        let start = details.len().saturating_sub(MAX_ERROR_OUTPUT);
+        let start = (start..details.len()).find(|i| details.is_char_boundary(*i)).unwrap_or(details.len());
+        Err(match &details[start..] {
+            "" => format!("{} exited with {}", self.words[0], result.status),
+            tail => format!("{} exited with {}: {}", self.words[0], result.status, tail),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line() {
+        let output = Path::new("/books/Some Title/Some Title.epub");
+        let line = |command: &str| PostProcess::parse(command).unwrap().command_line(output);
+
+        assert_eq!(line("ebook-convert {} {}.mobi"), [
+            "ebook-convert",
+            "/books/Some Title/Some Title.epub",
+            "/books/Some Title/Some Title.epub.mobi",
+        ]);
+        assert_eq!(line("rclone copy --progress 'remote:My Books'"), [
+            "rclone",
+            "copy",
+            "--progress",
+            "remote:My Books",
+            "/books/Some Title/Some Title.epub",
+        ]);
+
+        assert!(PostProcess::parse("echo 'unterminated").is_err());
+        assert!(PostProcess::parse("   ").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run() {
+        let output = Path::new("/tmp/book $(id).epub");
+
+        let echo = PostProcess::parse("echo converted {}").unwrap();
+        assert_eq!(echo.run(output).await.unwrap(), "converted /tmp/book $(id).epub");
+
+        let failing = PostProcess::parse("ls --no-such-option").unwrap();
+        let error = failing.run(output).await.unwrap_err();
+        assert!(error.starts_with("ls exited with"), "{}", error);
+
+        let missing = PostProcess::parse("no-such-post-process-command").unwrap();
+        assert!(missing.run(output).await.unwrap_err().contains("could not run no-such-post-process-command"));
+    }
+}
@@ -1,8 +1,40 @@
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use crate::*;
+    use crate::decrypt::{decrypt_for_version, NoProgress, should_skip, SkipReason};
     use std::fs;
     use tempfile::tempdir;
+
+    const TEST_DEVICE_ID: &str = decrypt::FIXTURE_DEVICE_ID;
+    const TEST_BOOK_KEY: &[u8; 16] = &decrypt::FIXTURE_BOOK_KEY;
+
+    fn encrypt(plaintext: &[u8], key: &[u8; 16]) -> Vec<u8> {
+        decrypt::encrypt_fixture(plaintext, key, &[7; 16])
+    }
+
+    // Write a v1 book whose .dat unlocks TEST_BOOK_KEY under TEST_DEVICE_ID
+    fn write_v1_book(book_dir: &std::path::Path, book_filename: &str, plaintext: &[u8]) -> BookInfo {
+        decrypt::write_fixture_dat(book_dir, TEST_DEVICE_ID).unwrap();
+        fs::write(book_dir.join(book_filename), encrypt(plaintext, TEST_BOOK_KEY)).unwrap();
+
+        BookInfo::new(book_dir.to_path_buf()).unwrap()
+    }
+
+    fn test_config(output_dir: &std::path::Path) -> Config {
+        Config {
+            device_id: TEST_DEVICE_ID.to_string(),
+            backup_originals: false,
+            output_directory: Some(output_dir.to_string_lossy().to_string()),
+            ..Default::default()
+        }
+    }
+
+    // Derive the key and decrypt the way a single attempt of process_single_book does
+    fn decrypt_test_book(book: &BookInfo, config: &Config) -> anyhow::Result<(BookInfo, BookResult)> {
+        let pb = indicatif::ProgressBar::hidden();
+        let key = derive_content_key(book, config, &pb)?;
+        decrypt_book_with_original_logic(book, config, &key, 0, &AtomicBool::new(false), &pb)
+    }
     
     #[test]
     fn test_book_format_detection() {
@@ -11,38 +43,418 @@ mod tests {
         assert_eq!(BookFormat::from_extension("unknown"), BookFormat::Unknown);
     }
     
+    #[test]
+    fn test_book_format_from_magic_bytes() {
+        use std::io::Write;
+
+        assert_eq!(BookFormat::from_magic_bytes(b"%PDF-1.7\n"), BookFormat::Pdf);
+        assert_eq!(BookFormat::from_magic_bytes(b"PK\x03\x04"), BookFormat::Unknown);
+        assert_eq!(BookFormat::from_magic_bytes(&[0x42; 64]), BookFormat::Unknown);
+        assert_eq!(BookFormat::from_magic_bytes(b""), BookFormat::Unknown);
+
+        let mut epub = Vec::new();
+        {
+            let stored = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored);
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut epub));
+            zip.start_file("mimetype", stored).unwrap();
+            zip.write_all(b"application/epub+zip").unwrap();
+            zip.finish().unwrap();
+        }
+        assert_eq!(BookFormat::from_magic_bytes(&epub), BookFormat::Epub);
+
+        // Extensionless book files are classified by content
+        let temp_dir = tempdir().unwrap();
+        let book_dir = temp_dir.path().join("pdf_book");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("pdf_book"), b"%PDF-1.4 plain content").unwrap();
+        fs::write(book_dir.join("pdf_book.dat"), b"fake dat content").unwrap();
+
+        let book = BookInfo::new(book_dir).unwrap();
+        assert_eq!(book.format, BookFormat::Pdf);
+        assert_eq!(book.book_filename, "pdf_book");
+    }
+    
     #[test]
     fn test_config_defaults() {
         let config = Config::default();
         assert_eq!(config.max_retries, 3);
         assert_eq!(config.timeout_seconds, 30);
-        assert!(!config.verbose);
+        assert_eq!(config.verbose, Verbosity::Normal);
         assert!(config.backup_originals);
         assert!(config.device_id.is_empty());
         assert!(config.user_idx.is_empty());
     }
     
     #[test]
-    fn test_library_finder_creation() {
+    fn test_parse_confidence() {
+        assert_eq!(parse_confidence("0.25"), Ok(0.25));
+        assert!(parse_confidence("1.5").is_err());
+        assert!(parse_confidence("high").is_err());
+    }
+
+    #[test]
+    fn test_extra_library_paths_are_searched() {
+        let temp_dir = tempdir().unwrap();
+        let external_root = temp_dir.path().join("external");
+        let book_dir = external_root.join("_123").join("1234567890");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("1234567890.epub"), b"fake epub content").unwrap();
+        fs::write(book_dir.join("1234567890.dat"), b"fake dat content").unwrap();
+
+        let config = Config {
+            user_idx: "123".to_string(),
+            extra_library_paths: vec![external_root.to_string_lossy().to_string()],
+            ..Default::default()
+        };
+
+        let books = LibraryFinder::new().find_books(&config).unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].id, "1234567890");
+        assert!(books[0].path.starts_with(&external_root));
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_library_is_reported() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("library");
+        let locked = root.join("_123");
+        let book_dir = locked.join("1234567890");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("1234567890.epub"), b"fake epub content").unwrap();
+        fs::write(book_dir.join("1234567890.dat"), b"fake dat content").unwrap();
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't apply to root, so there's nothing to test
+        if fs::read_dir(&locked).is_ok() {
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+
+        let config = Config {
+            user_idx: "123".to_string(),
+            library_path: Some(root.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let error = LibraryFinder::new().find_books(&config).unwrap_err().to_string();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert!(error.contains("couldn't be read (permission denied)"), "{}", error);
+        assert_eq!(error.matches(&*locked.to_string_lossy()).count(), 2, "{}", error);
+        assert!(!error.contains("Some locations couldn't be read:"), "{}", error);
+    }
+
+    #[test]
+    fn test_find_books_in_explicit_root() {
+        let temp_dir = tempdir().unwrap();
+        let write_book = |library: &std::path::Path, id: &str| {
+            let book_dir = library.join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            fs::write(book_dir.join(format!("{}.epub", id)), b"fake epub content").unwrap();
+            fs::write(book_dir.join(format!("{}.dat", id)), b"fake dat content").unwrap();
+        };
+
+        // Books directly in the root and in the account's `_{user_idx}` folder
+        let root = temp_dir.path().join("library");
+        write_book(&root, "direct_book");
+        write_book(&root.join("_123"), "user_book");
+        write_book(&root.join("_456"), "other_account_book");
+
+        let config = Config {
+            user_idx: "123".to_string(),
+            ..Default::default()
+        };
+        let mut ids: Vec<_> = LibraryFinder::new().find_books_in(&root, &config).unwrap()
+            .into_iter()
+            .map(|book| book.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, ["direct_book", "user_book"]);
+
+        // --library-path scans the same way
+        let config = Config {
+            library_path: Some(root.to_string_lossy().to_string()),
+            ..config
+        };
+        assert_eq!(LibraryFinder::new().find_books(&config).unwrap().len(), 2);
+
+        assert!(LibraryFinder::new().find_books_in(&temp_dir.path().join("missing"), &config).is_err());
+    }
+
+    #[test]
+    fn test_copied_library_tree() {
+        fn copy_tree(from: &std::path::Path, to: &std::path::Path) {
+            fs::create_dir_all(to).unwrap();
+            for entry in fs::read_dir(from).unwrap().flatten() {
+                if entry.path().is_dir() {
+                    copy_tree(&entry.path(), &to.join(entry.file_name()));
+                } else {
+                    fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+                }
+            }
+        }
+
+        // A library as RIDI lays it out, with metadata next to the account folder
+        let temp_dir = tempdir().unwrap();
+        let original = temp_dir.path().join("other-machine").join("Ridibooks").join("library");
+        write_v1_book(&original.join("_123").join("1234567890"), "1234567890.v1.pdf", b"%PDF-1.4 copied");
+        fs::create_dir_all(original.join("metadata")).unwrap();
+        fs::write(original.join("metadata").join("books.json"), r#"[{"id": "1234567890", "title": "Copied Title"}]"#).unwrap();
+
+        // Backed up somewhere no OS default points at, and the original gone
+        let copy = temp_dir.path().join("backup").join("ridi");
+        copy_tree(&original, &copy);
+        fs::remove_dir_all(temp_dir.path().join("other-machine")).unwrap();
+
+        let config = Config {
+            user_idx: "123".to_string(),
+            library_path: Some(copy.to_string_lossy().to_string()),
+            ..test_config(&temp_dir.path().join("output"))
+        };
+        let books = LibraryFinder::new().find_books(&config).unwrap();
+        assert_eq!(books.len(), 1);
+        assert!(books[0].path.starts_with(&copy));
+        assert_eq!(books[0].title.as_deref(), Some("Copied Title"));
+        assert_eq!(LibraryFinder::new().find_books_in(&copy, &config).unwrap().len(), 1);
+
+        let (_, result) = decrypt_test_book(&books[0], &config).unwrap();
+        assert_eq!(fs::read(result.output_path()).unwrap(), b"%PDF-1.4 copied");
+
+        // Nothing but the copy is searched, and the hint is about the copy
+        fs::remove_dir_all(copy.join("_123")).unwrap();
+        let error = LibraryFinder::new().find_books(&config).unwrap_err().to_string();
+        let checked: Vec<_> = error.lines()
+            .skip_while(|line| *line != "Checked paths:")
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .collect();
+        assert_eq!(checked, [copy.join("_123").display().to_string(), copy.display().to_string()]);
+        assert!(error.contains("or a copy of one"), "{}", error);
+        assert!(!error.contains("RIDI app is installed"), "{}", error);
+    }
+
+    #[test]
+    fn test_book_libraries_ranked_by_confidence() {
+        let temp_dir = tempdir().unwrap();
+        let write_book = |library: &std::path::Path, id: &str| {
+            let book_dir = library.join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            fs::write(book_dir.join(format!("{}.epub", id)), b"fake epub content").unwrap();
+            fs::write(book_dir.join(format!("{}.dat", id)), b"fake dat content").unwrap();
+        };
+
+        // A bare folder of books versus a real library root with RIDI metadata
+        let stale = temp_dir.path().join("stale");
+        write_book(&stale, "old_book");
+        let current = temp_dir.path().join("current");
+        write_book(&current, "new_book");
+        fs::create_dir_all(current.join("metadata")).unwrap();
+
+        let config = Config {
+            extra_library_paths: vec![
+                stale.to_string_lossy().to_string(),
+                current.to_string_lossy().to_string(),
+            ],
+            ..Default::default()
+        };
+
         let finder = LibraryFinder::new();
-        assert!(!finder.common_paths.is_empty());
+        let libraries = finder.find_book_libraries(&config).unwrap();
+        assert_eq!(libraries.len(), 2);
+        assert_eq!(libraries[0].0.path, current);
+        assert!(libraries[0].0.confidence > libraries[1].0.confidence);
+        assert_eq!(libraries[0].0.confidence_percent(), 70);
+
+        let books = finder.find_books(&config).unwrap();
+        assert_eq!(books[0].id, "new_book");
     }
     
+    #[test]
+    fn test_duplicate_books_across_libraries() {
+        let temp_dir = tempdir().unwrap();
+        let write_book = |library: &std::path::Path, id: &str| {
+            let book_dir = library.join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            fs::write(book_dir.join(format!("{}.epub", id)), b"fake epub content").unwrap();
+            fs::write(book_dir.join(format!("{}.dat", id)), b"fake dat content").unwrap();
+        };
+
+        // The same book in a bare folder and in a real library root with RIDI metadata
+        let stale = temp_dir.path().join("stale");
+        write_book(&stale, "1234567890");
+        write_book(&stale, "only_in_stale");
+        let current = temp_dir.path().join("current");
+        write_book(&current, "1234567890");
+        fs::create_dir_all(current.join("metadata")).unwrap();
+
+        let config = Config {
+            extra_library_paths: vec![
+                stale.to_string_lossy().to_string(),
+                current.to_string_lossy().to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let books = LibraryFinder::new().find_books(&config).unwrap();
+        let duplicates: Vec<_> = books.iter().filter(|book| book.id == "1234567890").collect();
+        assert_eq!(duplicates.len(), 1);
+        assert!(duplicates[0].path.starts_with(&current));
+        assert!(books.iter().any(|book| book.id == "only_in_stale"));
+        assert_eq!(books.len(), 2);
+
+        // --fast-scan stops at the first library with books, in search order
+        let fast = Config { fast_scan: true, ..config };
+        let libraries = LibraryFinder::new().find_book_libraries(&fast).unwrap();
+        assert_eq!(libraries.len(), 1);
+        assert_eq!(libraries[0].0.path, stale);
+    }
+
+    #[test]
+    fn test_count_books_matches_find_books() {
+        let temp_dir = tempdir().unwrap();
+        let write_book = |library: &std::path::Path, id: &str| {
+            let book_dir = library.join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            fs::write(book_dir.join(format!("{}.epub", id)), b"fake epub content").unwrap();
+            fs::write(book_dir.join(format!("{}.dat", id)), b"fake dat content").unwrap();
+        };
+
+        let root = temp_dir.path().join("library");
+        write_book(&root, "direct_book");
+        write_book(&root.join("_123"), "user_book");
+        write_book(&root.join("_123"), "1234567890");
+        // Not books: no book file yet, and a stray file
+        fs::create_dir_all(root.join("_123").join("downloading")).unwrap();
+        fs::write(root.join("_123").join("downloading").join("downloading.dat"), b"fake dat content").unwrap();
+        fs::write(root.join("_123").join("notes.txt"), b"not a book").unwrap();
+
+        let config = Config {
+            user_idx: "123".to_string(),
+            library_path: Some(root.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let finder = LibraryFinder::new();
+        assert_eq!(finder.count_books(&config).unwrap(), 3);
+        assert_eq!(finder.count_books(&config).unwrap(), finder.find_books(&config).unwrap().len());
+
+        // A book in two libraries is counted once, as find_books returns it once
+        let other = temp_dir.path().join("other");
+        write_book(&other, "1234567890");
+        write_book(&other, "only_in_other");
+        let config = Config {
+            library_path: None,
+            extra_library_paths: vec![
+                root.join("_123").to_string_lossy().to_string(),
+                other.to_string_lossy().to_string(),
+            ],
+            ..config
+        };
+        assert_eq!(finder.count_books(&config).unwrap(), finder.find_books(&config).unwrap().len());
+        let fast = Config { fast_scan: true, ..config };
+        assert_eq!(finder.count_books(&fast).unwrap(), finder.find_books(&fast).unwrap().len());
+
+        let missing = Config {
+            library_path: Some(temp_dir.path().join("missing").to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let error = finder.count_books(&missing).unwrap_err();
+        assert!(error.to_string().contains("No books found"), "{}", error);
+        assert!(finder.find_books(&missing).is_err());
+    }
+
+    #[test]
+    fn test_sample_books_are_skipped() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("library");
+        let write_book = |id: &str, book_filename: &str| {
+            let book_dir = root.join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            fs::write(book_dir.join(book_filename), b"fake epub content").unwrap();
+            fs::write(book_dir.join(format!("{}.dat", id)), b"fake dat content").unwrap();
+        };
+        write_book("1111111111", "1111111111.v11.epub");
+        write_book("2222222222", "2222222222.sample.v11.epub");
+        write_book("3333333333", "3333333333_preview.epub");
+        // Flagged by the RIDI metadata store instead of by name
+        write_book("4444444444", "4444444444.epub");
+        // "sample" in the id alone doesn't make one
+        write_book("sample", "sample.epub");
+        fs::create_dir_all(root.join("metadata")).unwrap();
+        fs::write(root.join("metadata").join("library.json"), r#"[
+            {"id": "1111111111", "title": "Bought"},
+            {"id": "4444444444", "title": "Free preview", "is_sample": true}
+        ]"#).unwrap();
+
+        let config = Config {
+            library_path: Some(root.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let books = LibraryFinder::new().find_books(&config).unwrap();
+        let mut samples: Vec<_> = books.iter().filter(|book| book.is_sample).map(|book| book.id.as_str()).collect();
+        samples.sort();
+        assert_eq!(samples, ["2222222222", "3333333333", "4444444444"]);
+
+        let (kept, skipped) = skip_samples(books.clone(), &config);
+        assert_eq!(skipped, 3);
+        assert!(kept.iter().all(|book| !book.is_sample));
+        assert_eq!(kept.len(), 2);
+
+        // --include-samples keeps them all
+        let config = Config { include_samples: true, ..config };
+        let (kept, skipped) = skip_samples(books, &config);
+        assert_eq!((kept.len(), skipped), (5, 0));
+
+        let args = Args::try_parse_from(["ridiculous", "decrypt", "--include-samples"]).unwrap();
+        assert!(matches!(args.selected_command(), Command::Decrypt(DecryptArgs { include_samples: true, .. })));
+    }
+
     #[tokio::test]
     async fn test_credential_validation_format() {
         let cred_manager = CredentialManager::new();
         
         // Test with invalid device ID format
-        let result = cred_manager.validate("invalid", "123").await;
+        let result = cred_manager.validate("invalid", "123", false).await;
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("Invalid device ID format"));
         
         // Test with empty user index
-        let result = cred_manager.validate("12345678-1234-1234-1234-123456789012", "").await;
+        let result = cred_manager.validate("12345678-1234-1234-1234-123456789012", "", false).await;
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("User index cannot be empty"));
     }
     
+    #[test]
+    fn test_credential_format_checks() {
+        let valid_id = "a1b2c3d4-e5f6-7890-abcd-ef1234567890";
+        assert!(CredentialManager::validate_format(valid_id, "1234567").is_ok());
+        assert!(CredentialManager::validate_format(&valid_id.to_uppercase(), "1").is_ok());
+
+        // Right length, wrong shape; other UUID spellings aren't accepted either
+        for device_id in [
+            "a1b2c3d4e5f6-7890-abcd-ef1234567890-",
+            "a1b2c3d4-e5f6-7890-abcd-ef123456789g",
+            "a1b2c3d4_e5f6_7890_abcd_ef1234567890",
+            "this-is-not-a-uuid-but-it-is-36-long",
+            "a1b2c3d4e5f67890abcdef1234567890",
+            "{a1b2c3d4-e5f6-7890-abcd-ef1234567890}",
+        ] {
+            let error = CredentialManager::validate_format(device_id, "123").unwrap_err();
+            assert!(error.to_string().contains("device_id must be a UUID like"), "{}", device_id);
+        }
+
+        // Whitespace and quotes picked up when pasting are dropped
+        assert_eq!(CredentialManager::clean_credential(&format!("  \"{}\"\n", valid_id)), valid_id);
+        assert_eq!(CredentialManager::clean_credential(" '1234567' "), "1234567");
+
+        for user_idx in ["abc", "0", "-5", "12.5", " 123"] {
+            let error = CredentialManager::validate_format(valid_id, user_idx).unwrap_err();
+            assert!(error.to_string().contains("positive number"), "{}", user_idx);
+        }
+    }
+    
     #[test]
     fn test_book_info_paths() {
         let temp_dir = tempdir().unwrap();
@@ -59,22 +471,1452 @@ mod tests {
         assert_eq!(book.format, BookFormat::Epub);
         assert!(book.get_data_file_path().ends_with("test_book.dat"));
         assert!(book.get_book_file_path().ends_with("test_book.epub"));
+
+        // Neither the extension nor the content says what this is, so it isn't guessed
+        let unknown_dir = temp_dir.path().join("unknown_book");
+        fs::create_dir_all(&unknown_dir).unwrap();
+        fs::write(unknown_dir.join("unknown_book.v1.mobi"), [0x42; 64]).unwrap();
+        fs::write(unknown_dir.join("unknown_book.dat"), b"fake dat content").unwrap();
+
+        let unknown = BookInfo::new(unknown_dir).unwrap();
+        assert_eq!(unknown.format, BookFormat::Unknown);
+        assert_eq!(unknown.book_filename, "unknown_book.v1.mobi");
+
+        // It's skipped, unless --assume-format says what it is
+        let books = skip_unrecognized(vec![book.clone(), unknown.clone()], true);
+        assert_eq!(books.iter().map(|book| book.id.as_str()).collect::<Vec<_>>(), ["test_book"]);
+
+        let mut books = vec![book, unknown];
+        assume_formats(&mut books, Some(&BookFormat::Pdf), &[]);
+        assert_eq!(skip_unrecognized(books, true).len(), 2);
+    }
+    
+    #[test]
+    fn test_already_decrypted_in_output_directory() {
+        let temp_dir = tempdir().unwrap();
+        let book_dir = temp_dir.path().join("library").join("test_book");
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::create_dir_all(&output_dir).unwrap();
+
+        fs::write(book_dir.join("test_book.v11.epub"), b"fake encrypted content").unwrap();
+        fs::write(book_dir.join("test_book.dat"), b"fake dat content").unwrap();
+
+        let book = BookInfo::new(book_dir).unwrap();
+        let config = Config {
+            output_directory: Some(output_dir.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(should_skip(&book, &config), SkipReason::None);
+
+        fs::write(output_dir.join(book.get_output_filename()), b"fake decrypted content").unwrap();
+        assert_eq!(should_skip(&book, &config), SkipReason::AlreadyDecryptedOutput);
+    }
+    
+    #[tokio::test]
+    async fn test_plaintext_book_is_copied_unchanged() {
+        use indicatif::ProgressBar;
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+        let book_dir = temp_dir.path().join("library").join("1234567890");
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("1234567890.dat"), b"fake dat content").unwrap();
+
+        let mut epub = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut epub));
+            zip.start_file("mimetype", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"application/epub+zip").unwrap();
+            zip.start_file("OEBPS/chapter1.xhtml", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"<html><body>No DRM here</body></html>").unwrap();
+            zip.finish().unwrap();
+        }
+        fs::write(book_dir.join("1234567890.epub"), &epub).unwrap();
+
+        let mut book = BookInfo::new(book_dir).unwrap();
+        let config = test_config(&output_dir);
+
+        let result = process_single_book(&mut book, &config, &ProgressBar::hidden(), false).await.unwrap();
+        let output_path = get_output_path(&book, &config).unwrap();
+        assert_eq!(result, BookResult::CopiedPlaintext(output_path.clone()));
+        assert_eq!(fs::read(&output_path).unwrap(), epub);
+
+        let mut state = ProcessingState::default();
+        state.record(&book, &Ok(result), Duration::from_millis(5));
+        assert!(state.is_completed("1234567890"));
+        assert_eq!(state.outcomes[0].status, "copied");
+
+        // Copied once; the next run finds it in the output directory
+        assert_eq!(should_skip(&book, &config), SkipReason::AlreadyDecryptedOutput);
+    }
+    
+    #[test]
+    fn test_manifest_migration_and_skips() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let manifest_path = temp_dir.path().join("ridiculous_state.manifest.json");
+        let config = Config { organize_output: true, ..test_config(&output_dir) };
+
+        let organized = write_v1_book(&temp_dir.path().join("1111111111"), "1111111111.v1.pdf", b"%PDF-1.4 one");
+        let legacy = write_v1_book(&temp_dir.path().join("2222222222"), "2222222222.v1.pdf", b"%PDF-1.4 two");
+        let fresh = write_v1_book(&temp_dir.path().join("3333333333"), "3333333333.v1.pdf", b"%PDF-1.4 three");
+
+        // Decrypted by an older version: one is only in the state under its --organize path,
+        // the other only in the output directory under the default name
+        let (_, result) = decrypt_test_book(&organized, &config).unwrap();
+        assert_eq!(should_skip(&organized, &config), SkipReason::None);
+        let mut state = ProcessingState::default();
+        state.record(&organized, &Ok(result), Duration::from_millis(5));
+        fs::write(output_dir.join(legacy.get_output_filename()), b"%PDF-1.4 two").unwrap();
+
+        // A dry run migrates in memory only
+        let books = [organized.clone(), legacy.clone(), fresh.clone()];
+        let manifest = load_manifest(&manifest_path, &ProcessingState::default(), &books, &config, true).unwrap();
+        assert!(!manifest_path.exists());
+        assert!(!manifest.is_decrypted(&organized, &config));
+
+        let manifest = load_manifest(&manifest_path, &state, &books, &config, false).unwrap();
+        assert!(manifest_path.exists());
+        assert!(manifest.is_decrypted(&organized, &config));
+        assert!(manifest.is_decrypted(&legacy, &config));
+        assert!(!manifest.is_decrypted(&fresh, &config));
+
+        // scan and stats go by the manifest too, so they find the --organize output
+        assert!(is_already_decrypted(&organized, &manifest, &config));
+        assert!(BookListing::new(&organized, &manifest, &config).already_decrypted);
+        assert_eq!(LibraryStats::new(&books, &manifest, &config).already_decrypted, 2);
+
+        // Books from every library are migrated, not only those the run was given
+        let all_libraries = Config { library_path: Some(temp_dir.path().to_string_lossy().to_string()), ..config.clone() };
+        let manifest = load_manifest(&temp_dir.path().join("other.manifest.json"), &state, &[], &all_libraries, true).unwrap();
+        assert!(manifest.is_decrypted(&organized, &config));
+        assert_eq!(manifest.entries().count(), 2);
+
+        // Once there's a manifest, it alone decides
+        let mut manifest = load_manifest(&manifest_path, &ProcessingState::default(), &[], &config, false).unwrap();
+        assert!(manifest.is_decrypted(&organized, &config));
+        let mut state = ProcessingState::default();
+        let (_, result) = decrypt_test_book(&fresh, &config).unwrap();
+        state.record(&fresh, &Ok(result), Duration::from_millis(5));
+        state.record(&legacy, &Err(anyhow::anyhow!("failed this time")), Duration::from_millis(5));
+        record_in_manifest(&mut manifest, &state);
+        assert!(manifest.is_decrypted(&fresh, &config));
+        assert!(manifest.is_decrypted(&legacy, &config));
+    }
+
+    #[test]
+    fn test_library_diff() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = Config { organize_output: true, ..test_config(&output_dir) };
+
+        let organized = write_v1_book(&temp_dir.path().join("1111111111"), "1111111111.v1.pdf", b"%PDF-1.4 one");
+        let default_name = write_v1_book(&temp_dir.path().join("2222222222"), "2222222222.v1.pdf", b"%PDF-1.4 two");
+        let mut missing = write_v1_book(&temp_dir.path().join("3333333333"), "3333333333.v1.pdf", b"%PDF-1.4 three");
+        missing.title = Some("Missing Title".to_string());
+
+        // One book decrypted under --organize and recorded in the manifest, one only
+        // under its default name, and the manifest entry of a book that has left the library
+        let (_, result) = decrypt_test_book(&organized, &config).unwrap();
+        let mut manifest = Manifest::default();
+        manifest.record(&organized.id, result.output_path().to_path_buf(), manifest::source_hash(&organized).unwrap());
+        fs::write(output_dir.join(default_name.get_output_filename()), b"%PDF-1.4 two").unwrap();
+        let gone = output_dir.join("Some Author").join("Gone.epub");
+        fs::create_dir_all(gone.parent().unwrap()).unwrap();
+        fs::write(&gone, b"epub").unwrap();
+        manifest.record("9999999999", gone, "hash".to_string());
+
+        // Unknown outputs count as orphans too, but other files don't
+        let flattened = output_dir.join("Flattened");
+        fs::create_dir_all(flattened.join("OEBPS")).unwrap();
+        fs::write(flattened.join("mimetype"), b"application/epub+zip").unwrap();
+        fs::write(flattened.join("OEBPS").join("chapter.pdf"), b"not a book of its own").unwrap();
+        fs::write(output_dir.join("run-report.json"), b"{}").unwrap();
+
+        let books = [organized, default_name, missing];
+        let diff = LibraryDiff::new(&books, &manifest, &output_dir, &config);
+        assert_eq!(diff.decrypted, 2);
+        assert_eq!(diff.not_decrypted, ["Missing Title (3333333333)"]);
+        let gone = Path::new("Some Author").join("Gone.epub").display().to_string();
+        assert_eq!(diff.orphaned, ["Flattened".to_string(), format!("{} (9999999999)", gone)]);
+
+        let rows = two_columns(["Left", "Right side"], &["a much longer entry".to_string()], &["x".into(), "y".into()]);
+        assert_eq!(rows, [
+            "Left                │ Right side",
+            "─────────────────── │ ──────────",
+            "a much longer entry │ x",
+            "                    │ y",
+        ]);
+        let rows = two_columns(["Left", "Right"], &["a".repeat(60)], &[]);
+        assert_eq!(rows[2], format!("{}… │", "a".repeat(47)));
+    }
+
+    #[test]
+    fn test_repair_state() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = test_config(&output_dir);
+
+        let deleted = write_v1_book(&temp_dir.path().join("1111111111"), "1111111111.v1.pdf", b"%PDF-1.4 one");
+        let intact = write_v1_book(&temp_dir.path().join("2222222222"), "2222222222.v1.pdf", b"%PDF-1.4 two");
+        let out_of_band = write_v1_book(&temp_dir.path().join("3333333333"), "3333333333.v1.pdf", b"%PDF-1.4 three");
+        let never = write_v1_book(&temp_dir.path().join("4444444444"), "4444444444.v1.pdf", b"%PDF-1.4 four");
+
+        // Two books decrypted and recorded, one of which has since been deleted, and one
+        // decrypted by an earlier version without being recorded, after failing once
+        let mut state = ProcessingState::default();
+        let mut manifest = Manifest::default();
+        for book in [&deleted, &intact] {
+            let (_, result) = decrypt_test_book(book, &config).unwrap();
+            let output_path = result.output_path().to_path_buf();
+            manifest.record(&book.id, output_path.clone(), manifest::source_hash(book).unwrap());
+            state.completed.push(CompletedBook {
+                id: book.id.clone(),
+                output_path: Some(output_path),
+                duration_ms: Some(10),
+                format: Some("pdf".to_string()),
+                sha256: None,
+            });
+        }
+        fs::remove_file(manifest.output_path(&deleted.id).unwrap()).unwrap();
+        decrypt_test_book(&out_of_band, &config).unwrap();
+        state.failed.push((out_of_band.id.clone(), "interrupted".to_string()));
+
+        let books = [deleted.clone(), intact.clone(), out_of_band.clone(), never.clone()];
+        let repair = repair_state(&mut state, &mut manifest, &books, &config);
+        assert_eq!(repair, StateRepair { removed: vec![deleted.id.clone()], added: vec![out_of_band.id.clone()] });
+        assert!(!state.is_completed(&deleted.id) && !state.is_completed(&never.id));
+        assert!(state.is_completed(&intact.id) && state.is_completed(&out_of_band.id));
+        assert!(state.failed.is_empty());
+        assert_eq!(manifest.output_path(&deleted.id), None);
+        assert!(manifest.is_decrypted(&out_of_band, &config));
+
+        // A second pass has nothing left to do
+        assert_eq!(repair_state(&mut state, &mut manifest, &books, &config), StateRepair::default());
+    }
+
+    #[test]
+    fn test_compressed_outputs_round_trip() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let plaintext = b"%PDF-1.4 a long enough book to compress, a long enough book to compress".repeat(50);
+        let book = write_v1_book(&temp_dir.path().join("1234567890"), "1234567890.v1.pdf", &plaintext);
+
+        for (compress, extension) in [(Compress::Gzip, "gz"), (Compress::Zstd, "zst")] {
+            let config = Config { compress, ..test_config(&output_dir) };
+            let (_, result) = decrypt_test_book(&book, &config).unwrap();
+            let output_path = result.output_path().to_path_buf();
+            assert_eq!(output_path, output_dir.join(format!("1234567890_decrypted.pdf.{}", extension)));
+            assert!(fs::metadata(&output_path).unwrap().len() < plaintext.len() as u64);
+
+            let mut decompressed = Vec::new();
+            open_output(&output_path).unwrap().read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, plaintext);
+
+            // Recognized as done, with --compress or without, and intact
+            assert_eq!(existing_output(&book, &config), Some(output_path.clone()));
+            assert_eq!(existing_output(&book, &test_config(&output_dir)), Some(output_path.clone()));
+            let completed = CompletedBook {
+                id: book.id.clone(),
+                output_path: Some(output_path.clone()),
+                duration_ms: None,
+                format: Some("pdf".to_string()),
+                sha256: None,
+            };
+            assert_eq!(check_completed_book(&completed), Some(Ok(())));
+            let mut outputs = Vec::new();
+            collect_outputs(&output_dir, &mut outputs);
+            assert_eq!(outputs, std::slice::from_ref(&output_path));
+
+            // A truncated one isn't
+            let compressed = fs::read(&output_path).unwrap();
+            fs::write(&output_path, &compressed[..compressed.len() / 2]).unwrap();
+            assert!(matches!(check_completed_book(&completed), Some(Err(_))));
+            fs::remove_file(&output_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_plaintext_container_detection() {
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+        let book_dir = temp_dir.path().join("library").join("test_book");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("test_book.dat"), b"fake dat content").unwrap();
+
+        let mut zip = zip::ZipWriter::new(fs::File::create(book_dir.join("test_book.epub")).unwrap());
+        zip.start_file("mimetype", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(b"application/epub+zip").unwrap();
+        zip.finish().unwrap();
+
+        let book = BookInfo::new(book_dir.clone()).unwrap();
+        assert_eq!(should_skip(&book, &Config::default()), SkipReason::PlaintextContainer);
+
+        // The same ZIP marked as a v11 book is an encrypted container, not plaintext
+        fs::rename(book_dir.join("test_book.epub"), book_dir.join("test_book.v11.epub")).unwrap();
+        let book = BookInfo::new(book_dir).unwrap();
+        assert_eq!(should_skip(&book, &Config::default()), SkipReason::None);
+    }
+    
+    #[test]
+    fn test_output_path_uses_output_directory() {
+        let temp_dir = tempdir().unwrap();
+        let book_dir = temp_dir.path().join("library").join("test_book");
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir_all(&book_dir).unwrap();
+
+        fs::write(book_dir.join("test_book.epub"), b"fake epub content").unwrap();
+        fs::write(book_dir.join("test_book.dat"), b"fake dat content").unwrap();
+
+        let book = BookInfo::new(book_dir).unwrap();
+        let config = Config {
+            output_directory: Some(output_dir.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let output_path = get_output_path(&book, &config).unwrap();
+        assert!(output_path.starts_with(&output_dir));
+        assert!(!output_path.starts_with(std::env::current_dir().unwrap()));
+        assert!(output_path.ends_with("test_book_decrypted.epub"));
+    }
+    
+    #[test]
+    fn test_drm_version_detection() {
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+
+        // v1: a raw AES-CBC blob
+        let v1_dir = temp_dir.path().join("v1_book");
+        fs::create_dir_all(&v1_dir).unwrap();
+        fs::write(v1_dir.join("v1_book.epub"), vec![0x42u8; 64]).unwrap();
+        fs::write(v1_dir.join("v1_book.dat"), b"fake dat content").unwrap();
+
+        let v1_book = BookInfo::new(v1_dir).unwrap();
+        assert_eq!(v1_book.detect_drm_version(), Some(DrmVersion::V1));
+        assert_eq!(v1_book.drm_version, DrmVersion::V1);
+
+        // v11: a ZIP container with per-entry encryption
+        let v11_dir = temp_dir.path().join("v11_book");
+        fs::create_dir_all(&v11_dir).unwrap();
+        let mut zip = zip::ZipWriter::new(fs::File::create(v11_dir.join("v11_book.v11.epub")).unwrap());
+        zip.start_file("OEBPS/chapter1.xhtml", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(&[0x42u8; 32]).unwrap();
+        zip.finish().unwrap();
+        fs::write(v11_dir.join("v11_book.dat"), b"fake dat content").unwrap();
+
+        let v11_book = BookInfo::new(v11_dir).unwrap();
+        assert_eq!(v11_book.detect_drm_version(), Some(DrmVersion::V11));
+        assert_eq!(v11_book.drm_version, DrmVersion::V11);
+
+        // A version marker this build doesn't know is kept, whatever the content looks like
+        let v12_dir = temp_dir.path().join("v12_book");
+        fs::create_dir_all(&v12_dir).unwrap();
+        fs::write(v12_dir.join("v12_book.v12.epub"), vec![0x42u8; 64]).unwrap();
+        fs::write(v12_dir.join("v12_book.dat"), b"fake dat content").unwrap();
+
+        let v12_book = BookInfo::new(v12_dir).unwrap();
+        assert_eq!(v12_book.drm_version, DrmVersion::Unknown(12));
+        assert_eq!(v12_book.drm_version.to_string(), "v12");
+
+        assert_eq!(DrmVersion::from_filename("1234567890.v1.pdf"), Some(DrmVersion::V1));
+        assert_eq!(DrmVersion::from_filename("1234567890.v11.epub"), Some(DrmVersion::V11));
+        assert_eq!(DrmVersion::from_filename("1234567890.epub"), None);
+    }
+
+    #[test]
+    fn test_decrypt_for_version() {
+        let v1 = encrypt(b"%PDF-1.4 content", TEST_BOOK_KEY);
+        assert_eq!(decrypt_for_version(DrmVersion::V1, &v1, TEST_BOOK_KEY).unwrap(), b"%PDF-1.4 content");
+
+        // Unknown versions are refused instead of being tried as v1
+        let error = decrypt_for_version(DrmVersion::Unknown(12), &v1, TEST_BOOK_KEY).unwrap_err();
+        assert!(matches!(error, DecryptError::UnsupportedDrmVersion { version: 12 }));
+        assert!(error.to_string().contains("Unsupported DRM version: v12"));
+
+        let temp_dir = tempdir().unwrap();
+        let book_dir = temp_dir.path().join("v12_book");
+        let mut book = write_v1_book(&book_dir, "v12_book.v12.epub", b"content");
+        assert_eq!(book.drm_version, DrmVersion::Unknown(12));
+        let output = temp_dir.path().join("out.epub");
+        let error = decrypt_book_to_file(&book, TEST_BOOK_KEY, &output, Recompress::Keep, &NoProgress).unwrap_err();
+        assert!(matches!(error, DecryptError::UnsupportedDrmVersion { version: 12 }));
+        assert!(!output.exists());
+
+        book.drm_version = DrmVersion::V1;
+        decrypt_book_to_file(&book, TEST_BOOK_KEY, &output, Recompress::Keep, &NoProgress).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"content");
+    }
+    
+    #[test]
+    fn test_pdf_decryption_end_to_end() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let pdf = b"%PDF-1.4\n1 0 obj << /Type /Catalog >> endobj\n%%EOF\n";
+
+        let book = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", pdf);
+        assert_eq!(book.format, BookFormat::Pdf);
+        assert_eq!(book.drm_version, DrmVersion::V1);
+
+        let config = test_config(&output_dir);
+        decrypt_test_book(&book, &config).unwrap();
+
+        let output = fs::read(output_dir.join("pdf_book_decrypted.pdf")).unwrap();
+        assert!(output.starts_with(b"%PDF-"));
+        assert_eq!(output, pdf);
+
+        // Output that isn't a PDF is reported and not left behind
+        let bogus = write_v1_book(&temp_dir.path().join("bogus_book"), "bogus_book.v1.pdf", b"not a pdf at all");
+        let error = decrypt_test_book(&bogus, &config).unwrap_err();
+        assert!(error.to_string().contains("not a valid PDF"));
+        assert!(!output_dir.join("bogus_book_decrypted.pdf").exists());
+        assert!(!output_dir.join("bogus_book_decrypted.pdf.part").exists());
+    }
+
+    #[test]
+    fn test_zipped_pdf_decrypts_to_standalone_pdf() {
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let book_dir = temp_dir.path().join("zipped_pdf");
+        let pdf = b"%PDF-1.4\n1 0 obj << /Type /Catalog >> endobj\n%%EOF\n";
+
+        decrypt::write_fixture_dat(&book_dir, TEST_DEVICE_ID).unwrap();
+        let mut zip = zip::ZipWriter::new(fs::File::create(book_dir.join("zipped_pdf.v11.pdf")).unwrap());
+        zip.start_file("zipped_pdf.pdf", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(&encrypt(pdf, TEST_BOOK_KEY)).unwrap();
+        zip.start_file("fonts/font.ttf", zip::write::FileOptions::default()).unwrap();
+        zip.write_all(&encrypt(b"font data", TEST_BOOK_KEY)).unwrap();
+        zip.finish().unwrap();
+
+        let book = BookInfo::new(book_dir).unwrap();
+        assert_eq!(book.format, BookFormat::Pdf);
+        assert_eq!(book.drm_version, DrmVersion::V11);
+        assert!(!is_plaintext_container(&book));
+
+        decrypt_test_book(&book, &test_config(&output_dir)).unwrap();
+        assert_eq!(fs::read(output_dir.join("zipped_pdf_decrypted.pdf")).unwrap(), pdf);
+    }
+
+    #[test]
+    fn test_incomplete_download_detection() {
+        let temp_dir = tempdir().unwrap();
+        let config = test_config(&temp_dir.path().join("output"));
+        let pdf = b"%PDF-1.4\n1 0 obj << /Type /Catalog >> endobj\n%%EOF\n";
+
+        let book = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", pdf);
+        assert_eq!(book.incomplete_download(), None);
+
+        // A download cut off mid-block
+        let book_path = book.get_book_file_path();
+        let full = fs::read(&book_path).unwrap();
+        fs::write(&book_path, &full[..full.len() - 5]).unwrap();
+        let error = decrypt_test_book(&book, &config).unwrap_err();
+        assert!(matches!(error.downcast_ref::<DecryptError>(), Some(DecryptError::IncompleteDownload { .. })));
+        assert!(is_incomplete_download(&error));
+        assert!(!is_incomplete_download(&anyhow::anyhow!("📥 Incomplete download, says the text")));
+        assert!(error.to_string().contains("Open RIDI to finish downloading"));
+        let mut state = ProcessingState::default();
+        state.record(&book, &Err(error), std::time::Duration::ZERO);
+        assert!(state.is_incomplete_download(&book.id));
+
+        // A whole file with RIDI's marker still next to it
+        fs::write(&book_path, &full).unwrap();
+        fs::write(book.path.join("pdf_book.v1.pdf.download"), b"").unwrap();
+        assert!(book.incomplete_download().unwrap().contains("pdf_book.v1.pdf.download"));
+
+        // A v11 book missing the end of its ZIP
+        let v11_dir = temp_dir.path().join("v11_book");
+        fs::create_dir_all(&v11_dir).unwrap();
+        fs::write(v11_dir.join("v11_book.v11.epub"), b"PK\x03\x04 only the first entry").unwrap();
+        let v11 = BookInfo::new(v11_dir).unwrap();
+        assert!(v11.incomplete_download().unwrap().contains("ZIP directory"));
+
+        // Empty files are never complete
+        fs::write(v11.get_book_file_path(), b"").unwrap();
+        assert_eq!(v11.incomplete_download().as_deref(), Some("the book file is empty"));
+    }
+
+    #[test]
+    fn test_on_existing_policies() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        fs::create_dir_all(&output_dir).unwrap();
+        let book = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", b"%PDF-1.4 new");
+        let output_path = output_dir.join("pdf_book_decrypted.pdf");
+        fs::write(&output_path, b"%PDF-1.4 old").unwrap();
+
+        let decrypt_with = |on_existing| {
+            let config = Config { on_existing, ..test_config(&output_dir) };
+            decrypt_test_book(&book, &config).unwrap().1
+        };
+
+        assert_eq!(decrypt_with(OnExisting::Skip), BookResult::KeptExisting(output_path.clone()));
+        assert_eq!(fs::read(&output_path).unwrap(), b"%PDF-1.4 old");
+
+        // Renamed copies take the first free number
+        let first_copy = output_dir.join("pdf_book_decrypted (1).pdf");
+        assert_eq!(decrypt_with(OnExisting::Rename), BookResult::Decrypted(first_copy.clone()));
+        assert_eq!(fs::read(&first_copy).unwrap(), b"%PDF-1.4 new");
+        assert_eq!(
+            decrypt_with(OnExisting::Rename),
+            BookResult::Decrypted(output_dir.join("pdf_book_decrypted (2).pdf"))
+        );
+        fs::remove_file(&first_copy).unwrap();
+        assert_eq!(unique_output_path(&output_path), first_copy);
+        assert_eq!(unique_output_path(&output_dir.join("no_extension")), output_dir.join("no_extension (1)"));
+        assert_eq!(
+            unique_output_path(&output_dir.join("pdf_book_decrypted.pdf.zst")),
+            output_dir.join("pdf_book_decrypted (1).pdf.zst")
+        );
+
+        assert_eq!(decrypt_with(OnExisting::Overwrite), BookResult::Decrypted(output_path.clone()));
+        assert_eq!(fs::read(&output_path).unwrap(), b"%PDF-1.4 new");
+    }
+
+    #[test]
+    fn test_book_choice_label() {
+        let temp_dir = tempdir().unwrap();
+        let mut book = write_v1_book(&temp_dir.path().join("1111111111"), "1111111111.v1.pdf", &[0; 2000]);
+        assert_eq!(book_choice_label(&book), "1111111111 - PDF, 2.0 KB");
+
+        book.title = Some("Re:Zero Vol. 1".to_string());
+        assert_eq!(book_choice_label(&book), "Re:Zero Vol. 1 (1111111111) - PDF, 2.0 KB");
+    }
+
+    #[test]
+    fn test_self_test_checks_pass() {
+        let temp_dir = tempdir().unwrap();
+        for (check, result) in self_test_checks(temp_dir.path()) {
+            assert!(result.is_ok(), "{}: {:?}", check, result);
+        }
+
+        // A book that decrypts to something else is caught
+        let book_dir = temp_dir.path().join("fixture");
+        let plaintext = decrypt::write_fixture_book(&book_dir, DrmVersion::V11).unwrap();
+        let book = BookInfo::new(book_dir).unwrap();
+        let decrypted = decrypt::decrypt_book(&book, &Config { device_id: TEST_DEVICE_ID.to_string(), ..Default::default() }).unwrap();
+        assert!(decrypt::fixture_matches(&plaintext, &decrypted));
+        assert!(!decrypt::fixture_matches(&plaintext, &plaintext[..plaintext.len() / 2]));
+        assert!(!decrypt::fixture_matches(b"%PDF-1.4 one", b"%PDF-1.4 two"));
+    }
+
+    #[test]
+    fn test_flatten_extracts_zip_books() {
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = Config { flatten: true, on_existing: OnExisting::Rename, ..test_config(&output_dir) };
+
+        let mut epub = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut epub));
+            zip.start_file("mimetype", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"application/epub+zip").unwrap();
+            zip.add_directory("OEBPS/images/", zip::write::FileOptions::default()).unwrap();
+            zip.start_file("OEBPS/Text/chapter1.xhtml", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"<html><body>Hello, RIDI</body></html>").unwrap();
+            zip.start_file("OEBPS/content.opf", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"<package><metadata><dc:title>Flat</dc:title></metadata></package>").unwrap();
+            zip.finish().unwrap();
+        }
+        let book = write_v1_book(&temp_dir.path().join("1234567890"), "1234567890.v1.epub", &epub);
+
+        let flat_dir = output_dir.join("1234567890_decrypted");
+        assert_eq!(decrypt_test_book(&book, &config).unwrap().1, BookResult::Decrypted(flat_dir.clone()));
+
+        let mut tree: Vec<_> = walkdir::WalkDir::new(&flat_dir)
+            .into_iter()
+            .map(|entry| entry.unwrap().path().strip_prefix(&flat_dir).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect();
+        tree.sort();
+        assert_eq!(tree, [
+            "",
+            "OEBPS",
+            "OEBPS/Text",
+            "OEBPS/Text/chapter1.xhtml",
+            "OEBPS/content.opf",
+            "OEBPS/images",
+            "mimetype",
+        ]);
+        assert_eq!(fs::read(flat_dir.join("OEBPS/Text/chapter1.xhtml")).unwrap(), b"<html><body>Hello, RIDI</body></html>");
+
+        // The folder counts as the book's output, with or without --flatten now
+        assert_eq!(existing_output(&book, &config), Some(flat_dir.clone()));
+        assert_eq!(should_skip(&book, &test_config(&output_dir)), SkipReason::AlreadyDecryptedOutput);
+
+        // Nothing but the folder is left in the output directory, and a second run gets its own
+        assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 1);
+        assert_eq!(
+            decrypt_test_book(&book, &config).unwrap().1,
+            BookResult::Decrypted(output_dir.join("1234567890_decrypted (1)"))
+        );
+        let completed = CompletedBook {
+            id: book.id.clone(),
+            output_path: Some(flat_dir),
+            duration_ms: None,
+            format: Some("epub".to_string()),
+            sha256: None,
+        };
+        assert_eq!(check_completed_book(&completed), Some(Ok(())));
+
+        // PDFs have nothing to unpack
+        let pdf = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", b"%PDF-1.4 flat");
+        let pdf_output = output_dir.join("pdf_book_decrypted.pdf");
+        assert_eq!(decrypt_test_book(&pdf, &config).unwrap().1, BookResult::Decrypted(pdf_output.clone()));
+        assert_eq!(fs::read(pdf_output).unwrap(), b"%PDF-1.4 flat");
+    }
+
+    #[test]
+    fn test_failed_write_leaves_no_partial_output() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = test_config(&output_dir);
+        let book = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", b"%PDF-1.4 content");
+
+        // A directory in the way makes moving the decrypted file into place fail
+        let output_path = output_dir.join("pdf_book_decrypted.pdf");
+        fs::create_dir_all(output_path.join("blocker")).unwrap();
+
+        let error = decrypt_test_book(&book, &config).unwrap_err();
+        assert!(error.to_string().contains("Could not write"));
+        assert!(output_path.is_dir());
+        assert!(!output_dir.join("pdf_book_decrypted.pdf.part").exists());
+    }
+
+    #[test]
+    fn test_interrupted_write_is_ignored_and_cleaned_up() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = test_config(&output_dir);
+        let book = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", b"%PDF-1.4 content");
+
+        // A run killed mid-write leaves only the staging file, which doesn't count as done
+        fs::create_dir_all(&output_dir).unwrap();
+        let leftover = output_dir.join("pdf_book_decrypted.pdf.part");
+        fs::write(&leftover, b"%PDF-1.4 trunc").unwrap();
+        let renamed_leftover = output_dir.join("pdf_book_decrypted (1).pdf.part");
+        fs::write(&renamed_leftover, b"%PDF").unwrap();
+        let other_book = output_dir.join("other_book_decrypted.pdf.part");
+        fs::write(&other_book, b"%PDF").unwrap();
+        // A --flatten folder cut short, from when the book was an EPUB
+        let flattened_leftover = output_dir.join("pdf_book_decrypted.part");
+        fs::create_dir_all(flattened_leftover.join("OEBPS")).unwrap();
+        assert_eq!(should_skip(&book, &config), SkipReason::None);
+
+        // The next run clears this book's leftovers, and only those
+        assert_eq!(remove_leftover_partials(std::slice::from_ref(&book), &config), 3);
+        assert!(!leftover.exists() && !renamed_leftover.exists() && !flattened_leftover.exists());
+        assert!(other_book.exists());
+
+        decrypt_test_book(&book, &config).unwrap();
+        assert_eq!(fs::read(output_dir.join("pdf_book_decrypted.pdf")).unwrap(), b"%PDF-1.4 content");
+        assert_eq!(should_skip(&book, &config), SkipReason::AlreadyDecryptedOutput);
+
+        // With --organize --compress, the compressed file is staged in the author's folder
+        let organized = Config { organize_output: true, compress: Compress::Gzip, ..config.clone() };
+        let mut titled = book.clone();
+        titled.title = Some("Some Title".to_string());
+        titled.author = Some("Some Author".to_string());
+        let organized_path = get_output_path(&titled, &organized).unwrap();
+        fs::create_dir_all(organized_path.parent().unwrap()).unwrap();
+        let staged = PathBuf::from(format!("{}.part", organized_path.display()));
+        let retry_staged = PathBuf::from(format!("{}.1.part", organized_path.display()));
+        fs::write(&staged, b"gzip").unwrap();
+        fs::write(&retry_staged, b"gzip").unwrap();
+        assert_eq!(remove_leftover_partials(std::slice::from_ref(&titled), &organized), 2);
+        assert!(!staged.exists() && !retry_staged.exists());
+    }
+
+    #[test]
+    fn test_library_stats() {
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+        let config = test_config(&temp_dir.path().join("output"));
+
+        let pdf = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", b"%PDF-1.4 content");
+        decrypt_test_book(&pdf, &config).unwrap();
+
+        let write_zip = |dir: std::path::PathBuf, filename: &str, entry: &str| {
+            fs::create_dir_all(&dir).unwrap();
+            let mut zip = zip::ZipWriter::new(fs::File::create(dir.join(filename)).unwrap());
+            zip.start_file(entry, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(&[0x42u8; 32]).unwrap();
+            zip.finish().unwrap();
+            fs::write(dir.join(format!("{}.dat", dir.file_name().unwrap().to_string_lossy())), b"fake dat content").unwrap();
+            BookInfo::new(dir).unwrap()
+        };
+        let v11 = write_zip(temp_dir.path().join("v11_book"), "v11_book.v11.epub", "OEBPS/chapter1.xhtml");
+        let plain = write_zip(temp_dir.path().join("plain_book"), "plain_book.epub", "OEBPS/content.opf");
+
+        let stats = LibraryStats::new(&[pdf.clone(), v11.clone(), plain], &Manifest::default(), &config);
+        assert_eq!(stats.books, 3);
+        assert_eq!(stats.by_format.get("epub"), Some(&2));
+        assert_eq!(stats.by_format.get("pdf"), Some(&1));
+        assert_eq!(stats.by_drm.get("v1"), Some(&1));
+        assert_eq!(stats.by_drm.get("v11"), Some(&1));
+        assert_eq!(stats.by_drm.get("no DRM"), Some(&1));
+        let encrypted = fs::metadata(pdf.get_book_file_path()).unwrap().len() + fs::metadata(v11.get_book_file_path()).unwrap().len();
+        assert_eq!(stats.encrypted_bytes, encrypted);
+        assert_eq!(stats.already_decrypted, 1);
+
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+
+    #[test]
+    fn test_decrypt_book_library_api() {
+        let temp_dir = tempdir().unwrap();
+        let config = test_config(&temp_dir.path().join("output"));
+        let pdf = b"%PDF-1.4\n%%EOF\n";
+
+        let book = write_v1_book(&temp_dir.path().join("api_book"), "api_book.v1.pdf", pdf);
+        assert_eq!(decrypt::decrypt_book(&book, &config).unwrap(), pdf);
+
+        // A missing .dat file names the file instead of a bare I/O error
+        fs::remove_file(book.get_data_file_path()).unwrap();
+        let error = decrypt::decrypt_book(&book, &config).unwrap_err();
+        assert!(matches!(error, decrypt::DecryptError::Unreadable { what: ".dat file", .. }));
+    }
+
+    #[test]
+    fn test_epub_output_is_validated() {
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = test_config(&output_dir);
+
+        let mut epub = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut epub));
+            zip.start_file("mimetype", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"application/epub+zip").unwrap();
+            zip.start_file("OEBPS/chapter1.xhtml", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"<html><body>Hello</body></html>").unwrap();
+            zip.finish().unwrap();
+        }
+
+        let book = write_v1_book(&temp_dir.path().join("good_book"), "good_book.v1.epub", &epub);
+        decrypt_test_book(&book, &config).unwrap();
+        assert_eq!(fs::read(output_dir.join("good_book_decrypted.epub")).unwrap(), epub);
+
+        // Garbage that happens to decrypt is rejected and removed
+        let bogus = write_v1_book(&temp_dir.path().join("bogus_book"), "bogus_book.v1.epub", b"definitely not a zip");
+        let error = decrypt_test_book(&bogus, &config).unwrap_err();
+        assert!(error.to_string().contains("not a valid EPUB"));
+        assert!(!output_dir.join("bogus_book_decrypted.epub").exists());
     }
     
+    #[test]
+    fn test_book_listing_json() {
+        let temp_dir = tempdir().unwrap();
+        let book_dir = temp_dir.path().join("test_book");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("test_book.v11.epub"), vec![0x42u8; 100]).unwrap();
+        fs::write(book_dir.join("test_book.dat"), b"fake dat content").unwrap();
+
+        let book = BookInfo::new(book_dir).unwrap();
+        let config = test_config(&temp_dir.path().join("output"));
+
+        let json = serde_json::to_value(BookListing::new(&book, &Manifest::default(), &config)).unwrap();
+        assert_eq!(json["id"], "test_book");
+        assert_eq!(json["title"], "test_book");
+        assert_eq!(json["format"], "epub");
+        assert_eq!(json["size_bytes"], 100);
+        assert_eq!(json["is_v11"], false);
+        assert_eq!(json["already_decrypted"], false);
+        assert_eq!(json["is_sample"], false);
+    }
+    
+    #[test]
+    fn test_book_filter_globs() {
+        let temp_dir = tempdir().unwrap();
+        let book_dir = temp_dir.path().join("1234567890");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("1234567890.epub"), b"fake epub content").unwrap();
+        fs::write(book_dir.join("1234567890.dat"), b"fake dat content").unwrap();
+
+        let mut book = BookInfo::new(book_dir).unwrap();
+        book.title = Some("The Rust Programming Language".to_string());
+
+        let patterns = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let matches = |only: &[&str], exclude: &[&str]| {
+            BookFilter::new(&patterns(only), &patterns(exclude)).unwrap().matches(&book)
+        };
+
+        assert!(matches(&[], &[]));
+        assert!(matches(&["1234*"], &[]));
+        assert!(matches(&["*rust*"], &[]));
+        assert!(matches(&["123456789?"], &[]));
+        assert!(!matches(&["999*"], &[]));
+        assert!(!matches(&["*"], &["*PROGRAMMING*"]));
+        assert!(matches(&["999*", "*language"], &["*python*"]));
+
+        // Regex metacharacters are literal
+        assert!(!matches(&["1234.*"], &[]));
+    }
+    
+    #[test]
+    fn test_since_filter_uses_download_time() {
+        use filetime::{set_file_mtime, FileTime};
+        use std::time::{Duration, SystemTime};
+
+        let temp_dir = tempdir().unwrap();
+        let write_book = |id: &str, age: Duration| {
+            let book_dir = temp_dir.path().join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            let mtime = FileTime::from_system_time(SystemTime::now() - age);
+            for file in [format!("{}.epub", id), format!("{}.dat", id)] {
+                fs::write(book_dir.join(&file), b"fake content").unwrap();
+                set_file_mtime(book_dir.join(&file), mtime).unwrap();
+            }
+            BookInfo::new(book_dir).unwrap()
+        };
+
+        let recent = write_book("recent_book", Duration::from_secs(3600));
+        let old = write_book("old_book", Duration::from_secs(30 * 24 * 3600));
+
+        let state_path = temp_dir.path().join("ridiculous_state.json");
+        let cutoff = since_cutoff("7d", &state_path).unwrap();
+        assert!(downloaded_since(&recent, cutoff, Verbosity::Normal));
+        assert!(!downloaded_since(&old, cutoff, Verbosity::Normal));
+        assert!(!downloaded_since(&recent, since_cutoff("30m", &state_path).unwrap(), Verbosity::Normal));
+
+        // Books whose times can't be read aren't filtered out
+        fs::remove_dir_all(&old.path).unwrap();
+        assert!(downloaded_since(&old, cutoff, Verbosity::Normal));
+
+        assert!(since_cutoff("yesterday", &state_path).is_err());
+    }
+
+    #[test]
+    fn test_watch_filter() {
+        use filetime::{set_file_mtime, FileTime};
+        use std::time::{Duration, SystemTime};
+
+        let temp_dir = tempdir().unwrap();
+        let config = test_config(&temp_dir.path().join("output"));
+        let write_book = |id: &str, age: Duration| {
+            let book_dir = temp_dir.path().join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            let mtime = FileTime::from_system_time(SystemTime::now() - age);
+            for file in [format!("{}.epub", id), format!("{}.dat", id)] {
+                fs::write(book_dir.join(&file), [0u8; 32]).unwrap();
+                set_file_mtime(book_dir.join(&file), mtime).unwrap();
+            }
+            BookInfo::new(book_dir).unwrap()
+        };
+        let books = vec![
+            write_book("1111111111", Duration::from_secs(3600)),
+            write_book("2222222222", Duration::from_secs(3600)),
+            write_book("3333333333", Duration::from_secs(3600)),
+            write_book("4444444444", Duration::from_secs(30 * 24 * 3600)),
+        ];
+        let ids = |books: &[BookInfo]| books.iter().map(|book| book.id.clone()).collect::<Vec<_>>();
+        let state_path = temp_dir.path().join("ridiculous_state.json");
+
+        // --books-from, --exclude and --since apply just as they do without --watch. Listed
+        // books that aren't downloaded yet are no error.
+        let list_path = temp_dir.path().join("books.txt");
+        fs::write(&list_path, "1111111111:pdf
+3333333333
+4444444444
+9999999999
+").unwrap();
+        let decrypt = DecryptArgs {
+            books_from: Some(list_path),
+            exclude: vec!["333*".to_string()],
+            since: Some("7d".to_string()),
+            ..Default::default()
+        };
+        let filter = WatchFilter::new(&decrypt, &config, &state_path).unwrap();
+        let selected = filter.select(books.clone(), &ProcessingState::default(), &Manifest::default(), true);
+        assert_eq!(ids(&selected), ["1111111111"]);
+        assert_eq!(selected[0].format, BookFormat::Pdf);
+
+        // --assume-format, and books already done are left out unless forced
+        let mut state = ProcessingState::default();
+        state.completed.push(CompletedBook {
+            id: "2222222222".to_string(),
+            output_path: None,
+            duration_ms: None,
+            format: None,
+            sha256: None,
+        });
+        let decrypt = DecryptArgs { assume_format: Some(BookFormat::Pdf), ..Default::default() };
+        let selected = WatchFilter::new(&decrypt, &config, &state_path).unwrap()
+            .select(books.clone(), &state, &Manifest::default(), true);
+        assert_eq!(ids(&selected), ["1111111111", "3333333333", "4444444444"]);
+        assert!(selected.iter().all(|book| book.format == BookFormat::Pdf));
+
+        let decrypt = DecryptArgs { force: true, ..Default::default() };
+        let selected = WatchFilter::new(&decrypt, &config, &state_path).unwrap()
+            .select(books, &state, &Manifest::default(), true);
+        assert_eq!(selected.len(), 4);
+    }
+
+    #[test]
+    fn test_books_from_id_list() {
+        let temp_dir = tempdir().unwrap();
+        let books: Vec<_> = ["1111111111", "2222222222", "3333333333"].iter()
+            .map(|id| {
+                let book_dir = temp_dir.path().join(id);
+                fs::create_dir_all(&book_dir).unwrap();
+                fs::write(book_dir.join(format!("{}.epub", id)), b"fake epub content").unwrap();
+                fs::write(book_dir.join(format!("{}.dat", id)), b"fake dat content").unwrap();
+                BookInfo::new(book_dir).unwrap()
+            })
+            .collect();
+        let ids = |books: &[BookInfo]| books.iter().map(|book| book.id.clone()).collect::<Vec<_>>();
+
+        let list_path = temp_dir.path().join("books.txt");
+        fs::write(&list_path, "# wanted\n3333333333\n\n  1111111111  \n").unwrap();
+        let selected = restrict_to_ids(books.clone(), &read_book_ids(&list_path).unwrap()).unwrap();
+        assert_eq!(ids(&selected), ["1111111111", "3333333333"]);
+
+        // The `scan --json` inventory works as a list too
+        let json_path = temp_dir.path().join("books.json");
+        fs::write(&json_path, r#"[{"id": "2222222222", "title": "Two"}]"#).unwrap();
+        let selected = restrict_to_ids(books.clone(), &read_book_ids(&json_path).unwrap()).unwrap();
+        assert_eq!(ids(&selected), ["2222222222"]);
+
+        // Unknown ids are an error rather than silently ignored
+        fs::write(&list_path, "1111111111\n9999999999\n").unwrap();
+        let error = restrict_to_ids(books, &read_book_ids(&list_path).unwrap()).unwrap_err();
+        assert!(error.to_string().contains("9999999999"));
+
+        // `<id>:<format>` forces that book's format
+        fs::write(&list_path, "1111111111:pdf\n2222222222 : EPUB\n3333333333\n").unwrap();
+        let listed = read_book_ids(&list_path).unwrap();
+        assert_eq!(listed[0], ListedBook { id: "1111111111".to_string(), format: Some(BookFormat::Pdf) });
+        assert_eq!(listed[1], ListedBook { id: "2222222222".to_string(), format: Some(BookFormat::Epub) });
+        assert_eq!(listed[2], ListedBook { id: "3333333333".to_string(), format: None });
+
+        fs::write(&list_path, "1111111111:mobi\n").unwrap();
+        let error = read_book_ids(&list_path).unwrap_err();
+        assert!(error.to_string().contains("mobi"));
+    }
+
+    #[test]
+    fn test_assume_format() {
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = test_config(&output_dir);
+        let pdf = b"%PDF-1.4\n1 0 obj << /Type /Catalog >> endobj\n%%EOF\n";
+
+        // A PDF misnamed as an EPUB fails verification unless its format is forced
+        let book = write_v1_book(&temp_dir.path().join("1111111111"), "1111111111.v1.epub", pdf);
+        assert_eq!(book.format, BookFormat::Epub);
+        assert!(decrypt_test_book(&book, &config).unwrap_err().to_string().contains("not a valid EPUB"));
+
+        let other = write_v1_book(&temp_dir.path().join("2222222222"), "2222222222.v1.epub", pdf);
+        let mut books = vec![book, other];
+        assume_formats(&mut books, Some(&BookFormat::Pdf), &[]);
+        assert!(books.iter().all(|book| book.format == BookFormat::Pdf));
+        assert_eq!(books[0].get_output_filename(), "1111111111_decrypted.pdf");
+
+        let (_, result) = decrypt_test_book(&books[0], &config).unwrap();
+        assert!(matches!(result, BookResult::Decrypted(path) if path == output_dir.join("1111111111_decrypted.pdf")));
+        assert_eq!(fs::read(output_dir.join("1111111111_decrypted.pdf")).unwrap(), pdf);
+
+        // A format from the books-from list wins over --assume-format
+        let listed = [ListedBook { id: "2222222222".to_string(), format: Some(BookFormat::Epub) }];
+        assume_formats(&mut books, Some(&BookFormat::Pdf), &listed);
+        assert_eq!(books[0].format, BookFormat::Pdf);
+        assert_eq!(books[1].format, BookFormat::Epub);
+    }
+    
+    #[test]
+    fn test_wrong_device_error_redacts_device_id() {
+        let temp_dir = tempdir().unwrap();
+        let book = write_v1_book(&temp_dir.path().join("1234567890"), "1234567890.v1.pdf", b"%PDF-1.4");
+
+        let wrong_device_id = "87654321-4321-4321-4321-210987654321";
+        let config = Config {
+            device_id: wrong_device_id.to_string(),
+            ..test_config(&temp_dir.path().join("output"))
+        };
+
+        let error = decrypt_test_book(&book, &config).unwrap_err();
+        for formatted in [error.to_string(), format!("{:#}", error), format!("{:?}", error)] {
+            assert!(!formatted.contains(wrong_device_id), "{}", formatted);
+            assert!(formatted.contains("4321"), "{}", formatted);
+        }
+
+        assert_eq!(redact(wrong_device_id), format!("{}4321", "*".repeat(32)));
+        assert_eq!(redact("abcd"), "****");
+        assert_eq!(redact(""), "");
+    }
+    
+    #[test]
+    fn test_subcommands_and_legacy_flags() {
+        use clap::{CommandFactory, Parser};
+
+        Args::command().debug_assert();
+        let parse = |argv: &[&str]| Args::try_parse_from(argv).unwrap();
+
+        // A bare invocation decrypts, with decrypt options at the top level
+        let args = parse(&["ridiculous", "--batch-mode", "--parallel", "2"]);
+        assert!(matches!(args.selected_command(), Command::Decrypt(DecryptArgs { batch_mode: true, parallel: 2, .. })));
+
+        let args = parse(&["ridiculous", "decrypt", "--dry-run", "--device-id", "abc"]);
+        assert!(matches!(args.selected_command(), Command::Decrypt(DecryptArgs { dry_run: true, .. })));
+        assert_eq!(args.device_id.as_deref(), Some("abc"));
+
+        // Shared options work on either side of the subcommand
+        let args = parse(&["ridiculous", "--verbose", "scan", "--json", "--profile", "work"]);
+        assert!(matches!(args.selected_command(), Command::Scan { json: true, count_only: false }));
+        assert!(args.json() && args.verbose == 1);
+        // --json keeps stdout clean whatever the -v level
+        assert_eq!(args.verbosity(), Verbosity::Normal);
+        assert_eq!(args.profile.as_deref(), Some("work"));
+
+        assert!(matches!(parse(&["ridiculous", "validate"]).selected_command(), Command::Validate));
+
+        // The older mode flags still pick the matching subcommand
+        assert!(matches!(parse(&["ridiculous", "--diagnose"]).selected_command(), Command::Diagnose));
+        assert!(matches!(parse(&["ridiculous", "--validate-only"]).selected_command(), Command::Validate));
+        assert!(matches!(parse(&["ridiculous", "--setup"]).selected_command(), Command::Setup));
+        assert!(matches!(parse(&["ridiculous", "--verify"]).selected_command(), Command::Verify));
+        assert!(matches!(parse(&["ridiculous", "--stats"]).selected_command(), Command::Stats));
+        assert!(matches!(parse(&["ridiculous", "--self-test"]).selected_command(), Command::SelfTest));
+        assert!(matches!(
+            parse(&["ridiculous", "--diff", "out"]).selected_command(),
+            Command::Diff { output_dir: Some(dir) } if dir == Path::new("out")
+        ));
+        assert!(matches!(parse(&["ridiculous", "--repair-state"]).selected_command(), Command::RepairState));
+        assert!(matches!(parse(&["ridiculous", "--list", "--json"]).selected_command(), Command::Scan { json: true, .. }));
+        assert!(matches!(parse(&["ridiculous", "scan", "--count-only"]).selected_command(), Command::Scan { count_only: true, .. }));
+        assert!(matches!(parse(&["ridiculous", "--count-only"]).selected_command(), Command::Scan { json: false, count_only: true }));
+        assert!(Args::try_parse_from(["ridiculous", "scan", "--count-only", "--json"]).is_err());
+
+        // --quiet goes anywhere, composes with --report, and can't be combined with --verbose
+        let args = parse(&["ridiculous", "decrypt", "-q", "--batch-mode", "--report", "report.json"]);
+        assert!(args.quiet);
+        assert!(matches!(args.selected_command(), Command::Decrypt(DecryptArgs { report: Some(_), .. })));
+        assert!(Args::try_parse_from(["ridiculous", "--quiet", "--verbose"]).is_err());
+        assert!(Args::try_parse_from(["ridiculous", "-q", "-vv"]).is_err());
+
+        // -v can be repeated, up to -vvv
+        assert_eq!(parse(&["ridiculous"]).verbosity(), Verbosity::Normal);
+        assert_eq!(parse(&["ridiculous", "-v"]).verbosity(), Verbosity::Books);
+        assert_eq!(parse(&["ridiculous", "-vv", "scan"]).verbosity(), Verbosity::Discovery);
+        assert_eq!(parse(&["ridiculous", "decrypt", "-vvv"]).verbosity(), Verbosity::Decryption);
+        assert_eq!(parse(&["ridiculous", "-vvvv"]).verbosity(), Verbosity::Decryption);
+
+        let args = parse(&["ridiculous", "--batch-mode", "--max-book-time", "10m"]);
+        assert!(matches!(args.selected_command(), Command::Decrypt(DecryptArgs { max_book_time: Some(time), .. }) if time.as_secs() == 600));
+
+        let args = parse(&["ridiculous", "--batch-mode", "--fail-fast"]);
+        assert!(matches!(args.selected_command(), Command::Decrypt(DecryptArgs { fail_fast: true, .. })));
+
+        // --select asks which books to decrypt, so it's for interactive runs only
+        assert!(matches!(parse(&["ridiculous", "--select"]).selected_command(), Command::Decrypt(DecryptArgs { select: true, .. })));
+        assert!(Args::try_parse_from(["ridiculous", "decrypt", "--select", "--batch-mode"]).is_err());
+    }
+    
+    #[test]
+    fn test_sanitize_path_component() {
+        // Separators never create extra directories
+        assert_eq!(sanitize_path_component("AC/DC").as_deref(), Some("AC_DC"));
+        assert_eq!(sanitize_path_component(r"Back\slash").as_deref(), Some("Back_slash"));
+
+        // Characters Windows rejects
+        assert_eq!(sanitize_path_component("Re:Zero").as_deref(), Some("Re_Zero"));
+        assert_eq!(sanitize_path_component("What? <Really> \"yes\" *|*").as_deref(), Some("What_ _Really_ _yes_ ___"));
+        assert_eq!(sanitize_path_component("Line\nbreak").as_deref(), Some("Line_break"));
+
+        // Reserved device names, with or without an extension, in any case
+        assert_eq!(sanitize_path_component("CON").as_deref(), Some("CON_"));
+        assert_eq!(sanitize_path_component("nul.txt").as_deref(), Some("nul.txt_"));
+        assert_eq!(sanitize_path_component("com1").as_deref(), Some("com1_"));
+        assert_eq!(sanitize_path_component("LPT9").as_deref(), Some("LPT9_"));
+        assert_eq!(sanitize_path_component("COM0").as_deref(), Some("COM0"));
+        assert_eq!(sanitize_path_component("Console").as_deref(), Some("Console"));
+
+        // Trailing dots and spaces are invalid on Windows
+        assert_eq!(sanitize_path_component("  Title... ").as_deref(), Some("Title"));
+
+        // Nothing usable left
+        assert_eq!(sanitize_path_component(""), None);
+        assert_eq!(sanitize_path_component(".."), None);
+        assert_eq!(sanitize_path_component("///"), None);
+
+        // Non-ASCII titles are kept and long ones are capped
+        assert_eq!(sanitize_path_component("해리 포터").as_deref(), Some("해리 포터"));
+        assert_eq!(sanitize_path_component(&"가".repeat(300)).unwrap().chars().count(), 100);
+    }
+
+    #[test]
+    fn test_organized_output_path() {
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = Config {
+            organize_output: true,
+            ..test_config(&output_dir)
+        };
+
+        let mut epub = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut epub));
+            zip.start_file("mimetype", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"application/epub+zip").unwrap();
+            zip.start_file("OEBPS/content.opf", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(br#"<package><metadata>
+                <dc:title>Re:Zero Vol. 1</dc:title>
+                <dc:creator opf:role="aut">Tappei Nagatsuki</dc:creator>
+                <dc:creator opf:role="ill">Shinichirou Otsuka</dc:creator>
+            </metadata></package>"#).unwrap();
+            zip.finish().unwrap();
+        }
+
+        let book = write_v1_book(&temp_dir.path().join("1234567890"), "1234567890.v1.epub", &epub);
+        let (mut book, _) = decrypt_test_book(&book, &config).unwrap();
+        assert_eq!(book.author.as_deref(), Some("Tappei Nagatsuki"));
+
+        let expected = output_dir.join("Tappei Nagatsuki").join("Re_Zero Vol. 1").join("Re_Zero Vol. 1.epub");
+        assert!(expected.exists());
+
+        assert_eq!(get_output_path(&book, &config).unwrap(), expected);
+
+        // Books in a series get a folder for it between author and title
+        let mut series_epub = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut series_epub));
+            zip.start_file("content.opf", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(br#"<package><metadata>
+                <dc:title>Vol. 2</dc:title>
+                <meta content="Re:Zero" name="calibre:series"/>
+            </metadata></package>"#).unwrap();
+            zip.finish().unwrap();
+        }
+        let series_metadata = read_epub_metadata(std::io::Cursor::new(series_epub));
+        assert_eq!(series_metadata.series.as_deref(), Some("Re:Zero"));
+        assert_eq!(series_metadata.author, None);
+
+        let mut sequel = book.clone();
+        sequel.apply_metadata(series_metadata);
+        assert_eq!(
+            get_output_path(&sequel, &config).unwrap(),
+            output_dir.join("Tappei Nagatsuki").join("Re_Zero").join("Vol. 2").join("Vol. 2.epub")
+        );
+
+        // Without metadata the book id stands in for the title
+        book.title = None;
+        book.author = None;
+        assert_eq!(
+            get_output_path(&book, &config).unwrap(),
+            output_dir.join("Unknown Author").join("1234567890").join("1234567890.epub")
+        );
+    }
+
+    #[test]
+    fn test_read_epub_metadata_from_opf() {
+        use std::io::Write;
+
+        let write_epub = |entries: &[(&str, &str)]| {
+            let mut epub = Vec::new();
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut epub));
+            for (name, content) in entries {
+                zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+                zip.write_all(content.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+            drop(zip);
+            epub
+        };
+
+        // container.xml decides which OPF is the package, over any other .opf entry
+        let epub = write_epub(&[
+            ("META-INF/container.xml", r#"<container><rootfiles>
+                <rootfile full-path="OPS/package.opf" media-type="application/oebps-package+xml"/>
+            </rootfiles></container>"#),
+            ("backup/old.opf", "<package><metadata><dc:title>Stale</dc:title></metadata></package>"),
+            ("OPS/package.opf", r#"<package><metadata>
+                <dc:title id="main">
+                    Tom &amp; Jerry &lt;Complete&gt;
+                </dc:title>
+                <dc:creator>Hanna</dc:creator>
+                <dc:creator>Barbera</dc:creator>
+            </metadata></package>"#),
+        ]);
+        let metadata = read_epub_metadata(std::io::Cursor::new(epub));
+        assert_eq!(metadata.title.as_deref(), Some("Tom & Jerry <Complete>"));
+        assert_eq!(metadata.author.as_deref(), Some("Hanna"));
+        assert_eq!(metadata.series, None);
+
+        // Without container.xml the first .opf is used; empty elements count as missing
+        let epub = write_epub(&[("content.opf", "<package><metadata><dc:title> </dc:title></metadata></package>")]);
+        assert_eq!(read_epub_metadata(std::io::Cursor::new(epub)), EpubMetadata::default());
+
+        // Not a ZIP at all, such as a decrypted PDF
+        assert_eq!(read_epub_metadata(std::io::Cursor::new(b"%PDF-1.4".to_vec())), EpubMetadata::default());
+    }
+
+    #[test]
+    fn test_comic_written_as_cbz() {
+        use std::io::Write;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = test_config(&output_dir);
+
+        // A page-image comic: no OPF package, just images
+        let mut comic = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut comic));
+            for page in ["001.jpg", "002.jpg", "003.png"] {
+                zip.start_file(format!("images/{}", page), zip::write::FileOptions::default()).unwrap();
+                zip.write_all(b"not really an image").unwrap();
+            }
+            zip.start_file("ComicInfo.xml", zip::write::FileOptions::default()).unwrap();
+            zip.write_all(b"<ComicInfo/>").unwrap();
+            zip.finish().unwrap();
+        }
+
+        let book = write_v1_book(&temp_dir.path().join("1234567890"), "1234567890.v1.epub", &comic);
+        assert_eq!(book.format, BookFormat::Epub);
+
+        let (book, result) = decrypt_test_book(&book, &config).unwrap();
+        assert_eq!(book.format, BookFormat::Comic);
+        assert_eq!(result.output_path(), output_dir.join("1234567890_decrypted.cbz").as_path());
+        assert_eq!(fs::read(result.output_path()).unwrap(), comic);
+        assert_eq!(should_skip(&book, &config), SkipReason::AlreadyDecryptedOutput);
+
+        // DRM-free comics are recognized straight from the library
+        let plain_dir = temp_dir.path().join("2222222222");
+        fs::create_dir_all(&plain_dir).unwrap();
+        fs::write(plain_dir.join("2222222222.epub"), &comic).unwrap();
+        let plain = BookInfo::new(plain_dir).unwrap();
+        assert_eq!(plain.format, BookFormat::Comic);
+    }
+
     #[test]
     fn test_processing_state_serialization() {
         let state = ProcessingState {
-            completed: vec!["book1".to_string(), "book2".to_string()],
+            completed: vec![
+                CompletedBook {
+                    id: "book1".to_string(),
+                    output_path: Some(std::path::PathBuf::from("/output/book1_decrypted.epub")),
+                    duration_ms: Some(1200),
+                    format: Some("epub".to_string()),
+                    sha256: Some("ab".repeat(32)),
+                },
+                CompletedBook {
+                    id: "book2".to_string(),
+                    output_path: None,
+                    duration_ms: None,
+                    format: None,
+                    sha256: None,
+                },
+            ],
             failed: vec![("book3".to_string(), "Network error".to_string())],
             in_progress: vec!["book4".to_string()],
+            last_run: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_714_564_800)),
+            ..Default::default()
         };
         
         let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains(r#""last_run":"2024-05-01T12:00:00Z""#));
         let deserialized: ProcessingState = serde_json::from_str(&json).unwrap();
         
-        assert_eq!(deserialized.completed.len(), 2);
+        assert_eq!(deserialized.last_run, state.last_run);
+        assert_eq!(deserialized.completed, state.completed);
         assert_eq!(deserialized.failed.len(), 1);
         assert_eq!(deserialized.in_progress.len(), 1);
+
+        // Pruning keeps only books still in the library
+        let mut pruned = deserialized.clone();
+        assert_eq!(pruned.prune(|id| id == "book1" || id == "book3"), 2);
+        assert!(pruned.is_completed("book1"));
+        assert!(!pruned.is_completed("book2"));
+        assert_eq!(pruned.failed.len(), 1);
+        assert!(pruned.in_progress.is_empty());
+        assert_eq!(pruned.last_run, state.last_run);
+    }
+
+    #[test]
+    fn test_verify_completed_output() {
+        use std::time::Duration;
+
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = test_config(&output_dir);
+
+        let book = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", b"%PDF-1.4 content");
+        let (_, result) = decrypt_test_book(&book, &config).unwrap();
+        let mut state = ProcessingState::default();
+        state.record(&book, &Ok(result), Duration::from_millis(10));
+        let completed = state.completed[0].clone();
+        assert_eq!(completed.sha256.as_deref().map(str::len), Some(64));
+        assert_eq!(check_completed_book(&completed), Some(Ok(())));
+
+        // Same structure, different bytes
+        let output_path = output_dir.join("pdf_book_decrypted.pdf");
+        fs::write(&output_path, b"%PDF-1.4 tampered").unwrap();
+        assert!(check_completed_book(&completed).unwrap().unwrap_err().contains("checksum"));
+
+        fs::write(&output_path, b"garbage").unwrap();
+        assert!(check_completed_book(&completed).unwrap().unwrap_err().contains("not a valid PDF"));
+
+        fs::remove_file(&output_path).unwrap();
+        assert!(check_completed_book(&completed).unwrap().unwrap_err().contains("missing"));
+
+        // Entries migrated from id-only state can't be checked
+        let legacy: CompletedBook = serde_json::from_str(r#""pdf_book""#).unwrap();
+        assert_eq!(check_completed_book(&legacy), None);
+    }
+
+    #[test]
+    fn test_processing_state_migrates_id_only_entries() {
+        let json = r#"{
+            "completed": ["book1", {"id": "book2", "output_path": "/output/book2_decrypted.pdf", "duration_ms": 40, "format": "pdf"}],
+            "failed": [],
+            "in_progress": []
+        }"#;
+
+        let state: ProcessingState = serde_json::from_str(json).unwrap();
+        assert_eq!(state.completed.len(), 2);
+        assert_eq!(state.completed[0].id, "book1");
+        assert_eq!(state.completed[0].output_path, None);
+        assert_eq!(state.completed[1].duration_ms, Some(40));
+        assert_eq!(state.completed[1].format.as_deref(), Some("pdf"));
+        assert!(state.is_completed("book1"));
+        assert!(!state.is_completed("book3"));
+        assert_eq!(state.last_run, None);
+    }
+    
+    #[test]
+    fn test_run_report() {
+        use std::time::Duration;
+
+        let temp_dir = tempdir().unwrap();
+        let config = test_config(&temp_dir.path().join("output"));
+        let mut books = Vec::new();
+        for id in ["good_book", "bad_book"] {
+            let book_dir = temp_dir.path().join("library").join(id);
+            fs::create_dir_all(&book_dir).unwrap();
+            fs::write(book_dir.join(format!("{}.epub", id)), b"fake epub content").unwrap();
+            fs::write(book_dir.join(format!("{}.dat", id)), b"fake dat content").unwrap();
+            books.push(BookInfo::new(book_dir).unwrap());
+        }
+
+        let mut state = ProcessingState::default();
+        let output_path = get_output_path(&books[0], &config).unwrap();
+        state.record(&books[0], &Ok(BookResult::Decrypted(output_path)), Duration::from_millis(1500));
+        state.record(&books[1], &Err(anyhow::anyhow!("Wrong device_id")), Duration::from_millis(250));
+
+        // Only the resume data is persisted
+        let saved: serde_json::Value = serde_json::to_value(&state).unwrap();
+        assert!(saved.get("outcomes").is_none());
+        assert_eq!(saved["completed"][0]["id"], "good_book");
+        assert_eq!(saved["completed"][0]["duration_ms"], 1500);
+
+        // Three books planned, two processed: the run was cut short
+        let report_path = temp_dir.path().join("report.json");
+        write_run_report(&report_path, &state, 3).unwrap();
+        let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report_path).unwrap()).unwrap();
+
+        assert_eq!(report["total"], 3);
+        assert_eq!(report["processed"], 2);
+        assert_eq!(report["completed"], 1);
+        assert_eq!(report["failed"], 1);
+        assert_eq!(report["aborted"], true);
+        assert_eq!(report["books"][0]["status"], "completed");
+        assert_eq!(report["books"][0]["duration_secs"], 1.5);
+        assert!(report["books"][0]["output_path"].as_str().unwrap().ends_with("good_book_decrypted.epub"));
+        assert_eq!(report["books"][1]["error"], "Wrong device_id");
+        assert!(report["books"][1]["output_path"].is_null());
+    }
+    
+    #[test]
+    fn test_atomic_write_preserves_previous_state() {
+        use std::io::Write;
+
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join("ridiculous_state.json");
+        fs::write(&state_path, r#"{"completed":["book1"],"failed":[],"in_progress":[]}"#).unwrap();
+
+        // A write that dies halfway must not touch the existing state
+        let result = write_atomically(&state_path, |file| {
+            file.write_all(br#"{"completed":["bo"#)?;
+            Err(std::io::Error::other("disk full"))
+        });
+        assert!(result.is_err());
+
+        let state: ProcessingState = serde_json::from_str(&fs::read_to_string(&state_path).unwrap()).unwrap();
+        assert!(state.is_completed("book1"));
+        assert!(!temp_dir.path().join("ridiculous_state.json.tmp").exists());
+
+        // A successful write replaces it
+        write_atomically(&state_path, |file| file.write_all(b"{}")).unwrap();
+        assert_eq!(fs::read_to_string(&state_path).unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_custom_state_file() {
+        use clap::Parser;
+        use std::time::{Duration, SystemTime};
+
+        let temp_dir = tempdir().unwrap();
+        let state_path = temp_dir.path().join("state").join("books.json");
+
+        // --state-file sets the config's state_path, which decides where state goes
+        let args = Args::try_parse_from([
+            "ridiculous".as_ref(),
+            "--config-path".as_ref(),
+            temp_dir.path().join("missing.toml").as_os_str(),
+            "--state-file".as_ref(),
+            state_path.as_os_str(),
+        ]).unwrap();
+        let config = load_config(&args).unwrap();
+        assert_eq!(state_file_path(&config), state_path);
+        assert_ne!(state_file_path(&Config::default()), state_path);
+
+        assert!(since_cutoff("last-run", &state_path).is_err());
+
+        let last_run = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut state = ProcessingState { last_run: Some(last_run), ..Default::default() };
+        state.failed.push(("book1".to_string(), "Wrong device_id".to_string()));
+        save_processing_state(&state_path, &state).unwrap();
+
+        let loaded = load_processing_state(&state_path).unwrap();
+        assert_eq!(loaded.failed, state.failed);
+        assert_eq!(loaded.last_run, Some(last_run));
+        assert_eq!(since_cutoff("last-run", &state_path).unwrap(), last_run);
+    }
+
+    #[test]
+    fn test_missing_home_dir_is_an_error_not_a_panic() {
+        let error = default_config_path(None).unwrap_err();
+        assert!(error.to_string().contains("--config-path"));
+
+        let home = std::path::PathBuf::from("/home/reader");
+        assert_eq!(default_config_path(Some(home.clone())).unwrap(), home.join(".ridiculous.toml"));
+    }
+
+    #[test]
+    fn test_state_lock_is_exclusive() {
+        let temp_dir = tempdir().unwrap();
+        let lock_path = temp_dir.path().join("ridiculous_state.lock");
+
+        let lock = lock_processing_state(&lock_path).unwrap();
+        let error = lock_processing_state(&lock_path).err().unwrap();
+        assert!(error.to_string().contains("already in progress"));
+
+        drop(lock);
+        assert!(lock_processing_state(&lock_path).is_ok());
     }
     
     #[test]
@@ -87,6 +1929,233 @@ mod tests {
         assert!(is_retryable_error(&anyhow!("IO error: broken pipe")));
         assert!(!is_retryable_error(&anyhow!("Authentication failed")));
         assert!(!is_retryable_error(&anyhow!("File not found")));
+
+        // Decryption errors are judged by variant, whatever their text says
+        let wrong_device = DecryptError::WrongDevice { device_id: "connection-timeout-network".to_string() };
+        assert!(!is_retryable_error(&anyhow::Error::from(wrong_device)));
+        let corrupted = DecryptError::PaddingError { reason: "temporary".to_string() };
+        assert!(!is_retryable_error(&anyhow::Error::from(corrupted).context("Decrypting book 1234567890")));
+
+        let interrupted = std::io::Error::new(std::io::ErrorKind::Interrupted, "read interrupted");
+        assert!(is_retryable_error(&anyhow::Error::from(DecryptError::Io(interrupted))));
+        let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "io error: missing");
+        assert!(!is_retryable_error(&anyhow::Error::from(DecryptError::Io(missing))));
+    }
+    
+    #[tokio::test]
+    async fn test_retry_respects_max_retries() {
+        use std::time::Duration;
+        use indicatif::ProgressBar;
+
+        let pb = ProgressBar::hidden();
+
+        for (max_retries, expected_attempts) in [(1, 1), (5, 5)] {
+            let mut attempts = 0;
+            let result: anyhow::Result<()> = retry_with_backoff(max_retries, Backoff { base_delay: Duration::from_millis(1), jitter: false }, &pb, || {
+                attempts += 1;
+                async { Err(anyhow::anyhow!("Connection timeout occurred")) }
+            }).await;
+
+            assert!(result.is_err());
+            assert_eq!(attempts, expected_attempts);
+        }
+
+        // Non-retryable errors stop immediately regardless of the budget
+        let mut attempts = 0;
+        let result: anyhow::Result<()> = retry_with_backoff(5, Backoff { base_delay: Duration::from_millis(1), jitter: false }, &pb, || {
+            attempts += 1;
+            async { Err(anyhow::anyhow!("Authentication failed")) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_key_derived_once_across_retries() {
+        use std::cell::Cell;
+        use std::time::Duration;
+        use indicatif::ProgressBar;
+
+        let pb = ProgressBar::hidden();
+        let derivations = Cell::new(0);
+        let attempts = Cell::new(0);
+        let result: anyhow::Result<()> = retry_with_key(
+            || {
+                derivations.set(derivations.get() + 1);
+                async { Ok(*TEST_BOOK_KEY) }
+            },
+            4,
+            Backoff { base_delay: Duration::from_millis(1), jitter: false },
+            &pb,
+            |key| {
+                attempts.set(attempts.get() + 1);
+                assert_eq!(&key, TEST_BOOK_KEY);
+                async { Err(anyhow::anyhow!("Connection timeout occurred")) }
+            },
+        ).await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 4);
+        assert_eq!(derivations.get(), 1);
+
+        // Deriving the key is retried too, and no decryption is attempted without one
+        let derivations = Cell::new(0);
+        let attempts = Cell::new(0);
+        let result: anyhow::Result<()> = retry_with_key(
+            || {
+                derivations.set(derivations.get() + 1);
+                async { Err(anyhow::anyhow!("Connection timeout reading .dat")) }
+            },
+            4,
+            Backoff { base_delay: Duration::from_millis(1), jitter: false },
+            &pb,
+            |_| {
+                attempts.set(attempts.get() + 1);
+                async { Ok(()) }
+            },
+        ).await;
+        assert!(result.is_err());
+        assert_eq!(derivations.get(), 4);
+        assert_eq!(attempts.get(), 0);
+
+        // A key that's derived on a retry is used from then on
+        let derivations = Cell::new(0);
+        let result = retry_with_key(
+            || {
+                derivations.set(derivations.get() + 1);
+                let derived = if derivations.get() == 1 { Err(anyhow::anyhow!("Operation timeout after 1s")) } else { Ok(*TEST_BOOK_KEY) };
+                async move { derived }
+            },
+            4,
+            Backoff { base_delay: Duration::from_millis(1), jitter: false },
+            &pb,
+            |key| async move { Ok(key) },
+        ).await;
+        assert_eq!(&result.unwrap(), TEST_BOOK_KEY);
+        assert_eq!(derivations.get(), 2);
+    }
+
+    #[test]
+    fn test_backoff_delays() {
+        use std::time::Duration;
+
+        let backoff = Backoff { base_delay: Duration::from_secs(1), jitter: false };
+        let delays: Vec<_> = (0..8).map(|attempt| backoff.delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30, 30]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(30));
+
+        // Jitter only ever shortens a delay, to no less than half
+        let jittered = Backoff::jittered(Duration::from_secs(1));
+        for attempt in 0..8 {
+            let delay = jittered.delay(attempt);
+            assert!(delay <= backoff.delay(attempt) && delay >= backoff.delay(attempt) / 2, "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_book_time_budget() {
+        use std::time::Duration;
+        use indicatif::ProgressBar;
+
+        // A book that keeps timing out is abandoned once the budget runs out, mid-retry
+        let pb = ProgressBar::hidden();
+        let mut attempts = 0;
+        let abandoned = AtomicBool::new(false);
+        let started = std::time::Instant::now();
+        let result: anyhow::Result<()> = within_book_budget(
+            Duration::from_millis(300),
+            retry_with_backoff(10, Backoff { base_delay: Duration::from_millis(100), jitter: false }, &pb, || {
+                attempts += 1;
+                run_with_timeout(Duration::from_millis(50), &abandoned, async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    Ok(())
+                })
+            }),
+        ).await;
+
+        let error = result.unwrap_err();
+        assert!(error.to_string().contains("exceeded time budget of 300ms"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!((2..10).contains(&attempts), "{} attempts", attempts);
+
+        // An attempt cut short by the budget rather than its own timeout is abandoned too
+        let abandoned = AtomicBool::new(false);
+        let result: anyhow::Result<()> = within_book_budget(
+            Duration::from_millis(20),
+            run_with_timeout(Duration::ZERO, &abandoned, async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            }),
+        ).await;
+        assert!(result.is_err());
+        assert!(abandoned.load(Ordering::Relaxed));
+        let finished = AtomicBool::new(false);
+        run_with_timeout(Duration::ZERO, &finished, async { Ok(()) }).await.unwrap();
+        assert!(!finished.load(Ordering::Relaxed));
+
+        // No budget, or one that isn't reached, changes nothing
+        assert_eq!(within_book_budget(Duration::ZERO, async { Ok(1) }).await.unwrap(), 1);
+        assert_eq!(within_book_budget(Duration::from_secs(5), async { Ok(2) }).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_triggers_retryable_error() {
+        use std::time::Duration;
+
+        let result: anyhow::Result<()> = run_with_timeout(Duration::from_millis(1), &AtomicBool::new(false), async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }).await;
+
+        let error = result.unwrap_err();
+        assert!(error.to_string().contains("timeout"));
+        assert!(is_retryable_error(&error));
+
+        // Fast operations are unaffected
+        let result = run_with_timeout(Duration::from_secs(5), &AtomicBool::new(false), async { Ok(42) }).await;
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_attempt_finishing_after_its_retry_writes_nothing() {
+        use std::time::Duration;
+
+        let temp_dir = tempdir().unwrap();
+        let output_dir = temp_dir.path().join("output");
+        let config = test_config(&output_dir);
+        let book = write_v1_book(&temp_dir.path().join("pdf_book"), "pdf_book.v1.pdf", b"%PDF-1.4 content");
+        let pb = indicatif::ProgressBar::hidden();
+        let key = derive_content_key(&book, &config, &pb).unwrap();
+
+        // The first attempt is stuck until after it times out and its retry has finished
+        let (release, stuck) = std::sync::mpsc::channel::<()>();
+        let (finished, first_result) = std::sync::mpsc::channel();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let result: anyhow::Result<()> = run_with_timeout(Duration::from_millis(20), &abandoned, async {
+            let (book, config, pb, abandoned) = (book.clone(), config.clone(), pb.clone(), Arc::clone(&abandoned));
+            let _ = tokio::task::spawn_blocking(move || {
+                stuck.recv().unwrap();
+                finished.send(decrypt_book_with_original_logic(&book, &config, &key, 0, &abandoned, &pb).map(|_| ())).unwrap();
+            }).await;
+            Ok(())
+        }).await;
+        assert!(result.unwrap_err().to_string().contains("timeout"));
+        assert!(abandoned.load(Ordering::Relaxed));
+
+        let (_, retried) = decrypt_book_with_original_logic(&book, &config, &key, 1, &AtomicBool::new(false), &pb).unwrap();
+        assert_eq!(retried.output_path(), output_dir.join("pdf_book_decrypted.pdf"));
+
+        release.send(()).unwrap();
+        let error = first_result.recv().unwrap().unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+
+        // Only the retry's output is left, with no stray copy or partial file beside it
+        let mut names: Vec<_> = fs::read_dir(&output_dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["pdf_book_decrypted.pdf"]);
+        assert_eq!(fs::read(output_dir.join("pdf_book_decrypted.pdf")).unwrap(), b"%PDF-1.4 content");
     }
     
     #[test]
@@ -106,17 +2175,213 @@ mod tests {
         assert!(size_str.contains("KB") || size_str.contains("B"));
     }
     
+    #[test]
+    fn test_versionless_config_is_upgraded() {
+        let temp_dir = tempdir().unwrap();
+        let config_path = temp_dir.path().join(".ridiculous.toml");
+
+        let v0 = r#"# Ridiculous Enhanced Configuration
+            device_id = "12345678-1234-1234-1234-123456789012"
+            user_idx = "123"
+            organize_output = true
+        "#;
+        fs::write(&config_path, v0).unwrap();
+
+        let mut config: Config = toml::from_str(v0).unwrap();
+        assert_eq!(config.version, 0);
+        upgrade_config_file(&mut config, &config_path, v0);
+        assert_eq!(config.version, CONFIG_VERSION);
+
+        // The account moves into a profile, other settings are kept, newer ones are written
+        // with their defaults, and the original is saved
+        let content = fs::read_to_string(&config_path).unwrap();
+        assert!(content.contains("on_existing"));
+        assert!(content.contains("[[profile]]"));
+        let mut upgraded: Config = toml::from_str(&content).unwrap();
+        assert_eq!(upgraded.version, CONFIG_VERSION);
+        assert_eq!(upgraded.user_idx, "");
+        assert_eq!(upgraded.profiles, vec![Profile {
+            name: "default".to_string(),
+            device_id: "12345678-1234-1234-1234-123456789012".to_string(),
+            user_idx: "123".to_string(),
+            output_directory: None,
+        }]);
+        assert!(upgraded.organize_output);
+        assert_eq!(upgraded.max_retries, 3);
+        assert_eq!(fs::read_to_string(temp_dir.path().join(".ridiculous.toml.v0.bak")).unwrap(), v0);
+        assert!(!upgraded.migrate());
+        upgraded.select_profile(None).unwrap();
+        assert_eq!(upgraded.user_idx, "123");
+
+        // Writing the account back keeps it in its profile
+        upgraded.user_idx = "456".to_string();
+        upgraded.store_account();
+        assert_eq!(upgraded.user_idx, "");
+        assert_eq!(upgraded.profiles[0].user_idx, "456");
+
+        // Without an account to convert, nothing changes, so the file and its comments stay
+        fs::remove_file(temp_dir.path().join(".ridiculous.toml.v0.bak")).unwrap();
+        let unchanged = "# Where my books go\noutput_directory = \"/tmp/books\"\n";
+        fs::write(&config_path, unchanged).unwrap();
+        let mut config: Config = toml::from_str(unchanged).unwrap();
+        upgrade_config_file(&mut config, &config_path, unchanged);
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.output_directory.as_deref(), Some("/tmp/books"));
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), unchanged);
+        assert!(!temp_dir.path().join(".ridiculous.toml.v0.bak").exists());
+
+        // A config from a newer version is read, but never rewritten
+        let future = "version = 99\nuser_idx = \"456\"\nsome_future_setting = true\n";
+        fs::write(&config_path, future).unwrap();
+        let mut config: Config = toml::from_str(future).unwrap();
+        upgrade_config_file(&mut config, &config_path, future);
+        assert_eq!(config.version, 99);
+        assert_eq!(config.user_idx, "456");
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), future);
+    }
+
+    #[test]
+    fn test_legacy_flat_config_round_trip() {
+        let legacy = r#"
+            device_id = "12345678-1234-1234-1234-123456789012"
+            user_idx = "123"
+            output_directory = "/tmp/books"
+            verbose = true
+        "#;
+
+        let mut config: Config = toml::from_str(legacy).unwrap();
+        assert!(config.profiles.is_empty());
+        // verbose was a bool before -v could be repeated
+        assert_eq!(config.verbose, Verbosity::Books);
+        config.select_profile(None).unwrap();
+        assert_eq!(config.user_idx, "123");
+        assert!(config.select_profile(Some("work")).is_err());
+
+        // Flat configs stay flat when written back
+        let toml_str = toml::to_string(&config).unwrap();
+        assert!(!toml_str.contains("profile"));
+        let reparsed: Config = toml::from_str(&toml_str).unwrap();
+        assert_eq!(reparsed.device_id, config.device_id);
+        assert_eq!(reparsed.output_directory, config.output_directory);
+    }
+
+    #[test]
+    fn test_multi_profile_config_round_trip() {
+        let multi = r#"
+            default = "partner"
+            output_directory = "/tmp/books"
+
+            [[profile]]
+            name = "me"
+            device_id = "11111111-1111-1111-1111-111111111111"
+            user_idx = "111"
+
+            [[profile]]
+            name = "partner"
+            device_id = "22222222-2222-2222-2222-222222222222"
+            user_idx = "222"
+            output_directory = "/tmp/partner"
+        "#;
+
+        let config: Config = toml::from_str(multi).unwrap();
+        assert_eq!(config.profiles.len(), 2);
+        assert_eq!(config.default_profile.as_deref(), Some("partner"));
+
+        let reparsed: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(reparsed.profiles, config.profiles);
+        assert_eq!(reparsed.default_profile, config.default_profile);
+
+        // The default key wins when no profile is requested
+        let mut selected = config.clone();
+        selected.select_profile(None).unwrap();
+        assert_eq!(selected.user_idx, "222");
+        assert_eq!(selected.output_directory.as_deref(), Some("/tmp/partner"));
+
+        // A profile without its own output directory keeps the shared one
+        let mut selected = config.clone();
+        selected.select_profile(Some("me")).unwrap();
+        assert_eq!(selected.device_id, "11111111-1111-1111-1111-111111111111");
+        assert_eq!(selected.output_directory.as_deref(), Some("/tmp/books"));
+
+        let mut without_default = config.clone();
+        without_default.default_profile = None;
+        without_default.select_profile(None).unwrap();
+        assert_eq!(without_default.user_idx, "111");
+
+        let error = config.clone().select_profile(Some("guest")).unwrap_err();
+        assert!(error.contains("me, partner"));
+    }
+    
+    #[test]
+    fn test_setup_config_file_round_trip() {
+        let config = Config {
+            device_id: TEST_DEVICE_ID.to_string(),
+            user_idx: "123".to_string(),
+            output_directory: Some("/tmp/books".to_string()),
+            backup_originals: false,
+            ..Default::default()
+        };
+
+        let rendered = render_config_file(&config).unwrap();
+        assert!(rendered.starts_with("# Ridiculous Enhanced Configuration"));
+
+        let parsed: Config = toml::from_str(&rendered).unwrap();
+        assert_eq!(parsed.device_id, TEST_DEVICE_ID);
+        assert_eq!(parsed.user_idx, "123");
+        assert_eq!(parsed.output_directory.as_deref(), Some("/tmp/books"));
+        assert!(!parsed.backup_originals);
+    }
+    
+    #[test]
+    fn test_client_options_from_config() {
+        use std::time::Duration;
+
+        let options = client_options(&Config::default());
+        assert_eq!(options.user_agent, ClientOptions::default().user_agent);
+        assert_eq!(options.timeout, Duration::from_secs(10));
+        assert_eq!(options.proxy, None);
+
+        let config = Config {
+            user_agent: Some("Mozilla/5.0".to_string()),
+            api_timeout_seconds: Some(60),
+            ca_cert: Some("/etc/ssl/corp.pem".to_string()),
+            ..Default::default()
+        };
+        let options = client_options(&config);
+        assert_eq!(options.user_agent, "Mozilla/5.0");
+        assert_eq!(options.timeout, Duration::from_secs(60));
+        assert_eq!(options.ca_cert, Some(std::path::PathBuf::from("/etc/ssl/corp.pem")));
+    }
+
     #[test]
     fn test_config_serialization() {
         let config = Config {
+            version: CONFIG_VERSION,
             device_id: "test-device-id".to_string(),
             user_idx: "12345".to_string(),
-            verbose: true,
+            verbose: Verbosity::Discovery,
             organize_output: true,
             backup_originals: false,
             output_directory: Some("/tmp/books".to_string()),
+            library_path: None,
+            extra_library_paths: vec!["/mnt/external/Ridibooks/library".to_string()],
+            fast_scan: false,
+            min_confidence: 0.5,
+            on_existing: OnExisting::Rename,
+            recompress: Recompress::Store,
+            compress: Compress::Zstd,
+            flatten: true,
+            include_samples: true,
             max_retries: 5,
             timeout_seconds: 60,
+            max_book_time_seconds: 600,
+            state_path: Some("/var/lib/ridiculous/state.json".to_string()),
+            proxy: Some("http://proxy.example.com:3128".to_string()),
+            ca_cert: None,
+            user_agent: Some("Mozilla/5.0".to_string()),
+            api_timeout_seconds: Some(45),
+            default_profile: None,
+            profiles: Vec::new(),
         };
         
         let toml_str = toml::to_string(&config).unwrap();
@@ -124,10 +2389,24 @@ mod tests {
         
         assert_eq!(deserialized.device_id, "test-device-id");
         assert_eq!(deserialized.user_idx, "12345");
-        assert!(deserialized.verbose);
+        assert_eq!(deserialized.verbose, Verbosity::Discovery);
+        assert!(toml_str.contains("verbose = 2"));
         assert!(deserialized.organize_output);
         assert!(!deserialized.backup_originals);
         assert_eq!(deserialized.max_retries, 5);
         assert_eq!(deserialized.timeout_seconds, 60);
+        assert_eq!(deserialized.extra_library_paths, config.extra_library_paths);
+        assert!(toml_str.contains(r#"on_existing = "rename""#));
+        assert_eq!(deserialized.on_existing, OnExisting::Rename);
+        assert!(toml_str.contains(r#"recompress = "store""#));
+        assert_eq!(deserialized.recompress, Recompress::Store);
+        assert!(toml_str.contains(r#"compress = "zstd""#));
+        assert_eq!(deserialized.compress, Compress::Zstd);
+        assert_eq!(deserialized.version, CONFIG_VERSION);
+        assert_eq!(deserialized.state_path, config.state_path);
+        assert_eq!(deserialized.proxy, config.proxy);
+        assert_eq!(deserialized.user_agent, config.user_agent);
+        assert_eq!(deserialized.api_timeout_seconds, Some(45));
+        assert_eq!(deserialized.min_confidence, 0.5);
     }
 }
\ No newline at end of file
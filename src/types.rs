@@ -1,45 +1,331 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::ffi::OsString;
 
+use crate::decrypt::DrmVersion;
+
+/// Schema version written to config files; bump it together with a step in `Config::migrate`
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Time limit for decrypting one book, in seconds, unless `timeout_seconds` says otherwise
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 30;
+
+/// Auto-detected library folders scoring below this aren't scanned, unless
+/// `min_confidence` says otherwise. Any folder with books scores at least 0.4.
+pub const DEFAULT_MIN_CONFIDENCE: f32 = 0.3;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]  // ← Added this for automatic defaults on missing fields
 pub struct Config {
+    /// Config file schema version; files from before it was recorded read as 0
+    #[serde(default)]
+    pub version: u32,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub device_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     pub user_idx: String,
-    pub verbose: bool,
+    pub verbose: Verbosity,
     pub organize_output: bool,
     pub backup_originals: bool,
     pub output_directory: Option<String>,
     pub library_path: Option<String>,
+    /// Additional library roots searched before the platform defaults, e.g. on an external drive
+    pub extra_library_paths: Vec<String>,
+    /// Stop discovery at the first library root with books instead of scanning them all
+    pub fast_scan: bool,
+    /// Confidence score (0.0-1.0) an auto-detected library folder needs to be scanned
+    pub min_confidence: f32,
+    /// What to do when a book's output file already exists
+    pub on_existing: OnExisting,
+    /// How entries are compressed when a decrypted v11 book is written back out
+    pub recompress: Recompress,
+    /// Compress each output file on its own, e.g. for archiving
+    pub compress: Compress,
+    /// Write EPUBs and comics as folders of their decrypted contents instead of single files
+    pub flatten: bool,
+    /// Also decrypt free sample chapters, which are skipped otherwise
+    pub include_samples: bool,
     pub max_retries: u32,
     pub timeout_seconds: u64,
+    /// Time limit for a book across all its attempts and retry delays; 0 for none
+    pub max_book_time_seconds: u64,
+    /// Where the processing state used by `--resume` is kept, instead of the cache directory
+    pub state_path: Option<String>,
+    /// Proxy for RIDI API requests, instead of the `HTTPS_PROXY`/`HTTP_PROXY` environment variables
+    pub proxy: Option<String>,
+    /// Extra root certificates (PEM) to trust, for proxies that intercept TLS
+    pub ca_cert: Option<String>,
+    /// User agent sent to the RIDI API, for when it rejects the default one
+    pub user_agent: Option<String>,
+    /// Time limit for each RIDI API request (10 seconds if unset)
+    pub api_timeout_seconds: Option<u64>,
+    /// Name of the profile used when `--profile` isn't given (defaults to the first one)
+    #[serde(rename = "default", skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    /// `[[profile]]` tables for configs shared by several RIDI accounts
+    #[serde(rename = "profile", skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<Profile>,
+}
+
+/// How an output file that already exists is handled when a book is written.
+/// Whether the book is processed at all is decided separately (see `--force`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OnExisting {
+    /// Leave the existing file alone
+    Skip,
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+    /// Write next to it as `<name> (1).<ext>`, `<name> (2).<ext>`, ...
+    Rename,
+}
+
+/// Compression used for the entries of a decrypted v11 book. Comics are mostly
+/// JPEGs that don't shrink any further, so deflating them again only costs time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Recompress {
+    /// Use each entry's compression from the original book
+    #[default]
+    Keep,
+    /// Deflate every entry (except the EPUB `mimetype`, which must stay stored)
+    Deflate,
+    /// Store every entry uncompressed
+    Store,
+}
+
+/// Compression of whole output files, for archiving: `<name>.pdf.zst` instead of
+/// `<name>.pdf`. EPUBs and comics are ZIPs already, so it mostly pays off for PDFs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compress {
+    /// Write outputs as they are
+    #[default]
+    None,
+    /// gzip, as `<name>.<ext>.gz`
+    Gzip,
+    /// Zstandard, as `<name>.<ext>.zst`
+    Zstd,
+}
+
+impl Compress {
+    /// The extension added to outputs compressed this way, without the dot
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Compress::None => None,
+            Compress::Gzip => Some("gz"),
+            Compress::Zstd => Some("zst"),
+        }
+    }
+
+    /// `path` with this compression's extension added, e.g. `book.pdf` to `book.pdf.zst`
+    pub fn apply(self, path: PathBuf) -> PathBuf {
+        match self.extension() {
+            Some(extension) => {
+                let mut path = path.into_os_string();
+                path.push(".");
+                path.push(extension);
+                PathBuf::from(path)
+            }
+            None => path,
+        }
+    }
+
+    /// How the output at `path` was compressed, going by its extension
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().map(|ext| ext.to_string_lossy().to_lowercase()).as_deref() {
+            Some("gz") => Compress::Gzip,
+            Some("zst") => Compress::Zstd,
+            _ => Compress::None,
+        }
+    }
+}
+
+/// How much is printed beyond the normal output: each `-v` adds a level. Written to
+/// config files as the number of `-v`s; `true` and `false` from older files read as 1 and 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    #[default]
+    Normal,
+    /// `-v`: what's decided for each book, e.g. skipped, retried or written where
+    Books,
+    /// `-vv`: also which library paths are probed and how likely each is to be the library
+    Discovery,
+    /// `-vvv`: also the steps of decrypting, such as key lengths and block counts, but
+    /// never the keys themselves
+    Decryption,
+}
+
+impl Verbosity {
+    /// The level for `count` `-v` flags; more than three are the same as three
+    pub fn from_count(count: u8) -> Self {
+        match count {
+            0 => Verbosity::Normal,
+            1 => Verbosity::Books,
+            2 => Verbosity::Discovery,
+            _ => Verbosity::Decryption,
+        }
+    }
+}
+
+impl Serialize for Verbosity {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for Verbosity {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Level {
+            Flag(bool),
+            Count(u8),
+        }
+
+        Ok(match Level::deserialize(deserializer)? {
+            Level::Flag(verbose) => Verbosity::from_count(verbose.into()),
+            Level::Count(count) => Verbosity::from_count(count),
+        })
+    }
+}
+
+/// One RIDI account in a multi-profile config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub device_id: String,
+    pub user_idx: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_directory: Option<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             device_id: String::new(),
             user_idx: String::new(),
-            verbose: false,
+            verbose: Verbosity::Normal,
             organize_output: false,
             backup_originals: true,
             output_directory: None,
             library_path: None,
+            extra_library_paths: Vec::new(),
+            fast_scan: false,
+            min_confidence: DEFAULT_MIN_CONFIDENCE,
+            on_existing: OnExisting::default(),
+            recompress: Recompress::default(),
+            compress: Compress::default(),
+            flatten: false,
+            include_samples: false,
             max_retries: 3,
-            timeout_seconds: 30,
+            timeout_seconds: DEFAULT_TIMEOUT_SECONDS,
+            max_book_time_seconds: 0,
+            state_path: None,
+            proxy: None,
+            ca_cert: None,
+            user_agent: None,
+            api_timeout_seconds: None,
+            default_profile: None,
+            profiles: Vec::new(),
         }
     }
 }
 
+impl Config {
+    /// Upgrades a config read from an older file to `CONFIG_VERSION`. Returns whether a
+    /// setting was converted, i.e. whether the file needs rewriting; a file that only
+    /// lacks the version key reads the same and is left alone. Configs from a newer
+    /// version are left as they are.
+    pub fn migrate(&mut self) -> bool {
+        if self.version >= CONFIG_VERSION {
+            return false;
+        }
+        self.version = CONFIG_VERSION;
+
+        // 0 -> 1: the account moves from the top-level keys into a [[profile]] table, so a
+        // second account can be added next to it. Settings missing from the file already
+        // took their defaults when it was parsed.
+        if !self.profiles.is_empty() || (self.device_id.is_empty() && self.user_idx.is_empty()) {
+            return false;
+        }
+        self.profiles.push(Profile {
+            name: "default".to_string(),
+            device_id: std::mem::take(&mut self.device_id),
+            user_idx: std::mem::take(&mut self.user_idx),
+            output_directory: None,
+        });
+        true
+    }
+
+    /// Moves the top-level account into the profile `select_profile(None)` picks, for
+    /// writing the config back; flat configs keep it at the top level
+    pub fn store_account(&mut self) {
+        let index = self.default_profile.as_ref()
+            .and_then(|name| self.profiles.iter().position(|profile| &profile.name == name))
+            .unwrap_or(0);
+        if let Some(profile) = self.profiles.get_mut(index) {
+            profile.device_id = std::mem::take(&mut self.device_id);
+            profile.user_idx = std::mem::take(&mut self.user_idx);
+        }
+    }
+
+    /// Copies the chosen profile's account into the top-level fields. Picks `name`,
+    /// else the `default` key, else the first profile; flat configs are left untouched.
+    pub fn select_profile(&mut self, name: Option<&str>) -> Result<(), String> {
+        if self.profiles.is_empty() {
+            return match name {
+                Some(name) => Err(format!("Profile '{}' not found: the config file defines no [[profile]] entries", name)),
+                None => Ok(()),
+            };
+        }
+
+        let wanted = name.or(self.default_profile.as_deref());
+        let profile = match wanted {
+            Some(wanted) => self.profiles.iter()
+                .find(|profile| profile.name == wanted)
+                .ok_or_else(|| format!(
+                    "Profile '{}' not found. Available profiles: {}",
+                    wanted,
+                    self.profiles.iter().map(|profile| profile.name.as_str()).collect::<Vec<_>>().join(", ")
+                ))?,
+            None => &self.profiles[0],
+        }.clone();
+
+        self.device_id = profile.device_id;
+        self.user_idx = profile.user_idx;
+        if profile.output_directory.is_some() {
+            self.output_directory = profile.output_directory;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BookInfo {
     pub id: String,
     pub format: BookFormat,
     pub path: PathBuf, // Directory containing the book files
     pub title: Option<String>,
+    pub author: Option<String>,
+    pub series: Option<String>,
     pub book_filename: String, // Actual filename (may include version like .v11.epub)
-    pub is_v11: bool, // Whether this uses v11 DRM format
+    pub drm_version: DrmVersion,
+    /// A free sample or preview rather than the purchased book
+    pub is_sample: bool,
+}
+
+// RIDI names the file of a free sample like the book's, plus a `sample` or `preview`
+// part: `{id}.sample.epub`, `{id}_preview.v11.epub`. The metadata store can also flag
+// one, see metadata::enrich_books.
+fn is_sample_filename(book_filename: &str, book_id: &str) -> bool {
+    book_filename.strip_prefix(book_id)
+        .unwrap_or(book_filename)
+        .to_lowercase()
+        .split(['.', '_', '-'])
+        .any(|part| part == "sample" || part == "preview")
 }
 
 impl BookInfo {
@@ -51,26 +337,114 @@ impl BookInfo {
 
         let (format, book_filename) = Self::detect_format_and_filename(&book_dir, &id)?;
 
-        // Check if this is a v11 format book (filename contains .v)
-        let is_v11 = book_filename.contains(".v");
-
-        Ok(Self {
+        let is_sample = is_sample_filename(&book_filename, &id);
+        let mut book = Self {
             id,
             format,
             path: book_dir,
             title: None,
+            author: None,
+            series: None,
             book_filename,
-            is_v11,
-        })
+            drm_version: DrmVersion::V1,
+            is_sample,
+        };
+
+        // The content tells v1 from v11 (a .v11 file that isn't a ZIP is a cut-off
+        // download, see incomplete_download). Any other version marker is kept so it's
+        // refused rather than tried as v1, and unreadable files fall back to the marker.
+        let marker = DrmVersion::from_filename(&book.book_filename);
+        book.drm_version = match (marker, book.detect_drm_version()) {
+            (Some(DrmVersion::Unknown(version)), _) => DrmVersion::Unknown(version),
+            (_, Some(detected)) => detected,
+            (marker, None) => marker.unwrap_or(DrmVersion::V1),
+        };
+
+        // Entry names of v11 and plaintext ZIPs are readable, so comics can be told apart
+        // from EPUBs before decrypting; v1 comics are only recognized once decrypted
+        if book.format == BookFormat::Epub {
+            if let Some(format) = std::fs::File::open(book.get_book_file_path()).ok().and_then(crate::decrypt::detect_zip_format) {
+                book.format = format;
+            }
+        }
+
+        Ok(book)
     }
-    
+
+    /// Detects the DRM scheme from the book file's content: [`DrmVersion::V11`] for a
+    /// ZIP container (local file header plus end-of-central-directory record),
+    /// [`DrmVersion::V1`] for anything else, `None` if the file can't be read.
+    pub fn detect_drm_version(&self) -> Option<DrmVersion> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        const LOCAL_HEADER_SIGNATURE: &[u8] = b"PK\x03\x04";
+        const END_OF_CENTRAL_DIRECTORY_SIGNATURE: &[u8] = b"PK\x05\x06";
+        // The EOCD record is 22 bytes plus an optional comment of up to 65535 bytes
+        const MAX_EOCD_SEARCH: u64 = 22 + 65535;
+
+        let mut file = std::fs::File::open(self.get_book_file_path()).ok()?;
+        let len = file.metadata().ok()?.len();
+
+        let mut header = [0u8; 4];
+        if len < header.len() as u64 || file.read_exact(&mut header).is_err() {
+            return Some(DrmVersion::V1);
+        }
+        if header != LOCAL_HEADER_SIGNATURE {
+            return Some(DrmVersion::V1);
+        }
+
+        let tail_len = len.min(MAX_EOCD_SEARCH);
+        file.seek(SeekFrom::Start(len - tail_len)).ok()?;
+        let mut tail = Vec::with_capacity(tail_len as usize);
+        file.read_to_end(&mut tail).ok()?;
+
+        if tail.windows(4).any(|window| window == END_OF_CENTRAL_DIRECTORY_SIGNATURE) {
+            Some(DrmVersion::V11)
+        } else {
+            Some(DrmVersion::V1)
+        }
+    }
+
+    /// Why this book looks only partly downloaded, if it does: RIDI's in-progress
+    /// marker file is still next to it, or the book file is empty or cut off
+    /// partway through. Unreadable files are left for decryption to report.
+    pub fn incomplete_download(&self) -> Option<String> {
+        const MARKER_SUFFIXES: [&str; 5] = [".download", ".downloading", ".crdownload", ".part", ".tmp"];
+        // v1 books are a 16-byte IV followed by whole AES blocks, at least one of them
+        const AES_BLOCK: u64 = 16;
+
+        if let Ok(entries) = std::fs::read_dir(&self.path) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_lowercase();
+                if MARKER_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+                    return Some(format!("download still in progress ({} found)", entry.file_name().to_string_lossy()));
+                }
+            }
+        }
+
+        let len = std::fs::metadata(self.get_book_file_path()).ok()?.len();
+        if len == 0 {
+            Some("the book file is empty".to_string())
+        } else if self.drm_version != DrmVersion::V1 {
+            None
+        } else if DrmVersion::from_filename(&self.book_filename) == Some(DrmVersion::V11) {
+            Some(format!("the book file ends before its ZIP directory ({} bytes)", len))
+        } else if len < 2 * AES_BLOCK || len % AES_BLOCK != 0 {
+            Some(format!("the book file is cut off ({} bytes, not a whole number of AES blocks)", len))
+        } else {
+            None
+        }
+    }
+
     fn detect_format_and_filename(book_dir: &PathBuf, book_id: &str) -> miette::Result<(BookFormat, String)> {
         // Try to find the actual book file in the directory
         // Files can be named {id}.epub or {id}.v*.epub (versioned)
         // IMPORTANT: Prioritize encrypted files (.v*.epub) over plain files
+        use std::io::Read;
 
         let mut plain_epub: Option<String> = None;
         let mut plain_pdf: Option<String> = None;
+        let mut unrecognized: Option<String> = None;
 
         for entry in std::fs::read_dir(book_dir).map_err(|e| miette::miette!("Cannot read book directory: {}", e))? {
             let entry = entry.map_err(|e| miette::miette!("Directory entry error: {}", e))?;
@@ -105,8 +479,16 @@ impl BookInfo {
                                         plain_pdf = Some(filename_str.to_string());
                                     }
                                 },
-                                _ => continue,
+                                // Key files and our own backups are never the book itself
+                                "dat" | "bak" => continue,
+                                _ => {
+                                    if unrecognized.is_none() {
+                                        unrecognized = Some(filename_str.to_string());
+                                    }
+                                }
                             }
+                        } else if unrecognized.is_none() {
+                            unrecognized = Some(filename_str.to_string());
                         }
                     }
                 }
@@ -122,6 +504,17 @@ impl BookInfo {
             return Ok((BookFormat::Pdf, pdf));
         }
 
+        // No known extension: let the file's content decide. If that doesn't either, the
+        // format stays Unknown rather than guessed, and the book is skipped unless the
+        // user says what it is
+        if let Some(filename) = unrecognized {
+            let mut header = Vec::with_capacity(64);
+            if let Ok(file) = std::fs::File::open(book_dir.join(&filename)) {
+                let _ = file.take(64).read_to_end(&mut header);
+            }
+            return Ok((BookFormat::from_magic_bytes(&header), filename));
+        }
+
         // If no book file found, return default (will fail later with proper error)
         Ok((BookFormat::Epub, format!("{}.epub", book_id)))
     }
@@ -144,75 +537,220 @@ impl BookInfo {
         filename
     }
     
+    /// `<Author>/<Title>/<Title>.<ext>` for `--organize`, with a `<Series>` folder
+    /// between author and title for books in a series. Falls back to the book id for
+    /// a missing title and to "Unknown Author" for a missing author.
+    pub fn get_organized_path(&self) -> PathBuf {
+        let title = self.title.as_deref()
+            .and_then(sanitize_path_component)
+            .unwrap_or_else(|| self.id.clone());
+        let author = self.author.as_deref()
+            .and_then(sanitize_path_component)
+            .unwrap_or_else(|| "Unknown Author".to_string());
+
+        let mut path = PathBuf::from(author);
+        if let Some(series) = self.series.as_deref().and_then(sanitize_path_component) {
+            path.push(series);
+        }
+        path.join(&title)
+            .join(format!("{}.{}", title, self.format.as_str()))
+    }
+    
+    /// Takes the title, author and series read from the book itself, keeping the
+    /// current values for anything it doesn't have.
+    pub fn apply_metadata(&mut self, metadata: EpubMetadata) {
+        self.title = metadata.title.or(self.title.take());
+        self.author = metadata.author.or(self.author.take());
+        self.series = metadata.series.or(self.series.take());
+    }
+
     pub fn get_display_name(&self) -> String {
         self.title.clone().unwrap_or_else(|| self.id.clone())
     }
     
-    pub fn is_already_decrypted(&self, config: &Config) -> bool {
-        // First check if the book file itself is already in plaintext (valid zip)
-        // This handles books that are already decrypted in their directory
-        if !self.is_v11 && !self.book_filename.contains(".v") {
-            // This is a plain epub/pdf without version marker
-            // Check if it's a valid zip (decrypted epubs are zip files)
-            let book_path = self.get_book_file_path();
-            if book_path.exists() {
-                // Try to open as zip to see if it's already decrypted
-                if let Ok(file) = std::fs::File::open(&book_path) {
-                    if let Ok(mut zip) = zip::ZipArchive::new(file) {
-                        // It's a valid zip, verify it's actually readable (has files)
-                        if zip.len() > 0 {
-                            // Try to read mimetype to confirm it's a valid EPUB
-                            if let Ok(_) = zip.by_name("mimetype") {
-                                return true;
-                            }
-                            // Or if it just has any files, consider it decrypted
-                            return true;
-                        }
-                    }
-                }
-            }
-        }
-
-        // Check if output file already exists in the output location
-        let output_path = if let Some(output_dir) = &config.output_directory {
-            // Check custom output directory if specified
-            PathBuf::from(output_dir).join(self.get_output_filename())
+    /// Directory decrypted books are written to: the configured output directory,
+    /// else the configured library path, else the library folder holding the book.
+    pub fn get_output_dir(&self, config: &Config) -> PathBuf {
+        if let Some(output_dir) = &config.output_directory {
+            PathBuf::from(output_dir)
         } else if let Some(library_path) = &config.library_path {
-            // Check in the library path (parent of book directories)
-            PathBuf::from(library_path).join(self.get_output_filename())
+            PathBuf::from(library_path)
         } else {
-            // Fallback: check in parent of book directory (library folder)
             self.path.parent()
-                .map(|p| p.join(self.get_output_filename()))
-                .unwrap_or_else(|| self.path.join(self.get_output_filename()))
-        };
-
-        output_path.exists()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|| self.path.clone())
+        }
     }
     
-    #[allow(dead_code)]  // ← Silences the warning
     pub fn format_file_size(&self) -> String {
-        match std::fs::metadata(&self.get_book_file_path()) {
-            Ok(metadata) => {
-                let size = metadata.len();
-                if size < 1024 {
-                    format!("{} B", size)
-                } else if size < 1024 * 1024 {
-                    format!("{:.1} KB", size as f64 / 1024.0)
-                } else {
-                    format!("{:.1} MB", size as f64 / (1024.0 * 1024.0))
-                }
-            }
+        match std::fs::metadata(self.get_book_file_path()) {
+            Ok(metadata) => format_size(metadata.len()),
             Err(_) => "Unknown size".to_string(),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Title, first author and series from an EPUB's OPF package document
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EpubMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub series: Option<String>,
+}
+
+/// Reads `<dc:title>`, the first `<dc:creator>` and the series (`calibre:series`
+/// or EPUB 3 `belongs-to-collection`) from a decrypted EPUB.
+/// All are `None` for non-EPUB data such as PDFs.
+pub fn read_epub_metadata<R: std::io::Read + std::io::Seek>(epub: R) -> EpubMetadata {
+    read_opf(epub)
+        .map(|opf| EpubMetadata {
+            title: read_dc_element(&opf, "title"),
+            author: read_dc_element(&opf, "creator"),
+            series: read_series(&opf),
+        })
+        .unwrap_or_default()
+}
+
+fn read_opf<R: std::io::Read + std::io::Seek>(epub: R) -> Option<String> {
+    use std::io::Read;
+
+    let mut zip = zip::ZipArchive::new(epub).ok()?;
+
+    // META-INF/container.xml points at the OPF; fall back to the first .opf entry
+    let opf_path = {
+        let mut container = String::new();
+        let from_container = zip.by_name("META-INF/container.xml").ok()
+            .and_then(|mut file| file.read_to_string(&mut container).ok())
+            .and_then(|_| {
+                regex::Regex::new(r#"full-path\s*=\s*"([^"]+)""#).ok()?
+                    .captures(&container)
+                    .map(|caps| caps[1].to_string())
+            });
+
+        match from_container {
+            Some(path) => path,
+            None => zip.file_names()
+                .find(|name| name.to_lowercase().ends_with(".opf"))?
+                .to_string(),
+        }
+    };
+
+    let mut opf = String::new();
+    zip.by_name(&opf_path).ok()?.read_to_string(&mut opf).ok()?;
+    Some(opf)
+}
+
+fn read_dc_element(opf: &str, element: &str) -> Option<String> {
+    let element_re = regex::Regex::new(&format!(r"(?s)<dc:{0}[^>]*>(.*?)</dc:{0}>", element)).ok()?;
+    let raw_value = element_re.captures(opf)?.get(1)?.as_str();
+    decode_xml_text(raw_value)
+}
+
+// `<meta name="calibre:series" content="..."/>`, else `<meta property="belongs-to-collection">...</meta>`
+fn read_series(opf: &str) -> Option<String> {
+    let meta_re = regex::Regex::new(r"<meta\b[^>]*>").ok()?;
+    let content_re = regex::Regex::new(r#"\bcontent\s*=\s*"([^"]*)""#).ok()?;
+    let calibre_series = meta_re.find_iter(opf)
+        .map(|tag| tag.as_str())
+        .filter(|tag| tag.contains(r#"name="calibre:series""#))
+        .find_map(|tag| decode_xml_text(content_re.captures(tag)?.get(1)?.as_str()));
+    if calibre_series.is_some() {
+        return calibre_series;
+    }
+
+    let collection_re = regex::Regex::new(r#"(?s)<meta\b[^>]*property="belongs-to-collection"[^>]*>(.*?)</meta>"#).ok()?;
+    decode_xml_text(collection_re.captures(opf)?.get(1)?.as_str())
+}
+
+fn decode_xml_text(raw_value: &str) -> Option<String> {
+    let value = raw_value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    let value = value.trim();
+
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// `size` bytes in B, KB, MB or GB, with one decimal above bytes.
+pub fn format_size(size: u64) -> String {
+    const KB: f64 = 1024.0;
+    if size < 1024 {
+        format!("{} B", size)
+    } else if (size as f64) < KB * KB {
+        format!("{:.1} KB", size as f64 / KB)
+    } else if (size as f64) < KB * KB * KB {
+        format!("{:.1} MB", size as f64 / (KB * KB))
+    } else {
+        format!("{:.1} GB", size as f64 / (KB * KB * KB))
+    }
+}
+
+/// All but the last 4 characters replaced with `*`, so a credential such as the
+/// device_id can still be told apart from others without being usable.
+pub fn redact(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let visible = if chars.len() > 4 { 4 } else { 0 };
+    let hidden = chars.len() - visible;
+
+    "*".repeat(hidden) + &chars[hidden..].iter().collect::<String>()
+}
+
+/// Makes `name` safe to use as a single path component on every platform, so organized
+/// libraries survive being copied to Windows or FAT-formatted drives: path separators,
+/// `: * ? " < > |` and control characters become `_`, trailing dots and spaces are
+/// dropped, and reserved Windows device names (CON, NUL, COM1, ...) get a `_` suffix.
+/// Returns `None` when nothing usable is left.
+pub fn sanitize_path_component(name: &str) -> Option<String> {
+    const MAX_CHARS: usize = 100;
+    const RESERVED: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+    let sanitized: String = name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_CHARS)
+        .collect();
+    let sanitized = sanitized.trim().trim_end_matches(['.', ' ']);
+
+    if sanitized.is_empty() || sanitized.chars().all(|c| c == '.' || c == '_') {
+        return None;
+    }
+
+    // Windows reserves these names even with an extension (e.g. "nul.txt")
+    let stem = sanitized.split('.').next().unwrap_or_default().to_uppercase();
+    let is_reserved = RESERVED.contains(&stem.as_str())
+        || ((stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.len() == 4
+            && stem.as_bytes()[3].is_ascii_digit()
+            && stem.as_bytes()[3] != b'0');
+
+    if is_reserved {
+        Some(format!("{}_", sanitized))
+    } else {
+        Some(sanitized.to_string())
+    }
+}
+
+/// Only EPUB and PDF can be chosen with `--assume-format`; comics are told apart
+/// from EPUBs after decrypting.
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum)]
 pub enum BookFormat {
     Epub,
     Pdf,
+    /// Image-based book: a ZIP of page images without an OPF package, written as `.cbz`
+    #[value(skip)]
+    Comic,
     #[allow(dead_code)]  // ← Silences the warning
+    #[value(skip)]
     Unknown,
 }
 
@@ -222,14 +760,37 @@ impl BookFormat {
         match ext.to_lowercase().as_str() {
             "epub" => BookFormat::Epub,
             "pdf" => BookFormat::Pdf,
+            "cbz" => BookFormat::Comic,
             _ => BookFormat::Unknown,
         }
     }
     
+    /// Recognizes a PDF by its `%PDF-` header and an EPUB by a ZIP whose first
+    /// entry is the uncompressed `mimetype` file naming `application/epub+zip`.
+    pub fn from_magic_bytes(bytes: &[u8]) -> Self {
+        // Local file header (30 bytes) followed by the "mimetype" name and its content
+        const EPUB_SIGNATURE_OFFSET: usize = 30;
+
+        if bytes.starts_with(b"%PDF-") {
+            return BookFormat::Pdf;
+        }
+
+        let epub_signature = bytes.starts_with(b"PK\x03\x04")
+            && bytes.get(EPUB_SIGNATURE_OFFSET..)
+                .is_some_and(|rest| rest.starts_with(b"mimetypeapplication/epub+zip"));
+
+        if epub_signature {
+            BookFormat::Epub
+        } else {
+            BookFormat::Unknown
+        }
+    }
+
     pub fn as_str(&self) -> &str {
         match self {
             BookFormat::Epub => "epub",
             BookFormat::Pdf => "pdf",
+            BookFormat::Comic => "cbz",
             BookFormat::Unknown => "unknown",
         }
     }
@@ -239,10 +800,15 @@ impl BookFormat {
 pub struct LibraryLocation {
     pub path: PathBuf,
     pub confidence: f32,
-    #[allow(dead_code)]  // ← Silences the warning
     pub source: LibrarySource,
 }
 
+impl LibraryLocation {
+    pub fn confidence_percent(&self) -> u32 {
+        (self.confidence * 100.0) as u32
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[allow(dead_code)]  // ← Silences all warnings for this enum
 pub enum LibrarySource {
@@ -0,0 +1,165 @@
+//! `--watch`: notices book folders RIDI creates or writes to and says when they've settled.
+//!
+//! RIDI writes the `.dat` and the book file separately and large books take a while, so a
+//! folder is only reported once nothing in it has changed for the settle time and the
+//! sizes of its files match between two checks. Whether it's a complete book is left to
+//! the caller, which runs it through the usual discovery and decryption.
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Starts watching `roots` recursively, sending every changed path to the returned
+/// channel. Events stop when the watcher is dropped.
+pub fn watch_roots(roots: &[PathBuf]) -> notify::Result<(RecommendedWatcher, mpsc::UnboundedReceiver<PathBuf>)> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) => {
+            for path in event.paths {
+                let _ = sender.send(path);
+            }
+        }
+        Err(e) => tracing::warn!("File watcher error: {}", e),
+    })?;
+
+    // A root inside another one is already covered by the recursive watch
+    for root in roots {
+        if !roots.iter().any(|other| other != root && root.starts_with(other)) {
+            watcher.watch(root, RecursiveMode::Recursive)?;
+        }
+    }
+
+    Ok((watcher, receiver))
+}
+
+/// The book folder `path` belongs to: the folder directly inside the deepest root
+/// containing it, e.g. `<root>/_<user_idx>/<id>` rather than `<root>/_<user_idx>`.
+pub fn book_dir_for(path: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    let root = roots.iter()
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())?;
+
+    match path.strip_prefix(root).ok()?.components().next()? {
+        Component::Normal(name) => Some(root.join(name)),
+        _ => None,
+    }
+}
+
+/// Book folders with recent changes, waiting to settle
+pub struct PendingBooks {
+    settle: Duration,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+struct Pending {
+    last_change: Instant,
+    // File names and sizes at the last check
+    sizes: Option<Vec<(String, u64)>>,
+}
+
+impl PendingBooks {
+    pub fn new(settle: Duration) -> Self {
+        Self { settle, pending: HashMap::new() }
+    }
+
+    /// Something changed in `book_dir` at `now`
+    pub fn touch(&mut self, book_dir: PathBuf, now: Instant) {
+        self.pending.entry(book_dir)
+            .and_modify(|pending| pending.last_change = now)
+            .or_insert(Pending { last_change: now, sizes: None });
+    }
+
+    /// Folders where nothing has changed for the settle time, neither by the watcher's
+    /// account nor in their file sizes, which are checked on every call. Folders that
+    /// have disappeared are dropped.
+    pub fn take_settled(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut settled = Vec::new();
+
+        self.pending.retain(|book_dir, pending| {
+            let Some(sizes) = file_sizes(book_dir) else {
+                return false;
+            };
+            // Catches writes the watcher didn't report
+            if pending.sizes.as_ref() != Some(&sizes) {
+                pending.sizes = Some(sizes);
+                pending.last_change = pending.last_change.max(now);
+            }
+            if now.duration_since(pending.last_change) < self.settle {
+                return true;
+            }
+            settled.push(book_dir.clone());
+            false
+        });
+
+        settled.sort();
+        settled
+    }
+}
+
+fn file_sizes(book_dir: &Path) -> Option<Vec<(String, u64)>> {
+    let mut sizes: Vec<_> = fs::read_dir(book_dir).ok()?
+        .flatten()
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            metadata.is_file().then(|| (entry.file_name().to_string_lossy().to_string(), metadata.len()))
+        })
+        .collect();
+    sizes.sort();
+    Some(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_book_dir_for() {
+        let roots = [PathBuf::from("/library"), PathBuf::from("/library/_123")];
+
+        assert_eq!(
+            book_dir_for(Path::new("/library/_123/456/456.v11.epub"), &roots),
+            Some(PathBuf::from("/library/_123/456"))
+        );
+        assert_eq!(book_dir_for(Path::new("/library/789/789.dat"), &roots), Some(PathBuf::from("/library/789")));
+        assert_eq!(book_dir_for(Path::new("/library/_123/456"), &roots), Some(PathBuf::from("/library/_123/456")));
+        assert_eq!(book_dir_for(Path::new("/library"), &roots), None);
+        assert_eq!(book_dir_for(Path::new("/elsewhere/456/456.dat"), &roots), None);
+    }
+
+    #[test]
+    fn test_pending_books_wait_for_stable_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let book_dir = temp_dir.path().join("1234567890");
+        fs::create_dir_all(&book_dir).unwrap();
+        fs::write(book_dir.join("1234567890.dat"), b"dat").unwrap();
+
+        let settle = Duration::from_secs(3);
+        let start = Instant::now();
+        let mut pending = PendingBooks::new(settle);
+        pending.touch(book_dir.clone(), start);
+
+        // The first check sees the files for the first time, which counts as a change
+        let first_check = start + Duration::from_secs(1);
+        assert!(pending.take_settled(first_check).is_empty());
+        assert!(pending.take_settled(first_check + Duration::from_secs(2)).is_empty());
+
+        // The book file shows up without an event: its size changed, so wait again
+        fs::write(book_dir.join("1234567890.v11.epub"), b"partial").unwrap();
+        assert!(pending.take_settled(first_check + settle).is_empty());
+        assert!(pending.take_settled(first_check + settle * 2 - Duration::from_secs(1)).is_empty());
+
+        // Unchanged for a whole settle period
+        assert_eq!(pending.take_settled(first_check + settle * 2), vec![book_dir.clone()]);
+        assert!(pending.pending.is_empty());
+
+        // A new change restarts the wait, and a removed folder is dropped
+        pending.touch(book_dir.clone(), start + settle * 4);
+        assert!(pending.take_settled(start + settle * 4).is_empty());
+        fs::remove_dir_all(&book_dir).unwrap();
+        assert!(pending.take_settled(start + settle * 6).is_empty());
+        assert!(pending.pending.is_empty());
+    }
+}